@@ -0,0 +1,1064 @@
+/// An inventory of the disks and partitions on the machine, for choosing where to install to and
+/// persist data, and for finding the installation media. Everything is read from sysfs and from
+/// the devices' own partition tables and filesystem superblocks, so it works the same whether or
+/// not blkid or udev are around.
+use crate::StorageIdentifier;
+use crate::install::partition::{partition_name, split_partition};
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use log::debug;
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Sizes in sysfs are always in 512 byte sectors, whatever the device's real sector size.
+const SYSFS_SECTOR_SIZE: u64 = 512;
+/// The ISO9660 primary volume descriptor is in the 16th 2KiB sector.
+const ISO9660_PVD_OFFSET: u64 = 16 * 2048;
+/// The ext2/3/4 superblock starts 1KiB into the filesystem.
+const EXT_SUPERBLOCK_OFFSET: u64 = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+/// Extents, 64 bit block numbers or flexible block groups make a filesystem ext4.
+const EXT_INCOMPAT_EXT4: u32 = 0x40 | 0x80 | 0x200;
+/// Swap space is marked at the end of its first page.
+const SWAP_PAGE_SIZE: u64 = 4096;
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const XFS_MAGIC: &[u8] = b"XFSB";
+/// The btrfs superblock starts 64KiB into the filesystem.
+const BTRFS_SUPERBLOCK_OFFSET: u64 = 64 << 10;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+/// How much of each end of a device [`is_blank`] checks.
+const BLANK_CHECK_SIZE: u64 = 1 << 20;
+
+/// Whether a block device is a whole disk or a partition of one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Disk,
+    Partition,
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceKind::Disk => write!(f, "disk"),
+            DeviceKind::Partition => write!(f, "part"),
+        }
+    }
+}
+
+/// The kind of partition table on a disk, named as blkid names them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionTable {
+    /// An MBR partition table.
+    Dos,
+    Gpt,
+}
+
+impl fmt::Display for PartitionTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionTable::Dos => write!(f, "dos"),
+            PartitionTable::Gpt => write!(f, "gpt"),
+        }
+    }
+}
+
+/// What's found in a filesystem (or swap or encrypted volume) superblock.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Filesystem {
+    /// The type, named as blkid and mount name it, eg `ext4` or `iso9660`.
+    #[serde(rename = "type")]
+    pub fs_type: String,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BlockDevice {
+    /// The kernel's name for the device, eg `sda1`.
+    pub name: String,
+    pub kind: DeviceKind,
+    /// The disk a partition is on.
+    pub disk: Option<String>,
+    /// The size in bytes.
+    pub size: u64,
+    /// Whether the device (or the disk a partition is on) is a spinning disk.
+    pub rotational: bool,
+    /// Whether the device (or the disk a partition is on) is removable media, such as an SD card.
+    pub removable: bool,
+    pub partition_table: Option<PartitionTable>,
+    pub filesystem: Option<Filesystem>,
+    /// The serial number a disk reports.
+    pub serial: Option<String>,
+    /// The World Wide Name of a disk, such as `naa.5000c500a1b2c3d4`.
+    pub wwn: Option<String>,
+    /// A partition's UUID from the partition table.
+    pub partuuid: Option<String>,
+}
+
+impl BlockDevice {
+    /// The device node, eg `/dev/sda1`.
+    pub fn path(&self) -> String {
+        format!("/dev/{}", self.name)
+    }
+
+    /// The partition number, for partitions.
+    pub fn number(&self) -> Option<u32> {
+        match self.kind {
+            DeviceKind::Disk => None,
+            DeviceKind::Partition => split_partition(&self.name).map(|(_, n)| n),
+        }
+    }
+}
+
+/// Lists the names of the block devices the kernel knows about, disks and partitions alike.
+pub fn block_devices(system: &SystemRoot) -> Result<Vec<String>, Error> {
+    let dir = system.path("/sys/class/block");
+    let mut names: Vec<String> = fs::read_dir(&dir)
+        .map_err(|e| anyhow!("Unable to list {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// The disk a partition is on, or `None` if `name` is a whole disk.
+pub fn parent_disk(system: &SystemRoot, name: &str) -> Option<String> {
+    let sys = system.path(&format!("/sys/class/block/{}", name));
+    if !sys.join("partition").exists() {
+        return None;
+    }
+    // In sysfs proper, partitions are found in the directory of their disk.
+    fs::canonicalize(&sys)
+        .ok()
+        .and_then(|path| Some(path.parent()?.file_name()?.to_string_lossy().to_string()))
+        .filter(|disk| {
+            system
+                .path(&format!("/sys/class/block/{}/{}", disk, name))
+                .exists()
+        })
+        .or_else(|| split_partition(name).map(|(disk, _)| disk))
+}
+
+/// Lists every disk and partition with anything on it, each disk followed by its partitions.
+/// Devices with nothing in them, like unused loop devices and empty card readers, are left out.
+pub fn inventory(system: &SystemRoot) -> Result<Vec<BlockDevice>, Error> {
+    let mut devices = vec![];
+    // The PARTUUIDs of each disk's partitions, which come from the disk's partition table.
+    let mut partuuids: HashMap<String, PartUuids> = HashMap::new();
+    for name in block_devices(system)? {
+        let sys = |device: &str, attribute: &str| {
+            fs::read_to_string(system.path(&format!("/sys/class/block/{}/{}", device, attribute)))
+                .map(|value| value.trim().to_string())
+                .ok()
+                .filter(|value| !value.is_empty())
+        };
+        let size = sys(&name, "size")
+            .and_then(|size| size.parse::<u64>().ok())
+            .unwrap_or(0)
+            * SYSFS_SECTOR_SIZE;
+        if size == 0 {
+            continue;
+        }
+        let disk = parent_disk(system, &name);
+        // Partitions don't have queue or removable attributes of their own.
+        let whole = disk.clone().unwrap_or_else(|| name.clone());
+        let flag = |attribute: &str| sys(&whole, attribute).as_deref() == Some("1");
+
+        let device = system.path(&format!("/dev/{}", name));
+        let mut partition_table = None;
+        let mut filesystem = None;
+        match File::open(&device) {
+            Ok(mut file) => {
+                if disk.is_none() {
+                    let sector_size = sys(&name, "queue/logical_block_size")
+                        .and_then(|size| size.parse().ok())
+                        .unwrap_or(SYSFS_SECTOR_SIZE);
+                    if let Ok(Some((table, uuids))) = read_partition_table(&mut file, sector_size) {
+                        partition_table = Some(table);
+                        partuuids.insert(name.clone(), uuids);
+                    }
+                }
+                filesystem = probe_file(&mut file).unwrap_or(None);
+            }
+            Err(e) => debug!("Unable to read {}: {}", device.display(), e),
+        }
+        let number = split_partition(&name).map(|(_, number)| number);
+        let partuuid = disk.as_ref().and_then(|disk| {
+            partuuids
+                .get(disk)?
+                .iter()
+                .find(|(n, _)| Some(*n) == number)
+                .map(|(_, uuid)| uuid.clone())
+        });
+
+        devices.push(BlockDevice {
+            kind: match disk {
+                Some(_) => DeviceKind::Partition,
+                None => DeviceKind::Disk,
+            },
+            rotational: flag("queue/rotational"),
+            removable: flag("removable"),
+            serial: disk.is_none().then(|| disk_serial(system, &name)).flatten(),
+            wwn: disk
+                .is_none()
+                .then(|| sys(&name, "wwid").or_else(|| sys(&name, "device/wwid")))
+                .flatten(),
+            name: name.clone(),
+            disk,
+            size,
+            partition_table,
+            filesystem,
+            partuuid,
+        });
+    }
+    // Names sort sda10 before sda2, and partitions need to come after their disks.
+    devices.sort_by_key(|d| {
+        (
+            d.disk.clone().unwrap_or_else(|| d.name.clone()),
+            d.number().unwrap_or(0),
+            d.name.clone(),
+        )
+    });
+    Ok(devices)
+}
+
+/// The serial number of a disk. NVMe, virtio and USB disks have it in sysfs as text, while SCSI
+/// and SATA disks only have the unit serial number page of their vital product data.
+fn disk_serial(system: &SystemRoot, name: &str) -> Option<String> {
+    let sys = system.path(&format!("/sys/class/block/{}", name));
+    for attribute in ["serial", "device/serial"] {
+        if let Ok(serial) = fs::read_to_string(sys.join(attribute))
+            && !serial.trim().is_empty()
+        {
+            return Some(serial.trim().to_string());
+        }
+    }
+    let page = fs::read(sys.join("device/vpd_pg80")).ok()?;
+    let length = *page.get(3)? as usize;
+    let serial = String::from_utf8_lossy(page.get(4..4 + length)?)
+        .trim()
+        .to_string();
+    Some(serial).filter(|s| !s.is_empty())
+}
+
+/// A disk, or a partition of one, as found from a [`StorageIdentifier`]. The partition needn't
+/// exist yet, so that one to be created by the installer can be identified.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// The kernel's name for the disk, eg `sda`.
+    pub disk: String,
+    pub partition: Option<u32>,
+}
+
+impl Location {
+    /// The kernel's name for the partition, or the disk if there's no partition.
+    pub fn name(&self) -> String {
+        match self.partition {
+            Some(number) => partition_name(&self.disk, number),
+            None => self.disk.clone(),
+        }
+    }
+
+    /// The device node, eg `/dev/sda1`.
+    pub fn path(&self) -> String {
+        format!("/dev/{}", self.name())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path())
+    }
+}
+
+/// Finds the one disk or partition an identifier refers to, failing if there isn't exactly one.
+pub fn resolve(system: &SystemRoot, identifier: &StorageIdentifier) -> Result<Location, Error> {
+    let devices = inventory(system)?;
+    let disks = || devices.iter().filter(|d| d.kind == DeviceKind::Disk);
+    let matching: Vec<&BlockDevice> = match identifier {
+        StorageIdentifier::Path { path } => {
+            let name = device_name(system, path);
+            match devices.iter().find(|d| d.name == name) {
+                Some(device) => vec![device],
+                None => {
+                    // A partition the installer is yet to create.
+                    if let Some((disk, number)) = split_partition(&name)
+                        && disks().any(|d| d.name == disk)
+                    {
+                        return Ok(Location {
+                            disk,
+                            partition: Some(number),
+                        });
+                    }
+                    vec![]
+                }
+            }
+        }
+        StorageIdentifier::Label { label } => {
+            by_filesystem(&devices, |fs| fs.label.as_deref() == Some(label.as_str()))
+        }
+        StorageIdentifier::Uuid { uuid } => by_filesystem(&devices, |fs| {
+            fs.uuid
+                .as_ref()
+                .is_some_and(|u| u.eq_ignore_ascii_case(uuid))
+        }),
+        StorageIdentifier::PartUuid { partuuid } => devices
+            .iter()
+            .filter(|d| {
+                d.partuuid
+                    .as_ref()
+                    .is_some_and(|u| u.eq_ignore_ascii_case(partuuid))
+            })
+            .collect(),
+        StorageIdentifier::Serial { serial, .. } => disks()
+            .filter(|d| d.serial.as_deref() == Some(serial.as_str()))
+            .collect(),
+        StorageIdentifier::Wwn { wwn, .. } => disks()
+            .filter(|d| d.wwn.as_ref().is_some_and(|w| bare_wwn(w) == bare_wwn(wwn)))
+            .collect(),
+        StorageIdentifier::LargestDisk {
+            rotational,
+            removable,
+            ..
+        } => {
+            let candidates: Vec<&BlockDevice> = disks()
+                // Loop devices, RAM disks and the like have no hardware behind them.
+                .filter(|d| {
+                    system
+                        .path(&format!("/sys/class/block/{}/device", d.name))
+                        .exists()
+                })
+                .filter(|d| *removable || !d.removable)
+                .filter(|d| rotational.is_none_or(|r| r == d.rotational))
+                .collect();
+            let largest = candidates.iter().map(|d| d.size).max();
+            candidates
+                .into_iter()
+                .filter(|d| Some(d.size) == largest)
+                .collect()
+        }
+    };
+
+    match matching.as_slice() {
+        [device] => Ok(match &device.disk {
+            Some(disk) => Location {
+                disk: disk.clone(),
+                partition: device.number(),
+            },
+            None => Location {
+                disk: device.name.clone(),
+                partition: identifier.partition(),
+            },
+        }),
+        [] => Err(anyhow!("No disk or partition matches {}", identifier)),
+        _ => Err(anyhow!(
+            "More than one device matches {} ({}), so it's not clear which to use",
+            identifier,
+            matching
+                .iter()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// The kernel's name for a device given by path, following links such as those in
+/// `/dev/disk/by-id`.
+pub(crate) fn device_name(system: &SystemRoot, path: &str) -> String {
+    let mut path = path.to_string();
+    // Links in /dev/disk are relative, eg ../../sda1.
+    for _ in 0..8 {
+        let Ok(target) = fs::read_link(system.path(&path)) else {
+            break;
+        };
+        let parent = Path::new(&path).parent().unwrap_or(Path::new("/"));
+        path = parent.join(target).to_string_lossy().to_string();
+    }
+    Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or(path)
+}
+
+/// The devices whose filesystem matches. A disk image written to a USB stick can show up both as
+/// the whole disk and as a partition covering it, in which case just the whole disk is kept.
+fn by_filesystem(
+    devices: &[BlockDevice],
+    matches: impl Fn(&Filesystem) -> bool,
+) -> Vec<&BlockDevice> {
+    let found: Vec<&BlockDevice> = devices
+        .iter()
+        .filter(|d| d.filesystem.as_ref().is_some_and(&matches))
+        .collect();
+    found
+        .iter()
+        .filter(|d| {
+            d.disk
+                .as_ref()
+                .is_none_or(|disk| !found.iter().any(|f| &f.name == disk))
+        })
+        .copied()
+        .collect()
+}
+
+/// WWNs are written with and without their type prefix, and in either case.
+fn bare_wwn(wwn: &str) -> String {
+    let wwn = wwn.trim().to_ascii_lowercase();
+    ["naa.", "eui.", "0x"]
+        .iter()
+        .find_map(|prefix| wwn.strip_prefix(prefix))
+        .unwrap_or(&wwn)
+        .to_string()
+}
+
+/// Reads the superblock of whatever is on `device`, which may be a block device or an image file.
+/// LUKS, ISO9660, ext2/3/4, XFS, btrfs, swap and FAT are recognised. `None` only means that none
+/// of those was found, not that the device is empty: NTFS, LVM and RAID members and the like
+/// aren't recognised either, so check [`is_blank`] before writing over a device.
+pub fn probe(device: &Path) -> Result<Option<Filesystem>, Error> {
+    let mut file = File::open(device)?;
+    probe_file(&mut file)
+}
+
+/// Whether `device` is really empty, with nothing but zeroes in its first and last MiB, where
+/// filesystems, volume managers and RAID keep their signatures.
+pub fn is_blank(device: &Path) -> Result<bool, Error> {
+    let mut file = File::open(device)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    let mut ends = vec![(0, size.min(BLANK_CHECK_SIZE))];
+    if size > BLANK_CHECK_SIZE {
+        let start = (size - BLANK_CHECK_SIZE).max(BLANK_CHECK_SIZE);
+        ends.push((start, size - start));
+    }
+    for (offset, length) in ends {
+        let mut buf = vec![0u8; length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        if buf.iter().any(|b| *b != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn probe_file(file: &mut File) -> Result<Option<Filesystem>, Error> {
+    let found = |fs_type: &str, label: Option<String>, uuid: Option<String>| {
+        Ok(Some(Filesystem {
+            fs_type: fs_type.to_string(),
+            label: label.filter(|l| !l.is_empty()),
+            uuid,
+        }))
+    };
+
+    let mut luks = [0u8; 208];
+    if read_at(file, 0, &mut luks)? && luks.starts_with(LUKS_MAGIC) {
+        // Only LUKS2 headers have a label.
+        let version = u16::from_be_bytes([luks[6], luks[7]]);
+        let label = (version == 2).then(|| c_string(&luks[24..72]));
+        return found("crypto_LUKS", label, Some(c_string(&luks[168..208])));
+    }
+
+    let mut pvd = [0u8; 830];
+    if read_at(file, ISO9660_PVD_OFFSET, &mut pvd)? && pvd[0] == 1 && &pvd[1..6] == b"CD001" {
+        let label = String::from_utf8_lossy(&pvd[40..72]).trim_end().to_string();
+        // blkid makes a UUID out of the volume creation time.
+        let created = &pvd[813..829];
+        let uuid = created.iter().all(u8::is_ascii_digit).then(|| {
+            let created = String::from_utf8_lossy(created);
+            format!(
+                "{}-{}-{}-{}-{}-{}-{}",
+                &created[0..4],
+                &created[4..6],
+                &created[6..8],
+                &created[8..10],
+                &created[10..12],
+                &created[12..14],
+                &created[14..16]
+            )
+        });
+        return found("iso9660", Some(label), uuid);
+    }
+
+    let mut superblock = [0u8; 136];
+    if read_at(file, EXT_SUPERBLOCK_OFFSET, &mut superblock)?
+        && u16::from_le_bytes([superblock[56], superblock[57]]) == EXT_MAGIC
+    {
+        let compat = u32::from_le_bytes(superblock[92..96].try_into().unwrap());
+        let incompat = u32::from_le_bytes(superblock[96..100].try_into().unwrap());
+        let fs_type = if incompat & EXT_INCOMPAT_EXT4 != 0 {
+            "ext4"
+        } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+            "ext3"
+        } else {
+            "ext2"
+        };
+        return found(
+            fs_type,
+            Some(c_string(&superblock[120..136])),
+            Some(uuid(&superblock[104..120])),
+        );
+    }
+
+    let mut xfs = [0u8; 120];
+    if read_at(file, 0, &mut xfs)? && xfs.starts_with(XFS_MAGIC) {
+        return found(
+            "xfs",
+            Some(c_string(&xfs[108..120])),
+            Some(uuid(&xfs[32..48])),
+        );
+    }
+
+    let mut btrfs = [0u8; 555];
+    if read_at(file, BTRFS_SUPERBLOCK_OFFSET, &mut btrfs)? && &btrfs[64..72] == BTRFS_MAGIC {
+        return found(
+            "btrfs",
+            Some(c_string(&btrfs[299..555])),
+            Some(uuid(&btrfs[32..48])),
+        );
+    }
+
+    let mut swap = [0u8; 10];
+    if read_at(file, SWAP_PAGE_SIZE - 10, &mut swap)? && &swap == b"SWAPSPACE2" {
+        let mut header = [0u8; 44];
+        read_at(file, 1024, &mut header)?;
+        return found(
+            "swap",
+            Some(c_string(&header[28..44])),
+            Some(uuid(&header[12..28])),
+        );
+    }
+
+    let mut boot = [0u8; 512];
+    if read_at(file, 0, &mut boot)? && boot[510..512] == [0x55, 0xaa] {
+        // FAT32 keeps its volume ID and label further in than FAT12/16 do.
+        let fields = if &boot[82..87] == b"FAT32" {
+            Some((67, 71))
+        } else if &boot[54..57] == b"FAT" {
+            Some((39, 43))
+        } else {
+            None
+        };
+        if let Some((serial, label)) = fields {
+            let serial = u32::from_le_bytes(boot[serial..serial + 4].try_into().unwrap());
+            let label = String::from_utf8_lossy(&boot[label..label + 11])
+                .trim_end()
+                .to_string();
+            return found(
+                "vfat",
+                Some(label).filter(|l| l != "NO NAME"),
+                Some(format!("{:04X}-{:04X}", serial >> 16, serial & 0xffff)),
+            );
+        }
+    }
+    Ok(None)
+}
+
+/// Partition numbers and the PARTUUIDs of the partitions.
+type PartUuids = Vec<(u32, String)>;
+
+/// Works out what kind of partition table, if any, is at the start of a disk, and the PARTUUIDs
+/// of the partitions in it.
+fn read_partition_table(
+    file: &mut File,
+    sector_size: u64,
+) -> Result<Option<(PartitionTable, PartUuids)>, Error> {
+    let mut header = [0u8; 92];
+    if read_at(file, sector_size, &mut header)? && &header[..8] == b"EFI PART" {
+        let entries_start = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        // The specification asks for room for 128 entries, and nothing uses more.
+        let count = u32::from_le_bytes(header[80..84].try_into().unwrap()).min(128);
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()).max(128);
+        let mut entries = vec![0u8; (count * entry_size) as usize];
+        let mut uuids = vec![];
+        if read_at(file, entries_start * sector_size, &mut entries)? {
+            for (i, entry) in entries.chunks(entry_size as usize).enumerate() {
+                if entry[..16].iter().any(|b| *b != 0) {
+                    uuids.push((i as u32 + 1, guid(&entry[16..32])));
+                }
+            }
+        }
+        return Ok(Some((PartitionTable::Gpt, uuids)));
+    }
+
+    let mut mbr = [0u8; 512];
+    if !read_at(file, 0, &mut mbr)? || mbr[510..512] != [0x55, 0xaa] {
+        return Ok(None);
+    }
+    // FAT boot sectors end the same way as an MBR, but what would be the partition entries holds
+    // boot code, which rarely has just valid status bytes in the right places.
+    let entries: Vec<&[u8]> = mbr[446..510].chunks(16).collect();
+    let valid = entries.iter().all(|e| e[0] == 0 || e[0] == 0x80);
+    if !valid || entries.iter().all(|e| e[4] == 0) {
+        return Ok(None);
+    }
+    let signature = u32::from_le_bytes(mbr[440..444].try_into().unwrap());
+    let uuids = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e[4] != 0)
+        .map(|(i, _)| (i as u32 + 1, format!("{:08x}-{:02x}", signature, i + 1)))
+        .collect();
+    Ok(Some((PartitionTable::Dos, uuids)))
+}
+
+/// Fills `buf` from `offset`, returning false if the device is too short.
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<bool, Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A NUL padded string.
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
+}
+
+/// Formats a GUID from a GPT partition table, whose first three fields are little endian.
+fn guid(bytes: &[u8]) -> String {
+    let mut swapped = bytes.to_vec();
+    swapped[0..4].reverse();
+    swapped[4..6].reverse();
+    swapped[6..8].reverse();
+    uuid(&swapped)
+}
+
+/// Formats a binary UUID the usual way.
+pub(crate) fn uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// A size in bytes the way lsblk shows them, eg `465.8G`.
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T", "P"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 || size.fract() == 0.0 {
+        format!("{}{}", size, units[unit])
+    } else {
+        format!("{:.1}{}", size, units[unit])
+    }
+}
+
+/// Lays the inventory out as a table, with partitions indented under their disks.
+pub fn table(devices: &[BlockDevice]) -> String {
+    let yes_no = |flag: bool| if flag { "yes" } else { "no" }.to_string();
+    let mut rows = vec![
+        [
+            "NAME", "SIZE", "TYPE", "ROTA", "RM", "PTTYPE", "FSTYPE", "LABEL", "UUID",
+        ]
+        .map(String::from),
+    ];
+    for device in devices {
+        let filesystem = device.filesystem.as_ref();
+        rows.push([
+            match device.kind {
+                DeviceKind::Disk => device.name.clone(),
+                DeviceKind::Partition => format!("  {}", device.name),
+            },
+            human_size(device.size),
+            device.kind.to_string(),
+            yes_no(device.rotational),
+            yes_no(device.removable),
+            device
+                .partition_table
+                .map(|t| t.to_string())
+                .unwrap_or_default(),
+            filesystem.map(|f| f.fs_type.clone()).unwrap_or_default(),
+            filesystem.and_then(|f| f.label.clone()).unwrap_or_default(),
+            filesystem.and_then(|f| f.uuid.clone()).unwrap_or_default(),
+        ]);
+    }
+
+    let mut widths = [0; 9];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table += line.join(" ").trim_end();
+        table += "\n";
+    }
+    table
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Writes enough of an ISO9660 image for its label to be read.
+    pub(crate) fn iso_image(label: &str) -> Vec<u8> {
+        let mut image = vec![0u8; ISO9660_PVD_OFFSET as usize + 2048];
+        let pvd = &mut image[ISO9660_PVD_OFFSET as usize..];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[40..72].copy_from_slice(format!("{:32}", label).as_bytes());
+        pvd[813..829].copy_from_slice(b"2025100112345600");
+        image
+    }
+
+    /// Writes enough of an ext4 filesystem for its label to be read.
+    pub(crate) fn ext4_image(label: &str) -> Vec<u8> {
+        let mut image = vec![0u8; 4096];
+        let superblock = &mut image[EXT_SUPERBLOCK_OFFSET as usize..];
+        superblock[56..58].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+        superblock[96..100].copy_from_slice(&0x2c0u32.to_le_bytes());
+        superblock[104..120].copy_from_slice(&[0x5a; 16]);
+        superblock[120..120 + label.len()].copy_from_slice(label.as_bytes());
+        image
+    }
+
+    /// Adds a block device to a system tree, with `content` standing in for the device node.
+    pub(crate) fn add_device(system: &SystemRoot, name: &str, content: &[u8]) {
+        let sys = system.path(&format!("/sys/class/block/{}", name));
+        fs::create_dir_all(&sys).unwrap();
+        if split_partition(name).is_some() {
+            fs::write(sys.join("partition"), "1\n").unwrap();
+        }
+        fs::write(sys.join("size"), format!("{}\n", content.len() / 512)).unwrap();
+        fs::create_dir_all(system.path("/dev")).unwrap();
+        fs::write(system.path(&format!("/dev/{}", name)), content).unwrap();
+    }
+
+    #[test]
+    fn filesystems() {
+        let dir = TempDir::new().unwrap();
+
+        let mut ext3 = ext4_image("data");
+        ext3[1024 + 92] = EXT_COMPAT_HAS_JOURNAL as u8;
+        ext3[1024 + 96..1024 + 100].copy_from_slice(&[0; 4]);
+        let mut swap = vec![0u8; 4096];
+        swap[4086..].copy_from_slice(b"SWAPSPACE2");
+        swap[1024 + 12..1024 + 28].copy_from_slice(&[0x11; 16]);
+        let mut vfat = vec![0u8; 4096];
+        vfat[82..87].copy_from_slice(b"FAT32");
+        vfat[67..71].copy_from_slice(&0x1234abcdu32.to_le_bytes());
+        vfat[71..82].copy_from_slice(b"EFI        ");
+        vfat[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let mut luks = vec![0u8; 4096];
+        luks[..6].copy_from_slice(LUKS_MAGIC);
+        luks[7] = 2;
+        luks[24..31].copy_from_slice(b"persist");
+        luks[168..204].copy_from_slice(b"0b5a8e0e-2c5e-4f0a-9d7a-0c1c3bd2f7f1");
+        let mut xfs = vec![0u8; 4096];
+        xfs[..4].copy_from_slice(XFS_MAGIC);
+        xfs[32..48].copy_from_slice(&[0x22; 16]);
+        xfs[108..112].copy_from_slice(b"data");
+        let mut btrfs = vec![0u8; 128 << 10];
+        btrfs[(64 << 10) + 32..(64 << 10) + 48].copy_from_slice(&[0x33; 16]);
+        btrfs[(64 << 10) + 64..(64 << 10) + 72].copy_from_slice(BTRFS_MAGIC);
+
+        for (name, content, expected) in [
+            (
+                "iso",
+                iso_image("HolOS-install"),
+                Some((
+                    "iso9660",
+                    Some("HolOS-install"),
+                    Some("2025-10-01-12-34-56-00"),
+                )),
+            ),
+            (
+                "ext4",
+                ext4_image("HolOS-system"),
+                Some((
+                    "ext4",
+                    Some("HolOS-system"),
+                    Some("5a5a5a5a-5a5a-5a5a-5a5a-5a5a5a5a5a5a"),
+                )),
+            ),
+            (
+                "ext3",
+                ext3,
+                Some((
+                    "ext3",
+                    Some("data"),
+                    Some("5a5a5a5a-5a5a-5a5a-5a5a-5a5a5a5a5a5a"),
+                )),
+            ),
+            (
+                "swap",
+                swap,
+                Some(("swap", None, Some("11111111-1111-1111-1111-111111111111"))),
+            ),
+            ("vfat", vfat, Some(("vfat", Some("EFI"), Some("1234-ABCD")))),
+            (
+                "xfs",
+                xfs,
+                Some((
+                    "xfs",
+                    Some("data"),
+                    Some("22222222-2222-2222-2222-222222222222"),
+                )),
+            ),
+            (
+                "btrfs",
+                btrfs,
+                Some(("btrfs", None, Some("33333333-3333-3333-3333-333333333333"))),
+            ),
+            (
+                "luks",
+                luks,
+                Some((
+                    "crypto_LUKS",
+                    Some("persist"),
+                    Some("0b5a8e0e-2c5e-4f0a-9d7a-0c1c3bd2f7f1"),
+                )),
+            ),
+            ("blank", vec![0u8; 1 << 16], None),
+            ("short", vec![0u8; 10], None),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            let expected = expected.map(|(fs_type, label, uuid)| Filesystem {
+                fs_type: fs_type.to_string(),
+                label: label.map(String::from),
+                uuid: uuid.map(String::from),
+            });
+            assert_eq!(probe(&path).unwrap(), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    /// Anything at either end of a device, recognised or not, means it isn't blank.
+    fn blank() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk");
+        let mut content = vec![0u8; 4 << 20];
+        fs::write(&path, &content).unwrap();
+        assert!(is_blank(&path).unwrap());
+        fs::write(&path, [0u8; 10]).unwrap();
+        assert!(is_blank(&path).unwrap());
+
+        // An NTFS boot sector isn't something probe knows, and neither is an md superblock
+        // near the end of the device.
+        content[3..11].copy_from_slice(b"NTFS    ");
+        fs::write(&path, &content).unwrap();
+        assert_eq!(probe(&path).unwrap(), None);
+        assert!(!is_blank(&path).unwrap());
+        content[3..11].copy_from_slice(&[0; 8]);
+        let end = content.len() - 8192;
+        content[end..end + 4].copy_from_slice(&0xa92b4efcu32.to_le_bytes());
+        fs::write(&path, &content).unwrap();
+        assert!(!is_blank(&path).unwrap());
+    }
+
+    #[test]
+    /// Disks come before their partitions, in partition number order, and partitions take their
+    /// flags from their disk.
+    fn listing() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        let mut mbr = vec![0u8; 1 << 20];
+        mbr[446 + 4] = 0x83;
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        add_device(&system, "sda", &mbr);
+        fs::create_dir_all(system.path("/sys/class/block/sda/queue")).unwrap();
+        fs::write(system.path("/sys/class/block/sda/queue/rotational"), "1\n").unwrap();
+        add_device(&system, "sda10", &[0u8; 4096]);
+        add_device(&system, "sda2", &ext4_image("HolOS-system"));
+        let mut gpt = vec![0u8; 4096];
+        gpt[512..520].copy_from_slice(b"EFI PART");
+        add_device(&system, "sdb", &gpt);
+        fs::write(system.path("/sys/class/block/sdb/removable"), "1\n").unwrap();
+        add_device(&system, "loop0", &[]);
+
+        let devices = inventory(&system).unwrap();
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["sda", "sda2", "sda10", "sdb"]);
+        assert_eq!(devices[0].partition_table, Some(PartitionTable::Dos));
+        assert_eq!(devices[1].kind, DeviceKind::Partition);
+        assert_eq!(devices[1].disk.as_deref(), Some("sda"));
+        assert_eq!(devices[1].number(), Some(2));
+        assert!(devices[1].rotational);
+        assert_eq!(devices[1].partition_table, None);
+        assert_eq!(devices[2].filesystem, None);
+        assert_eq!(devices[3].partition_table, Some(PartitionTable::Gpt));
+        assert!(devices[3].removable && !devices[3].rotational);
+
+        assert_eq!(
+            table(&devices),
+            "NAME    SIZE TYPE ROTA RM  PTTYPE FSTYPE LABEL        UUID
+sda     1M   disk yes  no  dos
+  sda2  4K   part yes  no         ext4   HolOS-system 5a5a5a5a-5a5a-5a5a-5a5a-5a5a5a5a5a5a
+  sda10 4K   part yes  no
+sdb     4K   disk no   yes gpt
+"
+        );
+        let json = serde_json::to_string(&devices[1]).unwrap();
+        assert!(json.contains(
+            r#""kind":"partition","disk":"sda","size":4096,"rotational":true,"removable":false,"partition_table":null,"filesystem":{"type":"ext4","label":"HolOS-system""#
+        ));
+    }
+
+    #[test]
+    /// A USB stick with the installation image on it has the label on both the disk and its
+    /// partition, while another disk with the same label is ambiguous.
+    fn labels() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        add_device(&system, "sda", &[0u8; 4096]);
+        add_device(&system, "sda1", &ext4_image("HolOS-system"));
+        add_device(&system, "sdb", &iso_image("HolOS-install"));
+        add_device(&system, "sdb1", &iso_image("HolOS-install"));
+        let label = |label: &str| {
+            resolve(
+                &system,
+                &StorageIdentifier::Label {
+                    label: label.to_string(),
+                },
+            )
+            .map(|location| location.path())
+        };
+
+        assert_eq!(label("HolOS-install").unwrap(), "/dev/sdb");
+        assert_eq!(label("HolOS-system").unwrap(), "/dev/sda1");
+        assert_eq!(
+            label("HolOS-data").unwrap_err().to_string(),
+            "No disk or partition matches LABEL=HolOS-data"
+        );
+
+        add_device(&system, "sdc", &iso_image("HolOS-install"));
+        assert_eq!(
+            label("HolOS-install").unwrap_err().to_string(),
+            "More than one device matches LABEL=HolOS-install (sdb, sdc), so it's not clear which \
+             to use"
+        );
+    }
+
+    #[test]
+    /// Disks can be found by their hardware identifiers or their role, and partitions by path,
+    /// UUID or PARTUUID.
+    fn identifiers() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        let mut mbr = vec![0u8; 8192];
+        mbr[440..444].copy_from_slice(&0x0b5a8e0eu32.to_le_bytes());
+        mbr[446 + 4] = 0x83;
+        mbr[462 + 4] = 0x83;
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        add_device(&system, "sda", &mbr);
+        let sda = system.path("/sys/class/block/sda");
+        fs::create_dir_all(sda.join("device")).unwrap();
+        fs::create_dir_all(sda.join("queue")).unwrap();
+        fs::write(sda.join("queue/rotational"), "1\n").unwrap();
+        fs::write(sda.join("device/wwid"), "naa.5000c500a1b2c3d4\n").unwrap();
+        // The unit serial number page, with the serial padded out as SATA disks do.
+        fs::write(sda.join("device/vpd_pg80"), b"\x00\x80\x00\x0a  WD-12345").unwrap();
+        add_device(&system, "sda1", &ext4_image("HolOS-system"));
+        add_device(&system, "sda2", &[0u8; 4096]);
+
+        let mut gpt = vec![0u8; 32768];
+        gpt[512..520].copy_from_slice(b"EFI PART");
+        gpt[512 + 72] = 2;
+        gpt[512 + 80] = 128;
+        gpt[512 + 84] = 128;
+        gpt[1024..1040].copy_from_slice(&[1; 16]);
+        gpt[1040..1056].copy_from_slice(&[
+            0x78, 0x56, 0x34, 0x12, 0x34, 0x12, 0x34, 0x12, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc,
+            0xde, 0xf0,
+        ]);
+        add_device(&system, "nvme0n1", &gpt);
+        let nvme = system.path("/sys/class/block/nvme0n1");
+        fs::create_dir_all(nvme.join("device")).unwrap();
+        fs::write(nvme.join("device/serial"), "S3Z1NB0K\n").unwrap();
+        add_device(&system, "nvme0n1p1", &[0u8; 4096]);
+        add_device(&system, "loop0", &[0u8; 65536]);
+        fs::create_dir_all(system.path("/dev/disk/by-label")).unwrap();
+        std::os::unix::fs::symlink("../../sda1", system.path("/dev/disk/by-label/HolOS-system"))
+            .unwrap();
+
+        let find = |yaml: &str| {
+            let identifier: StorageIdentifier = serde_yaml::from_str(yaml).unwrap();
+            resolve(&system, &identifier).map(|location| location.path())
+        };
+        assert_eq!(find("!path {path: /dev/sda2}").unwrap(), "/dev/sda2");
+        assert_eq!(find("!path {path: /dev/sda3}").unwrap(), "/dev/sda3");
+        assert_eq!(
+            find("!path {path: /dev/disk/by-label/HolOS-system}").unwrap(),
+            "/dev/sda1"
+        );
+        assert!(find("!path {path: /dev/sdb1}").is_err());
+        assert_eq!(
+            find("!uuid {uuid: 5A5A5A5A-5A5A-5A5A-5A5A-5A5A5A5A5A5A}").unwrap(),
+            "/dev/sda1"
+        );
+        assert_eq!(
+            find("!partuuid {partuuid: 0b5a8e0e-02}").unwrap(),
+            "/dev/sda2"
+        );
+        assert_eq!(
+            find("!partuuid {partuuid: 12345678-1234-1234-1234-56789abcdef0}").unwrap(),
+            "/dev/nvme0n1p1"
+        );
+        assert_eq!(find("!serial {serial: WD-12345}").unwrap(), "/dev/sda");
+        assert_eq!(
+            find("!serial {serial: S3Z1NB0K, partition: 2}").unwrap(),
+            "/dev/nvme0n1p2"
+        );
+        assert_eq!(
+            find("!wwn {wwn: 5000C500A1B2C3D4, partition: 1}").unwrap(),
+            "/dev/sda1"
+        );
+        // The loop device is bigger, but isn't a real disk.
+        assert_eq!(
+            find("!largest_disk {rotational: false, partition: 1}").unwrap(),
+            "/dev/nvme0n1p1"
+        );
+        assert_eq!(find("!largest_disk {}").unwrap(), "/dev/nvme0n1");
+        assert_eq!(
+            find("!largest_disk {rotational: true}").unwrap(),
+            "/dev/sda"
+        );
+
+        fs::write(nvme.join("size"), "16\n").unwrap();
+        assert_eq!(
+            find("!largest_disk {}").unwrap_err().to_string(),
+            "More than one device matches largest non-removable disk (nvme0n1, sda), so it's not \
+             clear which to use"
+        );
+        fs::write(sda.join("removable"), "1\n").unwrap();
+        fs::write(nvme.join("removable"), "1\n").unwrap();
+        assert!(find("!largest_disk {}").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(1 << 30), "1G");
+        assert_eq!(human_size(500107862016), "465.8G");
+    }
+}
@@ -0,0 +1,392 @@
+/// The init program of the initial ramdisk, run by the kernel as `/init`. It mounts the virtual
+/// filesystems, loads the kernel modules for the devices it finds until the root filesystem given
+/// by `root=` (`LABEL=`, `UUID=`, `PARTUUID=` or a device path) appears, mounts it, and hands over
+/// to the installed system's own init with busybox's `switch_root`. If anything goes wrong, it
+/// explains what it can see and starts a rescue shell on the console; once that exits, it tries
+/// again.
+use crate::StorageIdentifier;
+use crate::blockdev::{self, Location, resolve};
+use crate::cmdline::CmdLine;
+use crate::install::{Live, Slot, Tools};
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use log::{error, info, warn};
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::ffi::CString;
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the root filesystem's device to appear.
+pub const ROOT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Where the root filesystem is mounted before switching to it.
+const NEW_ROOT: &str = "/mnt";
+/// The installed system's init, in the root filesystem.
+const SYSTEM_INIT: &str = "/sbin/init";
+/// Modules to load before looking at any devices.
+const BASE_MODULES: &[&str] = &["ext4"];
+
+/// Whether this program was started as the initial ramdisk's `/init`.
+pub fn invoked_as_init() -> bool {
+    std::env::args_os()
+        .next()
+        .is_some_and(|arg| Path::new(&arg).file_name() == Some("init".as_ref()))
+}
+
+/// Boots the installed system, never returning: the kernel panics if init exits.
+pub fn main() -> ! {
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+    let system = SystemRoot::default();
+    loop {
+        let Err(e) = boot(&system);
+        error!("Unable to boot: {}", e);
+        eprintln!("{}", diagnostics(&system, &e));
+        eprintln!("Starting a rescue shell. Exit it to try booting again.");
+        if let Err(e) = Command::new("/bin/sh").status() {
+            error!("Unable to start a shell: {}", e);
+            sleep(Duration::from_secs(5));
+        }
+    }
+}
+
+fn boot(system: &SystemRoot) -> Result<Infallible, Error> {
+    for (fs_type, dir) in [("proc", "/proc"), ("sysfs", "/sys"), ("devtmpfs", "/dev")] {
+        mount(fs_type, dir, fs_type, 0)?;
+    }
+
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string())?;
+    let root = match &cmdline.root {
+        Some(root) => root.clone(),
+        None => {
+            let root = format!("LABEL={}", Slot::A.label());
+            warn!(
+                "There's no root= on the kernel command line, so using {}",
+                root
+            );
+            root
+        }
+    };
+    let mut modules = Modules::new(cmdline.rootfstype.as_deref());
+    let location = wait_for_root(
+        system,
+        &StorageIdentifier::from_root(&root),
+        ROOT_TIMEOUT,
+        || modules.load(system, &Live),
+    )?;
+    let device = location.path();
+
+    let fs_type = match &cmdline.rootfstype {
+        Some(fs_type) => fs_type.clone(),
+        None => blockdev::probe(&system.path(&device))?
+            .map(|fs| fs.fs_type)
+            .ok_or_else(|| anyhow!("There's no filesystem on {}", device))?,
+    };
+    let flags = match cmdline.read_write {
+        true => 0,
+        false => libc::MS_RDONLY,
+    };
+    info!("Mounting {} ({}) on {}", root, device, NEW_ROOT);
+    mount(&device, NEW_ROOT, &fs_type, flags)?;
+    let init = Path::new(NEW_ROOT).join(SYSTEM_INIT.trim_start_matches('/'));
+    if fs::symlink_metadata(&init).is_err() {
+        unmount(NEW_ROOT);
+        return Err(anyhow!("{} has no {}", root, SYSTEM_INIT));
+    }
+
+    // The installed system mounts its own.
+    for dir in ["/dev", "/sys", "/proc"] {
+        unmount(dir);
+    }
+    info!("Switching to the system on {}", device);
+    let e = Command::new("/bin/switch_root")
+        .args([NEW_ROOT, SYSTEM_INIT])
+        .exec();
+    for (fs_type, dir) in [("proc", "/proc"), ("sysfs", "/sys"), ("devtmpfs", "/dev")] {
+        let _ = mount(fs_type, dir, fs_type, 0);
+    }
+    unmount(NEW_ROOT);
+    Err(anyhow!("Unable to run switch_root: {}", e))
+}
+
+/// Waits for the device holding `root` to appear, calling `load_modules` each time round so that
+/// drivers for newly found devices are loaded.
+fn wait_for_root(
+    system: &SystemRoot,
+    root: &StorageIdentifier,
+    timeout: Duration,
+    mut load_modules: impl FnMut(),
+) -> Result<Location, Error> {
+    let start = Instant::now();
+    loop {
+        load_modules();
+        let error = match resolve(system, root) {
+            // A device path can name a partition that isn't there (yet).
+            Ok(location)
+                if system
+                    .path(&format!("/sys/class/block/{}", location.name()))
+                    .exists() =>
+            {
+                return Ok(location);
+            }
+            Ok(location) => anyhow!("{} doesn't exist", location),
+            Err(e) => e,
+        };
+        if start.elapsed() >= timeout {
+            return Err(anyhow!(
+                "The root filesystem {} didn't appear within {}s: {}",
+                root,
+                timeout.as_secs(),
+                error
+            ));
+        }
+        sleep(Duration::from_millis(250));
+    }
+}
+
+/// The kernel modules loaded so far, by the modalias or name they were loaded for.
+struct Modules {
+    tried: BTreeSet<String>,
+    pending: Vec<String>,
+}
+
+impl Modules {
+    fn new(fs_type: Option<&str>) -> Self {
+        let mut pending: Vec<String> = BASE_MODULES.iter().map(|m| m.to_string()).collect();
+        pending.extend(fs_type.map(|t| t.to_string()));
+        Modules {
+            tried: BTreeSet::new(),
+            pending,
+        }
+    }
+
+    /// Loads the modules for any devices that have appeared since the last time. The initial
+    /// ramdisk only has the modules the root filesystem needs, so whatever isn't there is skipped.
+    fn load(&mut self, system: &SystemRoot, tools: &dyn Tools) {
+        let mut aliases = std::mem::take(&mut self.pending);
+        aliases.extend(modaliases(&system.path("/sys/devices")));
+        let mut command = vec!["modprobe".to_string(), "-q".to_string(), "-a".to_string()];
+        command.extend(aliases.into_iter().filter(|a| self.tried.insert(a.clone())));
+        if command.len() > 3 {
+            // Some devices have no driver, so failures are expected.
+            let _ = tools.run(&command);
+        }
+    }
+}
+
+/// The modaliases of the devices under `dir`, in sysfs.
+fn modaliases(dir: &Path) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return found;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Links lead back up the device tree, or off to other parts of sysfs.
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if file_type.is_dir() {
+            found.extend(modaliases(&path));
+        } else if entry.file_name() == "modalias"
+            && let Ok(alias) = fs::read_to_string(&path)
+            && !alias.trim().is_empty()
+        {
+            found.insert(alias.trim().to_string());
+        }
+    }
+    found
+}
+
+/// What there is to go on when the root filesystem can't be mounted.
+fn diagnostics(system: &SystemRoot, error: &Error) -> String {
+    let mut out = format!("\nHolOS couldn't boot: {}\n\n", error);
+    match fs::read_to_string(system.cmdline()) {
+        Ok(cmdline) => writeln!(out, "Kernel command line: {}", cmdline.trim()).unwrap(),
+        Err(e) => writeln!(out, "Unable to read the kernel command line: {}", e).unwrap(),
+    }
+    match blockdev::inventory(system) {
+        Ok(devices) if devices.is_empty() => {
+            writeln!(out, "\nNo block devices were found").unwrap()
+        }
+        Ok(devices) => write!(out, "\nBlock devices:\n{}", blockdev::table(&devices)).unwrap(),
+        Err(e) => writeln!(out, "\nUnable to list the block devices: {}", e).unwrap(),
+    }
+    let modules: Vec<String> = fs::read_to_string(system.path("/proc/modules"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(|name| name.to_string())
+        .collect();
+    match modules.is_empty() {
+        true => writeln!(out, "\nNo kernel modules are loaded").unwrap(),
+        false => writeln!(out, "\nKernel modules loaded: {}", modules.join(" ")).unwrap(),
+    }
+    out
+}
+
+fn mount(source: &str, target: &str, fs_type: &str, flags: libc::c_ulong) -> Result<(), Error> {
+    fs::create_dir_all(target).map_err(|e| anyhow!("Unable to create {}: {}", target, e))?;
+    let c = |s: &str| CString::new(s).map_err(|e| anyhow!("Unable to mount {}: {}", source, e));
+    let (c_source, c_target, c_type) = (c(source)?, c(target)?, c(fs_type)?);
+    // SAFETY: the strings are NUL terminated and outlive the call, and there's no data.
+    let result = unsafe {
+        libc::mount(
+            c_source.as_ptr(),
+            c_target.as_ptr(),
+            c_type.as_ptr(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if result != 0 {
+        let e = std::io::Error::last_os_error();
+        // Already mounted by an earlier attempt.
+        if e.raw_os_error() == Some(libc::EBUSY) {
+            return Ok(());
+        }
+        return Err(anyhow!("Unable to mount {} on {}: {}", source, target, e));
+    }
+    Ok(())
+}
+
+fn unmount(target: &str) {
+    let Ok(c_target) = CString::new(target) else {
+        return;
+    };
+    // SAFETY: the string is NUL terminated and outlives the call.
+    if unsafe { libc::umount2(c_target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        warn!(
+            "Unable to unmount {}: {}",
+            target,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::tests::{add_device, ext4_image};
+    use std::cell::RefCell;
+    use tempfile::TempDir;
+
+    /// Stands in for modprobe, with the disk showing up once its controller's driver is loaded.
+    struct Modprobe {
+        system: SystemRoot,
+        commands: RefCell<Vec<String>>,
+    }
+
+    impl Tools for Modprobe {
+        fn run(&self, command: &[String]) -> Result<(), Error> {
+            self.commands.borrow_mut().push(command.join(" "));
+            if command.iter().any(|a| a.starts_with("pci:v00008086")) {
+                add_device(&self.system, "sda", &[]);
+                add_device(&self.system, "sda1", &ext4_image("HolOS-system-b"));
+            }
+            Ok(())
+        }
+
+        fn run_with_input(&self, command: &[String], _input: &[u8]) -> Result<(), Error> {
+            self.run(command)
+        }
+    }
+
+    fn controller(system: &SystemRoot) {
+        let pci = system.path("/sys/devices/pci0000:00/0000:00:1f.2");
+        fs::create_dir_all(&pci).unwrap();
+        fs::write(
+            pci.join("modalias"),
+            "pci:v00008086d00002922sv00001AF4sd00001100bc01sc06i01\n",
+        )
+        .unwrap();
+        fs::create_dir_all(system.path("/sys/devices/platform/serial8250")).unwrap();
+        fs::write(
+            system.path("/sys/devices/platform/serial8250/modalias"),
+            "platform:serial8250\n",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    /// The disk appears once the driver for its controller is loaded, and modules are only
+    /// loaded once.
+    fn root() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        controller(&system);
+        let tools = Modprobe {
+            system: system.clone(),
+            commands: RefCell::new(vec![]),
+        };
+        let mut modules = Modules::new(None);
+        let location = wait_for_root(
+            &system,
+            &StorageIdentifier::from_root("LABEL=HolOS-system-b"),
+            Duration::from_secs(5),
+            || modules.load(&system, &tools),
+        )
+        .unwrap();
+        assert_eq!(location.path(), "/dev/sda1");
+        assert_eq!(
+            *tools.commands.borrow(),
+            vec![
+                "modprobe -q -a ext4 pci:v00008086d00002922sv00001AF4sd00001100bc01sc06i01 platform:serial8250"
+            ]
+        );
+
+        let location = wait_for_root(
+            &system,
+            &StorageIdentifier::from_root("/dev/sda1"),
+            Duration::ZERO,
+            || modules.load(&system, &tools),
+        )
+        .unwrap();
+        assert_eq!(location.path(), "/dev/sda1");
+        assert_eq!(tools.commands.borrow().len(), 1);
+    }
+
+    #[test]
+    /// A root filesystem that doesn't appear is given up on, with an explanation.
+    fn timeout() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        add_device(&system, "sda", &[0; 4096]);
+        let mut error = anyhow!("");
+        for root in ["UUID=6f1c8b2e-3d4a-4b5c-9d6e-7f8091a2b3c4", "/dev/sda2"] {
+            error = wait_for_root(
+                &system,
+                &StorageIdentifier::from_root(root),
+                Duration::ZERO,
+                || {},
+            )
+            .unwrap_err();
+            assert!(error.to_string().starts_with(&format!(
+                "The root filesystem {} didn't appear within 0s: ",
+                root
+            )));
+        }
+
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(system.cmdline(), "root=/dev/sda2 ro\n").unwrap();
+        fs::write(
+            system.path("/proc/modules"),
+            "ahci 45056 0 - Live 0x0000000000000000\nlibahci 49152 1 ahci, Live 0x0000000000000000\n",
+        )
+        .unwrap();
+        let diagnostics = diagnostics(&system, &error);
+        assert!(diagnostics.contains(
+            "HolOS couldn't boot: The root filesystem /dev/sda2 didn't appear within 0s: /dev/sda2 doesn't exist\n"
+        ));
+        assert!(diagnostics.contains("Kernel command line: root=/dev/sda2 ro\n"));
+        assert!(diagnostics.contains("\nBlock devices:\n"));
+        assert!(diagnostics.contains("sda"));
+        assert!(diagnostics.contains("Kernel modules loaded: ahci libahci\n"));
+    }
+}
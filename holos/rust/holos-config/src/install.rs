@@ -1,37 +1,1067 @@
-use crate::HolosConfig;
-use anyhow::Error;
-use bzip2::read::BzDecoder;
-use cpio::NewcReader;
-use log::info;
-use std::io::{BufReader, Read};
-
-pub fn do_install(config: &HolosConfig) -> Result<(), Error> {
+/// Installation of HolOS from the media it was booted from onto a disk, so that it can boot without
+/// the media and keep its data. The target disk is partitioned and formatted, the root filesystem
+/// archive and kernel on the media are copied onto it, and an initial ramdisk and bootloader are
+/// set up to find and mount it at boot. If the persistent data partition is to be encrypted, the
+/// encrypted volume is created too.
+///
+/// The disk gets two system partitions, or slots. The system is installed into the first, and
+/// upgrades are written into whichever one isn't running (see [`upgrade`]).
+pub mod archive;
+pub mod grub;
+pub mod initramfs;
+pub mod journal;
+pub mod partition;
+pub mod plan;
+pub mod safety;
+pub mod settings;
+pub mod upgrade;
+
+use crate::blockdev::{Location, resolve};
+use crate::cmdline::CmdLine;
+use crate::storage::create_encrypted;
+use crate::system::SystemRoot;
+use crate::{EncryptionConfig, HolosConfig, StorageIdentifier, Unlock};
+use anyhow::{Error, anyhow};
+use journal::{Failure, JOURNAL_PATH, Journal};
+use log::{info, warn};
+use partition::{Layout, Size};
+use plan::{Persist, Plan};
+use serde_derive::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// The volume label of the installation media.
+pub const SOURCE_LABEL: &str = "HolOS-install";
+/// Where the kernel is, both on the media and on the installed system.
+pub const KERNEL_PATH: &str = "/boot/bzImage";
+/// The root filesystem archive on the media.
+pub const ROOTFS_PATH: &str = "/boot/rootfs.cpio.bz2";
+/// The initial ramdisk on the installed system.
+pub const INITRD_PATH: &str = "/boot/holos-initrd.img";
+/// The size of each system partition when the persistent data partition follows them on the same
+/// disk. When it's on another disk, the system partitions share the whole of the target disk.
+pub const SYSTEM_PARTITION_SIZE: u64 = 4 << 30;
+/// Where the media and target are mounted while installing.
+const WORK_DIR: &str = "/tmp/holos-install";
+/// The steps a retried installation doesn't repeat, if the last attempt at the same plan finished
+/// them.
+const RESUMABLE: &[Step] = &[Step::Partition, Step::Format, Step::Persist];
+/// How long to wait for the kernel to create the device for a new partition.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The two system partitions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// The label of the root filesystem in the slot, which the bootloader tells the initial
+    /// ramdisk to look for.
+    pub fn label(self) -> &'static str {
+        match self {
+            Slot::A => "HolOS-system-a",
+            Slot::B => "HolOS-system-b",
+        }
+    }
+
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// The slot the running system was booted from, going by the root filesystem on the kernel
+    /// command line. Systems installed before there were slots, and live systems, aren't in one.
+    pub fn running(system: &SystemRoot) -> Result<Option<Slot>, Error> {
+        let cmdline = CmdLine::from_file(&system.cmdline().display().to_string())?;
+        Ok([Slot::A, Slot::B]
+            .into_iter()
+            .find(|slot| cmdline.root == Some(format!("LABEL={}", slot.label()))))
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Slot::A => write!(f, "a"),
+            Slot::B => write!(f, "b"),
+        }
+    }
+}
+
+/// The steps of an installation or upgrade, in the order they're carried out.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Source,
+    Slot,
+    Image,
+    Check,
+    Partition,
+    Format,
+    Persist,
+    Mount,
+    Unpack,
+    Kernel,
+    Initrd,
+    Keys,
+    Config,
+    Verify,
+    Bootloader,
+    Unmount,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            Step::Source => "finding the installation media",
+            Step::Slot => "finding the system slot to upgrade",
+            Step::Image => "checking the upgrade image",
+            Step::Check => "checking the target disk",
+            Step::Partition => "partitioning the target disk",
+            Step::Format => "formatting the system partitions",
+            Step::Persist => "setting up the encrypted persistent data partition",
+            Step::Mount => "mounting the filesystems",
+            Step::Unpack => "unpacking the root filesystem",
+            Step::Kernel => "copying the kernel",
+            Step::Initrd => "building the initial ramdisk",
+            Step::Bootloader => "installing the bootloader",
+            Step::Keys => "copying SSH keys",
+            Step::Config => "writing the configuration",
+            Step::Verify => "verifying the new system",
+            Step::Unmount => "unmounting the filesystems",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// Why an installation failed, and at which step.
+#[derive(Debug)]
+pub struct InstallError {
+    pub step: Step,
+    pub error: Error,
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Installation failed while {}: {}", self.step, self.error)
+    }
+}
+
+impl std::error::Error for InstallError {}
+
+/// Attaches the step being carried out to an error.
+trait During<T> {
+    fn during(self, step: Step) -> Result<T, InstallError>;
+}
+
+impl<T, E: Into<Error>> During<T> for Result<T, E> {
+    fn during(self, step: Step) -> Result<T, InstallError> {
+        self.map_err(|e| InstallError {
+            step,
+            error: e.into(),
+        })
+    }
+}
+
+/// The external programs installation relies on (mkfs, mount, grub-install and so on).
+pub trait Tools {
+    /// Runs a command, failing if it can't be started or doesn't succeed.
+    fn run(&self, command: &[String]) -> Result<(), Error>;
+    /// Runs a command with `input` on its standard input. Used for passing keys to cryptsetup,
+    /// so the input is never logged.
+    fn run_with_input(&self, command: &[String], input: &[u8]) -> Result<(), Error>;
+}
+
+/// The programs on the machine we're running on.
+pub struct Live;
+
+impl Tools for Live {
+    fn run(&self, command: &[String]) -> Result<(), Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command"))?;
+        info!("Running {}", command.join(" "));
+        let status = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| anyhow!("Unable to run {}: {}", program, e))?;
+        if !status.success() {
+            return Err(anyhow!("{} failed: {}", command.join(" "), status));
+        }
+        Ok(())
+    }
+
+    fn run_with_input(&self, command: &[String], input: &[u8]) -> Result<(), Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command"))?;
+        info!("Running {}", command.join(" "));
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("Unable to run {}: {}", program, e))?;
+        // Dropped once written, so that the program sees the end of its input.
+        let written = child.stdin.take().unwrap().write_all(input);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("{} failed: {}", command.join(" "), status));
+        }
+        written.map_err(|e| anyhow!("Unable to write to {}: {}", program, e))
+    }
+}
+
+/// Installs HolOS onto the system partition given in the configuration. The plan is shown first,
+/// and only carried out once it's been confirmed at the console, unless `yes` is given. A disk
+/// with partitions or filesystems on it already is only wiped if `force` is given.
+pub fn do_install(
+    config: &HolosConfig,
+    config_file: &str,
+    system: &SystemRoot,
+    dry_run: bool,
+    yes: bool,
+    force: bool,
+) -> Result<(), Error> {
+    let mut installer = Installer::new(system, &Live);
+    installer.force = force;
+    installer.config_file = Some(config_file.to_string());
+    let plan = match installer.plan(config) {
+        Ok(plan) => plan,
+        Err(e) => {
+            if e.step == Step::Check {
+                show_disks(system, force);
+            }
+            return Err(e.into());
+        }
+    };
+    print!("{}", plan);
+    if let Some(previous) = installer.resumable(&plan) {
+        if let Some(failure) = &previous.failed {
+            println!(
+                "The last attempt failed while {}: {}",
+                failure.step, failure.error
+            );
+        }
+        let skipped: Vec<String> = previous
+            .done
+            .iter()
+            .filter(|step| RESUMABLE.contains(step))
+            .map(|step| step.to_string())
+            .collect();
+        if !skipped.is_empty() {
+            println!(
+                "Picking up where it left off, without {}",
+                skipped.join(", ")
+            );
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    if !yes && !confirm(&plan.disk)? {
+        return Err(anyhow!("Installation cancelled; nothing was changed"));
+    }
+    installer.execute(config, &plan)?;
+    info!("Installation done. It's safe to remove the installation media and reboot.");
     Ok(())
 }
 
-pub fn old(config: &HolosConfig) -> Result<(), Error> {
-    let mut file = std::fs::File::open("/tmp/t.cpio.bz2")?;
-    // Create a reader for the raw/compressed file
-    let mut reader = BufReader::new(file);
+/// Lists whether each disk in the machine could be installed onto, so that a refused disk can
+/// be seen alongside the others.
+fn show_disks(system: &SystemRoot, force: bool) {
+    match safety::check_disks(system, force) {
+        Ok(checks) => {
+            println!("Disks:");
+            for check in checks {
+                print!("{}", check);
+            }
+        }
+        Err(e) => warn!("Unable to check the disks: {}", e),
+    }
+}
 
-    // Add a reader on top of that to transparently decompress the gzip data
-    let mut cpio = Vec::new();
-    let mut bz_reader = BzDecoder::new(reader);
-    bz_reader.read_to_end(&mut cpio).unwrap();
+/// Asks for the go-ahead at the console, since everything on `disk` is about to be lost.
+fn confirm(disk: &str) -> Result<bool, Error> {
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Not installing without confirmation; give --yes to install without being asked"
+        ));
+    }
+    print!(
+        "Everything on {} will be lost. Type yes to carry on: ",
+        disk
+    );
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| anyhow!("Unable to read the answer: {}", e))?;
+    Ok(answer.trim() == "yes")
+}
 
-    loop {
-        let cpio_reader = NewcReader::new(&cpio)?;
+/// Carries out an installation. Devices are found and written through `system`, so the whole
+/// thing can be run against disk images standing in for the devices.
+pub struct Installer<'a> {
+    system: &'a SystemRoot,
+    tools: &'a dyn Tools,
+    /// Where the media and target are mounted.
+    pub work_dir: PathBuf,
+    /// How long to wait for the device of a new partition to appear.
+    pub device_timeout: Duration,
+    /// Whether to wipe a disk that has partitions or filesystems on it already.
+    pub force: bool,
+    /// The configuration file the configuration was read from, noted in the copy written to the
+    /// new system.
+    pub config_file: Option<String>,
+    mounted: RefCell<Vec<PathBuf>>,
+}
 
-        if cpio_reader.entry().is_trailer() {
-            // We've hit the end of the archive
-            break;
+impl<'a> Installer<'a> {
+    pub fn new(system: &'a SystemRoot, tools: &'a dyn Tools) -> Self {
+        Installer {
+            system,
+            tools,
+            work_dir: system.path(WORK_DIR),
+            device_timeout: DEVICE_TIMEOUT,
+            force: false,
+            config_file: None,
+            mounted: RefCell::new(vec![]),
         }
+    }
 
-        let name = cpio_reader.entry().name();
-        info!("File: {}", name);
+    /// Works out the plan for `config`, and carries it out straight away.
+    pub fn install(&self, config: &HolosConfig) -> Result<(), InstallError> {
+        let plan = self.plan(config)?;
+        self.execute(config, &plan)
+    }
 
-        //file = cpio_reader.skip()?;
+    /// The journal of an earlier attempt at the same plan that didn't finish, if there is one.
+    pub fn resumable(&self, plan: &Plan) -> Option<Journal> {
+        Journal::read(&self.system.path(JOURNAL_PATH))
+            .filter(|journal| journal.plan == plan.to_string())
     }
 
+    /// Carries out `plan`, recording each step in the journal as it's finished. The steps an
+    /// earlier attempt at the same plan finished before copying the system aren't repeated.
+    pub fn execute(&self, config: &HolosConfig, plan: &Plan) -> Result<(), InstallError> {
+        let path = self.system.path(JOURNAL_PATH);
+        let previous = self
+            .resumable(plan)
+            .unwrap_or_else(|| Journal::new(&plan.to_string()));
+        let mut journal = Journal::new(&previous.plan);
+
+        let result = self.execute_steps(config, plan, &previous, &mut journal);
+        match &result {
+            Ok(()) => {
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("Unable to remove {}: {}", path.display(), e);
+                }
+            }
+            Err(e) => {
+                journal.failed = Some(Failure {
+                    step: e.step,
+                    error: e.error.to_string(),
+                });
+                if let Err(e) = journal.write(&path) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        result
+    }
+
+    fn execute_steps(
+        &self,
+        config: &HolosConfig,
+        plan: &Plan,
+        previous: &Journal,
+        journal: &mut Journal,
+    ) -> Result<(), InstallError> {
+        info!("Installing from the media on {}", plan.source);
+        if previous.is_done(Step::Partition) {
+            info!("{} was already partitioned", plan.disk);
+        } else {
+            self.partition(plan).during(Step::Partition)?;
+        }
+        self.record(journal, Step::Partition)?;
+
+        // Whatever an earlier attempt copied into slot a would get in the way, so it's formatted
+        // again if the earlier attempt got that far.
+        for (slot, partition) in &plan.slots {
+            let copied_into = *slot == Slot::A && previous.is_done(Step::Mount);
+            if previous.is_done(Step::Format) && !copied_into {
+                info!("{} was already formatted", partition);
+                continue;
+            }
+            self.format_slot(*slot, partition).during(Step::Format)?;
+        }
+        self.record(journal, Step::Format)?;
+
+        if previous.is_done(Step::Persist) {
+            info!("The persistent data partition was already set up");
+        } else {
+            self.encrypt_persist(plan).during(Step::Persist)?;
+        }
+        self.record(journal, Step::Persist)?;
+
+        let result = self.copy_system(config, plan, journal);
+        let unmounted = self.unmount_all().during(Step::Unmount);
+        result.and(unmounted)?;
+        self.record(journal, Step::Unmount)
+    }
+
+    /// Notes that `step` is finished in the journal.
+    fn record(&self, journal: &mut Journal, step: Step) -> Result<(), InstallError> {
+        journal.record(step);
+        journal.write(&self.system.path(JOURNAL_PATH)).during(step)
+    }
+
+    /// Everything that happens with the media and system partition mounted.
+    fn copy_system(
+        &self,
+        config: &HolosConfig,
+        plan: &Plan,
+        journal: &mut Journal,
+    ) -> Result<(), InstallError> {
+        let source_dir = self.work_dir.join("source");
+        let target_dir = self.work_dir.join("target");
+        self.mount(&plan.source, &source_dir, &["-o", "ro"])
+            .during(Step::Mount)?;
+        self.mount(plan.partition(), &target_dir, &["-t", "ext4"])
+            .during(Step::Mount)?;
+        self.record(journal, Step::Mount)?;
+
+        info!("Unpacking the root filesystem");
+        let entries = archive::unpack(&source_dir.join(relative(ROOTFS_PATH)), &target_dir)
+            .during(Step::Unpack)?;
+        info!("Unpacked {} files", entries);
+        self.record(journal, Step::Unpack)?;
+
+        copy_kernel(&source_dir, &target_dir).during(Step::Kernel)?;
+        self.record(journal, Step::Kernel)?;
+
+        self.build_initrd(&target_dir, &plan.disk)
+            .during(Step::Initrd)?;
+        self.record(journal, Step::Initrd)?;
+
+        copy_keys(
+            &self.system.path("/root/.ssh"),
+            &target_dir.join("root/.ssh"),
+        )
+        .during(Step::Keys)?;
+        self.record(journal, Step::Keys)?;
+
+        settings::write(
+            config,
+            self.config_file.as_deref(),
+            self.system,
+            &target_dir,
+        )
+        .during(Step::Config)?;
+        // The installed system needs the key to unlock the persistent data partition with.
+        if let Some(EncryptionConfig {
+            unlock: Unlock::Keyfile { path },
+        }) = &config.storage.encryption
+        {
+            copy_key_file(&self.system.path(path), &target_dir.join(relative(path)))
+                .during(Step::Config)?;
+        }
+        self.record(journal, Step::Config)?;
+
+        let version = read_version(&target_dir);
+        let params = settings::boot_params(self.system).during(Step::Bootloader)?;
+        self.install_bootloader(
+            &[grub::Entry {
+                slot: Slot::A,
+                version,
+                params,
+            }],
+            &grub::GrubEnv::default(),
+            &target_dir,
+            &plan.disk,
+        )
+        .during(Step::Bootloader)?;
+        self.record(journal, Step::Bootloader)
+    }
+
+    /// Builds the initial ramdisk for the system mounted at `root`, with the modules needed to
+    /// reach it on `disk`.
+    fn build_initrd(&self, root: &Path, disk: &str) -> Result<(), Error> {
+        info!("Building the initial ramdisk");
+        let disk = disk.trim_start_matches("/dev/");
+        let report = initramfs::build(root, self.system, disk, &root.join(relative(INITRD_PATH)))?;
+        info!("Built the initial ramdisk: {}", report);
+        Ok(())
+    }
+
+    fn format_slot(&self, slot: Slot, partition: &str) -> Result<(), Error> {
+        self.run(&[
+            "mkfs.ext4",
+            "-F",
+            "-L",
+            slot.label(),
+            &self.device(partition),
+        ])
+    }
+
+    /// Creates the encrypted volume on the persistent data partition, if the plan has one. The
+    /// filesystem in it is set up by `configure` on first boot, like an unencrypted one.
+    fn encrypt_persist(&self, plan: &Plan) -> Result<(), Error> {
+        let Some(Persist {
+            device,
+            on_target,
+            unlock: Some(unlock),
+        }) = &plan.persist
+        else {
+            return Ok(());
+        };
+        // Anything found on a partition of the disk that's just been partitioned is left over
+        // from before, so there's nothing on it to keep.
+        if *on_target {
+            self.wait_for_device(device)?;
+        }
+        info!("Encrypting {}", device);
+        create_encrypted(device, unlock, *on_target, self.system, self.tools)
+    }
+
+    /// Writes the plan's partition table to the target disk, and waits for the kernel to pick it
+    /// up.
+    fn partition(&self, plan: &Plan) -> Result<(), Error> {
+        info!("Partitioning {}", plan.disk);
+        plan.layout.write(&self.system.path(&plan.disk))?;
+        self.run(&["blockdev", "--rereadpt", &self.device(&plan.disk)])?;
+        for (_, device) in &plan.slots {
+            self.wait_for_device(device)?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_device(&self, device: &str) -> Result<(), Error> {
+        let path = self.system.path(device);
+        let start = Instant::now();
+        while !path.exists() {
+            if start.elapsed() > self.device_timeout {
+                return Err(anyhow!("{} didn't appear after partitioning", device));
+            }
+            sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// Sets GRUB up to boot the systems in `entries` from the slot mounted at `root`, with the
+    /// given environment.
+    fn install_bootloader(
+        &self,
+        entries: &[grub::Entry],
+        env: &grub::GrubEnv,
+        root: &Path,
+        disk: &str,
+    ) -> Result<(), Error> {
+        let grub_dir = root.join("boot/grub");
+        fs::create_dir_all(&grub_dir)?;
+        fs::write(
+            root.join(relative(grub::CONFIG_PATH)),
+            grub::config(entries),
+        )?;
+        env.write(&root.join(relative(grub::ENV_PATH)))?;
+        self.run(&[
+            "grub-install",
+            &format!("--boot-directory={}", root.join("boot").display()),
+            &self.device(disk),
+        ])
+    }
+
+    fn mount(&self, device: &str, dir: &Path, options: &[&str]) -> Result<(), Error> {
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Unable to create {}: {}", dir.display(), e))?;
+        let mut command = vec!["mount"];
+        command.extend(options);
+        let device = self.device(device);
+        let dir_name = dir.display().to_string();
+        command.extend([device.as_str(), dir_name.as_str()]);
+        self.run(&command)?;
+        self.mounted.borrow_mut().push(dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Unmounts everything mounted so far, most recent first, carrying on past failures so as
+    /// much as possible is left unmounted.
+    fn unmount_all(&self) -> Result<(), Error> {
+        let mut failed = vec![];
+        while let Some(dir) = self.mounted.borrow_mut().pop() {
+            if let Err(e) = self.run(&["umount", &dir.display().to_string()]) {
+                warn!("{}", e);
+                failed.push(dir.display().to_string());
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow!("Unable to unmount {}", failed.join(", "))),
+        }
+    }
+
+    /// The path of a device under the system root.
+    fn device(&self, device: &str) -> String {
+        self.system.path(device).display().to_string()
+    }
+
+    fn run(&self, command: &[&str]) -> Result<(), Error> {
+        let command: Vec<String> = command.iter().map(|s| s.to_string()).collect();
+        self.tools.run(&command)
+    }
+}
+
+fn copy_key_file(source: &Path, target: &Path) -> Result<(), Error> {
+    fs::create_dir_all(target.parent().unwrap())?;
+    fs::copy(source, target).map_err(|e| anyhow!("Unable to copy {}: {}", source.display(), e))?;
+    fs::set_permissions(target, fs::Permissions::from_mode(0o400))?;
+    Ok(())
+}
+
+/// An absolute path on the installed system, relative to wherever it's mounted.
+fn relative(path: &str) -> &str {
+    path.trim_start_matches('/')
+}
+
+fn copy_kernel(source_dir: &Path, target_dir: &Path) -> Result<(), Error> {
+    let kernel = target_dir.join(relative(KERNEL_PATH));
+    fs::create_dir_all(kernel.parent().unwrap())?;
+    fs::copy(source_dir.join(relative(KERNEL_PATH)), &kernel)
+        .map_err(|e| anyhow!("Unable to copy {}: {}", KERNEL_PATH, e))?;
+    Ok(())
+}
+
+/// The version of HolOS installed at `root`.
+fn read_version(root: &Path) -> String {
+    fs::read_to_string(root.join("etc/holos-version"))
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+/// Copies the SSH keys trusted by the live system, so the same people can log in once it's
+/// installed.
+fn copy_keys(from: &Path, to: &Path) -> Result<(), Error> {
+    if !from.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(to)?;
+    fs::set_permissions(to, fs::Permissions::from_mode(0o700))?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            // fs::copy keeps the permissions, which ssh is particular about.
+            fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::blockdev::tests::{add_device, ext4_image, iso_image};
+    use archive::{ArchiveEntry, Content};
+    use bzip2::Compression;
+    use bzip2::write::BzEncoder;
+    use tempfile::TempDir;
+
+    /// Stands in for the external programs, doing just enough of what they would for the
+    /// installation to carry on. The commands it's asked to run are recorded with the system root
+    /// left out, along with anything given on their standard input.
+    pub(crate) struct Fake {
+        system: SystemRoot,
+        media: PathBuf,
+        fail: Option<&'static str>,
+        pub(crate) commands: RefCell<Vec<String>>,
+    }
+
+    impl Fake {
+        pub(crate) fn new(system: &SystemRoot, media: PathBuf) -> Self {
+            Fake {
+                system: system.clone(),
+                media,
+                fail: None,
+                commands: RefCell::new(vec![]),
+            }
+        }
+    }
+
+    impl Tools for Fake {
+        fn run(&self, command: &[String]) -> Result<(), Error> {
+            let root = self.system.root().display().to_string();
+            self.commands
+                .borrow_mut()
+                .push(command.join(" ").replace(&root, ""));
+            if self.fail == Some(command[0].as_str()) {
+                return Err(anyhow!("{} failed", command[0]));
+            }
+            match command[0].as_str() {
+                "blockdev" => {
+                    for partition in ["sda1", "sda2", "sda3"] {
+                        fs::write(self.system.path(&format!("/dev/{}", partition)), "").unwrap();
+                    }
+                }
+                // Labelled system slots can be found again, as they are after a reboot.
+                "mkfs.ext4" if command.contains(&"-F".to_string()) => {
+                    let label = &command[command.iter().position(|a| a == "-L").unwrap() + 1];
+                    let device = Path::new(command.last().unwrap());
+                    let name = device.file_name().unwrap().to_string_lossy();
+                    add_device(&self.system, &name, &ext4_image(label));
+                }
+                "mount" if command[2].ends_with("ro") => {
+                    let dir = Path::new(command.last().unwrap()).join("boot");
+                    fs::create_dir_all(&dir).unwrap();
+                    for file in ["bzImage", "rootfs.cpio.bz2"] {
+                        fs::copy(self.media.join(file), dir.join(file)).unwrap();
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        fn run_with_input(&self, command: &[String], input: &[u8]) -> Result<(), Error> {
+            self.run(command)?;
+            self.commands
+                .borrow_mut()
+                .push(format!("< {}", String::from_utf8_lossy(input)));
+            Ok(())
+        }
+    }
+
+    /// A system booted from installation media, with a blank disk to install onto.
+    pub(in crate::install) fn live_system(dir: &Path) -> (SystemRoot, PathBuf) {
+        let system = SystemRoot::new(dir.join("root"));
+        add_device(&system, "sda", &[]);
+        fs::write(system.path("/sys/class/block/sda/size"), "33554432\n").unwrap();
+        add_device(&system, "sdb", &iso_image(SOURCE_LABEL));
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(
+            system.cmdline(),
+            "root=live:LABEL=HolOS-install -- install github_usernames=alice\n",
+        )
+        .unwrap();
+        fs::write(system.path("/proc/mounts"), "proc /proc proc rw 0 0\n").unwrap();
+        fs::write(
+            system.path("/proc/swaps"),
+            "Filename\tType\tSize\tUsed\tPriority\n",
+        )
+        .unwrap();
+        fs::create_dir_all(system.path("/root/.ssh")).unwrap();
+        fs::write(
+            system.path("/root/.ssh/authorized_keys"),
+            "ssh-ed25519 AAAA\n",
+        )
+        .unwrap();
+
+        let media = dir.join("media");
+        fs::create_dir(&media).unwrap();
+        fs::write(media.join("bzImage"), "kernel").unwrap();
+        let entries = vec![
+            ArchiveEntry::new("bin", Content::Directory, 0o755),
+            ArchiveEntry::new("bin/busybox", Content::Data(b"busybox".to_vec()), 0o755),
+            ArchiveEntry::new("usr", Content::Directory, 0o755),
+            ArchiveEntry::new("usr/bin", Content::Directory, 0o755),
+            ArchiveEntry::new(
+                "usr/bin/holos-config",
+                Content::Data(b"holos-config".to_vec()),
+                0o755,
+            ),
+            ArchiveEntry::new("etc", Content::Directory, 0o755),
+            ArchiveEntry::new(
+                "etc/holos-version",
+                Content::Data(b"0.0.7\n".to_vec()),
+                0o644,
+            ),
+            ArchiveEntry::new("lib", Content::Directory, 0o755),
+            ArchiveEntry::new(
+                "lib/ld-musl-x86_64.so.1",
+                Content::Data(b"musl".to_vec()),
+                0o755,
+            ),
+            ArchiveEntry::new("sbin", Content::Directory, 0o755),
+            ArchiveEntry::new(
+                "sbin/init",
+                Content::Symlink("../bin/busybox".into()),
+                0o777,
+            ),
+            ArchiveEntry::new(
+                "lib/libc.so",
+                Content::Symlink("ld-musl-x86_64.so.1".into()),
+                0o777,
+            ),
+            ArchiveEntry::new("lib/modules", Content::Directory, 0o755),
+            ArchiveEntry::new("lib/modules/6.16.0", Content::Directory, 0o755),
+            ArchiveEntry::new(
+                "lib/modules/6.16.0/modules.dep",
+                Content::Data(b"".to_vec()),
+                0o644,
+            ),
+        ];
+        let rootfs = File::create(media.join("rootfs.cpio.bz2")).unwrap();
+        let mut encoder =
+            archive::pack(&entries, BzEncoder::new(rootfs, Compression::fast())).unwrap();
+        encoder.flush().unwrap();
+        encoder.finish().unwrap();
+        (system, media)
+    }
+
+    pub(in crate::install) fn config(storage: &str) -> HolosConfig {
+        serde_yaml::from_str(&format!(
+            "storage:\n{}\nnetwork:\n    nameservers:\n    interfaces:\nsecurity:\n    github_usernames:\n    ssh_keys:\n    rootpw_hash:\n",
+            storage
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    /// A whole installation onto a disk image, with the persistent data partition after the
+    /// system slots.
+    fn install() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let tools = Fake::new(&system, media);
+        let installer = Installer::new(&system, &tools);
+        installer
+            .install(&config(
+                "    install_partition: /dev/sda1\n    persist_partition: /dev/sda2",
+            ))
+            .unwrap();
+
+        assert_eq!(
+            *tools.commands.borrow(),
+            vec![
+                "blockdev --rereadpt /dev/sda",
+                "mkfs.ext4 -F -L HolOS-system-a /dev/sda1",
+                "mkfs.ext4 -F -L HolOS-system-b /dev/sda3",
+                "mount -o ro /dev/sdb /tmp/holos-install/source",
+                "mount -t ext4 /dev/sda1 /tmp/holos-install/target",
+                "grub-install --boot-directory=/tmp/holos-install/target/boot /dev/sda",
+                "umount /tmp/holos-install/target",
+                "umount /tmp/holos-install/source",
+            ]
+        );
+
+        let mbr = fs::read(system.path("/dev/sda")).unwrap();
+        assert_eq!(&mbr[510..512], &[0x55, 0xaa]);
+        // Both slots are 4GiB, and the data partition has the rest.
+        assert_eq!(&mbr[446 + 12..446 + 16], &(8u32 << 20).to_le_bytes());
+        assert_eq!(&mbr[478 + 8..478 + 12], &(24u32 << 20).to_le_bytes());
+        assert_eq!(&mbr[478 + 12..478 + 16], &(8u32 << 20).to_le_bytes());
+        assert_eq!(
+            &mbr[462 + 8..462 + 12],
+            &((8u32 << 20) + 2048).to_le_bytes()
+        );
+        assert_eq!(
+            &mbr[462 + 12..462 + 16],
+            &((16u32 << 20) - 2048).to_le_bytes()
+        );
+
+        let target = system.path("/tmp/holos-install/target");
+        assert_eq!(
+            fs::read_to_string(target.join("boot/bzImage")).unwrap(),
+            "kernel"
+        );
+        let grub_config = fs::read_to_string(target.join("boot/grub/grub.cfg")).unwrap();
+        assert!(grub_config.contains("HolOS 0.0.7 (slot a)"));
+        // The installed system boots with the configuration the live one had.
+        assert!(grub_config.contains(
+            "ro vga=791 config_file=/etc/holos/configs/installed.yaml github_usernames=alice\n"
+        ));
+        let installed: HolosConfig = serde_yaml::from_str(
+            &fs::read_to_string(target.join("etc/holos/configs/installed.yaml")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            installed.storage.install_partition,
+            Some(StorageIdentifier::Path {
+                path: "/dev/sda1".to_string()
+            })
+        );
+        assert!(target.join("root/.ssh/authorized_keys").exists());
+
+        let initrd = dir.path().join("initrd");
+        fs::create_dir(&initrd).unwrap();
+        archive::unpack(&target.join("boot/holos-initrd.img"), &initrd).unwrap();
+        assert_eq!(
+            fs::read_to_string(initrd.join("init")).unwrap(),
+            "holos-config"
+        );
+        assert_eq!(
+            fs::read_to_string(initrd.join("bin/sh")).unwrap(),
+            "busybox"
+        );
+        assert_eq!(
+            fs::read_to_string(initrd.join("lib/libc.so")).unwrap(),
+            "musl"
+        );
+        assert!(initrd.join("lib/modules/6.16.0/modules.dep").exists());
+    }
+
+    #[test]
+    /// The encrypted volume for the persistent data partition is created along with the system
+    /// partition, and the key file for it is copied onto the installed system.
+    fn encrypted() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let tools = Fake::new(&system, media);
+        Installer::new(&system, &tools)
+            .install(&config(
+                "    install_partition: /dev/sda1
+    persist_partition: /dev/sda2
+    encryption:
+        unlock: !keyfile
+            path: /etc/holos/persist.key",
+            ))
+            .unwrap();
+
+        let commands = tools.commands.borrow();
+        assert_eq!(commands[2], "mkfs.ext4 -F -L HolOS-system-b /dev/sda3");
+        assert!(commands[3].starts_with("cryptsetup luksFormat --batch-mode --type luks2 --uuid "));
+        assert!(commands[3].ends_with(" --key-file /etc/holos/persist.key /dev/sda2"));
+        assert_eq!(
+            commands[4],
+            "cryptsetup open --type luks --key-file /etc/holos/persist.key /dev/sda2 holos-data"
+        );
+        assert!(commands[5].starts_with("mkfs.ext4 -L HolOS-data -U "));
+        assert_eq!(commands[6], "cryptsetup close holos-data");
+
+        let key = system.path("/tmp/holos-install/target/etc/holos/persist.key");
+        assert_eq!(
+            fs::read(&key).unwrap(),
+            fs::read(system.path("/etc/holos/persist.key")).unwrap()
+        );
+        assert_eq!(
+            fs::metadata(&key).unwrap().permissions().mode() & 0o777,
+            0o400
+        );
+    }
+
+    #[test]
+    /// Failures say which step they happened at, and whatever was mounted is unmounted.
+    fn failures() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let mut tools = Fake::new(&system, media);
+        tools.fail = Some("grub-install");
+        let sda1 = config("    install_partition: /dev/sda1");
+        let error = Installer::new(&system, &tools).install(&sda1).unwrap_err();
+        assert_eq!(error.step, Step::Bootloader);
+        assert_eq!(
+            error.to_string(),
+            "Installation failed while installing the bootloader: grub-install failed"
+        );
+        assert!(tools.commands.borrow().ends_with(&[
+            "umount /tmp/holos-install/target".to_string(),
+            "umount /tmp/holos-install/source".to_string(),
+        ]));
+        // Without a data partition on the disk, the slots share it.
+        let mbr = fs::read(system.path("/dev/sda")).unwrap();
+        assert_eq!(
+            &mbr[446 + 12..446 + 16],
+            &((16u32 << 20) - 2048).to_le_bytes()
+        );
+        assert_eq!(&mbr[462 + 12..462 + 16], &(16u32 << 20).to_le_bytes());
+
+        tools.fail = Some("mkfs.ext4");
+        tools.commands.borrow_mut().clear();
+        let error = Installer::new(&system, &tools).install(&sda1).unwrap_err();
+        assert_eq!(error.step, Step::Format);
+        assert!(
+            !tools
+                .commands
+                .borrow()
+                .iter()
+                .any(|c| c.starts_with("umount"))
+        );
+
+        let error = Installer::new(&system, &tools)
+            .install(&config("    install_partition: /dev/sda"))
+            .unwrap_err();
+        assert_eq!(error.step, Step::Partition);
+
+        fs::remove_file(system.path("/dev/sdb")).unwrap();
+        let error = Installer::new(&system, &tools).install(&sda1).unwrap_err();
+        assert_eq!(error.step, Step::Source);
+    }
+
+    #[test]
+    /// A failed installation is recorded in the journal, and retrying it doesn't partition the
+    /// disk or encrypt the persistent data partition again.
+    fn resume() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let mut tools = Fake::new(&system, media);
+        tools.fail = Some("grub-install");
+        let config = config(
+            "    install_partition: /dev/sda1
+    persist_partition: /dev/sda2
+    encryption:
+        unlock: !keyfile
+            path: /etc/holos/persist.key",
+        );
+        let installer = Installer::new(&system, &tools);
+        installer.install(&config).unwrap_err();
+
+        let plan = installer.plan(&config).unwrap();
+        let journal = installer.resumable(&plan).unwrap();
+        assert_eq!(
+            journal.failed,
+            Some(Failure {
+                step: Step::Bootloader,
+                error: "grub-install failed".to_string()
+            })
+        );
+        assert_eq!(
+            journal.done,
+            vec![
+                Step::Partition,
+                Step::Format,
+                Step::Persist,
+                Step::Mount,
+                Step::Unpack,
+                Step::Kernel,
+                Step::Initrd,
+                Step::Keys,
+                Step::Config,
+            ]
+        );
+
+        tools.fail = None;
+        tools.commands.borrow_mut().clear();
+        Installer::new(&system, &tools).install(&config).unwrap();
+        // Only slot a, which the last attempt had copied into, is formatted again.
+        assert_eq!(
+            *tools.commands.borrow(),
+            vec![
+                "mkfs.ext4 -F -L HolOS-system-a /dev/sda1",
+                "mount -o ro /dev/sdb /tmp/holos-install/source",
+                "mount -t ext4 /dev/sda1 /tmp/holos-install/target",
+                "grub-install --boot-directory=/tmp/holos-install/target/boot /dev/sda",
+                "umount /tmp/holos-install/target",
+                "umount /tmp/holos-install/source",
+            ]
+        );
+        assert!(!system.path(JOURNAL_PATH).exists());
+
+        // A journal for some other plan is ignored.
+        let mut other = journal.clone();
+        other.plan = "Wipe /dev/sdc\n".to_string();
+        other.write(&system.path(JOURNAL_PATH)).unwrap();
+        assert_eq!(Installer::new(&system, &tools).resumable(&plan), None);
+    }
+}
@@ -0,0 +1,424 @@
+/// Reading and writing the "newc" cpio archives the kernel uses for initial ramdisks. The HolOS
+/// root filesystem is shipped as one (bzip2 compressed) on the installation media, and unpacked
+/// onto the target disk; the installed system's initial ramdisk is packed as one.
+use anyhow::{Error, anyhow};
+use bzip2::read::BzDecoder;
+use cpio::NewcReader;
+use cpio::newc::{Builder as NewcBuilder, ModeFileType, trailer};
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::fs::{File, Permissions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{PermissionsExt, lchown, symlink};
+use std::path::{Component, Path, PathBuf};
+
+const FILE_TYPE_MASK: u32 = 0o170000;
+const PERMISSION_MASK: u32 = 0o7777;
+/// Symbolic links are followed this many times at most while resolving a path, as in the kernel.
+const MAX_SYMLINKS: usize = 40;
+
+/// Unpacks the cpio archive at `archive` into `dest`, decompressing it first if it's compressed
+/// with bzip2. When running as root, ownership is kept and device nodes are created; otherwise
+/// everything belongs to the current user and device nodes are skipped. Returns the number of
+/// entries unpacked.
+pub fn unpack(archive: &Path, dest: &Path) -> Result<usize, Error> {
+    let file =
+        File::open(archive).map_err(|e| anyhow!("Unable to open {}: {}", archive.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let magic = reader.fill_buf()?;
+    let reader: Box<dyn Read> = if magic.starts_with(b"BZh") {
+        Box::new(BzDecoder::new(reader))
+    } else if magic.starts_with(b"0707") {
+        Box::new(reader)
+    } else {
+        return Err(anyhow!(
+            "{} is not a cpio archive, or is compressed with something other than bzip2",
+            archive.display()
+        ));
+    };
+    unpack_from(reader, dest, is_root())
+        .map_err(|e| anyhow!("Unable to unpack {}: {}", archive.display(), e))
+}
+
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+fn unpack_from(mut reader: Box<dyn Read>, dest: &Path, as_root: bool) -> Result<usize, Error> {
+    // Directories are created writable, and only given their real permissions once everything
+    // that goes in them has been unpacked.
+    let mut directories: Vec<(PathBuf, u32)> = vec![];
+    // Hard linked files appear once for each name, keyed by device and inode number.
+    let mut links: HashMap<(u32, u32, u32), PathBuf> = HashMap::new();
+    let mut count = 0;
+
+    loop {
+        let mut entry = NewcReader::new(reader)?;
+        if entry.entry().is_trailer() {
+            break;
+        }
+        let name = entry.entry().name().to_string();
+        let mode = entry.entry().mode();
+        let (uid, gid) = (entry.entry().uid(), entry.entry().gid());
+        let path = resolve(dest, &name)?;
+        if path == dest {
+            // The archive's own "." entry.
+            reader = entry.finish()?;
+            continue;
+        }
+
+        match mode & FILE_TYPE_MASK {
+            t if t == u32::from(ModeFileType::Directory) => {
+                if !path.is_dir() {
+                    fs::create_dir(&path)
+                        .map_err(|e| anyhow!("Unable to create {}: {}", name, e))?;
+                }
+                set_owner(&path, uid, gid, as_root)?;
+                fs::set_permissions(&path, Permissions::from_mode(0o755))?;
+                directories.push((path.clone(), mode));
+                reader = entry.finish()?;
+            }
+            t if t == u32::from(ModeFileType::Regular) => {
+                let key = (
+                    entry.entry().dev_major(),
+                    entry.entry().dev_minor(),
+                    entry.entry().ino(),
+                );
+                let linked = entry.entry().nlink() > 1;
+                remove_existing(&path)?;
+                match links.get(&key).filter(|_| linked) {
+                    Some(first) => {
+                        // Whichever of the names carries the data, it's shared by all of them.
+                        if entry.entry().file_size() > 0 {
+                            let mut file = File::create(first)?;
+                            io_copy(&mut entry, &mut file, &name)?;
+                        }
+                        fs::hard_link(first, &path)
+                            .map_err(|e| anyhow!("Unable to link {}: {}", name, e))?;
+                    }
+                    None => {
+                        let mut file = File::create(&path)
+                            .map_err(|e| anyhow!("Unable to create {}: {}", name, e))?;
+                        io_copy(&mut entry, &mut file, &name)?;
+                        if linked {
+                            links.insert(key, path.clone());
+                        }
+                    }
+                }
+                set_owner(&path, uid, gid, as_root)?;
+                fs::set_permissions(&path, Permissions::from_mode(mode & PERMISSION_MASK))?;
+                reader = entry.finish()?;
+            }
+            t if t == u32::from(ModeFileType::Symlink) => {
+                let mut target = vec![];
+                entry.read_to_end(&mut target)?;
+                remove_existing(&path)?;
+                symlink(OsString::from_vec(target), &path)
+                    .map_err(|e| anyhow!("Unable to create {}: {}", name, e))?;
+                set_owner(&path, uid, gid, as_root)?;
+                reader = entry.finish()?;
+            }
+            t if t == u32::from(ModeFileType::Char)
+                || t == u32::from(ModeFileType::Block)
+                || t == u32::from(ModeFileType::Fifo) =>
+            {
+                let (major, minor) = (entry.entry().rdev_major(), entry.entry().rdev_minor());
+                reader = entry.finish()?;
+                if !as_root {
+                    warn!(
+                        "Not creating device node {}, as we're not running as root",
+                        name
+                    );
+                    continue;
+                }
+                remove_existing(&path)?;
+                make_node(&path, mode, major, minor)
+                    .map_err(|e| anyhow!("Unable to create {}: {}", name, e))?;
+                set_owner(&path, uid, gid, as_root)?;
+                fs::set_permissions(&path, Permissions::from_mode(mode & PERMISSION_MASK))?;
+            }
+            _ => {
+                warn!("Skipping {}, as files of its type can't be unpacked", name);
+                reader = entry.finish()?;
+                continue;
+            }
+        }
+        count += 1;
+    }
+
+    for (path, mode) in directories.iter().rev() {
+        fs::set_permissions(path, Permissions::from_mode(mode & PERMISSION_MASK))?;
+    }
+    Ok(count)
+}
+
+fn io_copy(from: &mut impl Read, to: &mut File, name: &str) -> Result<(), Error> {
+    std::io::copy(from, to).map_err(|e| anyhow!("Unable to write {}: {}", name, e))?;
+    Ok(())
+}
+
+/// Removes whatever is at `path` (but not a directory), so that it can be replaced rather than
+/// written through, which would follow a symbolic link.
+fn remove_existing(path: &Path) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => Err(anyhow!("{} is a directory", path.display())),
+        Ok(_) => Ok(fs::remove_file(path)?),
+        Err(_) => Ok(()),
+    }
+}
+
+fn set_owner(path: &Path, uid: u32, gid: u32, as_root: bool) -> Result<(), Error> {
+    if as_root {
+        lchown(path, Some(uid), Some(gid))
+            .map_err(|e| anyhow!("Unable to change the owner of {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn make_node(path: &Path, mode: u32, major: u32, minor: u32) -> Result<(), Error> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid NUL terminated string, and mknod doesn't keep hold of it.
+    let result = unsafe {
+        libc::mknod(
+            c_path.as_ptr(),
+            mode as libc::mode_t,
+            libc::makedev(major, minor),
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Works out where `name` from an archive belongs under `dest`, as if `dest` were the root
+/// directory. Symbolic links already unpacked (such as `lib64 -> lib`) are followed, with
+/// absolute ones taken as relative to `dest`, and nothing can lead outside of `dest`. The last
+/// component isn't followed, as it's what's about to be created.
+fn resolve(dest: &Path, name: &str) -> Result<PathBuf, Error> {
+    let mut pending: Vec<OsString> = components(Path::new(name));
+    pending.reverse();
+    let mut resolved: Vec<OsString> = vec![];
+    let mut followed = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            resolved.pop();
+            continue;
+        }
+        let path = dest
+            .join(resolved.iter().collect::<PathBuf>())
+            .join(&component);
+        let is_last = pending.is_empty();
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_symlink() && !is_last => {
+                followed += 1;
+                if followed > MAX_SYMLINKS {
+                    return Err(anyhow!("Too many levels of symbolic links in {}", name));
+                }
+                let target = fs::read_link(&path)?;
+                if target.is_absolute() {
+                    resolved.clear();
+                }
+                pending.extend(components(&target).into_iter().rev());
+            }
+            _ => resolved.push(component),
+        }
+    }
+    Ok(dest.join(resolved.iter().collect::<PathBuf>()))
+}
+
+/// The names in a path, with `..` kept but the root and `.` dropped.
+fn components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Something to put in an archive being packed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveEntry {
+    /// Where it goes in the archive, without a leading `/`.
+    pub path: String,
+    pub content: Content,
+    /// The permission bits, such as 0o755.
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Content {
+    Directory,
+    /// A regular file, copied from the given file.
+    File(PathBuf),
+    /// A regular file with the given contents.
+    Data(Vec<u8>),
+    Symlink(PathBuf),
+    CharDevice(u32, u32),
+}
+
+impl ArchiveEntry {
+    pub fn new(path: &str, content: Content, mode: u32) -> Self {
+        ArchiveEntry {
+            path: path.trim_start_matches('/').to_string(),
+            content,
+            mode,
+        }
+    }
+}
+
+/// Packs `entries` into a newc cpio archive, in the order given. Everything is owned by root and
+/// timestamps are left at zero, so the same entries always produce the same archive.
+pub fn pack<W: Write>(entries: &[ArchiveEntry], mut writer: W) -> Result<W, Error> {
+    for (i, entry) in entries.iter().enumerate() {
+        let (file_type, data) = match &entry.content {
+            Content::Directory => (ModeFileType::Directory, vec![]),
+            Content::File(source) => (
+                ModeFileType::Regular,
+                fs::read(source)
+                    .map_err(|e| anyhow!("Unable to read {}: {}", source.display(), e))?,
+            ),
+            Content::Data(data) => (ModeFileType::Regular, data.clone()),
+            Content::Symlink(target) => (
+                ModeFileType::Symlink,
+                target.as_os_str().as_bytes().to_vec(),
+            ),
+            Content::CharDevice(..) => (ModeFileType::Char, vec![]),
+        };
+        let size = u32::try_from(data.len())
+            .map_err(|_| anyhow!("{} is too big for a cpio archive", entry.path))?;
+        let mut builder = NewcBuilder::new(&entry.path)
+            .ino(i as u32 + 1)
+            .mode(entry.mode & PERMISSION_MASK)
+            .set_mode_file_type(file_type)
+            .nlink(if entry.content == Content::Directory {
+                2
+            } else {
+                1
+            });
+        if let Content::CharDevice(major, minor) = entry.content {
+            builder = builder.rdev_major(major).rdev_minor(minor);
+        }
+        let mut file = builder.write(writer, size);
+        file.write_all(&data)?;
+        writer = file.finish()?;
+    }
+    Ok(trailer(writer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::TempDir;
+
+    fn raw_entry(
+        archive: Vec<u8>,
+        name: &str,
+        mode: u32,
+        ino: u32,
+        nlink: u32,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut file = NewcBuilder::new(name)
+            .ino(ino)
+            .mode(mode)
+            .nlink(nlink)
+            .write(archive, data.len() as u32);
+        file.write_all(data).unwrap();
+        file.finish().unwrap()
+    }
+
+    #[test]
+    /// What's packed comes back out the same, and the same entries always pack the same way.
+    fn round_trip() {
+        let dir = TempDir::new().unwrap();
+        let source = dir.path().join("busybox");
+        fs::write(&source, "#!/bin/sh\n").unwrap();
+        let entries = vec![
+            ArchiveEntry::new("bin", Content::Directory, 0o755),
+            ArchiveEntry::new("/bin/busybox", Content::File(source), 0o755),
+            ArchiveEntry::new("bin/sh", Content::Symlink("busybox".into()), 0o777),
+            ArchiveEntry::new("etc", Content::Directory, 0o700),
+            ArchiveEntry::new("etc/motd", Content::Data(b"Hello".to_vec()), 0o644),
+            ArchiveEntry::new("dev", Content::Directory, 0o755),
+            ArchiveEntry::new("dev/console", Content::CharDevice(5, 1), 0o600),
+        ];
+        let packed = pack(&entries, vec![]).unwrap();
+        assert_eq!(packed, pack(&entries, vec![]).unwrap());
+        let archive = dir.path().join("archive.cpio");
+        fs::write(&archive, &packed).unwrap();
+
+        let dest = dir.path().join("root");
+        fs::create_dir(&dest).unwrap();
+        let count = unpack(&archive, &dest).unwrap();
+        assert_eq!(count, if is_root() { 7 } else { 6 });
+        assert_eq!(
+            fs::read_to_string(dest.join("bin/busybox")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        assert_eq!(
+            fs::read_link(dest.join("bin/sh")).unwrap(),
+            Path::new("busybox")
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("bin/sh")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        assert_eq!(fs::read_to_string(dest.join("etc/motd")).unwrap(), "Hello");
+        let mode = |path: &str| fs::metadata(dest.join(path)).unwrap().permissions().mode();
+        assert_eq!(mode("bin/busybox") & 0o7777, 0o755);
+        assert_eq!(mode("etc") & 0o7777, 0o700);
+        assert_eq!(mode("etc/motd") & 0o7777, 0o644);
+        if is_root() {
+            let console = fs::metadata(dest.join("dev/console")).unwrap();
+            assert_eq!(console.rdev(), libc::makedev(5, 1));
+        }
+    }
+
+    #[test]
+    /// Hard links are kept whichever name carries the data, and absolute symbolic links are
+    /// followed within the destination rather than out of it.
+    fn links() {
+        let dir = TempDir::new().unwrap();
+        let mut archive = raw_entry(vec![], "usr", 0o040755, 1, 2, b"");
+        archive = raw_entry(archive, "lib", 0o120777, 2, 1, b"/usr");
+        // GNU cpio puts the data on the last of the names.
+        archive = raw_entry(archive, "lib/a", 0o100644, 3, 2, b"");
+        archive = raw_entry(archive, "lib/b", 0o100644, 3, 2, b"linked");
+        archive = raw_entry(archive, "../escape", 0o100644, 4, 1, b"contained");
+        let archive = trailer(archive).unwrap();
+        let path = dir.path().join("archive.cpio");
+        fs::write(&path, archive).unwrap();
+
+        let dest = dir.path().join("root");
+        fs::create_dir(&dest).unwrap();
+        assert_eq!(unpack(&path, &dest).unwrap(), 5);
+        assert_eq!(fs::read_to_string(dest.join("usr/a")).unwrap(), "linked");
+        assert_eq!(
+            fs::metadata(dest.join("usr/a")).unwrap().ino(),
+            fs::metadata(dest.join("usr/b")).unwrap().ino()
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("escape")).unwrap(),
+            "contained"
+        );
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[test]
+    fn not_an_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("rootfs.cpio.gz");
+        fs::write(&path, [0x1f, 0x8b, 8, 0]).unwrap();
+        let error = unpack(&path, dir.path()).unwrap_err();
+        assert!(error.to_string().contains("not a cpio archive"));
+    }
+}
@@ -0,0 +1,198 @@
+/// The GRUB configuration for booting the system slots, and the environment block GRUB and
+/// holos-config share to give a newly upgraded slot a trial boot. GRUB marks the environment when
+/// it starts a trial boot, and `holos-config confirm-boot` clears it once the system is up. If the
+/// mark is still there the next time GRUB runs, the trial boot never got that far, and the
+/// previous system is booted instead.
+use super::{INITRD_PATH, KERNEL_PATH, Slot};
+use anyhow::{Error, anyhow};
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// The configuration file, in the slot GRUB was installed to.
+pub const CONFIG_PATH: &str = "/boot/grub/grub.cfg";
+/// The environment block, next to the configuration.
+pub const ENV_PATH: &str = "/boot/grub/grubenv";
+/// Set in the environment for a slot that hasn't confirmed a successful boot yet.
+pub const TRIAL: &str = "holos_trial";
+/// Set by GRUB when it starts the trial boot.
+pub const TRIED: &str = "holos_tried";
+/// The kernel parameter telling the system it wasn't GRUB's first choice.
+pub const FALLBACK_PARAM: &str = "holos_fallback";
+/// GRUB only rewrites environment blocks in place, so they're always this size.
+const ENV_SIZE: usize = 1024;
+const ENV_HEADER: &str = "# GRUB Environment Block\n";
+
+/// A system in one of the slots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub slot: Slot,
+    /// The version of HolOS in the slot, as shown in the menu.
+    pub version: String,
+    /// Kernel parameters given after the ones every entry has.
+    pub params: Vec<String>,
+}
+
+/// The configuration for booting the systems in `entries`, the first by default. While the first
+/// is on trial, it gets one attempt, and after that the second is booted.
+pub fn config(entries: &[Entry]) -> String {
+    let mut out = String::from(
+        r#"set default="0"
+set timeout="5"
+
+load_env
+if [ "${holos_trial}" = "1" ]; then
+	if [ "${holos_tried}" = "1" ]; then
+		set default="1"
+	else
+		set holos_tried="1"
+		save_env holos_tried
+	fi
+fi
+"#,
+    );
+    for (i, entry) in entries.iter().enumerate() {
+        let mut params: String = entry.params.iter().map(|p| format!(" {}", p)).collect();
+        if i > 0 {
+            params += &format!(" {}", FALLBACK_PARAM);
+        }
+        write!(
+            out,
+            r#"
+menuentry "HolOS {} (slot {})" {{
+	search --no-floppy --label --set=root {}
+	linux {} root=LABEL={} ro vga=791{}
+	initrd {}
+}}
+"#,
+            entry.version,
+            entry.slot,
+            entry.slot.label(),
+            KERNEL_PATH,
+            entry.slot.label(),
+            params,
+            INITRD_PATH
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// The variables in a GRUB environment block.
+#[derive(Debug, Default, PartialEq)]
+pub struct GrubEnv {
+    vars: Vec<(String, String)>,
+}
+
+impl GrubEnv {
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let body = content
+            .strip_prefix(ENV_HEADER)
+            .ok_or_else(|| anyhow!("Not a GRUB environment block"))?;
+        let vars = body
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Ok(GrubEnv { vars })
+    }
+
+    /// Reads the environment block at `path`, which is empty if there isn't one.
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(content) => GrubEnv::parse(&content)
+                .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(GrubEnv::default()),
+            Err(e) => Err(anyhow!("Unable to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.unset(name);
+        self.vars.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.retain(|(n, _)| n != name);
+    }
+
+    /// The block, padded out to its fixed size.
+    pub fn render(&self) -> Result<String, Error> {
+        let mut out = String::from(ENV_HEADER);
+        for (name, value) in &self.vars {
+            writeln!(out, "{}={}", name, value)?;
+        }
+        if out.len() > ENV_SIZE {
+            return Err(anyhow!("The GRUB environment is too big"));
+        }
+        out += "#".repeat(ENV_SIZE - out.len()).as_str();
+        Ok(out)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        fs::write(path, self.render()?)
+            .map_err(|e| anyhow!("Unable to write {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let config = config(&[
+            Entry {
+                slot: Slot::B,
+                version: "0.0.8".to_string(),
+                params: vec!["config_file=/etc/holos/configs/installed.yaml".to_string()],
+            },
+            Entry {
+                slot: Slot::A,
+                version: "0.0.7".to_string(),
+                params: vec![],
+            },
+        ]);
+        assert!(config.contains("save_env holos_tried\n"));
+        assert!(config.contains(
+            "menuentry \"HolOS 0.0.8 (slot b)\" {\n\
+             \tsearch --no-floppy --label --set=root HolOS-system-b\n\
+             \tlinux /boot/bzImage root=LABEL=HolOS-system-b ro vga=791 \
+             config_file=/etc/holos/configs/installed.yaml\n\
+             \tinitrd /boot/holos-initrd.img\n}\n"
+        ));
+        assert!(config.contains(
+            "\tlinux /boot/bzImage root=LABEL=HolOS-system-a ro vga=791 holos_fallback\n"
+        ));
+    }
+
+    #[test]
+    fn env() {
+        let mut env = GrubEnv::default();
+        env.set(TRIAL, "1");
+        env.set("saved_entry", "0");
+        env.set(TRIAL, "1");
+        let rendered = env.render().unwrap();
+        assert_eq!(rendered.len(), ENV_SIZE);
+        assert!(rendered.starts_with("# GRUB Environment Block\nsaved_entry=0\nholos_trial=1\n##"));
+
+        let mut parsed = GrubEnv::parse(&rendered).unwrap();
+        assert_eq!(parsed, env);
+        assert_eq!(parsed.get(TRIAL), Some("1"));
+        parsed.unset(TRIAL);
+        assert_eq!(parsed.get(TRIAL), None);
+
+        assert!(GrubEnv::parse("holos_trial=1\n").is_err());
+        env.set("big", &"x".repeat(ENV_SIZE));
+        assert!(env.render().is_err());
+    }
+}
@@ -0,0 +1,557 @@
+/// The initial ramdisk for an installed system. It holds a busybox with just enough applets for the
+/// init program, the C library, the init program itself, and only the kernel modules needed to
+/// reach the root filesystem: the filesystem's own, and the drivers for the devices between the
+/// disk it's on and the system bus, found from their modaliases in sysfs through `modules.alias`.
+/// Each module brings along the ones it depends on in `modules.dep`. The same system and disk
+/// always give the same archive.
+use super::INITRD_PATH;
+use super::archive::{self, ArchiveEntry, Content};
+use crate::StorageIdentifier;
+use crate::blockdev::resolve;
+use crate::cmdline::CmdLine;
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use bzip2::Compression;
+use bzip2::write::BzEncoder;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The busybox applets the init program and rescue shell use.
+const APPLETS: &[&str] = &[
+    "sh",
+    "modprobe",
+    "lsmod",
+    "switch_root",
+    "mount",
+    "umount",
+    "ls",
+    "cat",
+    "dmesg",
+    "blkid",
+];
+/// The init program, on the installed system: holos-config, which is statically linked.
+const INIT_PATH: &str = "usr/bin/holos-config";
+/// Modules needed whichever disk the root filesystem is on.
+const BASE_MODULES: &[&str] = &["ext4"];
+
+/// What went into an initial ramdisk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// The size of the compressed archive, in bytes.
+    pub size: u64,
+    /// The names of the kernel modules in it, sorted.
+    pub modules: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}KiB", self.size.div_ceil(1024))?;
+        match self.modules.is_empty() {
+            true => write!(f, ", with no kernel modules"),
+            false => write!(
+                f,
+                ", with {} kernel modules: {}",
+                self.modules.len(),
+                self.modules.join(" ")
+            ),
+        }
+    }
+}
+
+/// Builds the initial ramdisk for the system at `root` to boot from `disk` (eg `sda`), whose
+/// devices are found in `system`'s sysfs, and writes it to `output`.
+pub fn build(root: &Path, system: &SystemRoot, disk: &str, output: &Path) -> Result<Report, Error> {
+    let mut entries = base_entries(root)?;
+    let mut modules = vec![];
+    match modules_dir(root)? {
+        Some(dir) => {
+            let index = ModuleIndex::read(&dir)?;
+            let wanted = wanted_modules(system, disk, &index);
+            modules = index.with_dependencies(&wanted);
+            add_modules(root, &dir, &index, &modules, &mut entries)?;
+        }
+        None => warn!(
+            "There are no kernel modules in {}/lib/modules",
+            root.display()
+        ),
+    }
+
+    let file = File::create(output)
+        .map_err(|e| anyhow!("Unable to create {}: {}", output.display(), e))?;
+    let encoder = archive::pack(
+        &entries,
+        BzEncoder::new(BufWriter::new(file), Compression::best()),
+    )?;
+    encoder
+        .finish()?
+        .flush()
+        .map_err(|e| anyhow!("Unable to write {}: {}", output.display(), e))?;
+    Ok(Report {
+        size: fs::metadata(output)?.len(),
+        modules,
+    })
+}
+
+/// Rebuilds the initial ramdisk of the running system, for the disk its root filesystem is on,
+/// and writes it to `output`, or where the bootloader loads it from.
+pub fn rebuild(system: &SystemRoot, output: Option<&str>) -> Result<Report, Error> {
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string())?;
+    let root = cmdline
+        .root
+        .ok_or_else(|| anyhow!("There's no root= on the kernel command line"))?;
+    let location = resolve(system, &StorageIdentifier::from_root(&root))?;
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => system.path(INITRD_PATH),
+    };
+    build(system.root(), system, &location.disk, &output)
+}
+
+/// Everything but the kernel modules: busybox and the applets, the musl C library it needs, and
+/// the init program.
+fn base_entries(root: &Path) -> Result<Vec<ArchiveEntry>, Error> {
+    let mut entries: Vec<ArchiveEntry> = [
+        "bin", "dev", "etc", "lib", "mnt", "proc", "sbin", "sys", "usr", "usr/bin", "usr/sbin",
+    ]
+    .iter()
+    .map(|dir| ArchiveEntry::new(dir, Content::Directory, 0o755))
+    .collect();
+    // The kernel opens the console before running init, and there's no devtmpfs yet.
+    entries.push(ArchiveEntry::new(
+        "dev/console",
+        Content::CharDevice(5, 1),
+        0o600,
+    ));
+
+    entries.push(ArchiveEntry::new(
+        "bin/busybox",
+        Content::File(existing(root, "bin/busybox")?),
+        0o755,
+    ));
+    for applet in APPLETS {
+        entries.push(ArchiveEntry::new(
+            &format!("bin/{}", applet),
+            Content::Symlink(PathBuf::from("busybox")),
+            0o777,
+        ));
+    }
+    let mut libraries = vec![];
+    for pattern in ["lib/ld-musl-x86_64*", "lib/libc.*"] {
+        let pattern = format!(
+            "{}/{}",
+            glob::Pattern::escape(&root.to_string_lossy()),
+            pattern
+        );
+        libraries.extend(glob::glob(&pattern)?.filter_map(|path| path.ok()));
+    }
+    if libraries.is_empty() {
+        return Err(anyhow!("The C library isn't in {}/lib", root.display()));
+    }
+    for library in libraries {
+        entries.push(tree_entry(root, &library)?);
+    }
+    entries.push(ArchiveEntry::new(
+        "init",
+        Content::File(existing(root, INIT_PATH)?),
+        0o755,
+    ));
+    Ok(entries)
+}
+
+/// The modules directory for the kernel the system at `root` was built with. There's only ever
+/// one kernel on a HolOS system.
+fn modules_dir(root: &Path) -> Result<Option<PathBuf>, Error> {
+    let modules = root.join("lib/modules");
+    if !modules.is_dir() {
+        return Ok(None);
+    }
+    let mut kernels: Vec<PathBuf> = fs::read_dir(&modules)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| path.join("modules.dep").is_file())
+        .collect();
+    kernels.sort();
+    match kernels.len() {
+        0 => Ok(None),
+        1 => Ok(kernels.pop()),
+        _ => Err(anyhow!(
+            "There are modules for more than one kernel in {}",
+            modules.display()
+        )),
+    }
+}
+
+/// The name a module is known by: its file name without the extensions, with dashes made
+/// underscores as the kernel does.
+fn module_name(path: &str) -> String {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let stem = file.split(".ko").next().unwrap_or(file);
+    stem.replace('-', "_")
+}
+
+/// What the kernel's module index files say.
+struct ModuleIndex {
+    /// Each module's file, and the files of the modules it depends on, by name. The files are
+    /// relative to the modules directory.
+    modules: BTreeMap<String, (String, Vec<String>)>,
+    /// The modalias patterns each module handles, in the order they're listed.
+    aliases: Vec<(String, String)>,
+}
+
+impl ModuleIndex {
+    fn read(dir: &Path) -> Result<Self, Error> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            fs::read_to_string(&path)
+                .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))
+        };
+        let modules = read("modules.dep")?
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(path, deps)| {
+                let deps = deps.split_whitespace().map(|d| d.to_string()).collect();
+                (module_name(path), (path.to_string(), deps))
+            })
+            .collect();
+        // There can be no aliases if every driver is built in.
+        let aliases = match dir.join("modules.alias").exists() {
+            true => read("modules.alias")?
+                .lines()
+                .filter_map(|line| {
+                    let mut fields = line.split_whitespace();
+                    match (fields.next(), fields.next(), fields.next()) {
+                        (Some("alias"), Some(pattern), Some(module)) => {
+                            Some((pattern.to_string(), module.replace('-', "_")))
+                        }
+                        _ => None,
+                    }
+                })
+                .collect(),
+            false => vec![],
+        };
+        Ok(ModuleIndex { modules, aliases })
+    }
+
+    /// The modules handling a device with the given modalias.
+    fn lookup(&self, modalias: &str) -> Vec<String> {
+        self.aliases
+            .iter()
+            .filter(|(pattern, _)| {
+                glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches(modalias))
+            })
+            .map(|(_, module)| module.clone())
+            .collect()
+    }
+
+    /// The modules in `wanted` that aren't built into the kernel, along with everything they
+    /// depend on, sorted by name.
+    fn with_dependencies(&self, wanted: &BTreeSet<String>) -> Vec<String> {
+        let mut found = BTreeSet::new();
+        let mut pending: Vec<String> = wanted.iter().cloned().collect();
+        while let Some(name) = pending.pop() {
+            let Some((_, deps)) = self.modules.get(&name) else {
+                // Built in, or not built at all.
+                continue;
+            };
+            if found.insert(name) {
+                pending.extend(deps.iter().map(|dep| module_name(dep)));
+            }
+        }
+        found.into_iter().collect()
+    }
+}
+
+/// The modules for the filesystem and the devices the disk hangs off. The disk's directory in
+/// sysfs is a link into the device tree, and each device above it has a modalias for the driver
+/// it needs, and a link to the module of the driver that's bound to it now.
+fn wanted_modules(system: &SystemRoot, disk: &str, index: &ModuleIndex) -> BTreeSet<String> {
+    let mut wanted: BTreeSet<String> = BASE_MODULES.iter().map(|m| m.to_string()).collect();
+    let sys = system.path("/sys");
+    let (Ok(sys), Ok(mut dir)) = (
+        fs::canonicalize(&sys),
+        fs::canonicalize(sys.join("class/block").join(disk)),
+    ) else {
+        warn!(
+            "{} isn't in sysfs, so only the filesystem modules are included",
+            disk
+        );
+        return wanted;
+    };
+    while dir.starts_with(&sys) && dir != sys {
+        if let Ok(modalias) = fs::read_to_string(dir.join("modalias")) {
+            wanted.extend(index.lookup(modalias.trim()));
+        }
+        if let Ok(module) = fs::read_link(dir.join("driver/module"))
+            && let Some(name) = module.file_name()
+        {
+            wanted.insert(name.to_string_lossy().replace('-', "_"));
+        }
+        dir.pop();
+    }
+    wanted
+}
+
+/// Adds the module files, and index files that only list them so that modprobe doesn't look for
+/// the rest.
+fn add_modules(
+    root: &Path,
+    dir: &Path,
+    index: &ModuleIndex,
+    modules: &[String],
+    entries: &mut Vec<ArchiveEntry>,
+) -> Result<(), Error> {
+    let files: BTreeSet<&str> = modules
+        .iter()
+        .map(|name| index.modules[name].0.as_str())
+        .collect();
+    let base = dir.strip_prefix(root)?.to_string_lossy().to_string();
+    let mut dirs = BTreeSet::new();
+    for file in &files {
+        let mut parent = Path::new(file).parent();
+        while let Some(p) = parent.filter(|p| !p.as_os_str().is_empty()) {
+            dirs.insert(p.to_path_buf());
+            parent = p.parent();
+        }
+    }
+    for parent in ["lib/modules".to_string(), base.clone()] {
+        entries.push(ArchiveEntry::new(&parent, Content::Directory, 0o755));
+    }
+    for parent in dirs {
+        entries.push(ArchiveEntry::new(
+            &format!("{}/{}", base, parent.display()),
+            Content::Directory,
+            0o755,
+        ));
+    }
+    for file in &files {
+        entries.push(ArchiveEntry::new(
+            &format!("{}/{}", base, file),
+            Content::File(dir.join(file)),
+            0o644,
+        ));
+    }
+
+    let mut dep = String::new();
+    for (name, (file, deps)) in &index.modules {
+        if modules.contains(name) {
+            dep += &format!("{}:", file);
+            for dep_file in deps {
+                dep += &format!(" {}", dep_file);
+            }
+            dep.push('\n');
+        }
+    }
+    let mut alias = String::new();
+    for (pattern, module) in &index.aliases {
+        if modules.contains(module) {
+            alias += &format!("alias {} {}\n", pattern, module);
+        }
+    }
+    for (name, content) in [("modules.dep", dep), ("modules.alias", alias)] {
+        entries.push(ArchiveEntry::new(
+            &format!("{}/{}", base, name),
+            Content::Data(content.into_bytes()),
+            0o644,
+        ));
+    }
+    // Without this, modprobe complains about modules that are built in.
+    if dir.join("modules.builtin").is_file() {
+        entries.push(tree_entry(root, &dir.join("modules.builtin"))?);
+    }
+    Ok(())
+}
+
+/// The path of a file that must exist on the installed system.
+fn existing(root: &Path, path: &str) -> Result<PathBuf, Error> {
+    let path = root.join(path);
+    match path.exists() {
+        true => Ok(path),
+        false => Err(anyhow!("{} is missing", path.display())),
+    }
+}
+
+/// An archive entry copying a file, directory or symbolic link from the installed system.
+fn tree_entry(root: &Path, path: &Path) -> Result<ArchiveEntry, Error> {
+    let metadata = fs::symlink_metadata(path)?;
+    let name = path.strip_prefix(root)?.to_string_lossy().to_string();
+    let content = if metadata.is_symlink() {
+        Content::Symlink(fs::read_link(path)?)
+    } else if metadata.is_dir() {
+        Content::Directory
+    } else {
+        Content::File(path.to_path_buf())
+    };
+    Ok(ArchiveEntry::new(
+        &name,
+        content,
+        metadata.permissions().mode(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    const KERNEL: &str = "lib/modules/6.16.0";
+
+    /// An installed system with a few modules, and a live one with an AHCI disk.
+    fn systems(dir: &Path) -> (PathBuf, SystemRoot) {
+        let root = dir.join("target");
+        for (path, content) in [
+            ("bin/busybox", "busybox"),
+            ("usr/bin/holos-config", "holos-config"),
+            ("lib/ld-musl-x86_64.so.1", "musl"),
+        ] {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), content).unwrap();
+        }
+        let modules = root.join(KERNEL);
+        for module in [
+            "kernel/fs/ext4/ext4.ko.xz",
+            "kernel/fs/jbd2/jbd2.ko.xz",
+            "kernel/fs/mbcache.ko.xz",
+            "kernel/drivers/ata/ahci.ko.xz",
+            "kernel/drivers/ata/libahci.ko.xz",
+            "kernel/drivers/ata/libata.ko.xz",
+            "kernel/drivers/scsi/sd_mod.ko.xz",
+            "kernel/drivers/block/virtio_blk.ko.xz",
+            "kernel/drivers/net/ethernet/realtek/r8169.ko.xz",
+        ] {
+            fs::create_dir_all(modules.join(module).parent().unwrap()).unwrap();
+            fs::write(modules.join(module), module).unwrap();
+        }
+        fs::write(
+            modules.join("modules.dep"),
+            "kernel/fs/ext4/ext4.ko.xz: kernel/fs/jbd2/jbd2.ko.xz kernel/fs/mbcache.ko.xz
+kernel/fs/jbd2/jbd2.ko.xz:
+kernel/fs/mbcache.ko.xz:
+kernel/drivers/ata/ahci.ko.xz: kernel/drivers/ata/libahci.ko.xz kernel/drivers/ata/libata.ko.xz
+kernel/drivers/ata/libahci.ko.xz: kernel/drivers/ata/libata.ko.xz
+kernel/drivers/ata/libata.ko.xz:
+kernel/drivers/scsi/sd_mod.ko.xz:
+kernel/drivers/block/virtio_blk.ko.xz:
+kernel/drivers/net/ethernet/realtek/r8169.ko.xz:
+",
+        )
+        .unwrap();
+        fs::write(
+            modules.join("modules.alias"),
+            "# Aliases extracted from modules themselves.
+alias pci:v*d*sv*sd*bc01sc06i01* ahci
+alias scsi:t-0x00* sd_mod
+alias virtio:d00000002v* virtio_blk
+alias pci:v000010ECd00008168sv*sd*bc*sc*i* r8169
+alias fs-ext4 ext4
+",
+        )
+        .unwrap();
+        fs::write(
+            modules.join("modules.builtin"),
+            "kernel/drivers/pci/pcieport.ko\n",
+        )
+        .unwrap();
+
+        let system = SystemRoot::new(dir.join("live"));
+        let pci = system.path("/sys/devices/pci0000:00/0000:00:1f.2");
+        let scsi = pci.join("ata1/host0/target0:0:0/0:0:0:0");
+        fs::create_dir_all(scsi.join("block/sda")).unwrap();
+        fs::write(
+            pci.join("modalias"),
+            "pci:v00008086d00002922sv00001AF4sd00001100bc01sc06i01\n",
+        )
+        .unwrap();
+        fs::write(scsi.join("modalias"), "scsi:t-0x00\n").unwrap();
+        // The SCSI disk driver is bound, but the controller's driver isn't loaded.
+        fs::create_dir_all(system.path("/sys/bus/scsi/drivers/sd")).unwrap();
+        fs::create_dir_all(system.path("/sys/module/sd_mod")).unwrap();
+        symlink(
+            system.path("/sys/module/sd_mod"),
+            system.path("/sys/bus/scsi/drivers/sd/module"),
+        )
+        .unwrap();
+        symlink(system.path("/sys/bus/scsi/drivers/sd"), scsi.join("driver")).unwrap();
+        fs::create_dir_all(system.path("/sys/class/block")).unwrap();
+        symlink(scsi.join("block/sda"), system.path("/sys/class/block/sda")).unwrap();
+        (root, system)
+    }
+
+    #[test]
+    fn modules() {
+        let dir = TempDir::new().unwrap();
+        let (root, system) = systems(dir.path());
+        let output = dir.path().join("initrd.img");
+        let report = build(&root, &system, "sda", &output).unwrap();
+        assert_eq!(
+            report.modules,
+            vec![
+                "ahci", "ext4", "jbd2", "libahci", "libata", "mbcache", "sd_mod"
+            ]
+        );
+        assert_eq!(report.size, fs::metadata(&output).unwrap().len());
+        assert!(
+            report.to_string().ends_with(
+                "KiB, with 7 kernel modules: ahci ext4 jbd2 libahci libata mbcache sd_mod"
+            )
+        );
+
+        let unpacked = dir.path().join("unpacked");
+        fs::create_dir(&unpacked).unwrap();
+        archive::unpack(&output, &unpacked).unwrap();
+        assert_eq!(
+            fs::read_to_string(unpacked.join("init")).unwrap(),
+            "holos-config"
+        );
+        let modules = unpacked.join(KERNEL);
+        assert!(modules.join("kernel/drivers/ata/libata.ko.xz").exists());
+        assert!(
+            !modules
+                .join("kernel/drivers/block/virtio_blk.ko.xz")
+                .exists()
+        );
+        assert!(!modules.join("kernel/drivers/net").exists());
+        assert_eq!(
+            fs::read_to_string(modules.join("modules.alias")).unwrap(),
+            "alias pci:v*d*sv*sd*bc01sc06i01* ahci\nalias scsi:t-0x00* sd_mod\nalias fs-ext4 ext4\n"
+        );
+        assert!(
+            fs::read_to_string(modules.join("modules.dep"))
+                .unwrap()
+                .contains(
+                    "kernel/fs/ext4/ext4.ko.xz: kernel/fs/jbd2/jbd2.ko.xz kernel/fs/mbcache.ko.xz\n"
+                )
+        );
+        assert!(modules.join("modules.builtin").exists());
+
+        // The same system and disk always give the same archive.
+        let again = dir.path().join("again.img");
+        build(&root, &system, "sda", &again).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), fs::read(&again).unwrap());
+    }
+
+    #[test]
+    /// A disk that isn't in sysfs still gets the filesystem modules.
+    fn unknown_disk() {
+        let dir = TempDir::new().unwrap();
+        let (root, system) = systems(dir.path());
+        let output = dir.path().join("initrd.img");
+        let report = build(&root, &system, "vda", &output).unwrap();
+        assert_eq!(report.modules, vec!["ext4", "jbd2", "mbcache"]);
+
+        fs::remove_dir_all(root.join("lib/modules")).unwrap();
+        let report = build(&root, &system, "sda", &output).unwrap();
+        assert!(report.modules.is_empty());
+        assert!(report.to_string().ends_with("KiB, with no kernel modules"));
+
+        fs::remove_file(root.join("usr/bin/holos-config")).unwrap();
+        assert!(build(&root, &system, "sda", &output).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+/// A record of how far an installation got, kept on the live system while it runs. If it fails,
+/// the journal says which step it failed at, and when the same plan is tried again, the steps
+/// before the root filesystem is copied (partitioning, formatting and encrypting the persistent
+/// data partition) aren't repeated. The journal is removed once an installation succeeds.
+use super::Step;
+use anyhow::{Error, anyhow};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Where the journal is kept.
+pub const JOURNAL_PATH: &str = "/var/lib/holos/install-journal.json";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Journal {
+    /// The plan being carried out, as it was shown.
+    pub plan: String,
+    /// The steps finished so far, in order.
+    pub done: Vec<Step>,
+    /// The step the last attempt failed at, and why.
+    pub failed: Option<Failure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    pub step: Step,
+    pub error: String,
+}
+
+impl Journal {
+    pub fn new(plan: &str) -> Self {
+        Journal {
+            plan: plan.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Reads the journal at `path`, if there is one. One that can't be read is ignored, since
+    /// starting the installation over is always safe.
+    pub fn read(path: &Path) -> Option<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Unable to read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        serde_json::from_str(&content)
+            .map_err(|e| warn!("Ignoring {}: {}", path.display(), e))
+            .ok()
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow!("Unable to create {}: {}", parent.display(), e))?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| anyhow!("Unable to write {}: {}", path.display(), e))
+    }
+
+    pub fn is_done(&self, step: Step) -> bool {
+        self.done.contains(&step)
+    }
+
+    pub fn record(&mut self, step: Step) {
+        if !self.is_done(step) {
+            self.done.push(step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn journal() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("var/lib/holos/install-journal.json");
+        assert_eq!(Journal::read(&path), None);
+
+        let mut journal = Journal::new("Wipe /dev/sda\n");
+        journal.record(Step::Partition);
+        journal.record(Step::Format);
+        journal.record(Step::Partition);
+        journal.failed = Some(Failure {
+            step: Step::Persist,
+            error: "cryptsetup failed".to_string(),
+        });
+        journal.write(&path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains("\"done\": [\n    \"partition\",\n    \"format\"\n  ]"));
+
+        let read = Journal::read(&path).unwrap();
+        assert_eq!(read, journal);
+        assert!(read.is_done(Step::Format));
+        assert!(!read.is_done(Step::Persist));
+
+        fs::write(&path, "{").unwrap();
+        assert_eq!(Journal::read(&path), None);
+    }
+}
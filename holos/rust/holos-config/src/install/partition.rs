@@ -0,0 +1,287 @@
+/// Partitioning of the target disk. HolOS uses an MBR ("msdos") partition table, which the BIOSes
+/// of every machine we install onto can boot from. It's written directly rather than through
+/// parted, so that the layout can be checked without a disk to write it to.
+use anyhow::{Error, anyhow};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECTOR_SIZE: u64 = 512;
+/// Partitions start and end on 1MiB boundaries, as they do with parted and fdisk.
+const ALIGNMENT: u64 = 2048;
+/// The MBR can only describe sectors with 32 bit addresses, so only the first 2TiB of a disk.
+pub const MAX_SECTORS: u64 = u32::MAX as u64;
+/// MBR partition type for Linux filesystems.
+const LINUX_PARTITION: u8 = 0x83;
+
+/// A partition to create, as part of a [`Layout`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partition {
+    /// The partition number, from 1 to 4.
+    pub number: u32,
+    /// The first sector of the partition.
+    pub start: u64,
+    /// The length of the partition in sectors.
+    pub sectors: u64,
+    /// Whether the BIOS should consider the partition bootable.
+    pub bootable: bool,
+}
+
+impl Partition {
+    pub fn size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE
+    }
+}
+
+/// The partition table to write to a disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    /// Identifies the disk, and makes up the first half of each partition's PARTUUID.
+    pub signature: u32,
+    pub partitions: Vec<Partition>,
+}
+
+/// What a partition in a [`Layout`] should be given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Bytes(u64),
+    /// Everything left over once the other partitions are laid out.
+    Rest,
+}
+
+impl Layout {
+    /// Lays partitions out one after another in order of their number, each aligned to 1MiB. At
+    /// most one partition can take the rest of the disk. Only the first partition is bootable.
+    pub fn new(disk_sectors: u64, sizes: &[(u32, Size)]) -> Result<Self, Error> {
+        let mut sizes = sizes.to_vec();
+        sizes.sort_by_key(|(number, _)| *number);
+        for (i, (number, _)) in sizes.iter().enumerate() {
+            if !(1..=4).contains(number) {
+                return Err(anyhow!(
+                    "Partition {} is not a primary MBR partition",
+                    number
+                ));
+            }
+            if sizes[..i].iter().any(|(n, _)| n == number) {
+                return Err(anyhow!("Partition {} is listed twice", number));
+            }
+        }
+        if sizes.iter().filter(|(_, size)| *size == Size::Rest).count() > 1 {
+            return Err(anyhow!("Only one partition can take the rest of the disk"));
+        }
+
+        let usable = disk_sectors.min(MAX_SECTORS) / ALIGNMENT * ALIGNMENT;
+        let fixed: u64 = sizes
+            .iter()
+            .map(|(_, size)| match size {
+                Size::Bytes(bytes) => align(bytes.div_ceil(SECTOR_SIZE)),
+                Size::Rest => 0,
+            })
+            .sum();
+        let available = usable.saturating_sub(ALIGNMENT);
+        if fixed > available {
+            return Err(anyhow!(
+                "The disk is too small: {}MiB is needed, but only {}MiB is available",
+                fixed * SECTOR_SIZE / (1 << 20),
+                available * SECTOR_SIZE / (1 << 20)
+            ));
+        }
+
+        let mut start = ALIGNMENT;
+        let mut partitions = vec![];
+        for (number, size) in sizes {
+            let sectors = match size {
+                Size::Bytes(bytes) => align(bytes.div_ceil(SECTOR_SIZE)),
+                Size::Rest => available - fixed,
+            };
+            if sectors == 0 {
+                return Err(anyhow!(
+                    "There's no space left on the disk for partition {}",
+                    number
+                ));
+            }
+            partitions.push(Partition {
+                number,
+                start,
+                sectors,
+                bootable: partitions.is_empty(),
+            });
+            start += sectors;
+        }
+
+        Ok(Layout {
+            signature: new_signature(),
+            partitions,
+        })
+    }
+
+    pub fn get(&self, number: u32) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+
+    /// The PARTUUID the kernel gives a partition, which `root=PARTUUID=` can refer to.
+    pub fn partuuid(&self, number: u32) -> String {
+        format!("{:08x}-{:02x}", self.signature, number)
+    }
+
+    /// The master boot record describing this layout. The boot code is left empty for the
+    /// bootloader to fill in.
+    pub fn mbr(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut mbr = [0u8; SECTOR_SIZE as usize];
+        mbr[440..444].copy_from_slice(&self.signature.to_le_bytes());
+        for partition in &self.partitions {
+            let entry = 446 + 16 * (partition.number as usize - 1);
+            mbr[entry] = if partition.bootable { 0x80 } else { 0 };
+            // Cylinder/head/sector addresses are long obsolete. These values tell everything to
+            // use the LBA addresses instead.
+            mbr[entry + 1..entry + 4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            mbr[entry + 4] = LINUX_PARTITION;
+            mbr[entry + 5..entry + 8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            mbr[entry + 8..entry + 12].copy_from_slice(&(partition.start as u32).to_le_bytes());
+            mbr[entry + 12..entry + 16].copy_from_slice(&(partition.sectors as u32).to_le_bytes());
+        }
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        mbr
+    }
+
+    /// Writes the partition table to the start of `disk`, which may be a block device or a disk
+    /// image. The rest of the first 1MiB is cleared too, so that no trace of an earlier GPT
+    /// partition table is left to confuse anything.
+    pub fn write(&self, disk: &Path) -> Result<(), Error> {
+        let unable = |e: std::io::Error| anyhow!("Unable to write to {}: {}", disk.display(), e);
+        let mut device = OpenOptions::new().write(true).open(disk).map_err(unable)?;
+        device.seek(SeekFrom::Start(0)).map_err(unable)?;
+        device.write_all(&self.mbr()).map_err(unable)?;
+        device
+            .write_all(&vec![0u8; ((ALIGNMENT - 1) * SECTOR_SIZE) as usize])
+            .map_err(unable)?;
+        device.sync_all().map_err(unable)
+    }
+}
+
+fn align(sectors: u64) -> u64 {
+    sectors.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Disk signatures only need to differ between the disks in a machine, so the time is plenty.
+fn new_signature() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let signature = now.subsec_nanos() ^ (now.as_secs() as u32) ^ std::process::id();
+    signature.max(1)
+}
+
+/// The kernel's name for partition `number` of `disk`. Disks whose names end in a digit have a
+/// `p` before the partition number, eg `nvme0n1p1` rather than `sda1`.
+pub fn partition_name(disk: &str, number: u32) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+/// Splits a partition name back into the disk it's on and its number, eg `sda1` into `sda` and 1,
+/// or `mmcblk0p2` into `mmcblk0` and 2.
+pub fn split_partition(name: &str) -> Option<(String, u32)> {
+    let digit = |c: char| c.is_ascii_digit();
+    let disk = name.trim_end_matches(digit);
+    let number = name[disk.len()..].parse().ok().filter(|n| *n > 0)?;
+    if let Some(base) = disk.strip_suffix('p')
+        && base.ends_with(digit)
+    {
+        return Some((base.to_string(), number));
+    }
+    // Disks with a digit in their name, like nvme0n1 or loop0, always have a `p` before the
+    // partition number.
+    if disk.is_empty() || disk.contains(digit) {
+        return None;
+    }
+    Some((disk.to_string(), number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn names() {
+        assert_eq!(partition_name("sda", 1), "sda1");
+        assert_eq!(partition_name("nvme0n1", 2), "nvme0n1p2");
+        assert_eq!(split_partition("sda1"), Some(("sda".to_string(), 1)));
+        assert_eq!(split_partition("vda12"), Some(("vda".to_string(), 12)));
+        assert_eq!(
+            split_partition("nvme0n1p2"),
+            Some(("nvme0n1".to_string(), 2))
+        );
+        assert_eq!(split_partition("loop0p1"), Some(("loop0".to_string(), 1)));
+        assert_eq!(split_partition("sda"), None);
+        assert_eq!(split_partition("nvme0n1"), None);
+        assert_eq!(split_partition("loop0"), None);
+    }
+
+    #[test]
+    /// A fixed size system partition, with the rest of the disk after it.
+    fn layout() {
+        let gib = 1 << 30;
+        let disk = 16 * gib / SECTOR_SIZE;
+        let layout = Layout::new(disk, &[(2, Size::Rest), (1, Size::Bytes(4 * gib))]).unwrap();
+        assert_eq!(
+            layout.partitions,
+            vec![
+                Partition {
+                    number: 1,
+                    start: 2048,
+                    sectors: 4 * gib / SECTOR_SIZE,
+                    bootable: true,
+                },
+                Partition {
+                    number: 2,
+                    start: 2048 + 4 * gib / SECTOR_SIZE,
+                    sectors: disk - 2048 - 4 * gib / SECTOR_SIZE,
+                    bootable: false,
+                },
+            ]
+        );
+
+        let mbr = Layout {
+            signature: 0x1234abcd,
+            ..layout
+        }
+        .mbr();
+        assert_eq!(&mbr[440..444], &[0xcd, 0xab, 0x34, 0x12]);
+        assert_eq!(mbr[446], 0x80);
+        assert_eq!(mbr[446 + 4], 0x83);
+        assert_eq!(&mbr[446 + 8..446 + 12], &2048u32.to_le_bytes());
+        assert_eq!(mbr[462], 0);
+        assert_eq!(&mbr[478..510], &[0; 32]);
+        assert_eq!(&mbr[510..], &[0x55, 0xaa]);
+
+        assert!(Layout::new(disk, &[(1, Size::Bytes(16 * gib))]).is_err());
+        assert!(Layout::new(disk, &[(1, Size::Rest), (2, Size::Rest)]).is_err());
+        assert!(Layout::new(disk, &[(5, Size::Rest)]).is_err());
+    }
+
+    #[test]
+    /// Disk images work as well as real disks, and anything left from an earlier GPT partition
+    /// table is cleared.
+    fn write_image() {
+        let dir = TempDir::new().unwrap();
+        let image = dir.path().join("disk.img");
+        fs::write(&image, vec![0xffu8; 4 << 20]).unwrap();
+        let layout = Layout::new(8192, &[(1, Size::Rest)]).unwrap();
+        layout.write(&image).unwrap();
+
+        let written = fs::read(&image).unwrap();
+        assert_eq!(written.len(), 4 << 20);
+        assert_eq!(&written[..512], &layout.mbr());
+        assert!(written[512..1 << 20].iter().all(|b| *b == 0));
+        assert_eq!(written[1 << 20], 0xff);
+        assert_eq!(layout.partuuid(1), format!("{:08x}-01", layout.signature));
+    }
+}
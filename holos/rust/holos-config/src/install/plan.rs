@@ -0,0 +1,343 @@
+/// What an installation is going to do, worked out before anything is written: which disk is
+/// wiped, the partitions it's given, what's copied onto them and how the bootloader is set up. The
+/// plan is shown with `install --dry-run`, and has to be confirmed before it's carried out.
+use super::*;
+
+/// An installation, ready to be carried out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    /// The device the installation media is on.
+    pub source: String,
+    /// The disk that's wiped, eg `/dev/sda`.
+    pub disk: String,
+    /// The size of the disk, in bytes.
+    pub disk_size: u64,
+    /// The partition table written to the disk.
+    pub layout: Layout,
+    /// The device of each system slot. The system is installed into the first.
+    pub slots: Vec<(Slot, String)>,
+    /// The persistent data partition, if there is one.
+    pub persist: Option<Persist>,
+}
+
+/// What happens to the persistent data partition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Persist {
+    pub device: String,
+    /// Whether the partition is created on the wiped disk, rather than being on another one.
+    pub on_target: bool,
+    /// How the encrypted volume created on it is unlocked, if it's to be encrypted.
+    pub unlock: Option<Unlock>,
+}
+
+impl Plan {
+    /// The device of the slot the system is installed into.
+    pub fn partition(&self) -> &str {
+        &self.slots[0].1
+    }
+
+    /// What each partition in the layout is for.
+    fn role(&self, device: &str) -> String {
+        if let Some((slot, _)) = self.slots.iter().find(|(_, d)| d == device) {
+            return format!("system slot {} ({})", slot, slot.label());
+        }
+        match &self.persist {
+            Some(persist) if persist.device == device => "persistent data".to_string(),
+            _ => "unused".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Install from the media on {}", self.source)?;
+        writeln!(
+            f,
+            "Wipe {} ({}MiB) and give it a new MBR partition table:",
+            self.disk,
+            self.disk_size >> 20
+        )?;
+        let disk = self.disk.trim_start_matches("/dev/");
+        for partition in &self.layout.partitions {
+            let device = format!("/dev/{}", partition::partition_name(disk, partition.number));
+            writeln!(
+                f,
+                "    {}: {}MiB, {}",
+                device,
+                partition.size() >> 20,
+                self.role(&device)
+            )?;
+        }
+        let slots: Vec<String> = self.slots.iter().map(|(_, d)| d.clone()).collect();
+        writeln!(f, "Format {} as ext4", slots.join(" and "))?;
+        if let Some(persist) = &self.persist {
+            match &persist.unlock {
+                Some(unlock) => writeln!(
+                    f,
+                    "Encrypt {} for the persistent data, unlocked with the {}",
+                    persist.device, unlock
+                )?,
+                None if persist.on_target => writeln!(
+                    f,
+                    "Leave {} for the persistent data, to be formatted on first boot",
+                    persist.device
+                )?,
+                None => writeln!(
+                    f,
+                    "Leave {} as it is, for the persistent data",
+                    persist.device
+                )?,
+            }
+        }
+        writeln!(f, "Copy into {}:", self.partition())?;
+        writeln!(f, "    the root filesystem in {} on the media", ROOTFS_PATH)?;
+        writeln!(f, "    the kernel, {}", KERNEL_PATH)?;
+        writeln!(f, "    the SSH keys trusted by this system")?;
+        writeln!(
+            f,
+            "    this system's configuration, as {}",
+            settings::INSTALLED_CONFIG_PATH
+        )?;
+        if let Some(Persist {
+            unlock: Some(Unlock::Keyfile { path }),
+            ..
+        }) = &self.persist
+        {
+            writeln!(f, "    the key file {}", path)?;
+        }
+        writeln!(f, "Build the initial ramdisk, {}", INITRD_PATH)?;
+        writeln!(
+            f,
+            "Install GRUB on {}, booting slot {}",
+            self.disk, self.slots[0].0
+        )
+    }
+}
+
+impl Installer<'_> {
+    /// Works out what installing with `config` will do, without changing anything.
+    pub fn plan(&self, config: &HolosConfig) -> Result<Plan, InstallError> {
+        let source = resolve(
+            self.system,
+            &StorageIdentifier::Label {
+                label: SOURCE_LABEL.to_string(),
+            },
+        )
+        .during(Step::Source)?
+        .path();
+        let mut plan = match self.plan_disk(config) {
+            Ok(plan) => plan,
+            Err(e) => {
+                // A disk that's too small to lay out, or shouldn't be touched at all, is
+                // explained in full. What's on it is left out, since the plan was wrong anyway.
+                if let Ok(Some(disk)) = self.target_disk(config) {
+                    self.check_disk(&disk, true).during(Step::Check)?;
+                }
+                return Err(e).during(Step::Partition);
+            }
+        };
+        plan.source = source;
+        // What's on the disk doesn't matter if it was partitioned by an earlier attempt at the
+        // same plan.
+        let resumed = self
+            .resumable(&plan)
+            .is_some_and(|journal| journal.is_done(Step::Partition));
+        self.check_disk(plan.disk.trim_start_matches("/dev/"), resumed)
+            .during(Step::Check)?;
+        Ok(plan)
+    }
+
+    /// The disk holding the install partition, if it can be found.
+    fn target_disk(&self, config: &HolosConfig) -> Result<Option<String>, Error> {
+        match &config.storage.install_partition {
+            Some(install) => Ok(Some(resolve(self.system, install)?.disk)),
+            None => Ok(None),
+        }
+    }
+
+    /// Refuses `disk` if it's not safe to wipe. What's on it already doesn't matter if the
+    /// installation is forced, or if `existing` is given.
+    fn check_disk(&self, disk: &str, existing: bool) -> Result<(), Error> {
+        let check = safety::check_disks(self.system, self.force || existing)?
+            .into_iter()
+            .find(|check| check.disk == disk)
+            .ok_or_else(|| anyhow!("Unable to find /dev/{}", disk))?;
+        if check.refusals.is_empty() {
+            return Ok(());
+        }
+        let reasons: Vec<String> = check.refusals.iter().map(|r| r.to_string()).collect();
+        Err(anyhow!(
+            "/dev/{} can't be installed onto, as {}",
+            disk,
+            reasons.join("; ")
+        ))
+    }
+
+    fn plan_disk(&self, config: &HolosConfig) -> Result<Plan, Error> {
+        let install = config
+            .storage
+            .install_partition
+            .as_ref()
+            .ok_or_else(|| anyhow!("No install_partition is configured"))?;
+        let Location {
+            disk,
+            partition: Some(number),
+        } = resolve(self.system, install)?
+        else {
+            return Err(anyhow!(
+                "{} is a whole disk; give the partition number to install to",
+                install
+            ));
+        };
+
+        let size_path = self.system.path(&format!("/sys/class/block/{}/size", disk));
+        let disk_sectors: u64 = fs::read_to_string(&size_path)
+            .map_err(|e| anyhow!("Unable to find the size of {}: {}", disk, e))?
+            .trim()
+            .parse()
+            .map_err(|e| anyhow!("Unable to find the size of {}: {}", disk, e))?;
+
+        let mut persist = None;
+        let mut persist_number = None;
+        if let Some(identifier) = &config.storage.persist_partition {
+            let location = resolve(self.system, identifier)?;
+            let on_target = location.disk == disk;
+            if on_target {
+                let n = location.partition.ok_or_else(|| {
+                    anyhow!("The persist partition can't be the whole of the install disk")
+                })?;
+                if n == number {
+                    return Err(anyhow!(
+                        "{} can't be both the install and persist partition",
+                        location
+                    ));
+                }
+                persist_number = Some(n);
+            }
+            persist = Some(Persist {
+                device: location.path(),
+                on_target,
+                unlock: config.storage.encryption.as_ref().map(|e| e.unlock.clone()),
+            });
+        }
+        // The second system partition takes the first number that's left.
+        let second = (1..=4)
+            .find(|n| *n != number && Some(*n) != persist_number)
+            .unwrap();
+        let sizes = match persist_number {
+            Some(persist_number) => vec![
+                (number, Size::Bytes(SYSTEM_PARTITION_SIZE)),
+                (second, Size::Bytes(SYSTEM_PARTITION_SIZE)),
+                (persist_number, Size::Rest),
+            ],
+            None => {
+                let half = disk_sectors.min(partition::MAX_SECTORS) * partition::SECTOR_SIZE / 2;
+                vec![
+                    (number, Size::Bytes(half.saturating_sub(1 << 20))),
+                    (second, Size::Rest),
+                ]
+            }
+        };
+
+        Ok(Plan {
+            source: String::new(),
+            disk: format!("/dev/{}", disk),
+            disk_size: disk_sectors * partition::SECTOR_SIZE,
+            layout: Layout::new(disk_sectors, &sizes)?,
+            slots: vec![
+                (
+                    Slot::A,
+                    format!("/dev/{}", partition::partition_name(&disk, number)),
+                ),
+                (
+                    Slot::B,
+                    format!("/dev/{}", partition::partition_name(&disk, second)),
+                ),
+            ],
+            persist,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::tests::{add_device, ext4_image};
+    use crate::install::tests::{Fake, config, live_system};
+    use tempfile::TempDir;
+
+    #[test]
+    /// Working out the plan doesn't touch anything.
+    fn plan() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let tools = Fake::new(&system, media);
+        let plan = Installer::new(&system, &tools)
+            .plan(&config(
+                "    install_partition: /dev/sda1
+    persist_partition: /dev/sda2
+    encryption:
+        unlock: !keyfile
+            path: /etc/holos/persist.key",
+            ))
+            .unwrap();
+        assert!(tools.commands.borrow().is_empty());
+        assert!(fs::read(system.path("/dev/sda")).unwrap().is_empty());
+
+        assert_eq!(plan.source, "/dev/sdb");
+        assert_eq!(plan.partition(), "/dev/sda1");
+        assert_eq!(
+            plan.to_string(),
+            "Install from the media on /dev/sdb
+Wipe /dev/sda (16384MiB) and give it a new MBR partition table:
+    /dev/sda1: 4096MiB, system slot a (HolOS-system-a)
+    /dev/sda2: 8191MiB, persistent data
+    /dev/sda3: 4096MiB, system slot b (HolOS-system-b)
+Format /dev/sda1 and /dev/sda3 as ext4
+Encrypt /dev/sda2 for the persistent data, unlocked with the key file /etc/holos/persist.key
+Copy into /dev/sda1:
+    the root filesystem in /boot/rootfs.cpio.bz2 on the media
+    the kernel, /boot/bzImage
+    the SSH keys trusted by this system
+    this system's configuration, as /etc/holos/configs/installed.yaml
+    the key file /etc/holos/persist.key
+Build the initial ramdisk, /boot/holos-initrd.img
+Install GRUB on /dev/sda, booting slot a
+"
+        );
+    }
+
+    #[test]
+    /// Disks that aren't safe to wipe are refused before anything is done.
+    fn refused() {
+        let dir = TempDir::new().unwrap();
+        let (system, media) = live_system(dir.path());
+        let tools = Fake::new(&system, media);
+        add_device(&system, "sda1", &ext4_image("old"));
+        let sda = config("    install_partition: /dev/sda1");
+
+        let mut installer = Installer::new(&system, &tools);
+        let e = installer.plan(&sda).unwrap_err();
+        assert_eq!(e.step, Step::Check);
+        assert_eq!(
+            e.error.to_string(),
+            "/dev/sda can't be installed onto, as it already has partitions (sda1), which would \
+             be lost (give --force to wipe it anyway)"
+        );
+        installer.force = true;
+        installer.plan(&sda).unwrap();
+
+        // Not even forcing gets it to wipe the installation media, which is too small to lay out
+        // anyway.
+        let e = installer
+            .plan(&config("    install_partition: /dev/sdb1"))
+            .unwrap_err();
+        assert_eq!(e.step, Step::Check);
+        assert_eq!(
+            e.error.to_string(),
+            "/dev/sdb can't be installed onto, as it holds the installation media \
+             (HolOS-install); it's only 0MiB, and at least 8192MiB is needed"
+        );
+        assert!(tools.commands.borrow().is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+/// Checks that keep the installer off disks it has no business wiping. A disk is refused if it
+/// holds the installation media, if anything on it is in use (mounted, used as swap, or under a
+/// device mapper or RAID device), or if it's too small for the two system slots. A disk that
+/// already has partitions or a filesystem on it is refused too, unless the installation is
+/// forced, since wiping it loses whatever is there.
+use super::{SOURCE_LABEL, SYSTEM_PARTITION_SIZE};
+use crate::blockdev::{BlockDevice, DeviceKind, device_name, inventory};
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use std::fmt;
+use std::fs;
+
+/// The smallest disk that's installed onto, with room for both system slots.
+pub const MIN_DISK_SIZE: u64 = 2 * SYSTEM_PARTITION_SIZE;
+
+/// Why a disk can't be installed onto.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Refusal {
+    /// The disk holds the installation media.
+    Source,
+    /// A filesystem on the disk is mounted.
+    Mounted { device: String, on: String },
+    /// The disk, or a partition on it, is in use as swap.
+    Swap { device: String },
+    /// The disk, or a partition on it, is part of another block device, like an encrypted
+    /// volume or a RAID array.
+    Held { device: String, by: Vec<String> },
+    /// The disk is smaller than [`MIN_DISK_SIZE`].
+    TooSmall { size: u64 },
+    /// The disk has partitions, a partition table or a filesystem on it. Forcing the
+    /// installation gets past this one.
+    Existing { contents: String },
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Refusal::Source => write!(f, "it holds the installation media ({})", SOURCE_LABEL),
+            Refusal::Mounted { device, on } => write!(f, "{} is mounted on {}", device, on),
+            Refusal::Swap { device } => write!(f, "{} is in use as swap", device),
+            Refusal::Held { device, by } => write!(f, "{} is in use by {}", device, by.join(", ")),
+            Refusal::TooSmall { size } => write!(
+                f,
+                "it's only {}MiB, and at least {}MiB is needed",
+                size >> 20,
+                MIN_DISK_SIZE >> 20
+            ),
+            Refusal::Existing { contents } => write!(
+                f,
+                "it already has {}, which would be lost (give --force to wipe it anyway)",
+                contents
+            ),
+        }
+    }
+}
+
+/// Whether a disk can be installed onto, and if not, why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskCheck {
+    /// The kernel's name for the disk, eg `sda`.
+    pub disk: String,
+    /// The size of the disk, in bytes.
+    pub size: u64,
+    pub refusals: Vec<Refusal>,
+}
+
+impl fmt::Display for DiskCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.refusals.is_empty() {
+            return writeln!(
+                f,
+                "/dev/{} ({}MiB): can be installed onto",
+                self.disk,
+                self.size >> 20
+            );
+        }
+        writeln!(
+            f,
+            "/dev/{} ({}MiB): refused, as",
+            self.disk,
+            self.size >> 20
+        )?;
+        for refusal in &self.refusals {
+            writeln!(f, "    {}", refusal)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks every disk in the machine. With `force`, disks are allowed to have partitions or
+/// filesystems on them already.
+pub fn check_disks(system: &SystemRoot, force: bool) -> Result<Vec<DiskCheck>, Error> {
+    let devices = inventory(system)?;
+    let mounts = read_mounts(system, "/proc/mounts")?;
+    let swaps = read_mounts(system, "/proc/swaps")?;
+    let source = devices
+        .iter()
+        .find(|d| {
+            d.filesystem
+                .as_ref()
+                .and_then(|f| f.label.as_deref())
+                .is_some_and(|label| label == SOURCE_LABEL)
+        })
+        .map(|d| d.disk.clone().unwrap_or_else(|| d.name.clone()));
+
+    let mut checks = vec![];
+    for disk in devices.iter().filter(|d| d.kind == DeviceKind::Disk) {
+        let partitions: Vec<&BlockDevice> = devices
+            .iter()
+            .filter(|d| d.disk.as_ref() == Some(&disk.name))
+            .collect();
+        let mut refusals = vec![];
+        if source.as_ref() == Some(&disk.name) {
+            refusals.push(Refusal::Source);
+        }
+        for device in std::iter::once(disk).chain(partitions.iter().copied()) {
+            for (name, on) in &mounts {
+                if *name == device.name {
+                    refusals.push(Refusal::Mounted {
+                        device: device.path(),
+                        on: on.clone(),
+                    });
+                }
+            }
+            if swaps.iter().any(|(name, _)| *name == device.name) {
+                refusals.push(Refusal::Swap {
+                    device: device.path(),
+                });
+            }
+            let by = holders(system, &device.name);
+            if !by.is_empty() {
+                refusals.push(Refusal::Held {
+                    device: device.path(),
+                    by,
+                });
+            }
+        }
+        if disk.size < MIN_DISK_SIZE {
+            refusals.push(Refusal::TooSmall { size: disk.size });
+        }
+        if !force && let Some(contents) = contents(disk, &partitions) {
+            refusals.push(Refusal::Existing { contents });
+        }
+        checks.push(DiskCheck {
+            disk: disk.name.clone(),
+            size: disk.size,
+            refusals,
+        });
+    }
+    Ok(checks)
+}
+
+/// What's on a disk already, if anything.
+fn contents(disk: &BlockDevice, partitions: &[&BlockDevice]) -> Option<String> {
+    if !partitions.is_empty() {
+        let names: Vec<&str> = partitions.iter().map(|p| p.name.as_str()).collect();
+        return Some(format!("partitions ({})", names.join(", ")));
+    }
+    if let Some(table) = disk.partition_table {
+        return Some(format!("a partition table ({})", table));
+    }
+    disk.filesystem
+        .as_ref()
+        .map(|f| format!("a filesystem ({})", f.fs_type))
+}
+
+/// The devices listed in `/proc/mounts` or `/proc/swaps`, with the second field of each line
+/// (where it's mounted, or the kind of swap). Without the list, there's no telling whether a disk
+/// is in use, so not being able to read it is an error.
+fn read_mounts(system: &SystemRoot, path: &str) -> Result<Vec<(String, String)>, Error> {
+    let content = fs::read_to_string(system.path(path)).map_err(|e| {
+        anyhow!(
+            "Unable to read {}, so can't tell which disks are in use: {}",
+            path,
+            e
+        )
+    })?;
+    Ok(content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let second = fields.next()?;
+            device
+                .starts_with("/dev/")
+                .then(|| (device_name(system, device), second.to_string()))
+        })
+        .collect())
+}
+
+/// The devices built on top of a block device, such as the device mapper device of an open
+/// encrypted volume.
+fn holders(system: &SystemRoot, name: &str) -> Vec<String> {
+    let dir = system.path(&format!("/sys/class/block/{}/holders", name));
+    let mut holders: Vec<String> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    holders.sort();
+    holders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::tests::{add_device, ext4_image, iso_image};
+    use crate::install::partition::{Layout, Size};
+    use tempfile::TempDir;
+
+    /// Adds a 16GiB disk, with a partition table if it's given `partitions`.
+    fn add_disk(system: &SystemRoot, name: &str, partitions: &[(&str, &[u8])]) {
+        add_device(system, name, &[]);
+        fs::write(
+            system.path(&format!("/sys/class/block/{}/size", name)),
+            "33554432\n",
+        )
+        .unwrap();
+        if partitions.is_empty() {
+            return;
+        }
+        Layout::new(33554432, &[(1, Size::Bytes(4 << 30)), (2, Size::Rest)])
+            .unwrap()
+            .write(&system.path(&format!("/dev/{}", name)))
+            .unwrap();
+        for (partition, content) in partitions {
+            add_device(system, partition, content);
+        }
+    }
+
+    #[test]
+    fn disks() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        add_disk(&system, "sda", &[]);
+        add_device(&system, "sdb", &iso_image(SOURCE_LABEL));
+        add_disk(
+            &system,
+            "sdc",
+            &[("sdc1", &ext4_image("data")), ("sdc2", &[0; 4096])],
+        );
+        fs::create_dir_all(system.path("/sys/class/block/sdc2/holders/dm-0")).unwrap();
+        add_disk(&system, "sdd", &[("sdd1", &[0; 4096])]);
+        add_disk(&system, "sde", &[]);
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(
+            system.path("/proc/mounts"),
+            "proc /proc proc rw 0 0
+/dev/sdb /media/install iso9660 ro 0 0
+/dev/sdc1 /data ext4 rw,relatime 0 0
+",
+        )
+        .unwrap();
+        fs::write(
+            system.path("/proc/swaps"),
+            "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority
+/dev/sde                                partition\t8388604\t\t0\t\t-2
+",
+        )
+        .unwrap();
+
+        let listing: String = check_disks(&system, false)
+            .unwrap()
+            .iter()
+            .map(|check| check.to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            "/dev/sda (16384MiB): can be installed onto
+/dev/sdb (0MiB): refused, as
+    it holds the installation media (HolOS-install)
+    /dev/sdb is mounted on /media/install
+    it's only 0MiB, and at least 8192MiB is needed
+    it already has a filesystem (iso9660), which would be lost (give --force to wipe it anyway)
+/dev/sdc (16384MiB): refused, as
+    /dev/sdc1 is mounted on /data
+    /dev/sdc2 is in use by dm-0
+    it already has partitions (sdc1, sdc2), which would be lost (give --force to wipe it anyway)
+/dev/sdd (16384MiB): refused, as
+    it already has partitions (sdd1), which would be lost (give --force to wipe it anyway)
+/dev/sde (16384MiB): refused, as
+    /dev/sde is in use as swap
+"
+        );
+
+        // Forcing only gets past what's on the disk already.
+        let forced = check_disks(&system, true).unwrap();
+        let refused: Vec<&str> = forced
+            .iter()
+            .filter(|check| !check.refusals.is_empty())
+            .map(|check| check.disk.as_str())
+            .collect();
+        assert_eq!(refused, vec!["sdb", "sdc", "sde"]);
+        assert_eq!(forced[2].refusals.len(), 2);
+
+        // A filesystem written across the whole disk counts too.
+        add_disk(&system, "sdf", &[]);
+        fs::write(system.path("/dev/sdf"), ext4_image("old")).unwrap();
+        let checks = check_disks(&system, false).unwrap();
+        assert_eq!(
+            checks[5].refusals,
+            vec![Refusal::Existing {
+                contents: "a filesystem (ext4)".to_string()
+            }]
+        );
+
+        // Without the list of swap devices, there's no telling which disks are safe.
+        fs::remove_file(system.path("/proc/swaps")).unwrap();
+        assert!(
+            check_disks(&system, true)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Unable to read /proc/swaps, so can't tell which disks are in use")
+        );
+    }
+}
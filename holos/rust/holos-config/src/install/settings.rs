@@ -0,0 +1,181 @@
+/// The configuration an installed system boots with. The live system's configuration, as it
+/// stands once the kernel command line has had its say, is written into the new system, and the
+/// new system's boot entries point `config_file=` at it. The command line parameters that chose
+/// the configuration go into the boot entries too, so the installed system comes up configured the
+/// same way the live one was, rather than falling back to the default configuration.
+use crate::HolosConfig;
+use crate::cmdline::CmdLine;
+use crate::models::Model;
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use log::warn;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// Where the configuration is written in the new system.
+pub const INSTALLED_CONFIG_PATH: &str = "/etc/holos/configs/installed.yaml";
+
+/// The kernel parameters the new system's boot entries carry, taken from the running system's
+/// command line. Flags that only make sense for the live system (`install`, `live`) and secrets
+/// (`persist_passphrase=`) aren't carried over.
+pub fn boot_params(system: &SystemRoot) -> Result<Vec<String>, Error> {
+    let cmdline = running_cmdline(system)?;
+    let mut params = vec![format!("config_file={}", INSTALLED_CONFIG_PATH)];
+    if !cmdline.github_usernames.is_empty() {
+        params.push(format!(
+            "github_usernames={}",
+            cmdline.github_usernames.join(",")
+        ));
+    }
+    Ok(params)
+}
+
+/// The running system's kernel command line.
+fn running_cmdline(system: &SystemRoot) -> Result<CmdLine, Error> {
+    CmdLine::from_file(&system.cmdline().display().to_string())
+        .map_err(|e| anyhow!("Unable to read the kernel command line: {}", e))
+}
+
+/// The configuration file for the new system: `config`, headed by a note of where it came from
+/// and the hardware model detected. `source` is the configuration file it was read from, if that's
+/// known. If the model can't be detected, that's only logged.
+pub fn render(
+    config: &HolosConfig,
+    source: Option<&str>,
+    system: &SystemRoot,
+) -> Result<String, Error> {
+    let mut out = String::from("# The configuration HolOS was installed with");
+    if let Some(source) = source {
+        out += &format!(", from {}", source);
+    }
+    out += ".\n";
+    let cmdline = running_cmdline(system)?;
+    if !cmdline.github_usernames.is_empty() {
+        out += &format!(
+            "# github_usernames was given on the kernel command line: {}\n",
+            cmdline.github_usernames.join(",")
+        );
+    }
+    match Model::detect_model(system) {
+        Ok(model) => out += &format!("# Detected hardware model: {}\n", model),
+        Err(e) => warn!("Unable to detect the hardware model: {}", e),
+    }
+    out += &serde_yaml::to_string(config)
+        .map_err(|e| anyhow!("Unable to write out the configuration: {}", e))?;
+    Ok(out)
+}
+
+/// Writes the configuration into the new system mounted at `root`. It can hold wireless
+/// passphrases, so only root can read it.
+pub fn write(
+    config: &HolosConfig,
+    source: Option<&str>,
+    system: &SystemRoot,
+    root: &Path,
+) -> Result<(), Error> {
+    let content = render(config, source, system)?;
+    let path = root.join(INSTALLED_CONFIG_PATH.trim_start_matches('/'));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("Unable to create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&path, content).map_err(|e| anyhow!("Unable to write {}: {}", path.display(), e))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| anyhow!("Unable to set the permissions of {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CONFIG: &str = "
+storage:
+    install_partition: !label
+        label: HolOS-system-a
+    persist_partition: !largest_disk
+        rotational: true
+        partition: 2
+    encryption:
+        unlock: !keyfile
+            path: /etc/holos/persist.key
+network:
+    nameservers: [1.1.1.1]
+    interfaces:
+        - identifier: !mac_address
+              address: 00:11:22:33:44:55
+          static_addresses:
+              - address: 192.168.1.10/24
+                gateway: 192.168.1.1
+          wireless:
+              ssid: holo
+              psk: correct-horse
+security:
+    github_usernames: [alice]
+    ssh_keys: []
+    rootpw_hash: null
+";
+
+    #[test]
+    fn installed_config() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path().join("live"));
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(
+            system.cmdline(),
+            "root=live:LABEL=HolOS-install -- install config_file=/etc/holos/configs/holoport.yaml \
+             github_usernames=bob,carol persist_passphrase=secret\n",
+        )
+        .unwrap();
+        let mut config: HolosConfig = serde_yaml::from_str(CONFIG).unwrap();
+        config.security.github_usernames = vec!["bob".to_string(), "carol".to_string()];
+
+        assert_eq!(
+            boot_params(&system).unwrap(),
+            vec![
+                "config_file=/etc/holos/configs/installed.yaml",
+                "github_usernames=bob,carol"
+            ]
+        );
+
+        let root = dir.path().join("target");
+        write(
+            &config,
+            Some("/etc/holos/configs/holoport.yaml"),
+            &system,
+            &root,
+        )
+        .unwrap();
+        let path = root.join("etc/holos/configs/installed.yaml");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(
+            "# The configuration HolOS was installed with, from /etc/holos/configs/holoport.yaml.
+# github_usernames was given on the kernel command line: bob,carol
+# Detected hardware model: Unknown Model
+"
+        ));
+        assert!(!content.contains("secret"));
+
+        // What's written reads back as the same configuration.
+        let installed: HolosConfig = serde_yaml::from_str(&content).unwrap();
+        installed.validate().unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&installed).unwrap(),
+            serde_yaml::to_string(&config).unwrap()
+        );
+        assert_eq!(installed.security.github_usernames, vec!["bob", "carol"]);
+
+        // Without anything chosen on the command line, the installed configuration is still the
+        // one booted.
+        fs::write(system.cmdline(), "root=live:LABEL=HolOS-install\n").unwrap();
+        assert_eq!(
+            boot_params(&system).unwrap(),
+            vec!["config_file=/etc/holos/configs/installed.yaml"]
+        );
+    }
+}
@@ -0,0 +1,342 @@
+/// Upgrading an installed system in place, without touching its data. The new release is written
+/// into the system slot that isn't running, checked against the image it came from, and made the
+/// one GRUB boots next, on trial. Unless the new system gets as far as confirming its boot with
+/// `holos-config confirm-boot`, GRUB goes back to the previous one the time after.
+use super::grub::{self, Entry, GrubEnv};
+use super::*;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+
+/// Upgrades the running system from an installation image (an ISO file, or a device it's been
+/// written to), optionally checking the image's SHA-256 checksum first.
+pub fn do_upgrade(
+    config: &HolosConfig,
+    config_file: &str,
+    system: &SystemRoot,
+    image: &str,
+    sha256: Option<&str>,
+) -> Result<(), Error> {
+    let mut installer = Installer::new(system, &Live);
+    installer.config_file = Some(config_file.to_string());
+    let slot = installer
+        .upgrade(config, image, sha256)
+        .map_err(|e| anyhow!("Upgrade failed while {}: {}", e.step, e.error))?;
+    info!(
+        "Upgrade written to slot {}. It will be tried out on the next boot.",
+        slot
+    );
+    Ok(())
+}
+
+impl Installer<'_> {
+    /// Writes the release in `image` into the slot that isn't running, and makes it the one booted
+    /// next. Gives the slot it was written to.
+    pub fn upgrade(
+        &self,
+        config: &HolosConfig,
+        image: &str,
+        sha256: Option<&str>,
+    ) -> Result<Slot, InstallError> {
+        let running = Slot::running(self.system)
+            .during(Step::Slot)?
+            .ok_or_else(|| {
+                anyhow!(
+                    "The running system isn't in a system slot, so it can't be upgraded in place. Reinstall to set the slots up"
+                )
+            })
+            .during(Step::Slot)?;
+        let slot = running.other();
+        let location = resolve(
+            self.system,
+            &StorageIdentifier::Label {
+                label: slot.label().to_string(),
+            },
+        )
+        .during(Step::Slot)?;
+        info!("Upgrading slot {} on {}", slot, location);
+
+        if let Some(expected) = sha256 {
+            check_sha256(&self.system.path(image), expected).during(Step::Image)?;
+        }
+        self.format_slot(slot, &location.path())
+            .during(Step::Format)?;
+
+        let result = self.write_slot(config, image, slot, &location, running);
+        let unmounted = self.unmount_all().during(Step::Unmount);
+        result.and(unmounted)?;
+        Ok(slot)
+    }
+
+    /// Everything that happens with the image and new slot mounted.
+    fn write_slot(
+        &self,
+        config: &HolosConfig,
+        image: &str,
+        slot: Slot,
+        location: &Location,
+        running: Slot,
+    ) -> Result<(), InstallError> {
+        let source_dir = self.work_dir.join("source");
+        let target_dir = self.work_dir.join("target");
+        let is_device = fs::metadata(self.system.path(image))
+            .map(|m| m.file_type().is_block_device())
+            .unwrap_or(false);
+        let options = match is_device {
+            true => "ro",
+            false => "loop,ro",
+        };
+        self.mount(image, &source_dir, &["-o", options])
+            .during(Step::Mount)?;
+        self.mount(&location.path(), &target_dir, &["-t", "ext4"])
+            .during(Step::Mount)?;
+
+        info!("Unpacking the root filesystem");
+        let entries = archive::unpack(&source_dir.join(relative(ROOTFS_PATH)), &target_dir)
+            .during(Step::Unpack)?;
+        info!("Unpacked {} files", entries);
+        copy_kernel(&source_dir, &target_dir).during(Step::Kernel)?;
+        self.build_initrd(&target_dir, &location.disk)
+            .during(Step::Initrd)?;
+
+        // Whoever can log in to the running system should still be able to after the upgrade.
+        copy_keys(
+            &self.system.path("/root/.ssh"),
+            &target_dir.join("root/.ssh"),
+        )
+        .during(Step::Keys)?;
+        settings::write(
+            config,
+            self.config_file.as_deref(),
+            self.system,
+            &target_dir,
+        )
+        .during(Step::Config)?;
+        if let Some(EncryptionConfig {
+            unlock: Unlock::Keyfile { path },
+        }) = &config.storage.encryption
+        {
+            copy_key_file(&self.system.path(path), &target_dir.join(relative(path)))
+                .during(Step::Config)?;
+        }
+
+        verify(&source_dir, &target_dir, entries).during(Step::Verify)?;
+
+        // Both systems boot with the configuration the running one has.
+        let params = settings::boot_params(self.system).during(Step::Bootloader)?;
+        let mut env = GrubEnv::default();
+        env.set(grub::TRIAL, "1");
+        self.install_bootloader(
+            &[
+                Entry {
+                    slot,
+                    version: read_version(&target_dir),
+                    params: params.clone(),
+                },
+                Entry {
+                    slot: running,
+                    version: read_version(self.system.root()),
+                    params,
+                },
+            ],
+            &env,
+            &target_dir,
+            &format!("/dev/{}", location.disk),
+        )
+        .during(Step::Bootloader)
+    }
+}
+
+/// Checks the new system against the image it came from, before it's made bootable.
+fn verify(source_dir: &Path, target_dir: &Path, unpacked: usize) -> Result<(), Error> {
+    if unpacked == 0 {
+        return Err(anyhow!("The root filesystem archive is empty"));
+    }
+    for path in ["sbin/init", "etc/holos-version", relative(INITRD_PATH)] {
+        if fs::symlink_metadata(target_dir.join(path)).is_err() {
+            return Err(anyhow!("/{} is missing from the new system", path));
+        }
+    }
+    let kernel = relative(KERNEL_PATH);
+    if sha256_file(&source_dir.join(kernel))? != sha256_file(&target_dir.join(kernel))? {
+        return Err(anyhow!("The kernel wasn't copied intact"));
+    }
+    Ok(())
+}
+
+fn check_sha256(image: &Path, expected: &str) -> Result<(), Error> {
+    let found = sha256_file(image)?;
+    if !found.eq_ignore_ascii_case(expected.trim()) {
+        return Err(anyhow!(
+            "The image's SHA-256 checksum is {}, not {}",
+            found,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> Result<String, Error> {
+    let mut file =
+        File::open(path).map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .map_err(|e| anyhow!("Unable to read {}: {}", path.display(), e))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Confirms that the running system booted successfully, so that GRUB carries on booting it if it
+/// was on trial. Run once everything else has started.
+pub fn confirm_boot(system: &SystemRoot) -> Result<(), Error> {
+    let Some(slot) = Slot::running(system)? else {
+        info!("Not running from a system slot, so there's no boot to confirm");
+        return Ok(());
+    };
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string())?;
+    if cmdline.fallback_flag {
+        warn!(
+            "Booted the system in slot {} rather than the newer one in slot {}. If that was on trial, it never confirmed its boot",
+            slot,
+            slot.other()
+        );
+        return Ok(());
+    }
+
+    let path = system.path(grub::ENV_PATH);
+    let mut env = GrubEnv::read(&path)?;
+    if env.get(grub::TRIAL).is_none() {
+        return Ok(());
+    }
+    env.unset(grub::TRIAL);
+    env.unset(grub::TRIED);
+    env.write(&path)?;
+    info!("Confirmed the trial boot of the system in slot {}", slot);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::tests::{Fake, config, live_system};
+    use tempfile::TempDir;
+
+    /// A system installed from the media in `live_system`, and booted from slot a.
+    fn installed(dir: &Path) -> (SystemRoot, Fake) {
+        let (system, media) = live_system(dir);
+        let tools = Fake::new(&system, media);
+        Installer::new(&system, &tools).install(&storage()).unwrap();
+        for dir in ["/proc", "/etc", "/tmp"] {
+            fs::create_dir_all(system.path(dir)).unwrap();
+        }
+        fs::write(
+            system.path("/proc/cmdline"),
+            "root=LABEL=HolOS-system-a ro vga=791\n",
+        )
+        .unwrap();
+        fs::write(system.path("/etc/holos-version"), "0.0.6\n").unwrap();
+        fs::write(system.path("/tmp/holos.iso"), "image").unwrap();
+        tools.commands.borrow_mut().clear();
+        (system, tools)
+    }
+
+    fn storage() -> HolosConfig {
+        config("    install_partition: /dev/sda1\n    persist_partition: /dev/sda2")
+    }
+
+    #[test]
+    /// The new release goes into the other slot, which is booted next on trial.
+    fn upgrade() {
+        let dir = TempDir::new().unwrap();
+        let (system, tools) = installed(dir.path());
+        let mut upgrader = Installer::new(&system, &tools);
+        upgrader.work_dir = system.path("/tmp/holos-upgrade");
+        let checksum = "6105d6cc76af400325e94d588ce511be5bfdbb73b437dc51eca43917d7a43e3d";
+        let slot = upgrader
+            .upgrade(&storage(), "/tmp/holos.iso", Some(checksum))
+            .unwrap();
+        assert_eq!(slot, Slot::B);
+        assert_eq!(
+            *tools.commands.borrow(),
+            vec![
+                "mkfs.ext4 -F -L HolOS-system-b /dev/sda3",
+                "mount -o loop,ro /tmp/holos.iso /tmp/holos-upgrade/source",
+                "mount -t ext4 /dev/sda3 /tmp/holos-upgrade/target",
+                "grub-install --boot-directory=/tmp/holos-upgrade/target/boot /dev/sda",
+                "umount /tmp/holos-upgrade/target",
+                "umount /tmp/holos-upgrade/source",
+            ]
+        );
+
+        let target = system.path("/tmp/holos-upgrade/target");
+        let grub_config = fs::read_to_string(target.join("boot/grub/grub.cfg")).unwrap();
+        let new = grub_config.find("HolOS 0.0.7 (slot b)").unwrap();
+        let old = grub_config.find("HolOS 0.0.6 (slot a)").unwrap();
+        assert!(new < old);
+        assert_eq!(
+            grub_config
+                .matches("ro vga=791 config_file=/etc/holos/configs/installed.yaml")
+                .count(),
+            2
+        );
+        assert!(target.join("etc/holos/configs/installed.yaml").exists());
+        let env = GrubEnv::read(&target.join("boot/grub/grubenv")).unwrap();
+        assert_eq!(env.get(grub::TRIAL), Some("1"));
+        assert!(target.join("root/.ssh/authorized_keys").exists());
+
+        // A damaged image is caught before anything is written.
+        tools.commands.borrow_mut().clear();
+        let error = upgrader
+            .upgrade(&storage(), "/tmp/holos.iso", Some(&"0".repeat(64)))
+            .unwrap_err();
+        assert_eq!(error.step, Step::Image);
+        assert!(tools.commands.borrow().is_empty());
+    }
+
+    #[test]
+    /// Only systems installed into slots can be upgraded in place.
+    fn unslotted() {
+        let dir = TempDir::new().unwrap();
+        let (system, tools) = installed(dir.path());
+        fs::write(system.path("/proc/cmdline"), "root=LABEL=HolOS-system ro\n").unwrap();
+        let error = Installer::new(&system, &tools)
+            .upgrade(&storage(), "/tmp/holos.iso", None)
+            .unwrap_err();
+        assert_eq!(error.step, Step::Slot);
+    }
+
+    #[test]
+    fn confirm() {
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::create_dir_all(system.path("/boot/grub")).unwrap();
+        let path = system.path(grub::ENV_PATH);
+        let mut env = GrubEnv::default();
+        env.set(grub::TRIAL, "1");
+        env.set(grub::TRIED, "1");
+
+        // The previous system doesn't confirm the one that failed.
+        env.write(&path).unwrap();
+        fs::write(
+            system.path("/proc/cmdline"),
+            "root=LABEL=HolOS-system-a ro holos_fallback\n",
+        )
+        .unwrap();
+        confirm_boot(&system).unwrap();
+        assert_eq!(GrubEnv::read(&path).unwrap(), env);
+
+        fs::write(
+            system.path("/proc/cmdline"),
+            "root=LABEL=HolOS-system-b ro\n",
+        )
+        .unwrap();
+        confirm_boot(&system).unwrap();
+        assert_eq!(GrubEnv::read(&path).unwrap(), GrubEnv::default());
+        // There's nothing to do the next time.
+        confirm_boot(&system).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+/// The SSH keys trusted to log in as root. They come from two places: the `ssh_keys` listed in the
+/// security section of the configuration, and the keys the `github_usernames` have published on
+/// GitHub. Both are merged into the one `authorized_keys` file. Each key is checked before it's
+/// trusted: its algorithm has to be one OpenSSH still accepts, and the key itself has to decode
+/// and match it. DSA keys and RSA keys shorter than [`MIN_RSA_BITS`] are turned away, and a key
+/// given more than once is only written once. Options given ahead of a key, such as `from=` or
+/// `no-pty`, are kept.
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+/// Where the trusted keys are written.
+pub const AUTHORIZED_KEYS_PATH: &str = "/root/.ssh/authorized_keys";
+/// The shortest RSA key that's trusted, in bits.
+pub const MIN_RSA_BITS: u32 = 2048;
+/// The key types that are recognised, trusted or not. Anything else at the start of a line is
+/// taken to be options.
+const KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "sk-ssh-ed25519@openssh.com",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Where a key came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The `ssh_keys` in the configuration.
+    Config,
+    /// Published on GitHub by `user`, as the key numbered `id`.
+    GitHub { user: String, id: i64 },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Config => write!(f, "ssh_keys in the configuration"),
+            Source::GitHub { user, id } => write!(f, "GitHub user {} (key {})", user, id),
+        }
+    }
+}
+
+/// A public key, as found on a line of an `authorized_keys` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    /// The options restricting what the key can be used for, eg `from="10.0.0.0/8",no-pty`.
+    pub options: Option<String>,
+    /// The key type, eg `ssh-ed25519`.
+    pub algorithm: String,
+    /// The key itself, decoded from base64.
+    pub blob: Vec<u8>,
+    pub comment: Option<String>,
+    /// The size of the key, in bits.
+    pub bits: u32,
+}
+
+impl PublicKey {
+    /// Parses and checks a key given as `[options] <algorithm> <base64> [comment]`.
+    pub fn parse(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        let (options, line) = match split_options(line)? {
+            Some((options, rest)) => (Some(options.to_string()), rest),
+            None => (None, line),
+        };
+        let mut fields = line.split_whitespace();
+        let algorithm = fields.next().ok_or_else(|| anyhow!("The key is empty"))?;
+        let encoded = fields
+            .next()
+            .ok_or_else(|| anyhow!("The {} key has nothing after its type", algorithm))?;
+        let comment: Vec<&str> = fields.collect();
+        let blob = STANDARD
+            .decode(encoded)
+            .map_err(|e| anyhow!("The {} key isn't valid base64: {}", algorithm, e))?;
+
+        let mut reader = Reader(&blob);
+        let inner = reader.string()?;
+        if inner != algorithm.as_bytes() {
+            return Err(anyhow!(
+                "The key is labelled {}, but holds a {} key",
+                algorithm,
+                String::from_utf8_lossy(inner)
+            ));
+        }
+        let bits = match algorithm {
+            "ssh-dss" => return Err(anyhow!("DSA keys aren't trusted, as they're too weak")),
+            "ssh-rsa" => {
+                let _exponent = reader.string()?;
+                let bits = mpint_bits(reader.string()?);
+                if bits < MIN_RSA_BITS {
+                    return Err(anyhow!(
+                        "The RSA key is only {} bits, and at least {} are needed",
+                        bits,
+                        MIN_RSA_BITS
+                    ));
+                }
+                bits
+            }
+            "ssh-ed25519" | "sk-ssh-ed25519@openssh.com" => {
+                if reader.string()?.len() != 32 {
+                    return Err(anyhow!("The {} key is the wrong length", algorithm));
+                }
+                256
+            }
+            "ecdsa-sha2-nistp256"
+            | "ecdsa-sha2-nistp384"
+            | "ecdsa-sha2-nistp521"
+            | "sk-ecdsa-sha2-nistp256@openssh.com" => {
+                let curve = reader.string()?;
+                if !algorithm.contains(&*String::from_utf8_lossy(curve)) {
+                    return Err(anyhow!("The {} key is on the wrong curve", algorithm));
+                }
+                let _point = reader.string()?;
+                match curve {
+                    b"nistp384" => 384,
+                    b"nistp521" => 521,
+                    _ => 256,
+                }
+            }
+            _ => return Err(anyhow!("{} isn't a known SSH key type", algorithm)),
+        };
+        Ok(PublicKey {
+            options,
+            algorithm: algorithm.to_string(),
+            blob,
+            comment: (!comment.is_empty()).then(|| comment.join(" ")),
+            bits,
+        })
+    }
+
+    /// The fingerprint OpenSSH shows for the key, eg `SHA256:o6D+fU3X...`.
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "SHA256:{}",
+            STANDARD_NO_PAD.encode(Sha256::digest(&self.blob))
+        )
+    }
+
+    /// The kind of key, named the way `ssh-keygen -l` names them.
+    pub fn kind(&self) -> &'static str {
+        match self.algorithm.as_str() {
+            "ssh-rsa" => "RSA",
+            "ssh-ed25519" => "ED25519",
+            "sk-ssh-ed25519@openssh.com" => "ED25519-SK",
+            "sk-ecdsa-sha2-nistp256@openssh.com" => "ECDSA-SK",
+            _ => "ECDSA",
+        }
+    }
+}
+
+/// Splits the options off the front of a line, if it has any, returning them and the rest of
+/// the line. The options end at the first space that isn't quoted. As with sshd, a line only has
+/// options if a known key type follows them.
+fn split_options(line: &str) -> Result<Option<(&str, &str)>, Error> {
+    let is_key_type = |s: &str| {
+        s.split_whitespace()
+            .next()
+            .is_some_and(|word| KEY_TYPES.contains(&word))
+    };
+    if line.is_empty() || is_key_type(line) {
+        return Ok(None);
+    }
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut end = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                end = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(anyhow!("The key's options have an unterminated quote"));
+    }
+    Ok(end
+        .map(|end| (&line[..end], line[end..].trim_start()))
+        .filter(|(_, rest)| is_key_type(rest)))
+}
+
+/// Reads the length-prefixed strings a key is made of.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn string(&mut self) -> Result<&'a [u8], Error> {
+        let truncated = || anyhow!("The key is truncated");
+        let length = self.0.get(..4).ok_or_else(truncated)?;
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        let value = self.0.get(4..4 + length).ok_or_else(truncated)?;
+        self.0 = &self.0[4 + length..];
+        Ok(value)
+    }
+}
+
+/// The number of bits in a big-endian integer, without its leading zeros.
+fn mpint_bits(value: &[u8]) -> u32 {
+    let mut bytes = value.iter().skip_while(|b| **b == 0);
+    match bytes.next() {
+        Some(first) => (8 - first.leading_zeros()) + 8 * bytes.count() as u32,
+        None => 0,
+    }
+}
+
+/// A key that's trusted, and everywhere it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustedKey {
+    pub key: PublicKey,
+    pub sources: Vec<Source>,
+}
+
+impl TrustedKey {
+    /// The key's line in `authorized_keys`. Keys from GitHub don't have comments of their own,
+    /// so they're given the user and key number.
+    fn line(&self) -> String {
+        let comment = match &self.sources[0] {
+            Source::Config => self.key.comment.clone(),
+            Source::GitHub { user, id } => Some(format!("{}_{}", user, id)),
+        };
+        let mut line = String::new();
+        if let Some(options) = &self.key.options {
+            line += &format!("{} ", options);
+        }
+        line += &format!("{} {}", self.key.algorithm, STANDARD.encode(&self.key.blob));
+        if let Some(comment) = comment {
+            line += &format!(" {}", comment);
+        }
+        line
+    }
+}
+
+/// A key that was turned away, and why.
+#[derive(Debug)]
+pub struct Rejected {
+    pub source: Source,
+    pub error: Error,
+}
+
+/// The keys to trust, merged from everywhere they were given.
+#[derive(Debug, Default)]
+pub struct KeySet {
+    pub trusted: Vec<TrustedKey>,
+    pub rejected: Vec<Rejected>,
+}
+
+impl KeySet {
+    /// Checks and merges `keys`, in order. A key given again is noted as coming from there too,
+    /// rather than being trusted twice.
+    pub fn merge(keys: Vec<(Source, String)>) -> Self {
+        let mut set = KeySet::default();
+        for (source, line) in keys {
+            let key = match PublicKey::parse(&line) {
+                Ok(key) => key,
+                Err(error) => {
+                    set.rejected.push(Rejected { source, error });
+                    continue;
+                }
+            };
+            match set.trusted.iter_mut().find(|t| t.key.blob == key.blob) {
+                Some(trusted) => {
+                    info!(
+                        "{} from {} is already trusted, from {}",
+                        key.fingerprint(),
+                        source,
+                        trusted.sources[0]
+                    );
+                    trusted.sources.push(source);
+                }
+                None => set.trusted.push(TrustedKey {
+                    key,
+                    sources: vec![source],
+                }),
+            }
+        }
+        set
+    }
+
+    /// The content of `authorized_keys`.
+    pub fn authorized_keys(&self) -> String {
+        self.trusted
+            .iter()
+            .map(|trusted| format!("{}\n", trusted.line()))
+            .collect()
+    }
+
+    /// Writes the trusted keys to `/root/.ssh/authorized_keys`, readable only by root as ssh
+    /// insists. The keys that were turned away are logged. With no keys to trust, the existing
+    /// file is left alone rather than locking everyone out, and the new file only replaces the
+    /// old one once it's safely on disk.
+    pub fn write(&self, system: &SystemRoot) -> Result<(), Error> {
+        for rejected in &self.rejected {
+            warn!(
+                "Not trusting a key from {}: {}",
+                rejected.source, rejected.error
+            );
+        }
+        if self.trusted.is_empty() {
+            return Err(match self.rejected.len() {
+                0 => anyhow!(
+                    "No SSH keys are configured; leaving {} as it is",
+                    AUTHORIZED_KEYS_PATH
+                ),
+                n => anyhow!(
+                    "None of the {} SSH keys given can be trusted; leaving {} as it is",
+                    n,
+                    AUTHORIZED_KEYS_PATH
+                ),
+            });
+        }
+        let path = system.path(AUTHORIZED_KEYS_PATH);
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .map_err(|e| anyhow!("Unable to create {}: {}", dir.display(), e))?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+            .map_err(|e| anyhow!("Unable to set the permissions of {}: {}", dir.display(), e))?;
+
+        let new = dir.join(".authorized_keys.new");
+        let unable = |e: std::io::Error| anyhow!("Unable to write {}: {}", new.display(), e);
+        // Created with its final permissions, in case an earlier attempt left one behind.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&new)
+            .map_err(unable)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(unable)?;
+        file.write_all(self.authorized_keys().as_bytes())
+            .map_err(unable)?;
+        file.sync_all().map_err(unable)?;
+        fs::rename(&new, &path)
+            .map_err(|e| anyhow!("Unable to replace {}: {}", path.display(), e))?;
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| anyhow!("Unable to sync {}: {}", dir.display(), e))?;
+        info!("Trusting {} SSH keys", self.trusted.len());
+        Ok(())
+    }
+}
+
+/// Lists each trusted key the way `ssh-keygen -l` does, with where it came from, followed by the
+/// keys that were turned away.
+impl fmt::Display for KeySet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trusted in &self.trusted {
+            let sources: Vec<String> = trusted.sources.iter().map(|s| s.to_string()).collect();
+            writeln!(
+                f,
+                "{} {} ({}) from {}",
+                trusted.key.bits,
+                trusted.key.fingerprint(),
+                trusted.key.kind(),
+                sources.join(", and ")
+            )?;
+        }
+        for rejected in &self.rejected {
+            writeln!(f, "Rejected from {}: {}", rejected.source, rejected.error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const ED25519: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJSjoUdZtMoOBtv/S8k0VJnXKWFP12bfE2aGyzxN20eC alice@laptop";
+    const RSA_3072: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC5o6+52QzH9fqXi0UKtdd3sVrb7WqYwHHwZqkh8ocQhm5iP90RE32K58JtMmpsh6iMH3YRhtngLwS7+xE0m7WyB1NFTzfDgH4qa9eEP9eZQ+w5qDiOgSUX/LH55OCo6aaulqKW6rc5qwhD5BYXv2PbmlHttFHO90f/q7ODAxR8ruaVR2a/EOeZLyhDNigenl7Kjp591trFIkwkhLgUB8D8ADingL/gBgmn96EyhBCJxI1rb2cyjKRCwo9qTwwAjHBEnyg3avX2aJDls+ZBhV7RXP35AVqhL+EXP7afNTmZXVh2OWkRtefMOcbGjuxSVk0OGlHU4ve6DqHg0sWfVO3R6AART/XD7yBslHHXQ/0Wh1vgXJspMGfRGt5zKAW7mGBnxrvInyOKghjhehsykQOpOl6yLANOH3VI4KWMC/LBm2hkuDcwTNFEWv/BhNR0U3ZHRjs11m1PIe2pRh1X9s2a02JiEXj7TI2RRpPkHUMG3d15f7Ioyx7Vii9JowHAUBc=";
+    const RSA_1024: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQC+TJIuuP/lgf0TBU80LwuEeU66btET9r0HSkw7eK+Z5Q1l17bjEcnFJ0uIba58jGlIvnw7/zm5m35t4OvQiFPxReZ7YYQ4/dhVgPFGkPKGjiQswLCRZ+0gKwTcM62YRj66IgdN7LblwMdml7ayoiK9e848YM7Va8M6BfUruP+6uQ== old";
+    const ECDSA_384: &str = "ecdsa-sha2-nistp384 AAAAE2VjZHNhLXNoYTItbmlzdHAzODQAAAAIbmlzdHAzODQAAABhBL8s/4EZp16EEadzPV7aSboBgAeEqMM40LGLCB3ivJLdJlIr2i03/pnC3+jFMG8fwpu5asPynB7Wt2sIv5XaCnervSoISIur6REV5ae/OJV5TbM1e7HstcXPlAmcsJ1M2A== ec";
+    const DSA: &str = "ssh-dss AAAAB3NzaC1kc3MAAACBAK90xddKbhIrAGAEaCcEfv6zPI/bssJA1w+bRARgbRcLlUj+8TiDyIYjl2IloP3zzRoR0HK/WdvpM8R035G5RQa0ewH3LtGOSniz5SdQEgwiyv5FHkgxf8dXV2cFz0uJjpbjt6tAK6IV47odVqISJANJwmHQLkdL4kVUcqVDoUMrAAAAFQCIfEEV6+1XIHkwXp5NPlICcyq5fQAAAIEAoiDZbnHyq96ZzVpFQr06pIh8aVicQuZIWs5gkuSn27xnd0EOLm/YLrpCaE6kuT5Ce74Q2HpqGLL+gNEBs3UoEBVrlENGDFIX4ifZ5RIpGS468XxcglFKkEx47zgC8Clmxuf0h6PijkFeZWuXYUPU0s5NHlcaF3P116SM3AhkRIYAAACABXFVzUegnkz8gL931VNuiTHqw2IMjJqUOTXLVqtDdTEfIOg2q4RsLltDZbz5qddT//5WcxjDdCBOnN5faDrpLdNjDjf5Li9WygfURcWRi+P/oBpvgjVppGAmQNRs2RnyYMDYa524httynoQY1Egk2ak0WOBhj1fA/BYAcA20HSw= root@vm";
+
+    #[test]
+    /// Fingerprints and sizes match what ssh-keygen -l shows for the same keys.
+    fn parse() {
+        let key = PublicKey::parse(ED25519).unwrap();
+        assert_eq!(
+            key.fingerprint(),
+            "SHA256:o6D+fU3XiJtB13pA7g5mHzUVTMZbda4fYKdHMNA6azU"
+        );
+        assert_eq!((key.bits, key.kind()), (256, "ED25519"));
+        assert_eq!(key.comment.as_deref(), Some("alice@laptop"));
+
+        let key = PublicKey::parse(RSA_3072).unwrap();
+        assert_eq!(
+            key.fingerprint(),
+            "SHA256:0oihpM3WduzlDWWZPnXy/TmeH0X51FlITHaXIEzOGsE"
+        );
+        assert_eq!((key.bits, key.kind()), (3072, "RSA"));
+        assert_eq!(key.comment, None);
+
+        let key = PublicKey::parse(ECDSA_384).unwrap();
+        assert_eq!(
+            key.fingerprint(),
+            "SHA256:4zceaHf1BWJ6o3WlziBcUlNmZTD8Gt1H0bODC2NqVpA"
+        );
+        assert_eq!((key.bits, key.kind()), (384, "ECDSA"));
+
+        // Options ahead of the key are kept, quoted spaces and all.
+        let options = r#"from="10.0.0.0/8,192.168.1.*",command="echo \"hi there\"",no-pty"#;
+        let key = PublicKey::parse(&format!("{} {}", options, ED25519)).unwrap();
+        assert_eq!(key.options.as_deref(), Some(options));
+        assert_eq!(key.algorithm, "ssh-ed25519");
+        assert_eq!(key.comment.as_deref(), Some("alice@laptop"));
+        assert_eq!(PublicKey::parse(ED25519).unwrap().options, None);
+
+        let error = |line: &str| PublicKey::parse(line).unwrap_err().to_string();
+        assert_eq!(
+            error(RSA_1024),
+            "The RSA key is only 1024 bits, and at least 2048 are needed"
+        );
+        assert_eq!(error(DSA), "DSA keys aren't trusted, as they're too weak");
+        assert_eq!(error(""), "The key is empty");
+        assert_eq!(
+            error("ssh-ed25519"),
+            "The ssh-ed25519 key has nothing after its type"
+        );
+        assert!(
+            error("ssh-ed25519 AAAA!!!!").starts_with("The ssh-ed25519 key isn't valid base64")
+        );
+        assert_eq!(
+            error(&ED25519.replace("ssh-ed25519", "ssh-rsa")),
+            "The key is labelled ssh-rsa, but holds a ssh-ed25519 key"
+        );
+        assert_eq!(
+            error("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJSj"),
+            "The key is truncated"
+        );
+        assert_eq!(
+            error("ssh-foo AAAAB3NzaC1mb28="),
+            "ssh-foo isn't a known SSH key type"
+        );
+        assert_eq!(
+            error(&format!("from=\"10.0.0.1 {}", ED25519)),
+            "The key's options have an unterminated quote"
+        );
+        assert_eq!(
+            error(&format!("no-pty {}", DSA)),
+            "DSA keys aren't trusted, as they're too weak"
+        );
+    }
+
+    #[test]
+    /// Keys from the configuration and GitHub end up in one file, each once.
+    fn merge() {
+        let bare = ED25519.rsplit_once(' ').unwrap().0.to_string();
+        let github = |id| Source::GitHub {
+            user: "alice".to_string(),
+            id,
+        };
+        let set = KeySet::merge(vec![
+            (Source::Config, ED25519.to_string()),
+            (Source::Config, DSA.to_string()),
+            (github(1), bare),
+            (github(2), RSA_3072.to_string()),
+            (github(3), RSA_1024.to_string()),
+            (
+                Source::Config,
+                format!("no-pty,no-port-forwarding {}", ECDSA_384),
+            ),
+        ]);
+        assert_eq!(set.trusted.len(), 3);
+        assert_eq!(set.trusted[0].sources, vec![Source::Config, github(1)]);
+        assert_eq!(
+            set.to_string(),
+            "256 SHA256:o6D+fU3XiJtB13pA7g5mHzUVTMZbda4fYKdHMNA6azU (ED25519) from ssh_keys in the \
+             configuration, and GitHub user alice (key 1)
+3072 SHA256:0oihpM3WduzlDWWZPnXy/TmeH0X51FlITHaXIEzOGsE (RSA) from GitHub user alice (key 2)
+384 SHA256:4zceaHf1BWJ6o3WlziBcUlNmZTD8Gt1H0bODC2NqVpA (ECDSA) from ssh_keys in the configuration
+Rejected from ssh_keys in the configuration: DSA keys aren't trusted, as they're too weak
+Rejected from GitHub user alice (key 3): The RSA key is only 1024 bits, and at least 2048 are \
+             needed
+"
+        );
+
+        let dir = TempDir::new().unwrap();
+        let system = SystemRoot::new(dir.path());
+        set.write(&system).unwrap();
+        let path = system.path(AUTHORIZED_KEYS_PATH);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "{}\n{} alice_2\nno-pty,no-port-forwarding {}\n",
+                ED25519, RSA_3072, ECDSA_384
+            )
+        );
+        assert!(!system.path("/root/.ssh/.authorized_keys.new").exists());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::metadata(system.path("/root/.ssh"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o700
+        );
+
+        // Without a single key to trust, the keys already trusted stay.
+        let untrusted = KeySet::merge(vec![(Source::Config, DSA.to_string())]);
+        assert_eq!(
+            untrusted.write(&system).unwrap_err().to_string(),
+            "None of the 1 SSH keys given can be trusted; leaving /root/.ssh/authorized_keys as \
+             it is"
+        );
+        assert!(KeySet::merge(vec![]).write(&system).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), set.authorized_keys());
+    }
+}
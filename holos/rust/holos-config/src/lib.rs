@@ -1,10 +1,20 @@
+use anyhow::{Error, anyhow};
 use ipnet::IpNet;
 use serde_derive::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::fmt;
 use std::net::IpAddr;
+use std::path::Path;
 
+pub mod blockdev;
+pub mod init;
 pub mod install;
+pub mod keys;
+pub mod managed;
 pub mod models;
+pub mod network;
+pub mod storage;
+pub mod system;
 
 /// Configuration file to define how HolOS should be run and where it should persist itself or its
 /// data, as well as a variety of security and network related concerns.
@@ -18,22 +28,537 @@ pub struct HolosConfig {
     pub security: SecurityConfig,
 }
 
+impl HolosConfig {
+    /// Sanity-checks the parts of the configuration that serde can't, so that a bad configuration
+    /// file is rejected as a whole before we start writing anything out to the system.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.storage.validate()?;
+        self.network.validate()
+    }
+}
+
 /// Configuration for data/system persistence.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct StorageConfig {
     /// Partition to install Holos to.
-    pub install_partition: Option<String>,
+    #[serde(default, deserialize_with = "storage_identifier")]
+    pub install_partition: Option<StorageIdentifier>,
     /// Partition to persist Holo and Holochain data to.
-    pub persist_partition: Option<String>,
+    #[serde(default, deserialize_with = "storage_identifier")]
+    pub persist_partition: Option<StorageIdentifier>,
+    /// Whether a filesystem may be created on the persist partition when it's blank. Off by
+    /// default, so that a mistake in identifying the partition can't wipe out anything on it.
+    #[serde(default)]
+    pub format_persist: bool,
+    /// Keeps the persist partition in a LUKS encrypted volume, so that the Holochain keystore on
+    /// it can't be read from a disk taken out of the node. Unencrypted if not given.
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl StorageConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        for identifier in [&self.install_partition, &self.persist_partition]
+            .into_iter()
+            .flatten()
+        {
+            identifier.validate()?;
+        }
+        if let Some(encryption) = &self.encryption {
+            if self.persist_partition.is_none() {
+                return Err(anyhow!(
+                    "Encryption is configured, but there's no persist_partition to encrypt"
+                ));
+            }
+            encryption.unlock.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encryption of the persist partition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// Where the key to unlock the volume at boot comes from.
+    pub unlock: Unlock,
+}
+
+/// Ways of unlocking an encrypted persist partition.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Unlock {
+    /// A key kept in a file on the system partition, which is generated when the volume is
+    /// created if it doesn't exist already. This protects the data on a persist partition taken
+    /// out on its own, but not on a whole node.
+    #[serde(rename = "keyfile")]
+    Keyfile { path: String },
+    /// A passphrase given on the kernel command line as `persist_passphrase=...`.
+    #[serde(rename = "cmdline")]
+    Cmdline,
+    /// A passphrase typed in at the console.
+    #[serde(rename = "prompt")]
+    Prompt,
+}
+
+impl Unlock {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Unlock::Keyfile { path } = self {
+            if !path.starts_with('/') {
+                return Err(anyhow!("Key file {} must be an absolute path", path));
+            }
+            // The key can't be kept on the volume it unlocks.
+            if Path::new(path).starts_with(storage::DATA_DIR) {
+                return Err(anyhow!(
+                    "Key file {} can't be on the encrypted volume itself",
+                    path
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Unlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unlock::Keyfile { path } => write!(f, "key file {}", path),
+            Unlock::Cmdline => write!(f, "passphrase from the kernel command line"),
+            Unlock::Prompt => write!(f, "passphrase typed at the console"),
+        }
+    }
+}
+
+/// Identifies a disk or partition. Device names like `/dev/sda1` depend on the order disks are
+/// found in, which changes when disks are added or a USB stick is left plugged in, so the more
+/// stable identifiers are preferred. Identifiers for a disk can name a partition on it by number,
+/// which needn't exist yet when installing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StorageIdentifier {
+    #[serde(rename = "path")]
+    Path {
+        /// The device node, eg /dev/sda1, or a link to one such as /dev/disk/by-id/...
+        path: String,
+    },
+    #[serde(rename = "label")]
+    Label {
+        /// The label of the filesystem on the partition. Eg, HolOS-data
+        label: String,
+    },
+    #[serde(rename = "uuid")]
+    Uuid {
+        /// The UUID of the filesystem on the partition, as shown by blkid.
+        uuid: String,
+    },
+    #[serde(rename = "partuuid")]
+    PartUuid {
+        /// The partition's own UUID, from the partition table. Eg, 0b5a8e0e-01 for partition 1
+        /// of an MBR disk.
+        partuuid: String,
+    },
+    #[serde(rename = "serial")]
+    Serial {
+        /// The serial number the disk reports.
+        serial: String,
+        /// Partition number on the disk.
+        partition: Option<u32>,
+    },
+    #[serde(rename = "wwn")]
+    Wwn {
+        /// The disk's World Wide Name, with or without its naa./eui. prefix.
+        wwn: String,
+        /// Partition number on the disk.
+        partition: Option<u32>,
+    },
+    #[serde(rename = "largest_disk")]
+    LargestDisk {
+        /// Only consider spinning disks (true) or solid state ones (false). Either if not given.
+        rotational: Option<bool>,
+        /// Consider removable disks too, such as SD cards. Usually a bad idea.
+        #[serde(default)]
+        removable: bool,
+        /// Partition number on the disk.
+        partition: Option<u32>,
+    },
+}
+
+impl StorageIdentifier {
+    /// Checks that the identifier has something to find a device with.
+    pub fn validate(&self) -> Result<(), Error> {
+        let value = match self {
+            Self::Path { path } => path,
+            Self::Label { label } => label,
+            Self::Uuid { uuid } => uuid,
+            Self::PartUuid { partuuid } => partuuid,
+            Self::Serial { serial, .. } => serial,
+            Self::Wwn { wwn, .. } => wwn,
+            Self::LargestDisk { .. } => "-",
+        };
+        if value.trim().is_empty() {
+            return Err(anyhow!("Storage identifier {:?} is empty", self));
+        }
+        if let Self::Serial { partition, .. }
+        | Self::Wwn { partition, .. }
+        | Self::LargestDisk { partition, .. } = self
+            && *partition == Some(0)
+        {
+            return Err(anyhow!("Partitions are numbered from 1, in {}", self));
+        }
+        Ok(())
+    }
+
+    /// The root filesystem as given by `root=` on the kernel command line: `LABEL=`, `UUID=`,
+    /// `PARTUUID=` or a device path.
+    pub fn from_root(value: &str) -> Self {
+        if let Some(label) = value.strip_prefix("LABEL=") {
+            Self::Label {
+                label: label.to_string(),
+            }
+        } else if let Some(uuid) = value.strip_prefix("UUID=") {
+            Self::Uuid {
+                uuid: uuid.to_string(),
+            }
+        } else if let Some(partuuid) = value.strip_prefix("PARTUUID=") {
+            Self::PartUuid {
+                partuuid: partuuid.to_string(),
+            }
+        } else {
+            Self::Path {
+                path: value.to_string(),
+            }
+        }
+    }
+
+    /// The partition number given along with an identifier for a disk.
+    pub fn partition(&self) -> Option<u32> {
+        match self {
+            Self::Serial { partition, .. }
+            | Self::Wwn { partition, .. }
+            | Self::LargestDisk { partition, .. } => *partition,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Path { path } => write!(f, "{}", path),
+            Self::Label { label } => write!(f, "LABEL={}", label),
+            Self::Uuid { uuid } => write!(f, "UUID={}", uuid),
+            Self::PartUuid { partuuid } => write!(f, "PARTUUID={}", partuuid),
+            Self::Serial { serial, .. } => write!(f, "disk with serial number {}", serial),
+            Self::Wwn { wwn, .. } => write!(f, "disk with WWN {}", wwn),
+            Self::LargestDisk {
+                rotational,
+                removable,
+                ..
+            } => {
+                write!(f, "largest")?;
+                if !removable {
+                    write!(f, " non-removable")?;
+                }
+                match rotational {
+                    Some(true) => write!(f, " rotational")?,
+                    Some(false) => write!(f, " solid state")?,
+                    None => {}
+                }
+                write!(f, " disk")
+            }
+        }?;
+        if let Some(partition) = self.partition() {
+            write!(f, ", partition {}", partition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage identifiers can also be given as a bare device path, as they were before there was any
+/// other kind, eg `install_partition: /dev/sda1`.
+fn storage_identifier<'de, D>(deserializer: D) -> Result<Option<StorageIdentifier>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    struct IdentifierVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for IdentifierVisitor {
+        type Value = Option<StorageIdentifier>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "a device path, or a tagged storage identifier such as !label"
+            )
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E: serde::de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_str<E: serde::de::Error>(self, path: &str) -> Result<Self::Value, E> {
+            Ok(Some(StorageIdentifier::Path {
+                path: path.to_string(),
+            }))
+        }
+
+        fn visit_enum<A: serde::de::EnumAccess<'de>>(
+            self,
+            data: A,
+        ) -> Result<Self::Value, A::Error> {
+            use serde::Deserialize;
+            StorageIdentifier::deserialize(serde::de::value::EnumAccessDeserializer::new(data))
+                .map(Some)
+        }
+    }
+
+    deserializer.deserialize_any(IdentifierVisitor)
 }
 
 /// Network interface and nameserver configuration.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NetworkConfig {
-    /// A list of IP address (IPv6 or IPv4) addresses to use as DNS nameservers.
+    /// A list of IP address (IPv6 or IPv4) addresses to use as DNS nameservers. When set, these
+    /// take precedence over any nameservers offered by DHCP. When empty, DHCP-provided
+    /// nameservers are used.
     pub nameservers: Vec<IpAddr>,
-    /// A list of network interfaces to bring up on boot.
+    /// Domains to search when resolving unqualified host names. Only used along with
+    /// `nameservers`.
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+    /// Resolver options, as described in resolv.conf(5). Eg, `timeout:2` or `rotate`. Only used
+    /// along with `nameservers`.
+    #[serde(default)]
+    pub resolver_options: Vec<String>,
+    /// A list of network interfaces to bring up on boot. If neither this nor
+    /// `virtual_interfaces` lists anything, every wired Ethernet interface is brought up with
+    /// DHCP instead.
     pub interfaces: Vec<NetworkInterface>,
+    /// Bonds, bridges and VLANs built on top of the interfaces above (or each other).
+    #[serde(default)]
+    pub virtual_interfaces: Vec<VirtualInterface>,
+    /// How `configure --guarded` decides whether the node can still be reached after its network
+    /// configuration has changed.
+    #[serde(default)]
+    pub verify: VerifyConfig,
+}
+
+impl NetworkConfig {
+    /// Whether the interfaces to configure should be chosen automatically, as none are listed.
+    pub fn automatic(&self) -> bool {
+        self.interfaces.is_empty() && self.virtual_interfaces.is_empty()
+    }
+
+    /// Checks the network configuration as a whole, including the references between virtual
+    /// interfaces and the devices they're built from.
+    pub fn validate(&self) -> Result<(), Error> {
+        for entry in self
+            .search_domains
+            .iter()
+            .chain(self.resolver_options.iter())
+        {
+            if entry.is_empty() || entry.contains(char::is_whitespace) {
+                return Err(anyhow!(
+                    "Invalid resolver search domain or option: {:?}",
+                    entry
+                ));
+            }
+        }
+        self.verify.validate()?;
+        for iface in &self.interfaces {
+            iface.validate()?;
+        }
+
+        for (i, vif) in self.virtual_interfaces.iter().enumerate() {
+            if vif.name.is_empty()
+                || vif.name.len() > 15
+                || vif.name.contains(|c: char| c == '/' || c.is_whitespace())
+            {
+                return Err(anyhow!("{:?} is not a valid interface name", vif.name));
+            }
+            if self.virtual_interfaces[..i]
+                .iter()
+                .any(|v| v.name == vif.name)
+            {
+                return Err(anyhow!("Virtual interface {} is defined twice", vif.name));
+            }
+            vif.ip_settings()?.validate(&vif.name)?;
+            match &vif.kind {
+                VirtualKind::Bond { members, .. } | VirtualKind::Bridge { members } => {
+                    if members.is_empty() && matches!(vif.kind, VirtualKind::Bond { .. }) {
+                        return Err(anyhow!("Bond {} has no members", vif.name));
+                    }
+                    for member in members {
+                        member.validate()?;
+                        // Bond and bridge members are taken over by their master, so can't be
+                        // configured with addresses of their own.
+                        if self.interfaces.iter().any(|i| i.identifier == *member) {
+                            return Err(anyhow!(
+                                "{} is a member of {}, so can't also be listed in interfaces",
+                                member,
+                                vif.name
+                            ));
+                        }
+                    }
+                }
+                VirtualKind::Vlan { parent, id } => {
+                    parent.validate()?;
+                    if *id == 0 || *id > 4094 {
+                        return Err(anyhow!("VLAN {} has invalid id {}", vif.name, id));
+                    }
+                }
+            }
+            for reference in vif.kind.members() {
+                if let DeviceIdentifier::Virtual { name } = reference
+                    && !self.virtual_interfaces.iter().any(|v| v.name == *name)
+                {
+                    return Err(anyhow!(
+                        "{} refers to undefined virtual interface {}",
+                        vif.name,
+                        name
+                    ));
+                }
+            }
+        }
+
+        self.validate_default_routes()?;
+
+        // Make sure the virtual interfaces can be brought up in some order.
+        let mut remaining: Vec<&VirtualInterface> = self.virtual_interfaces.iter().collect();
+        while !remaining.is_empty() {
+            let before = remaining.len();
+            let names: Vec<String> = remaining.iter().map(|v| v.name.clone()).collect();
+            remaining.retain(|v| {
+                v.kind.members().iter().any(
+                    |m| matches!(m, DeviceIdentifier::Virtual { name } if names.contains(name)),
+                )
+            });
+            if remaining.len() == before {
+                return Err(anyhow!(
+                    "Virtual interfaces {} depend on each other in a loop",
+                    names.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every backend turns a gateway into a default route, and default routes for the same address
+    /// family on different interfaces, or through different gateways, fight over which one is
+    /// used. Only one default gateway per address family is allowed, unless the extra default
+    /// routes are given as routes with explicit metrics.
+    fn validate_default_routes(&self) -> Result<(), Error> {
+        let owners = self
+            .interfaces
+            .iter()
+            .map(|i| (i.identifier.to_string(), &i.static_addresses, &i.routes))
+            .chain(
+                self.virtual_interfaces
+                    .iter()
+                    .map(|v| (v.name.clone(), &v.static_addresses, &v.routes)),
+            );
+        let mut defaults: Vec<(String, IpAddr)> = vec![];
+        for (owner, addresses, routes) in owners {
+            let gateways = addresses.iter().filter_map(|a| a.gateway).chain(
+                routes
+                    .iter()
+                    .filter(|r| r.destination.prefix_len() == 0 && r.metric.is_none())
+                    .filter_map(|r| r.gateway),
+            );
+            for gateway in gateways {
+                let clash = defaults.iter().find(|(o, g)| {
+                    g.is_ipv4() == gateway.is_ipv4() && (*o != owner || *g != gateway)
+                });
+                if let Some((other, other_gateway)) = clash {
+                    return Err(anyhow!(
+                        "{} and {} both give an {} default gateway ({} and {}); only one is \
+                         allowed, unless the others are given as routes with a metric",
+                        other,
+                        owner,
+                        if gateway.is_ipv4() { "IPv4" } else { "IPv6" },
+                        other_gateway,
+                        gateway
+                    ));
+                }
+                defaults.push((owner.clone(), gateway));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Connectivity checks made after a guarded network reconfiguration. Once the new configuration
+/// is in place, a default gateway must answer pings, a name must resolve, and the probe target
+/// must accept a TCP connection, all within the timeout. Otherwise the previous configuration is
+/// restored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifyConfig {
+    /// A `host:port` to connect to, by name or address (IPv6 addresses in brackets). Names also
+    /// check that DNS is working. Set to null to only check the gateway.
+    #[serde(default = "VerifyConfig::default_probe")]
+    pub probe: Option<String>,
+    /// A name that has to resolve, so that DNS is checked even when the probe is given by address
+    /// or left out. Set to null to skip the check.
+    #[serde(default = "VerifyConfig::default_resolve")]
+    pub resolve: Option<String>,
+    /// How long the new configuration has to pass every check, in seconds.
+    #[serde(default = "VerifyConfig::default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        VerifyConfig {
+            probe: VerifyConfig::default_probe(),
+            resolve: VerifyConfig::default_resolve(),
+            timeout: VerifyConfig::default_timeout(),
+        }
+    }
+}
+
+impl VerifyConfig {
+    fn default_probe() -> Option<String> {
+        Some("holo.host:443".to_string())
+    }
+
+    fn default_resolve() -> Option<String> {
+        Some("holo.host".to_string())
+    }
+
+    fn default_timeout() -> u64 {
+        60
+    }
+
+    /// Splits the probe target into its host and port.
+    pub fn probe_target(&self) -> Result<Option<(String, u16)>, Error> {
+        let probe = match &self.probe {
+            Some(probe) => probe,
+            None => return Ok(None),
+        };
+        let invalid = || anyhow!("Probe target {:?} is not of the form host:port", probe);
+        let (host, port) = probe.rsplit_once(':').ok_or_else(invalid)?;
+        let host = match host.strip_prefix('[') {
+            Some(bracketed) => bracketed.strip_suffix(']').ok_or_else(invalid)?,
+            None if host.contains(':') => return Err(invalid()),
+            None => host,
+        };
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(Some((host.to_string(), port)))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.probe_target()?;
+        if let Some(name) = &self.resolve
+            && (name.is_empty() || name.contains(char::is_whitespace))
+        {
+            return Err(anyhow!("{:?} is not a name that can be resolved", name));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -45,10 +570,264 @@ pub struct NetworkInterface {
     pub identifier: DeviceIdentifier,
     /// List of static addresses to assign (IPv6 or IPv4) in cases where DHCP isn't desired.
     pub static_addresses: Vec<InterfaceAddress>,
+    /// How to configure IPv4. Defaults to `static` if any IPv4 addresses are listed above,
+    /// `disabled` if only IPv6 addresses are, and `dhcp4` otherwise.
+    #[serde(default)]
+    pub ipv4: Option<Ipv4Mode>,
+    /// How to configure IPv6. Defaults to `static` if any IPv6 addresses are listed above, and
+    /// `slaac` otherwise.
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Mode>,
+    /// Routes to add, beyond the default routes given by gateways.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// MTU to set on the interface, if the default isn't suitable.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    /// Wireless network to associate with, for Wi-Fi interfaces.
+    pub wireless: Option<WirelessConfig>,
+}
+
+impl NetworkInterface {
+    /// Checks the interface for problems that serde can't catch: identifiers without enough
+    /// detail to find a device, incomplete wireless settings, and unreachable gateways.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(wireless) = &self.wireless {
+            wireless.validate()?;
+        }
+        if let DeviceIdentifier::Virtual { name } = &self.identifier {
+            return Err(anyhow!(
+                "Virtual interface {} can only be used as a member of another virtual interface",
+                name
+            ));
+        }
+        self.identifier.validate()?;
+        self.ip_settings()?.validate(&self.identifier.to_string())
+    }
+
+    /// The IP configuration of the interface, with the defaults for each address family filled in.
+    pub fn ip_settings(&self) -> Result<IpSettings, Error> {
+        IpSettings::resolve(
+            &self.identifier.to_string(),
+            &self.static_addresses,
+            self.ipv4,
+            self.ipv6,
+            &self.routes,
+            self.mtu,
+        )
+    }
+}
+
+/// How an interface gets its IPv4 addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Ipv4Mode {
+    #[serde(rename = "dhcp4")]
+    Dhcp4,
+    /// Only the addresses listed in `static_addresses`.
+    #[serde(rename = "static")]
+    Static,
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+/// How an interface gets its IPv6 addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Ipv6Mode {
+    /// Stateful DHCPv6, along with router advertisements for routes.
+    #[serde(rename = "dhcp6")]
+    Dhcp6,
+    /// Stateless autoconfiguration from router advertisements.
+    #[serde(rename = "slaac")]
+    Slaac,
+    /// Only the addresses listed in `static_addresses`. Router advertisements are ignored.
+    #[serde(rename = "static")]
+    Static,
+    /// No IPv6 at all, not even a link-local address.
+    #[serde(rename = "disabled")]
+    Disabled,
+}
+
+/// A static route, in addition to any default routes given by gateways.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    /// The network to route, in CIDR notation.
+    pub destination: IpNet,
+    /// The router to send traffic through. Without one, the destination is treated as on-link.
+    #[serde(default)]
+    pub gateway: Option<IpAddr>,
+    /// Route priority, with lower values preferred.
+    #[serde(default)]
+    pub metric: Option<u32>,
+}
+
+/// The IP configuration of a single interface, with the mode for each address family resolved.
+#[derive(Debug, Clone)]
+pub struct IpSettings {
+    pub ipv4: Ipv4Mode,
+    pub ipv6: Ipv6Mode,
+    /// Static addresses of both families.
+    pub addresses: Vec<InterfaceAddress>,
+    pub routes: Vec<Route>,
+    pub mtu: Option<u32>,
+}
+
+/// DHCP for IPv4, and autoconfiguration for IPv6.
+impl Default for IpSettings {
+    fn default() -> Self {
+        IpSettings {
+            ipv4: Ipv4Mode::Dhcp4,
+            ipv6: Ipv6Mode::Slaac,
+            addresses: vec![],
+            routes: vec![],
+            mtu: None,
+        }
+    }
+}
+
+impl IpSettings {
+    /// Works out the mode for each address family, filling in defaults from the static addresses
+    /// given, and checking that explicit modes don't contradict them.
+    fn resolve(
+        owner: &str,
+        static_addresses: &[InterfaceAddress],
+        ipv4: Option<Ipv4Mode>,
+        ipv6: Option<Ipv6Mode>,
+        routes: &[Route],
+        mtu: Option<u32>,
+    ) -> Result<Self, Error> {
+        let has_v4 = static_addresses.iter().any(|a| a.address.addr().is_ipv4());
+        let has_v6 = static_addresses.iter().any(|a| a.address.addr().is_ipv6());
+        let ipv4 = match ipv4 {
+            Some(mode) => mode,
+            None if has_v4 => Ipv4Mode::Static,
+            None if has_v6 => Ipv4Mode::Disabled,
+            None => Ipv4Mode::Dhcp4,
+        };
+        let ipv6 = match ipv6 {
+            Some(mode) => mode,
+            None if has_v6 => Ipv6Mode::Static,
+            None => Ipv6Mode::Slaac,
+        };
+        if has_v4 != (ipv4 == Ipv4Mode::Static) {
+            return Err(anyhow!(
+                "{} must list IPv4 static addresses if, and only if, its ipv4 mode is static",
+                owner
+            ));
+        }
+        if has_v6 != (ipv6 == Ipv6Mode::Static) {
+            return Err(anyhow!(
+                "{} must list IPv6 static addresses if, and only if, its ipv6 mode is static",
+                owner
+            ));
+        }
+        Ok(IpSettings {
+            ipv4,
+            ipv6,
+            addresses: static_addresses.to_vec(),
+            routes: routes.to_vec(),
+            mtu,
+        })
+    }
+
+    /// Checks that gateways and routes are reachable with the address families enabled, and that
+    /// the MTU is usable.
+    pub fn validate(&self, owner: &str) -> Result<(), Error> {
+        validate_gateways(owner, &self.addresses)?;
+
+        for route in &self.routes {
+            let v4 = route.destination.addr().is_ipv4();
+            if (v4 && self.ipv4 == Ipv4Mode::Disabled) || (!v4 && self.ipv6 == Ipv6Mode::Disabled) {
+                return Err(anyhow!(
+                    "Route to {} on {} needs an address family that is disabled",
+                    route.destination,
+                    owner
+                ));
+            }
+            if let Some(gateway) = route.gateway {
+                if gateway.is_ipv4() != v4 {
+                    return Err(anyhow!(
+                        "Gateway {} for the route to {} on {} is not the same address family",
+                        gateway,
+                        route.destination,
+                        owner
+                    ));
+                }
+                // With DHCP or autoconfiguration we can't know the subnet in advance.
+                let static_family = if v4 {
+                    self.ipv4 == Ipv4Mode::Static
+                } else {
+                    self.ipv6 == Ipv6Mode::Static
+                };
+                let link_local = matches!(gateway, IpAddr::V6(v6) if v6.is_unicast_link_local());
+                if static_family
+                    && !link_local
+                    && !self.addresses.iter().any(|a| a.address.contains(&gateway))
+                {
+                    return Err(anyhow!(
+                        "Gateway {} for the route to {} on {} is outside of every subnet \
+                         assigned to the interface",
+                        gateway,
+                        route.destination,
+                        owner
+                    ));
+                }
+            }
+        }
+
+        if let Some(mtu) = self.mtu {
+            // The minimums required by IPv4 (RFC 791) and IPv6 (RFC 8200) respectively.
+            let minimum = if self.ipv6 == Ipv6Mode::Disabled {
+                68
+            } else {
+                1280
+            };
+            if !(minimum..=65535).contains(&mtu) {
+                return Err(anyhow!(
+                    "MTU {} for {} is out of range ({} to 65535)",
+                    mtu,
+                    owner,
+                    minimum
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that every gateway listed for an interface is actually reachable on-link through one of
+/// the addresses assigned to it. A gateway outside of every subnet on the interface would leave
+/// the node with an unusable default route.
+fn validate_gateways(owner: &str, addresses: &[InterfaceAddress]) -> Result<(), Error> {
+    for addr in addresses {
+        if let Some(gateway) = addr.gateway {
+            if addr.address.addr().is_ipv4() != gateway.is_ipv4() {
+                return Err(anyhow!(
+                    "Gateway {} for {} is not the same address family as {}",
+                    gateway,
+                    owner,
+                    addr.address
+                ));
+            }
+            // IPv6 routers are commonly only reachable by their link-local address, which is
+            // on-link by definition, even if it's outside of every configured prefix.
+            let link_local = match gateway {
+                IpAddr::V6(v6) => v6.is_unicast_link_local(),
+                IpAddr::V4(_) => false,
+            };
+            if !link_local && !addresses.iter().any(|a| a.address.contains(&gateway)) {
+                return Err(anyhow!(
+                    "Gateway {} for {} is outside of every subnet assigned to the interface",
+                    gateway,
+                    owner
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 #[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DeviceIdentifier {
     #[serde(rename = "pci_address")]
     PciAddress {
@@ -57,20 +836,301 @@ pub enum DeviceIdentifier {
     },
     #[serde(rename = "virtio")]
     Virtio {
-        /// Address on the virtio bus. Eg, 0000:00:03.0/virtio2?
+        /// Address on the virtio bus. Eg, 0000:00:03.0/virtio2, virtio2, or just the PCI address
+        /// 0000:00:03.0 if it only has the one virtio device.
         address: String,
     },
     #[serde(rename = "usb")]
     Usb {
-        /// TODO: This probably needs to be the vendor and product IDs, rather than the address,
-        /// which is more likely to change. However, if we use vendor/product IDs, a machine with
-        /// two USB sticks of the same model could yield inconsistent results.
+        /// Vendor and product IDs in hex, as shown by lsusb. Eg, 0bda:8153. On its own, this
+        /// is ambiguous on machines with two adapters of the same model, so it can be combined
+        /// with the serial number and/or port below.
+        id: Option<String>,
+        /// The serial number reported by the device, for models that have a unique one.
+        serial: Option<String>,
+        /// The physical port path the device is plugged into, as named in sysfs. Eg, 1-1.2 for
+        /// port 2 on the hub plugged into port 1 of bus 1.
+        port: Option<String>,
+    },
+    #[serde(rename = "mac_address")]
+    MacAddress {
+        /// Identify the network device by its hardware address. Eg, 00:1a:2b:3c:4d:5e
         address: String,
     },
+    #[serde(rename = "driver")]
+    Driver {
+        /// Use the first interface (in bus order) bound to one of these kernel drivers, in order
+        /// of preference. Eg, [e1000e, r8169]. Handy where models vary, but each only has one NIC.
+        names: Vec<String>,
+    },
+    #[serde(rename = "virtual")]
+    Virtual {
+        /// Another interface from `virtual_interfaces`, by name. Only valid as a member or parent
+        /// of a virtual interface, for building VLANs on bonds and the like.
+        name: String,
+    },
+}
+
+impl DeviceIdentifier {
+    /// Checks that the identifier has enough, well-formed, detail to find a device with.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Self::Usb { id, serial, port } => {
+                if id.is_none() && serial.is_none() && port.is_none() {
+                    return Err(anyhow!(
+                        "USB interfaces need at least one of id, serial or port to identify them"
+                    ));
+                }
+                if let Some(id) = id
+                    && id.split_once(':').is_none()
+                {
+                    return Err(anyhow!("USB id {} should be in vendor:product form", id));
+                }
+            }
+            Self::MacAddress { address } => {
+                let octets: Vec<&str> = address.split(':').collect();
+                if octets.len() != 6
+                    || octets
+                        .iter()
+                        .any(|o| o.len() != 2 || u8::from_str_radix(o, 16).is_err())
+                {
+                    return Err(anyhow!("{} is not a valid MAC address", address));
+                }
+            }
+            Self::Driver { names } => {
+                if names.is_empty() {
+                    return Err(anyhow!("Driver identifiers need at least one driver name"));
+                }
+            }
+            Self::PciAddress { .. } | Self::Virtio { .. } | Self::Virtual { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DeviceIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PciAddress { address } => write!(f, "PCI device {}", address),
+            Self::Virtio { address } => write!(f, "virtio device {}", address),
+            Self::Usb { id, serial, port } => {
+                write!(f, "USB device")?;
+                if let Some(id) = id {
+                    write!(f, " {}", id)?;
+                }
+                if let Some(serial) = serial {
+                    write!(f, " serial {}", serial)?;
+                }
+                if let Some(port) = port {
+                    write!(f, " on port {}", port)?;
+                }
+                Ok(())
+            }
+            Self::MacAddress { address } => write!(f, "MAC address {}", address),
+            Self::Driver { names } => write!(f, "driver {}", names.join("/")),
+            Self::Virtual { name } => write!(f, "virtual interface {}", name),
+        }
+    }
+}
+
+/// A bond, bridge or VLAN interface, built from other interfaces.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualInterface {
+    /// Name to give the interface. Eg, bond0, br0 or vlan100.
+    pub name: String,
+    /// What sort of interface this is, and what it's made of.
+    pub kind: VirtualKind,
+    /// List of static addresses to assign. As with physical interfaces, DHCP is used if empty.
+    #[serde(default)]
+    pub static_addresses: Vec<InterfaceAddress>,
+    /// As for physical interfaces.
+    #[serde(default)]
+    pub ipv4: Option<Ipv4Mode>,
+    /// As for physical interfaces.
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Mode>,
+    /// Routes to add, beyond the default routes given by gateways.
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// MTU to set on the interface, if the default isn't suitable.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+}
+
+impl VirtualInterface {
+    /// The IP configuration of the interface, with the defaults for each address family filled in.
+    pub fn ip_settings(&self) -> Result<IpSettings, Error> {
+        IpSettings::resolve(
+            &self.name,
+            &self.static_addresses,
+            self.ipv4,
+            self.ipv6,
+            &self.routes,
+            self.mtu,
+        )
+    }
+}
+
+/// The different kinds of virtual interfaces, along with the interfaces they depend on. Members
+/// and parents may be physical devices, or other virtual interfaces via `!virtual`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum VirtualKind {
+    #[serde(rename = "bond")]
+    Bond {
+        /// Bonding mode, as per the kernel bonding driver.
+        mode: BondMode,
+        /// Interfaces to bond together.
+        members: Vec<DeviceIdentifier>,
+    },
+    #[serde(rename = "bridge")]
+    Bridge {
+        /// Interfaces to add to the bridge as ports. May be empty.
+        #[serde(default)]
+        members: Vec<DeviceIdentifier>,
+    },
+    #[serde(rename = "vlan")]
+    Vlan {
+        /// Interface carrying the tagged traffic.
+        parent: DeviceIdentifier,
+        /// 802.1Q VLAN ID, 1 to 4094.
+        id: u16,
+    },
+}
+
+impl VirtualKind {
+    /// All of the interfaces this one needs to be up before it can be brought up.
+    pub fn members(&self) -> Vec<&DeviceIdentifier> {
+        match self {
+            Self::Bond { members, .. } | Self::Bridge { members } => members.iter().collect(),
+            Self::Vlan { parent, .. } => vec![parent],
+        }
+    }
+}
+
+/// Link aggregation modes supported by the kernel bonding driver.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BondMode {
+    #[serde(rename = "balance-rr")]
+    BalanceRr,
+    #[serde(rename = "active-backup")]
+    ActiveBackup,
+    #[serde(rename = "balance-xor")]
+    BalanceXor,
+    #[serde(rename = "broadcast")]
+    Broadcast,
+    #[serde(rename = "802.3ad")]
+    Ieee8023ad,
+    #[serde(rename = "balance-tlb")]
+    BalanceTlb,
+    #[serde(rename = "balance-alb")]
+    BalanceAlb,
+}
+
+impl fmt::Display for BondMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ret = match self {
+            Self::BalanceRr => "balance-rr",
+            Self::ActiveBackup => "active-backup",
+            Self::BalanceXor => "balance-xor",
+            Self::Broadcast => "broadcast",
+            Self::Ieee8023ad => "802.3ad",
+            Self::BalanceTlb => "balance-tlb",
+            Self::BalanceAlb => "balance-alb",
+        };
+
+        write!(f, "{}", ret)
+    }
+}
+
+/// Wi-Fi network settings for a wireless interface.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WirelessConfig {
+    /// Name of the network to associate with.
+    pub ssid: String,
+    /// Passphrase for the network, between 8 and 63 characters.
+    pub psk: Option<String>,
+    /// Pre-computed hash of the passphrase (64 hex digits, as produced by wpa_passphrase), to
+    /// avoid storing the passphrase itself. Not usable with SAE (WPA3).
+    pub psk_hash: Option<String>,
+    /// Key management to use. Defaults to WPA-PSK. Open networks must ask for `none` explicitly.
+    pub key_management: Option<KeyManagement>,
+    /// Set for networks that don't broadcast their SSID, so that we probe for them.
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+/// Wi-Fi key management types.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum KeyManagement {
+    /// WPA2 personal, with a pre-shared key.
+    #[serde(rename = "wpa-psk")]
+    WpaPsk,
+    /// WPA3 personal.
+    #[serde(rename = "sae")]
+    Sae,
+    /// An open network, with no encryption at all.
+    #[serde(rename = "none")]
+    None,
+}
+
+impl WirelessConfig {
+    /// The key management in effect, taking defaults into account.
+    pub fn key_management(&self) -> KeyManagement {
+        self.key_management.unwrap_or(KeyManagement::WpaPsk)
+    }
+
+    /// Checks that the credentials given make sense for the key management in use. In particular,
+    /// a network with no credentials is only acceptable if it was explicitly asked to be open.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            return Err(anyhow!(
+                "SSID {:?} must be between 1 and 32 bytes",
+                self.ssid
+            ));
+        }
+        match (self.key_management(), &self.psk, &self.psk_hash) {
+            (KeyManagement::None, None, None) => Ok(()),
+            (KeyManagement::None, _, _) => Err(anyhow!(
+                "Network {} is open, but a passphrase was given",
+                self.ssid
+            )),
+            (_, Some(_), Some(_)) => Err(anyhow!(
+                "Network {} has both a passphrase and a passphrase hash; use one",
+                self.ssid
+            )),
+            (_, None, None) => Err(anyhow!(
+                "Network {} has no passphrase. Use `key_management: none` for open networks",
+                self.ssid
+            )),
+            (KeyManagement::Sae, None, Some(_)) => Err(anyhow!(
+                "Network {} uses SAE, which needs the passphrase rather than its hash",
+                self.ssid
+            )),
+            (_, Some(psk), None) => {
+                if psk.len() < 8 || psk.len() > 63 || !psk.chars().all(|c| (' '..='~').contains(&c))
+                {
+                    return Err(anyhow!(
+                        "Passphrase for {} must be 8 to 63 printable ASCII characters",
+                        self.ssid
+                    ));
+                }
+                Ok(())
+            }
+            (_, None, Some(hash)) => {
+                if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(anyhow!(
+                        "Passphrase hash for {} must be 64 hex digits",
+                        self.ssid
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 /// Addresses and gateways to assign to a network interface.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InterfaceAddress {
     /// An IPv6 or IPv4 CIDR-syntax address/netmask to assign to this interface.
     pub address: IpNet,
@@ -85,8 +1145,8 @@ pub struct SecurityConfig {
     /// published on github by that user, and add those as authorized/trusted keys on the local
     /// instance.
     pub github_usernames: Vec<String>,
-    /// If github keys are not available or desired, explicit keys may be specified as a list of
-    /// strings.
+    /// Explicit keys, as `authorized_keys` lines, trusted alongside (or instead of) the github
+    /// users' keys.
     pub ssh_keys: Vec<String>,
     /// The root password is disabled by default, with ssh keys being preferred. If a root password
     /// is required or desired, include the hashed password as a string here.
@@ -96,7 +1156,6 @@ pub struct SecurityConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_yaml;
     use std::str::FromStr;
 
     const TEST_CONFIG: &str = "
@@ -106,6 +1165,8 @@ storage:
 network:
     nameservers:
         - 1.1.1.1
+    search_domains:
+        - example.com
     interfaces:
         # The most accurate way to select an interface is by PCI address
         - identifier: !pci_address
@@ -128,16 +1189,498 @@ security:
 
         dbg!(&c);
 
-        assert_eq!(c.storage.persist_partition, Some("/dev/sda2".to_string()));
-        assert_eq!(c.storage.install_partition, Some("/dev/sda1".to_string()));
+        assert_eq!(
+            c.storage.persist_partition,
+            Some(StorageIdentifier::Path {
+                path: "/dev/sda2".to_string()
+            })
+        );
+        assert_eq!(
+            c.storage.install_partition,
+            Some(StorageIdentifier::Path {
+                path: "/dev/sda1".to_string()
+            })
+        );
         assert_eq!(
             c.network.nameservers[0],
             IpAddr::from_str("1.1.1.1").unwrap()
         );
+        assert_eq!(c.network.search_domains, vec!["example.com"]);
+        assert_eq!(c.network.resolver_options.len(), 0);
         assert_eq!(c.network.interfaces.len(), 1);
         assert_eq!(c.network.interfaces[0].static_addresses.len(), 2);
         assert_eq!(c.security.github_usernames.len(), 1);
         assert_eq!(c.security.ssh_keys.len(), 0);
+        c.validate().unwrap();
+    }
+
+    #[test]
+    /// A gateway that can't be reached through any of the interface's subnets should be rejected.
+    fn gateway_outside_subnet() {
+        let iface: NetworkInterface = serde_yaml::from_str(
+            "
+identifier: !pci_address
+    address: '00:14.1'
+static_addresses:
+    - address: 10.0.0.100/24
+      gateway: 10.0.1.1
+    - address: 2001:db8::100/64
+",
+        )
+        .unwrap();
+        assert!(iface.validate().is_err());
+    }
+
+    #[test]
+    /// The gateway only needs to be in one of the interface's subnets, not necessarily the one
+    /// it's listed alongside. IPv6 link-local gateways are always acceptable.
+    fn gateway_in_other_subnet() {
+        let iface: NetworkInterface = serde_yaml::from_str(
+            "
+identifier: !pci_address
+    address: '00:14.1'
+static_addresses:
+    - address: 10.0.0.100/24
+    - address: 192.168.1.10/24
+      gateway: 10.0.0.1
+    - address: 2001:db8::100/64
+      gateway: fe80::1
+",
+        )
+        .unwrap();
+        iface.validate().unwrap();
+    }
+
+    #[test]
+    /// Default gateways of the same address family on different interfaces would give
+    /// conflicting default routes, unless the extra ones are routes with metrics.
+    fn conflicting_default_gateways() {
+        let network = |yaml: &str| -> NetworkConfig { serde_yaml::from_str(yaml).unwrap() };
+        let two = network(
+            "
+nameservers: []
+interfaces:
+    - identifier: !mac_address
+          address: 00:11:22:33:44:55
+      static_addresses:
+          - address: 10.0.0.100/24
+            gateway: 10.0.0.1
+          - address: 2001:db8::100/64
+            gateway: fe80::1
+    - identifier: !mac_address
+          address: 00:11:22:33:44:66
+      static_addresses:
+          - address: 192.168.1.10/24
+            gateway: 192.168.1.1
+",
+        );
+        assert_eq!(
+            two.validate().unwrap_err().to_string(),
+            "MAC address 00:11:22:33:44:55 and MAC address 00:11:22:33:44:66 both give an IPv4 \
+             default gateway (10.0.0.1 and 192.168.1.1); only one is allowed, unless the others \
+             are given as routes with a metric"
+        );
+
+        // One IPv4 and one IPv6 default gateway don't conflict, and neither does a second
+        // default route with a metric.
+        network(
+            "
+nameservers: []
+interfaces:
+    - identifier: !mac_address
+          address: 00:11:22:33:44:55
+      static_addresses:
+          - address: 10.0.0.100/24
+            gateway: 10.0.0.1
+    - identifier: !mac_address
+          address: 00:11:22:33:44:66
+      static_addresses:
+          - address: 192.168.1.10/24
+          - address: 2001:db8::100/64
+            gateway: 2001:db8::1
+      routes:
+          - destination: 0.0.0.0/0
+            gateway: 192.168.1.1
+            metric: 200
+",
+        )
+        .validate()
+        .unwrap();
+
+        // A default route without a metric counts as a default gateway.
+        let route = network(
+            "
+nameservers: []
+interfaces:
+    - identifier: !mac_address
+          address: 00:11:22:33:44:55
+      static_addresses:
+          - address: 10.0.0.100/24
+            gateway: 10.0.0.1
+      routes:
+          - destination: 0.0.0.0/0
+            gateway: 10.0.0.254
+",
+        );
+        assert!(route.validate().is_err());
+    }
+
+    #[test]
+    /// Wireless networks need credentials that suit their key management, and are only open if
+    /// explicitly asked to be.
+    fn wireless_validation() {
+        let wireless = |yaml: &str| -> WirelessConfig { serde_yaml::from_str(yaml).unwrap() };
+
+        wireless("ssid: home\npsk: correct horse battery\n")
+            .validate()
+            .unwrap();
+        wireless("ssid: home\nkey_management: sae\npsk: correct horse battery\n")
+            .validate()
+            .unwrap();
+        wireless(&format!(
+            "ssid: home\npsk_hash: {}\nhidden: true\n",
+            "ab".repeat(32)
+        ))
+        .validate()
+        .unwrap();
+        wireless("ssid: cafe\nkey_management: none\n")
+            .validate()
+            .unwrap();
+
+        assert!(wireless("ssid: cafe\n").validate().is_err());
+        assert!(wireless("ssid: home\npsk: short\n").validate().is_err());
+        assert!(
+            wireless("ssid: cafe\nkey_management: none\npsk: correct horse battery\n")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            wireless(&format!(
+                "ssid: home\nkey_management: sae\npsk_hash: {}\n",
+                "ab".repeat(32)
+            ))
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    /// Virtual interfaces must refer to things that exist, without going round in circles.
+    fn virtual_interface_validation() {
+        let network = |yaml: &str| -> NetworkConfig {
+            serde_yaml::from_str(&format!("nameservers:\ninterfaces:\n{}", yaml)).unwrap()
+        };
+
+        network(
+            "
+virtual_interfaces:
+    - name: br0
+      kind: !bridge
+        members:
+            - !virtual
+                name: bond0
+    - name: bond0
+      kind: !bond
+        mode: 802.3ad
+        members:
+            - !mac_address
+                address: 00:1a:2b:3c:4d:5e
+",
+        )
+        .validate()
+        .unwrap();
+
+        // An undefined member
+        assert!(
+            network(
+                "
+virtual_interfaces:
+    - name: br0
+      kind: !bridge
+        members:
+            - !virtual
+                name: bond0
+",
+            )
+            .validate()
+            .is_err()
+        );
+
+        // A loop
+        assert!(
+            network(
+                "
+virtual_interfaces:
+    - name: vlan1
+      kind: !vlan
+        parent: !virtual
+            name: vlan2
+        id: 1
+    - name: vlan2
+      kind: !vlan
+        parent: !virtual
+            name: vlan1
+        id: 2
+",
+            )
+            .validate()
+            .is_err()
+        );
+
+        // A bond member that also has its own configuration
+        assert!(
+            network(
+                "
+    - identifier: !pci_address
+        address: '0000:01:00.0'
+      static_addresses:
+virtual_interfaces:
+    - name: bond0
+      kind: !bond
+        mode: active-backup
+        members:
+            - !pci_address
+                address: '0000:01:00.0'
+",
+            )
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn verify_probe() {
+        let verify = |yaml: &str| -> VerifyConfig { serde_yaml::from_str(yaml).unwrap() };
+
+        let defaults = verify("{}");
+        assert_eq!(defaults.timeout, 60);
+        assert_eq!(
+            defaults.probe_target().unwrap(),
+            Some(("holo.host".to_string(), 443))
+        );
+        assert_eq!(
+            verify("probe: '[2001:db8::1]:22'").probe_target().unwrap(),
+            Some(("2001:db8::1".to_string(), 22))
+        );
+        assert_eq!(verify("probe: null").probe_target().unwrap(), None);
+        assert_eq!(defaults.resolve.as_deref(), Some("holo.host"));
+        assert!(verify("resolve: null").validate().is_ok());
+        assert!(verify("resolve: 'holo host'").validate().is_err());
+
+        for bad in [
+            "holo.host",
+            "2001:db8::1:22",
+            "holo.host:https",
+            ":443",
+            "[::1:22",
+        ] {
+            assert!(
+                verify(&format!("probe: '{}'", bad)).validate().is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    /// Storage can be identified by a bare path as before, or in any of the more stable ways.
+    fn storage_identifiers() {
+        let storage: StorageConfig = serde_yaml::from_str(
+            "
+install_partition: !largest_disk
+    rotational: true
+    partition: 1
+persist_partition: !label
+    label: HolOS-data
+",
+        )
+        .unwrap();
+        assert_eq!(
+            storage.install_partition,
+            Some(StorageIdentifier::LargestDisk {
+                rotational: Some(true),
+                removable: false,
+                partition: Some(1)
+            })
+        );
+        assert_eq!(
+            storage.install_partition.unwrap().to_string(),
+            "largest non-removable rotational disk, partition 1"
+        );
+        assert_eq!(
+            storage.persist_partition.unwrap().to_string(),
+            "LABEL=HolOS-data"
+        );
+
+        let storage: StorageConfig =
+            serde_yaml::from_str("install_partition: /dev/vda1\npersist_partition:\n").unwrap();
+        assert_eq!(storage.install_partition.unwrap().to_string(), "/dev/vda1");
+        assert_eq!(storage.persist_partition, None);
+        let storage: StorageConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(storage.install_partition, None);
+
+        let storage: StorageConfig = serde_yaml::from_str(
+            "install_partition: !serial\n    serial: S3Z1\n    partition: 0\n",
+        )
+        .unwrap();
+        assert!(storage.validate().is_err());
+        assert!(serde_yaml::from_str::<StorageConfig>("install_partition: !uuid {}").is_err());
+    }
+
+    #[test]
+    fn root_param() {
+        for (value, expected) in [
+            (
+                "LABEL=HolOS-system-a",
+                StorageIdentifier::Label {
+                    label: "HolOS-system-a".to_string(),
+                },
+            ),
+            (
+                "UUID=6f1c8b2e-3d4a-4b5c-9d6e-7f8091a2b3c4",
+                StorageIdentifier::Uuid {
+                    uuid: "6f1c8b2e-3d4a-4b5c-9d6e-7f8091a2b3c4".to_string(),
+                },
+            ),
+            (
+                "PARTUUID=0b5a8e0e-01",
+                StorageIdentifier::PartUuid {
+                    partuuid: "0b5a8e0e-01".to_string(),
+                },
+            ),
+            (
+                "/dev/sda1",
+                StorageIdentifier::Path {
+                    path: "/dev/sda1".to_string(),
+                },
+            ),
+        ] {
+            assert_eq!(StorageIdentifier::from_root(value), expected);
+            assert_eq!(expected.to_string(), value);
+        }
+    }
+
+    #[test]
+    /// Encryption says how the persist partition is unlocked, and key files can't be on it.
+    fn storage_encryption() {
+        let storage: StorageConfig = serde_yaml::from_str(
+            "
+persist_partition: !label
+    label: HolOS-data
+encryption:
+    unlock: !keyfile
+        path: /etc/holos/persist.key
+",
+        )
+        .unwrap();
+        storage.validate().unwrap();
+        for (unlock, expected) in [("!prompt", Unlock::Prompt), ("cmdline", Unlock::Cmdline)] {
+            let storage: StorageConfig = serde_yaml::from_str(&format!(
+                "persist_partition: /dev/sda2\nencryption:\n    unlock: {}\n",
+                unlock
+            ))
+            .unwrap();
+            assert_eq!(storage.encryption.unwrap().unlock, expected);
+        }
+
+        let storage: StorageConfig = serde_yaml::from_str(
+            "persist_partition: /dev/sda2\nencryption:\n    unlock: !keyfile\n        path: /data/key\n",
+        )
+        .unwrap();
+        assert!(storage.validate().is_err());
+        let storage: StorageConfig =
+            serde_yaml::from_str("encryption:\n    unlock: !prompt\n").unwrap();
+        assert!(storage.validate().is_err());
+    }
+
+    #[test]
+    /// USB identifiers need something to match on.
+    fn usb_identifier() {
+        let iface: NetworkInterface = serde_yaml::from_str(
+            "
+identifier: !usb
+    id: 0bda:8153
+    port: 1-1.2
+static_addresses:
+",
+        )
+        .unwrap();
+        iface.validate().unwrap();
+
+        let iface: NetworkInterface =
+            serde_yaml::from_str("identifier: !usb {}\nstatic_addresses:\n").unwrap();
+        assert!(iface.validate().is_err());
+    }
+
+    #[test]
+    /// Address family modes default from the static addresses, and routes and the MTU have to
+    /// make sense with the families enabled.
+    fn ip_modes() {
+        let iface = |yaml: &str| -> NetworkInterface {
+            serde_yaml::from_str(&format!(
+                "identifier: !pci_address {{address: '00:14.1'}}\n{}",
+                yaml
+            ))
+            .unwrap()
+        };
+
+        let ip = iface("static_addresses:\n").ip_settings().unwrap();
+        assert_eq!((ip.ipv4, ip.ipv6), (Ipv4Mode::Dhcp4, Ipv6Mode::Slaac));
+        let ip = iface("static_addresses:\n    - address: 2001:db8::100/64\n")
+            .ip_settings()
+            .unwrap();
+        assert_eq!((ip.ipv4, ip.ipv6), (Ipv4Mode::Disabled, Ipv6Mode::Static));
+
+        iface(
+            "
+static_addresses:
+    - address: 10.0.0.100/24
+ipv6: disabled
+mtu: 576
+routes:
+    - destination: 192.168.0.0/16
+      gateway: 10.0.0.254
+      metric: 100
+    - destination: 172.16.0.0/12
+",
+        )
+        .validate()
+        .unwrap();
+        iface("static_addresses:\nipv6: dhcp6\nroutes:\n    - destination: 2001:db8:1::/48\n      gateway: fe80::1\n")
+            .validate()
+            .unwrap();
+
+        // Modes that contradict the addresses listed
+        assert!(
+            iface("static_addresses:\n    - address: 10.0.0.100/24\nipv4: dhcp4\n")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            iface("static_addresses:\nipv6: static\n")
+                .validate()
+                .is_err()
+        );
+        // A route for a disabled family
+        assert!(
+            iface("static_addresses:\nipv6: disabled\nroutes:\n    - destination: 2001:db8::/32\n")
+                .validate()
+                .is_err()
+        );
+        // A route gateway that isn't on-link, or is of the wrong family
+        assert!(
+            iface("static_addresses:\n    - address: 10.0.0.100/24\nroutes:\n    - destination: 192.168.0.0/16\n      gateway: 10.1.0.1\n")
+                .validate()
+                .is_err()
+        );
+        assert!(
+            iface("static_addresses:\nroutes:\n    - destination: 192.168.0.0/16\n      gateway: fe80::1\n")
+                .validate()
+                .is_err()
+        );
+        // IPv6 needs a larger MTU than IPv4 alone
+        assert!(iface("static_addresses:\nmtu: 576\n").validate().is_err());
+        assert!(iface("static_addresses:\nmtu: 70000\n").validate().is_err());
     }
 }
 
@@ -161,6 +1704,19 @@ pub mod cmdline {
         /// This flag just indicates that we likely booted the live image, rather than from a hard
         /// drive or similar.
         pub live_flag: bool,
+        /// The passphrase for an encrypted persist partition, when it's to be unlocked with one
+        /// given on the command line.
+        pub persist_passphrase: Option<String>,
+        /// The root filesystem the kernel was told to boot, eg `LABEL=HolOS-system-a`.
+        pub root: Option<String>,
+        /// Set by the bootloader when it boots the previous system because an upgraded one never
+        /// confirmed its trial boot.
+        pub fallback_flag: bool,
+        /// The type of the root filesystem, if it's given rather than detected.
+        pub rootfstype: Option<String>,
+        /// Whether the root filesystem is mounted read-write from the start, with `rw`. It's
+        /// read-only (`ro`) otherwise.
+        pub read_write: bool,
     }
 
     impl CmdLine {
@@ -169,6 +1725,11 @@ pub mod cmdline {
             let mut github_usernames: Vec<String> = vec![];
             let mut install_flag: bool = false;
             let mut live_flag: bool = false;
+            let mut persist_passphrase: Option<String> = None;
+            let mut root: Option<String> = None;
+            let mut fallback_flag: bool = false;
+            let mut rootfstype: Option<String> = None;
+            let mut read_write: bool = false;
 
             let path = Path::new(file);
             let f = File::open(path)?;
@@ -185,6 +1746,16 @@ pub mod cmdline {
                         install_flag = true;
                     } else if arg == "live" {
                         live_flag = true;
+                    } else if let Some(passphrase) = arg.strip_prefix("persist_passphrase=") {
+                        persist_passphrase = Some(passphrase.to_string());
+                    } else if let Some(device) = arg.strip_prefix("root=") {
+                        root = Some(device.to_string());
+                    } else if arg == "holos_fallback" {
+                        fallback_flag = true;
+                    } else if let Some(fs_type) = arg.strip_prefix("rootfstype=") {
+                        rootfstype = Some(fs_type.to_string());
+                    } else if arg == "rw" || arg == "ro" {
+                        read_write = arg == "rw";
                     }
                 }
             }
@@ -194,6 +1765,11 @@ pub mod cmdline {
                 github_usernames,
                 install_flag,
                 live_flag,
+                persist_passphrase,
+                root,
+                fallback_flag,
+                rootfstype,
+                read_write,
             })
         }
     }
@@ -209,15 +1785,18 @@ pub mod cmdline {
         const CMDLINE_WITH_GITHUB_USERS: &str =
             "root=LABEL=holos_root ro crashkernel=xxx,yyy github_usernames=username1,username2";
         const CMDLINE_WITH_INSTALL_FLAG: &str = "root=LABEL=holos_root ro -- install";
+        const CMDLINE_WITH_PASSPHRASE: &str =
+            "root=LABEL=HolOS-system ro -- persist_passphrase=correct-horse";
+        const CMDLINE_WITH_ROOT_OPTIONS: &str = "root=PARTUUID=0b5a8e0e-01 ro rootfstype=xfs rw";
 
         #[test]
         fn test_config_file() {
             // Write a string to a throwaway file and then make sure the above code can parse it.
             let tempfile = NamedTempFile::new().unwrap();
             let filename = tempfile.path();
-            fs::write(&filename, CMDLINE_WITH_CONFIG_FILE).unwrap();
+            fs::write(filename, CMDLINE_WITH_CONFIG_FILE).unwrap();
 
-            let overrides = CmdLine::from_file(&filename.to_str().unwrap()).unwrap();
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
             assert_eq!(
                 overrides.config_file,
                 Some("/etc/holos/configs/holoport.yaml".to_string())
@@ -229,9 +1808,9 @@ pub mod cmdline {
             // Write a string to a throwaway file and then make sure the above code can parse it.
             let tempfile = NamedTempFile::new().unwrap();
             let filename = tempfile.path();
-            fs::write(&filename, CMDLINE_WITH_GITHUB_USERS).unwrap();
+            fs::write(filename, CMDLINE_WITH_GITHUB_USERS).unwrap();
 
-            let overrides = CmdLine::from_file(&filename.to_str().unwrap()).unwrap();
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
             assert_eq!(overrides.github_usernames, vec!["username1", "username2"]);
         }
 
@@ -240,10 +1819,10 @@ pub mod cmdline {
             // Write a string to a throwaway file and then make sure the above code can parse it.
             let tempfile = NamedTempFile::new().unwrap();
             let filename = tempfile.path();
-            fs::write(&filename, CMDLINE_WITH_INSTALL_FLAG).unwrap();
+            fs::write(filename, CMDLINE_WITH_INSTALL_FLAG).unwrap();
 
-            let overrides = CmdLine::from_file(&filename.to_str().unwrap()).unwrap();
-            assert_eq!(overrides.install_flag, true);
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
+            assert!(overrides.install_flag);
         }
 
         #[test]
@@ -251,10 +1830,42 @@ pub mod cmdline {
             // Write a string to a throwaway file and then make sure the above code can parse it.
             let tempfile = NamedTempFile::new().unwrap();
             let filename = tempfile.path();
-            fs::write(&filename, CMDLINE_WITH_GITHUB_USERS).unwrap();
+            fs::write(filename, CMDLINE_WITH_GITHUB_USERS).unwrap();
+
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
+            assert!(!overrides.install_flag);
+        }
+
+        #[test]
+        fn test_persist_passphrase() {
+            // Write a string to a throwaway file and then make sure the above code can parse it.
+            let tempfile = NamedTempFile::new().unwrap();
+            let filename = tempfile.path();
+            fs::write(filename, CMDLINE_WITH_PASSPHRASE).unwrap();
+
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
+            assert_eq!(
+                overrides.persist_passphrase,
+                Some("correct-horse".to_string())
+            );
+            assert_eq!(overrides.root, Some("LABEL=HolOS-system".to_string()));
+            assert!(!overrides.fallback_flag);
+            assert_eq!(overrides.rootfstype, None);
+            assert!(!overrides.read_write);
+        }
+
+        #[test]
+        fn test_root_options() {
+            // Write a string to a throwaway file and then make sure the above code can parse it.
+            let tempfile = NamedTempFile::new().unwrap();
+            let filename = tempfile.path();
+            fs::write(filename, CMDLINE_WITH_ROOT_OPTIONS).unwrap();
 
-            let overrides = CmdLine::from_file(&filename.to_str().unwrap()).unwrap();
-            assert_eq!(overrides.install_flag, false);
+            let overrides = CmdLine::from_file(filename.to_str().unwrap()).unwrap();
+            assert_eq!(overrides.root, Some("PARTUUID=0b5a8e0e-01".to_string()));
+            assert_eq!(overrides.rootfstype, Some("xfs".to_string()));
+            // The last of ro and rw wins, as it does for the kernel.
+            assert!(overrides.read_write);
         }
     }
 }
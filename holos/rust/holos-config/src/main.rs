@@ -1,17 +1,28 @@
 use clap::{Parser, Subcommand};
 use holos_config::{
-    HolosConfig, cmdline::CmdLine, install::do_install, models::Model, models::ModelConfig,
+    HolosConfig, blockdev,
+    cmdline::CmdLine,
+    init,
+    install::{Live, do_install, initramfs, upgrade},
+    keys::{KeySet, Source},
+    models::Model,
+    models::ModelConfig,
+    network::{
+        Backend, BackendKind, automatic_interfaces, guard, ifupdown::Ifupdown, interface_name,
+        netifrc::Netifrc, networkd::Networkd, plan as network_plan, record_automatic,
+    },
+    storage,
+    system::SystemRoot,
 };
 use local_ip_address::list_afinet_netifas;
-use log::info;
+use log::{error, info};
 use serde::Deserialize;
 use std::env;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Write};
-use std::os::unix::fs::{PermissionsExt, symlink};
-use std::path::Path;
+use std::fs::File;
+use std::io::BufReader;
 use syslog::Facility;
+use tokio::signal::unix::{SignalKind, signal};
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -23,11 +34,61 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    Configure {},
+    Configure {
+        /// Show the changes that would be made to the storage and network configuration, without
+        /// making them.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// The network management system to configure (netifrc, ifupdown or networkd). Detected
+        /// from the running system if not given.
+        #[arg(long)]
+        backend: Option<BackendKind>,
+        /// Reload the running network with the new configuration, and put the previous one back
+        /// if the node can no longer reach its gateway, DNS and probe target.
+        #[arg(long, default_value_t = false, conflicts_with = "dry_run")]
+        guarded: bool,
+    },
+    /// Trust the ssh_keys in the configuration, and the keys of the github_usernames, for root.
     TrustedKeys {},
+    /// List the keys trusted-keys would trust, with their fingerprints and where each came from.
+    ListKeys {},
     EtcIssue {},
-    Install {},
+    /// Install HolOS onto the disk holding the install_partition, wiping it.
+    Install {
+        /// Show what would be done, without doing it.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Carry on without asking for confirmation.
+        #[arg(long, default_value_t = false, conflicts_with = "dry_run")]
+        yes: bool,
+        /// Wipe the disk even if it has partitions or filesystems on it already.
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Write a new release into the system slot that isn't running, and boot it next on trial.
+    Upgrade {
+        /// The installation image (an ISO file, or a device it's been written to).
+        image: String,
+        /// The image's expected SHA-256 checksum, checked before anything is written.
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+    /// Rebuild the initial ramdisk of the running system, with only the kernel modules needed to
+    /// reach its root filesystem.
+    Initramfs {
+        /// Where to write it, rather than where the bootloader loads it from.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Confirm that the running system booted successfully, ending its trial.
+    ConfirmBoot {},
     DetectModel {},
+    /// List the disks and partitions on the machine, and what's on them.
+    BlockDevices {
+        /// Print the list as JSON rather than as a table.
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
 }
 
 /// The structure we get keys from github in
@@ -38,14 +99,58 @@ pub struct GithubKeys {
     pub created_at: String,
 }
 
+/// The keys to trust: the ones in the configuration, then each GitHub user's in turn.
+async fn trusted_keys(config: &HolosConfig) -> Result<KeySet, Box<dyn std::error::Error>> {
+    let mut keys: Vec<(Source, String)> = config
+        .security
+        .ssh_keys
+        .iter()
+        .map(|key| (Source::Config, key.clone()))
+        .collect();
+    let client = reqwest::Client::new();
+    for user in &config.security.github_usernames {
+        info!("Downloading keys for github user: {}", user);
+        let uri = format!("https://api.github.com/users/{}/keys", user);
+        info!("URI: {}", uri);
+        let res = client
+            .get(uri)
+            .header("User-Agent", "HolOS Configurator")
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<GithubKeys>>()
+            .await?;
+        for key in res {
+            let source = Source::GitHub {
+                user: user.clone(),
+                id: key.id.into(),
+            };
+            keys.push((source, key.key));
+        }
+    }
+    Ok(KeySet::merge(keys))
+}
+
 // We allow the user to tell us which configuration file to use through things like boot-time
 // parameters. We also try to find the right configuration file for specific models of machine that
 // we're familiar with (such as holoports). If we can't find a suitable one, we fall back to
 // something that's likely to work.
 const DEFAULT_CONFIG_FILE_PATH: &str = "/etc/holos/configs/default.yaml";
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // As the initial ramdisk's /init, there are no arguments and no configuration to load. This
+    // comes before the async runtime is started, so that no worker threads are left running
+    // across switch_root.
+    if init::invoked_as_init() {
+        init::main();
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     // Set up logging. When running locally, use env_logger, but when running in production, send
@@ -59,12 +164,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         false => env_logger::init(),
     }
 
-    let cmdline_path = match env::var("CMDLINE_PATH") {
-        Ok(v) => v,
-        Err(_) => "/proc/cmdline".to_string(),
-    };
-    info!("Using {} as kernel command line source", cmdline_path);
-    let overrides = CmdLine::from_file(&cmdline_path)?;
+    // Everything we read from or write to the system goes through here, so that it can all be
+    // pointed at a fixture tree (or an installed system) with HOLOS_ROOT.
+    let system = SystemRoot::from_env();
+    if system != SystemRoot::default() {
+        info!("Using {} as the system root", system.root().display());
+    }
+
+    // The kernel command line is read from the system root too, like everything else, so that
+    // what's read here is what the installer and the boot checks see.
+    let overrides = CmdLine::from_file(&system.cmdline().display().to_string())?;
 
     let mut config_file_path = DEFAULT_CONFIG_FILE_PATH.to_string();
     // This is the case where the user has told us the path to an explicit configuration file,
@@ -75,11 +184,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         config_file_path = model_config.to_owned();
     }
     info!("Configuration file {} selected.", config_file_path);
-    let path = Path::new(&config_file_path);
+    let path = system.path(&config_file_path);
     let file = File::open(path)?;
     let reader = BufReader::new(file);
 
     let mut config: HolosConfig = serde_yaml::from_reader(reader)?; // Use from_reader
+    config.validate()?;
 
     if !overrides.github_usernames.is_empty() {
         config.security.github_usernames = overrides.github_usernames.clone();
@@ -87,42 +197,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     match &cli.command {
         Commands::DetectModel {} => {
-            println!("Model: {}", Model::detect_model().unwrap());
+            println!("Model: {}", Model::detect_model(&system).unwrap());
         }
-        Commands::TrustedKeys {} => {
-            // Retrieve keys from github, if desired.
-            let mut keys = String::new();
-            for user in config.security.github_usernames {
-                info!("Downloading keys for github user: {}", user);
-                let uri = format!("https://api.github.com/users/{}/keys", user);
-                info!("URI: {}", uri);
-                let client = reqwest::Client::new();
-                let res = client
-                    .get(uri)
-                    .header("User-Agent", "HolOS Configurator")
-                    .send()
-                    .await?
-                    .json::<Vec<GithubKeys>>()
-                    .await?;
-
-                for key in res {
-                    keys += format!("{} {}_{}\n", key.key, user, key.id).as_str();
-                }
+        Commands::BlockDevices { json } => {
+            let devices = blockdev::inventory(&system)?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&devices)?),
+                false => print!("{}", blockdev::table(&devices)),
             }
-            fs::create_dir_all("/root/.ssh")?;
-            fs::write("/root/.ssh/authorized_keys", keys)?;
-            let metadata = fs::metadata("/root/.ssh")?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o700);
-            fs::set_permissions("/root/.ssh", permissions)?;
-            let metadata = fs::metadata("/root/.ssh/authorized_keys")?;
-            let mut permissions = metadata.permissions();
-            permissions.set_mode(0o600);
-            fs::set_permissions("/root/.ssh/authorized_keys", permissions)?;
+        }
+        Commands::TrustedKeys {} => {
+            // If any of the keys can't be fetched, the keys already trusted are left alone,
+            // rather than locking out whoever they belong to.
+            trusted_keys(&config).await?.write(&system)?;
+        }
+        Commands::ListKeys {} => {
+            print!("{}", trusted_keys(&config).await?);
         }
         Commands::EtcIssue {} => {
             let mut issue: String;
-            let version = fs::read_to_string("/etc/holos-version")?;
+            let version = fs::read_to_string(system.path("/etc/holos-version"))?;
             issue = format!("\n\nHolOS Version: {}\n", version);
             issue += format!("Live boot: {}\n", overrides.live_flag).as_str();
             issue += format!(
@@ -131,112 +225,180 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             )
             .as_str();
             issue += "IP address configuration:\n";
+            // Note which configured interface each name corresponds to, so that it's clear which
+            // of the selectors in the configuration file matched what.
+            let configured: Vec<(String, String)> = if config.network.automatic() {
+                automatic_interfaces(&system)?
+                    .into_iter()
+                    .map(|name| (name, "automatic".to_string()))
+                    .collect()
+            } else {
+                config
+                    .network
+                    .interfaces
+                    .iter()
+                    .filter_map(|iface| {
+                        interface_name(&iface.identifier, &system.sysfs())
+                            .ok()
+                            .map(|name| (name, iface.identifier.to_string()))
+                    })
+                    .collect()
+            };
             let network_interfaces = list_afinet_netifas();
             match network_interfaces {
                 Ok(nics) => {
                     for (name, ip) in nics {
                         if name != "lo" && name != "virbr0" && name != "docker0" {
-                            issue += format!("    {} => {}\n", name, ip).as_str();
+                            match configured.iter().find(|(n, _)| *n == name) {
+                                Some((_, identifier)) => {
+                                    issue += format!("    {} ({}) => {}\n", name, identifier, ip)
+                                        .as_str();
+                                }
+                                None => {
+                                    issue += format!("    {} => {}\n", name, ip).as_str();
+                                }
+                            }
                         }
                     }
                 }
                 Err(_) => {
-                    issue += format!("Unable to retrieve IP addresses.\n").as_str();
+                    issue += "Unable to retrieve IP addresses.\n";
                 }
             }
             issue += "\n\n";
-            issue += format!("Hardware Model: {}", Model::detect_model().unwrap()).as_str();
+            issue += format!("Hardware Model: {}", Model::detect_model(&system).unwrap()).as_str();
             issue += "\n";
 
-            fs::write("/etc/issue", issue)?;
+            fs::write(system.path("/etc/issue"), issue)?;
         }
-        Commands::Install {} => {
-            do_install(&config)?;
+        Commands::Install {
+            dry_run,
+            yes,
+            force,
+        } => {
+            do_install(&config, &config_file_path, &system, *dry_run, *yes, *force)?;
         }
-        Commands::Configure {} => {
-            let interfaces_path = match env::var("INTERFACES_PATH") {
-                Ok(v) => v,
-                Err(_) => "/etc/network/interfaces.d".to_string(),
-            };
-            info!(
-                "Using {} as network interface definition path",
-                interfaces_path
-            );
-            // Create network interface configurations
-            for iface in config.network.interfaces {
-                info!("Configuring interface: {:?}", iface.identifier);
-                if !iface.static_addresses.is_empty() {
-                    // Adding support for static IPs isn't hard, we're just time constrained. The process
-                    // would still be the same -- writing the configuration to the interfaces files.
-                    eprintln!("Static addresses have not yet been implemented.");
-                    info!("Static addresses have not yet been implemented.");
-                    // Fall through and use DHCP anyway
+        Commands::Upgrade { image, sha256 } => {
+            upgrade::do_upgrade(
+                &config,
+                &config_file_path,
+                &system,
+                image,
+                sha256.as_deref(),
+            )?;
+        }
+        Commands::Initramfs { output } => {
+            let report = initramfs::rebuild(&system, output.as_deref())?;
+            println!("Built the initial ramdisk: {}", report);
+        }
+        Commands::ConfirmBoot {} => {
+            upgrade::confirm_boot(&system)?;
+        }
+        Commands::Configure {
+            dry_run,
+            backend,
+            guarded,
+        } => {
+            let kind = backend.unwrap_or_else(|| BackendKind::detect(&system));
+            let backend: Box<dyn Backend> = match kind {
+                BackendKind::Netifrc => Box::new(Netifrc),
+                BackendKind::Ifupdown => {
+                    let interfaces_path = match env::var("INTERFACES_PATH") {
+                        Ok(v) => v,
+                        Err(_) => "/etc/network/interfaces.d".to_string(),
+                    };
+                    info!(
+                        "Using {} as network interface definition path",
+                        interfaces_path
+                    );
+                    Box::new(Ifupdown {
+                        interfaces_dir: interfaces_path,
+                    })
                 }
-                let interface_name = match iface.identifier {
-                    holos_config::DeviceIdentifier::PciAddress { address } => {
-                        // Given that all of the network drivers have been loaded at this point, we can
-                        // take a look at the PCI device specified by the address in the configuration
-                        // file, and map it back to an interface name. This will work regardless of driver
-                        // load order, or which network interface device naming convention is employed.
-                        let net_path = format!("/sys/bus/pci/devices/{}/net", address);
-                        info!("Looking for interface name for {} in {}", address, net_path);
-                        let mut entries = fs::read_dir(&net_path)?;
-                        // There is only ever one entry in this directory.
-                        let mut ret = String::new();
-                        if let Some(entry) = entries.next() {
-                            let entry = entry?;
-                            info!(
-                                "Using interface name {} for address {}",
-                                entry.file_name().display(),
-                                address
-                            );
-                            ret = format!("{}", entry.file_name().display());
-                        }
-                        Some(ret)
-                    }
-                    holos_config::DeviceIdentifier::Virtio { address } => {
-                        // Not currently supported. Assume the name `eth0`
-                        info!(
-                            "Virtio devices not currently fully implemented for device {}",
-                            address
-                        );
-                        None
-                    }
-                    holos_config::DeviceIdentifier::Usb { address } => {
-                        // Not currently supported. Assume the name `eth0`
-                        info!(
-                            "USB devices not currently fully implemented for device {}",
-                            address
-                        );
-                        None
+                BackendKind::Networkd => Box::new(Networkd),
+            };
+            info!("Configuring the network with {}", backend.name());
+            // Settings the chosen backend can't apply are turned away before anything changes.
+            backend.validate(&config.network)?;
+
+            // The persistent data partition comes first, so that it's in place before anything
+            // started after us writes data. A problem with it is reported, but doesn't stop the
+            // network being configured, or the node couldn't be reached to put it right.
+            let mut storage_failure = None;
+            if overrides.live_flag {
+                info!("Live boot; leaving the persistent data partition alone");
+            } else {
+                let result = storage::plan(&config.storage, &system).and_then(|plan| {
+                    match plan {
+                        Some(plan) if *dry_run => print!("{}", storage::describe(&plan, &system)?),
+                        Some(plan) => storage::apply(&plan, &system, &Live)?,
+                        None => info!("No persist_partition is configured"),
                     }
-                };
-                if let Some(interface) = interface_name {
-                    // Magic OpenRC ju-ju. Try and create the symlink. If it fails, continue
-                    // anyway.
-                    symlink(
-                        "/etc/init.d/net.lo",
-                        format!("/etc/init.d/net.{}", interface),
-                    )
-                    .ok();
-                    // TODO: This is not necessary for each boot when the OS is installed. We
-                    // should first check for a `config_XXX` line for our interface first, and
-                    // replace it if present, or append it if not. The code below is fine. It just
-                    // appends a duplicate line each boot.
-                    let netifrc_stanza = format!(
-                        "config_{}=\"dhcp\"\nudhcpc_{}=\"-b -t 7\"\n",
-                        interface, interface
-                    );
-                    let mut file = OpenOptions::new()
-                        .append(true)
-                        .create(false)
-                        .open("/etc/conf.d/net")?;
-                    file.write_all(netifrc_stanza.as_bytes())?;
-                } else {
-                    info!("Unable to determine interface name for interface. Skipping.");
-                    continue;
+                    Ok(())
+                });
+                if let Err(e) = result {
+                    error!("Unable to set up the persistent data partition: {}", e);
+                    storage_failure = Some(e);
                 }
             }
+
+            // Work out which interfaces to configure on this particular machine, and in which
+            // order.
+            let plan = network_plan(&config.network, &system.sysfs())?;
+            for failure in &plan.failures {
+                // Carry on with the rest of the interfaces, but make sure the failure is visible
+                // rather than quietly leaving the interface unconfigured.
+                error!("Unable to configure interface {}", failure);
+            }
+            if plan.automatic {
+                let names: Vec<&str> = plan.interfaces.iter().map(|i| i.name.as_str()).collect();
+                info!(
+                    "No interfaces configured; using DHCP on wired interfaces: {}",
+                    names.join(", ")
+                );
+            }
+            for iface in &plan.interfaces {
+                info!("Configuring interface: {}", iface.name);
+            }
+            let rendered = backend.render(&plan, &config.network, &system)?;
+
+            if *dry_run {
+                print!("{}", rendered.diff(&system)?);
+                return storage_failure.map_or(Ok(()), |e| Err(e.into()));
+            }
+            if *guarded {
+                if system != SystemRoot::default() {
+                    return Err("Guarded configuration only works on the running system".into());
+                }
+                // Reconfiguring the network is likely to drop the session we were started from.
+                // Keep going regardless, or nothing would be left to roll back a bad
+                // configuration.
+                let _hangup = signal(SignalKind::hangup())?;
+                guard::guarded_apply(
+                    &rendered,
+                    &plan,
+                    backend.as_ref(),
+                    &config.network.verify,
+                    &system,
+                    &guard::Live,
+                )?;
+            } else {
+                rendered.apply(&system)?;
+            }
+            record_automatic(&plan, &system)?;
+
+            if !plan.failures.is_empty() {
+                return Err(format!(
+                    "Unable to configure some interfaces:\n    {}",
+                    plan.failures.join("\n    ")
+                )
+                .into());
+            }
+            if let Some(e) = storage_failure {
+                return Err(
+                    format!("Unable to set up the persistent data partition: {}", e).into(),
+                );
+            }
         }
     }
 
@@ -0,0 +1,268 @@
+/// Some of the configuration files we generate are shared with the administrator, who may well
+/// have added their own settings by hand. Rather than overwriting or blindly appending to these
+/// files, holos-config keeps its content in named blocks surrounded by marker comments, and only
+/// ever touches the content between those markers.
+///
+/// ```text
+/// # BEGIN holos-config eth0
+/// config_eth0="dhcp"
+/// # END holos-config eth0
+/// ```
+use anyhow::{Error, anyhow};
+use std::fmt::Write;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+const BEGIN_MARKER: &str = "# BEGIN holos-config ";
+const END_MARKER: &str = "# END holos-config ";
+
+#[derive(Debug, PartialEq)]
+enum Section {
+    /// Lines that aren't ours, and which are preserved verbatim.
+    Unmanaged(Vec<String>),
+    /// A named block of content owned by holos-config.
+    Managed { name: String, body: Vec<String> },
+}
+
+/// A configuration file split into hand-written content and holos-config managed blocks.
+#[derive(Debug, Default)]
+pub struct ManagedFile {
+    sections: Vec<Section>,
+}
+
+impl ManagedFile {
+    /// Splits the existing content of a file into managed and unmanaged sections. Unterminated or
+    /// mismatched blocks are an error, since we can't safely tell where our content ends.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut sections: Vec<Section> = vec![];
+        let mut unmanaged: Vec<String> = vec![];
+        let mut current: Option<(String, Vec<String>)> = None;
+
+        for line in content.lines() {
+            match current.as_mut() {
+                None => {
+                    if let Some(name) = line.strip_prefix(BEGIN_MARKER) {
+                        if !unmanaged.is_empty() {
+                            sections.push(Section::Unmanaged(std::mem::take(&mut unmanaged)));
+                        }
+                        current = Some((name.trim().to_string(), vec![]));
+                    } else if line.starts_with(END_MARKER) {
+                        return Err(anyhow!("Unexpected block end marker: {}", line));
+                    } else {
+                        unmanaged.push(line.to_string());
+                    }
+                }
+                Some((name, body)) => {
+                    if let Some(end) = line.strip_prefix(END_MARKER) {
+                        if end.trim() != name {
+                            return Err(anyhow!(
+                                "Block {} is terminated by a marker for {}",
+                                name,
+                                end.trim()
+                            ));
+                        }
+                        let (name, body) = current.take().unwrap();
+                        sections.push(Section::Managed { name, body });
+                    } else if line.starts_with(BEGIN_MARKER) {
+                        return Err(anyhow!("Block {} is never terminated", name));
+                    } else {
+                        body.push(line.to_string());
+                    }
+                }
+            }
+        }
+
+        if let Some((name, _)) = current {
+            return Err(anyhow!("Block {} is never terminated", name));
+        }
+        if !unmanaged.is_empty() {
+            sections.push(Section::Unmanaged(unmanaged));
+        }
+
+        Ok(ManagedFile { sections })
+    }
+
+    /// Names of all of the managed blocks in the file, in order.
+    pub fn block_names(&self) -> Vec<String> {
+        self.sections
+            .iter()
+            .filter_map(|s| match s {
+                Section::Managed { name, .. } => Some(name.clone()),
+                Section::Unmanaged(_) => None,
+            })
+            .collect()
+    }
+
+    /// Replaces the content of the named block in place, or appends a new block to the end of the
+    /// file if there isn't one by that name already.
+    pub fn set_block(&mut self, name: &str, content: &str) {
+        let new_body: Vec<String> = content.lines().map(|l| l.to_string()).collect();
+        for section in self.sections.iter_mut() {
+            if let Section::Managed { name: n, body } = section
+                && n == name
+            {
+                *body = new_body;
+                return;
+            }
+        }
+        self.sections.push(Section::Managed {
+            name: name.to_string(),
+            body: new_body,
+        });
+    }
+
+    /// Drops any managed blocks for which `keep` returns false. Unmanaged content is untouched.
+    pub fn retain_blocks<F: Fn(&str) -> bool>(&mut self, keep: F) {
+        self.sections.retain(|s| match s {
+            Section::Managed { name, .. } => keep(name),
+            Section::Unmanaged(_) => true,
+        });
+    }
+
+    /// Removes unmanaged lines matching `pred`. This is only intended for migrating content that
+    /// older versions of holos-config wrote without markers.
+    pub fn remove_unmanaged_lines<F: Fn(&str) -> bool>(&mut self, pred: F) {
+        for section in self.sections.iter_mut() {
+            if let Section::Unmanaged(lines) = section {
+                lines.retain(|l| !pred(l));
+            }
+        }
+        self.sections
+            .retain(|s| !matches!(s, Section::Unmanaged(lines) if lines.is_empty()));
+    }
+
+    /// Produces the full content of the file.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for section in &self.sections {
+            match section {
+                Section::Unmanaged(lines) => {
+                    for line in lines {
+                        out += line;
+                        out += "\n";
+                    }
+                }
+                Section::Managed { name, body } => {
+                    writeln!(out, "{}{}", BEGIN_MARKER, name).unwrap();
+                    for line in body {
+                        out += line;
+                        out += "\n";
+                    }
+                    writeln!(out, "{}{}", END_MARKER, name).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Reads a configuration file we're about to update, treating a missing file as empty.
+pub fn read_or_empty(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(v) => Ok(v),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow!("Unable to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Produces a simple line-based diff between two versions of a file, with removed lines prefixed
+/// by `-`, added lines by `+` and unchanged lines by a space. Only used to show the user what
+/// would change in dry-run mode, so it favours simplicity over compactness.
+pub fn diff(old: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence table, built from the end of both files.
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            writeln!(out, " {}", old[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            writeln!(out, "+{}", new[j]).unwrap();
+            j += 1;
+        } else {
+            writeln!(out, "-{}", old[i]).unwrap();
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXISTING: &str = "\
+# Hand-written settings
+modules=\"iproute2\"
+# BEGIN holos-config eth0
+config_eth0=\"dhcp\"
+# END holos-config eth0
+config_wlan0=\"dhcp\"
+# BEGIN holos-config eth1
+config_eth1=\"dhcp\"
+# END holos-config eth1
+";
+
+    #[test]
+    /// Parsing and rendering without changes should be lossless.
+    fn round_trip() {
+        let f = ManagedFile::parse(EXISTING).unwrap();
+        assert_eq!(f.block_names(), vec!["eth0", "eth1"]);
+        assert_eq!(f.render(), EXISTING);
+    }
+
+    #[test]
+    /// Blocks are replaced in place, stale blocks are dropped, new blocks are appended, and
+    /// nothing else moves.
+    fn update_blocks() {
+        let mut f = ManagedFile::parse(EXISTING).unwrap();
+        f.set_block("eth0", "config_eth0=\"10.0.0.2/24\"\n");
+        f.set_block("eth2", "config_eth2=\"dhcp\"\n");
+        f.retain_blocks(|name| name != "eth1");
+        assert_eq!(
+            f.render(),
+            "\
+# Hand-written settings
+modules=\"iproute2\"
+# BEGIN holos-config eth0
+config_eth0=\"10.0.0.2/24\"
+# END holos-config eth0
+config_wlan0=\"dhcp\"
+# BEGIN holos-config eth2
+config_eth2=\"dhcp\"
+# END holos-config eth2
+"
+        );
+    }
+
+    #[test]
+    /// We refuse to edit a file if we can't tell where our content ends.
+    fn unterminated_block() {
+        assert!(ManagedFile::parse("# BEGIN holos-config eth0\nconfig_eth0=\"dhcp\"\n").is_err());
+        assert!(
+            ManagedFile::parse("# BEGIN holos-config eth0\n# END holos-config eth1\n").is_err()
+        );
+    }
+
+    #[test]
+    fn simple_diff() {
+        assert_eq!(diff("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(diff("", "a\n"), "+a\n");
+    }
+}
@@ -1,5 +1,6 @@
 /// This module uses some basic/crude heuristics to try and determine the model of machine we're
 /// running on, to provide a potential default configuration file.
+use crate::system::SystemRoot;
 use anyhow::Error;
 use bitmask_enum::bitmask;
 use glob::glob;
@@ -80,24 +81,27 @@ impl Model {
     /// hardware for something that looks approximately like something. Nothing we do is *really*
     /// tied heavily to the hardware, but a few hints can help us to provide the user with some
     /// good defaults.
-    pub fn detect_model() -> Result<Self, Error> {
+    ///
+    /// All of the hardware is looked up through `system`, so that detection can be run against a
+    /// fixture sysfs tree.
+    pub fn detect_model(system: &SystemRoot) -> Result<Self, Error> {
         let mut found_flags: ModelHeuristicFlags = ModelHeuristicFlags::Empty;
 
         // Scan the USB bus for largely-unique devices.
-        for dev in glob(Self::USB_MODALIAS_GLOB)? {
+        for dev in glob(&system.glob_pattern(Self::USB_MODALIAS_GLOB))? {
             let dev = dev?;
             if let Some(modalias) = Self::string_attr(format!("{}", dev.display())) {
                 if modalias.starts_with("usb:v27C6p533Cd") {
                     // This exists in the Dell XPS-13 and gives me a device to test this code path
                     // against on my laptop. Otherwise, entirely useless. :)
-                    found_flags = found_flags | ModelHeuristicFlags::HasDellXpsFPR;
+                    found_flags |= ModelHeuristicFlags::HasDellXpsFPR;
                 } else if modalias.starts_with("usb:v1A86p7523d") {
                     // This is the USB-attached LED present in holoports
                     info!(
                         "Found USB device matching holoport LED at {}",
                         dev.display()
                     );
-                    found_flags = found_flags | ModelHeuristicFlags::HasHoloportLED;
+                    found_flags |= ModelHeuristicFlags::HasHoloportLED;
                 }
             }
         }
@@ -106,7 +110,7 @@ impl Model {
         // differentiated by a larger rotational drive and an additional SSD. Previous code used
         // specific model names to identify drives, but that doesn't necessarily work when drives
         // are replaced and largely doesn't matter to the code.
-        for dev in glob(Self::BLOCKDEV_HARDWARE_GLOB)? {
+        for dev in glob(&system.glob_pattern(Self::BLOCKDEV_HARDWARE_GLOB))? {
             let mut dev = dev?;
             let is_rotational: bool;
             let is_removable: bool;
@@ -120,11 +124,7 @@ impl Model {
                 Self::integer_attr(format!("{}/queue/rotational", dev.display()))
             {
                 info!("Rotational: {}", rotational);
-                if rotational == 1 {
-                    is_rotational = true;
-                } else {
-                    is_rotational = false;
-                }
+                is_rotational = rotational == 1;
             } else {
                 // semi-sane default
                 is_rotational = false;
@@ -133,11 +133,7 @@ impl Model {
             // Is it a removable drive?
             if let Some(removable) = Self::integer_attr(format!("{}/removable", dev.display())) {
                 info!("Removable: {}", removable);
-                if removable == 1 {
-                    is_removable = true;
-                } else {
-                    is_removable = false;
-                }
+                is_removable = removable == 1;
             } else {
                 // semi-sane default
                 is_removable = true;
@@ -160,26 +156,26 @@ impl Model {
             // model of SATA controller that matches the holoports, but probably not critical for
             // right now.
             if is_rotational && !is_removable {
-                found_flags = found_flags | ModelHeuristicFlags::HasHoloportHDD;
+                found_flags |= ModelHeuristicFlags::HasHoloportHDD;
             } else if !is_rotational && !is_removable {
-                found_flags = found_flags | ModelHeuristicFlags::HasHoloportPlusSSD;
+                found_flags |= ModelHeuristicFlags::HasHoloportPlusSSD;
             }
 
             // This is to detect KVM VMs -- primary for internal testing.
             if let Some(device_name) = dev.file_name() {
                 let device_name = device_name.to_string_lossy();
                 if device_name.starts_with("vd") {
-                    found_flags = found_flags | ModelHeuristicFlags::HasVirtIODrive;
+                    found_flags |= ModelHeuristicFlags::HasVirtIODrive;
                 }
             }
         }
         // Some static identification heuristics.
-        if let Some(product_name) = Self::string_attr("/sys/class/dmi/id/product_name".to_string())
-        {
+        let product_name_path = system.path("/sys/class/dmi/id/product_name");
+        if let Some(product_name) = Self::string_attr(format!("{}", product_name_path.display())) {
             // Specific to the Dell I'm testing on. Other models with SMI/DMI data could be added
             // here too.
             if product_name == "XPS 13 9310" {
-                found_flags = found_flags | ModelHeuristicFlags::HasDellXpsSMI;
+                found_flags |= ModelHeuristicFlags::HasDellXpsSMI;
             }
         }
 
@@ -0,0 +1,1388 @@
+/// This module turns the network section of the configuration file into the configuration
+/// consumed by the network management on the running system. The configuration is first resolved
+/// against the hardware into a [`NetworkPlan`], which a [`Backend`] then renders into the files
+/// understood by netifrc, ifupdown or systemd-networkd. On a live system, [`guard`] can apply
+/// those files with a fallback to the previous configuration if the node loses connectivity.
+use crate::managed::read_or_empty;
+use crate::system::SystemRoot;
+use crate::{
+    BondMode, DeviceIdentifier, InterfaceAddress, IpSettings, KeyManagement, NetworkConfig, Route,
+    VirtualInterface, VirtualKind, WirelessConfig,
+};
+use anyhow::{Error, anyhow};
+use log::info;
+use std::fmt::Write as _;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub mod guard;
+pub mod ifupdown;
+pub mod netifrc;
+pub mod networkd;
+
+/// The resolver configuration file, which we only take over when nameservers are configured.
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+/// Directory holding the per-interface wpa_supplicant configuration for wireless interfaces.
+pub const WPA_SUPPLICANT_CONF_DIR: &str = "/etc/wpa_supplicant";
+
+/// Maps a device identifier from the configuration file back to the name the kernel has given the
+/// interface, by looking the device up in sysfs (rooted at `sysfs`). This works regardless of
+/// driver load order, or which network interface device naming convention is employed.
+pub fn interface_name(identifier: &DeviceIdentifier, sysfs: &Path) -> Result<String, Error> {
+    match identifier {
+        DeviceIdentifier::PciAddress { address } => {
+            single_interface(&sysfs.join("bus/pci/devices").join(address).join("net"))
+        }
+        DeviceIdentifier::Virtio { address } => {
+            // Virtio network devices hang off a virtio bus device, which in turn usually hangs off
+            // a PCI device. We accept the full `<pci address>/virtioN` form, the bare `virtioN`
+            // name on the virtio bus, or just the PCI address if there's only one virtio device
+            // under it.
+            let net_path = if address.contains('/') {
+                sysfs.join("bus/pci/devices").join(address).join("net")
+            } else if address.starts_with("virtio") {
+                sysfs.join("bus/virtio/devices").join(address).join("net")
+            } else {
+                let pci_path = sysfs.join("bus/pci/devices").join(address);
+                let mut virtio: Vec<String> = fs::read_dir(&pci_path)
+                    .map_err(|e| {
+                        anyhow!(
+                            "No PCI device {} for virtio ({}): {}",
+                            address,
+                            pci_path.display(),
+                            e
+                        )
+                    })?
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|name| name.starts_with("virtio"))
+                    .collect();
+                virtio.sort();
+                match virtio.len() {
+                    1 => pci_path.join(&virtio[0]).join("net"),
+                    0 => return Err(anyhow!("PCI device {} is not a virtio device", address)),
+                    _ => {
+                        return Err(anyhow!(
+                            "PCI device {} has several virtio devices ({}); specify one",
+                            address,
+                            virtio.join(", ")
+                        ));
+                    }
+                }
+            };
+            single_interface(&net_path)
+        }
+        DeviceIdentifier::Usb { id, serial, port } => {
+            usb_interface(id.as_deref(), serial.as_deref(), port.as_deref(), sysfs)
+        }
+        DeviceIdentifier::MacAddress { address } => {
+            let address = address.to_lowercase();
+            let matches: Vec<String> = physical_interfaces(sysfs)?
+                .into_iter()
+                .filter(|(name, _)| {
+                    fs::read_to_string(sysfs.join("class/net").join(name).join("address"))
+                        .is_ok_and(|a| a.trim().to_lowercase() == address)
+                })
+                .map(|(name, _)| name)
+                .collect();
+            match matches.len() {
+                1 => Ok(matches[0].clone()),
+                0 => Err(anyhow!("No network interface has MAC address {}", address)),
+                _ => Err(anyhow!(
+                    "Several network interfaces have MAC address {} ({})",
+                    address,
+                    matches.join(", ")
+                )),
+            }
+        }
+        // Virtual interfaces are created by us, with the name given in the configuration.
+        DeviceIdentifier::Virtual { name } => Ok(name.clone()),
+        DeviceIdentifier::Driver { names } => {
+            let interfaces = physical_interfaces(sysfs)?;
+            for driver in names {
+                for (name, _) in &interfaces {
+                    let bound =
+                        fs::read_link(sysfs.join("class/net").join(name).join("device/driver"))
+                            .ok()
+                            .and_then(|l| l.file_name().map(|n| n.to_string_lossy().to_string()));
+                    if bound.as_deref() == Some(driver.as_str()) {
+                        info!("Using interface name {} bound to driver {}", name, driver);
+                        return Ok(name.clone());
+                    }
+                }
+            }
+            Err(anyhow!(
+                "No network interface is bound to any of the drivers {}",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Lists the interfaces in `/sys/class/net` that are backed by a hardware device, along with the
+/// path of that device, sorted in bus order. Virtual interfaces (bridges, bonds, etc) are left
+/// out, since they often share the MAC address of one of their members.
+fn physical_interfaces(sysfs: &Path) -> Result<Vec<(String, PathBuf)>, Error> {
+    let class_path = sysfs.join("class/net");
+    let mut interfaces: Vec<(String, PathBuf)> = fs::read_dir(&class_path)
+        .map_err(|e| {
+            anyhow!(
+                "Unable to list interfaces in {}: {}",
+                class_path.display(),
+                e
+            )
+        })?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let device = fs::canonicalize(e.path().join("device")).ok()?;
+            Some((e.file_name().to_string_lossy().to_string(), device))
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(interfaces)
+}
+
+/// Lists the wired Ethernet interfaces backed by hardware, in bus order. Loopback, bridges, bonds
+/// and other virtual interfaces have no device behind them, and wireless interfaces are marked as
+/// such in sysfs, so all of those are left out.
+pub fn ethernet_interfaces(sysfs: &Path) -> Result<Vec<String>, Error> {
+    Ok(physical_interfaces(sysfs)?
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| {
+            let iface = sysfs.join("class/net").join(name);
+            // ARPHRD_ETHER. Wireless interfaces share it, but have extra entries of their own.
+            let ethernet = fs::read_to_string(iface.join("type")).map_or(true, |t| t.trim() == "1");
+            ethernet && !iface.join("wireless").exists() && !iface.join("phy80211").exists()
+        })
+        .collect())
+}
+
+/// Finds the network interface belonging to the one USB device matching all of the criteria
+/// given. USB devices are listed in sysfs by port path (eg, `1-1.2`), with each of their
+/// interfaces alongside them (eg, `1-1.2:1.0`), and it's the interfaces that have network devices.
+fn usb_interface(
+    id: Option<&str>,
+    serial: Option<&str>,
+    port: Option<&str>,
+    sysfs: &Path,
+) -> Result<String, Error> {
+    let devices_path = sysfs.join("bus/usb/devices");
+    let (vendor, product) = match id.map(|id| id.split_once(':')) {
+        Some(Some((v, p))) => (Some(v.to_lowercase()), Some(p.to_lowercase())),
+        Some(None) => {
+            return Err(anyhow!(
+                "USB id {} should be in vendor:product form",
+                id.unwrap()
+            ));
+        }
+        None => (None, None),
+    };
+
+    let mut entries: Vec<String> = fs::read_dir(&devices_path)
+        .map_err(|e| {
+            anyhow!(
+                "Unable to list USB devices in {}: {}",
+                devices_path.display(),
+                e
+            )
+        })?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    entries.sort();
+
+    let mut matches: Vec<(String, Vec<String>)> = vec![];
+    // Interfaces have a colon in their name, and root hubs are named usbN. Neither are devices
+    // we'd want to match against.
+    for dev in entries
+        .iter()
+        .filter(|e| !e.contains(':') && !e.starts_with("usb"))
+    {
+        let dev_path = devices_path.join(dev);
+        let attr = |name: &str| {
+            fs::read_to_string(dev_path.join(name))
+                .ok()
+                .map(|v| v.trim().to_string())
+        };
+        if port.is_some_and(|p| p != dev)
+            || vendor
+                .as_ref()
+                .is_some_and(|v| attr("idVendor").map(|a| a.to_lowercase()).as_ref() != Some(v))
+            || product
+                .as_ref()
+                .is_some_and(|p| attr("idProduct").map(|a| a.to_lowercase()).as_ref() != Some(p))
+            || serial.is_some_and(|s| attr("serial").as_deref() != Some(s))
+        {
+            continue;
+        }
+
+        let prefix = format!("{}:", dev);
+        let mut names: Vec<String> = vec![];
+        for iface in entries.iter().filter(|e| e.starts_with(&prefix)) {
+            if let Ok(net) = fs::read_dir(devices_path.join(iface).join("net")) {
+                names.extend(
+                    net.filter_map(|e| e.ok())
+                        .map(|e| e.file_name().to_string_lossy().to_string()),
+                );
+            }
+        }
+        // A match that isn't a network device (a USB stick of the same model, say) isn't
+        // interesting, but shouldn't count towards ambiguity either.
+        if !names.is_empty() {
+            names.sort();
+            matches.push((dev.clone(), names));
+        }
+    }
+
+    let description = format!(
+        "id={} serial={} port={}",
+        id.unwrap_or("*"),
+        serial.unwrap_or("*"),
+        port.unwrap_or("*")
+    );
+    match matches.len() {
+        0 => Err(anyhow!("No USB network device matches {}", description)),
+        1 => {
+            let (dev, mut names) = matches.remove(0);
+            if names.len() > 1 {
+                return Err(anyhow!(
+                    "USB device {} has several network interfaces ({}); unable to choose",
+                    dev,
+                    names.join(", ")
+                ));
+            }
+            info!("Using interface name {} for USB device {}", names[0], dev);
+            Ok(names.remove(0))
+        }
+        _ => Err(anyhow!(
+            "Several USB network devices match {}, on ports {}. Add a serial number or port to \
+             the configuration to choose one.",
+            description,
+            matches
+                .iter()
+                .map(|(dev, _)| dev.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Devices with network interfaces have a `net` directory in sysfs, containing a single entry
+/// named after the interface. Anything else is an error, since guessing could configure the wrong
+/// interface.
+fn single_interface(net_path: &Path) -> Result<String, Error> {
+    info!("Looking for interface name in {}", net_path.display());
+    let mut names: Vec<String> = fs::read_dir(net_path)
+        .map_err(|e| anyhow!("No network device found at {}: {}", net_path.display(), e))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    match names.len() {
+        1 => {
+            info!(
+                "Using interface name {} for {}",
+                names[0],
+                net_path.display()
+            );
+            Ok(names.remove(0))
+        }
+        0 => Err(anyhow!(
+            "No network interface found in {}",
+            net_path.display()
+        )),
+        _ => Err(anyhow!(
+            "Several network interfaces found in {} ({}); unable to choose",
+            net_path.display(),
+            names.join(", ")
+        )),
+    }
+}
+
+/// What sort of interface is being configured. Other interfaces are referred to by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InterfaceKind {
+    Physical,
+    Bond {
+        mode: BondMode,
+        members: Vec<String>,
+    },
+    Bridge {
+        members: Vec<String>,
+    },
+    Vlan {
+        parent: String,
+        id: u16,
+    },
+}
+
+/// A single interface, with its identifiers resolved to the names on the running system.
+#[derive(Debug, Clone)]
+pub struct InterfacePlan {
+    pub name: String,
+    pub kind: InterfaceKind,
+    /// How the interface gets its addresses, or `None` for interfaces that are only there as
+    /// members of others, and have no addresses of their own.
+    pub addressing: Option<IpSettings>,
+    pub wireless: Option<WirelessConfig>,
+}
+
+impl InterfacePlan {
+    /// Names of the interfaces that need to be up before this one can be brought up.
+    pub fn dependencies(&self) -> Vec<&str> {
+        match &self.kind {
+            InterfaceKind::Physical => vec![],
+            InterfaceKind::Bond { members, .. } | InterfaceKind::Bridge { members } => {
+                members.iter().map(|m| m.as_str()).collect()
+            }
+            InterfaceKind::Vlan { parent, .. } => vec![parent.as_str()],
+        }
+    }
+}
+
+/// The network configuration as it applies to this particular machine.
+#[derive(Debug, Default)]
+pub struct NetworkPlan {
+    /// Interfaces in the order they should be brought up, with every interface coming after all
+    /// of the interfaces it depends on.
+    pub interfaces: Vec<InterfacePlan>,
+    /// Interfaces that couldn't be configured, such as devices that couldn't be found, along with
+    /// the reason why.
+    pub failures: Vec<String>,
+    /// Set when no interfaces were configured, and the wired interfaces found on the machine were
+    /// chosen instead.
+    pub automatic: bool,
+}
+
+impl NetworkPlan {
+    /// Looks up an interface in the plan by name.
+    pub fn get(&self, name: &str) -> Option<&InterfacePlan> {
+        self.interfaces.iter().find(|i| i.name == name)
+    }
+
+    /// The bond or bridge that the named interface is a member of, if any.
+    pub fn master_of(&self, name: &str) -> Option<&InterfacePlan> {
+        self.interfaces.iter().find(|i| {
+            matches!(
+                i.kind,
+                InterfaceKind::Bond { .. } | InterfaceKind::Bridge { .. }
+            ) && i.dependencies().contains(&name)
+        })
+    }
+
+    /// VLANs carried by the named interface, as `(vlan id, vlan interface name)` pairs.
+    pub fn vlans_on(&self, parent: &str) -> Vec<(u16, &str)> {
+        self.interfaces
+            .iter()
+            .filter_map(|i| match &i.kind {
+                InterfaceKind::Vlan { parent: p, id } if p == parent => {
+                    Some((*id, i.name.as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Resolves the network configuration against the devices present on this machine (as found in
+/// sysfs, rooted at `sysfs`), producing the full set of interfaces to configure in dependency
+/// order.
+///
+/// Devices that can't be found are recorded as failures, along with any virtual interfaces that
+/// can't be built without them, so that the rest of the network can still be brought up.
+/// Contradictions in the configuration itself, such as a device being used twice, are errors.
+///
+/// With no interfaces configured at all, every wired Ethernet interface is brought up with DHCP,
+/// so that a machine booted with a minimal configuration is still reachable.
+pub fn plan(network: &NetworkConfig, sysfs: &Path) -> Result<NetworkPlan, Error> {
+    let mut plan = NetworkPlan::default();
+
+    if network.automatic() {
+        plan.automatic = true;
+        for name in ethernet_interfaces(sysfs)? {
+            plan.interfaces.push(InterfacePlan {
+                name,
+                kind: InterfaceKind::Physical,
+                addressing: Some(IpSettings::default()),
+                wireless: None,
+            });
+        }
+        if plan.interfaces.is_empty() {
+            plan.failures
+                .push("automatic: no wired network interfaces found".to_string());
+        }
+        return Ok(plan);
+    }
+
+    for iface in &network.interfaces {
+        let name = match interface_name(&iface.identifier, sysfs) {
+            Ok(name) => name,
+            Err(e) => {
+                plan.failures.push(format!("{}: {}", iface.identifier, e));
+                continue;
+            }
+        };
+        if plan.get(&name).is_some() {
+            return Err(anyhow!(
+                "{} resolves to {}, which is already configured",
+                iface.identifier,
+                name
+            ));
+        }
+        plan.interfaces.push(InterfacePlan {
+            name,
+            kind: InterfaceKind::Physical,
+            addressing: Some(iface.ip_settings()?),
+            wireless: iface.wireless.clone(),
+        });
+    }
+
+    // Work through the virtual interfaces so that each is only handled once everything it's
+    // built from has been. The configuration has already been checked for loops.
+    let mut remaining: Vec<&VirtualInterface> = network.virtual_interfaces.iter().collect();
+    let mut failed: Vec<String> = vec![];
+    while !remaining.is_empty() {
+        let pending: Vec<String> = remaining.iter().map(|v| v.name.clone()).collect();
+        let (ready, waiting): (Vec<&VirtualInterface>, Vec<&VirtualInterface>) =
+            remaining.into_iter().partition(|v| {
+                !v.kind.members().iter().any(
+                    |m| matches!(m, DeviceIdentifier::Virtual { name } if pending.contains(name)),
+                )
+            });
+        if ready.is_empty() {
+            return Err(anyhow!(
+                "Virtual interfaces {} depend on each other in a loop",
+                pending.join(", ")
+            ));
+        }
+        remaining = waiting;
+
+        for vif in ready {
+            let mut members: Vec<String> = vec![];
+            for member in vif.kind.members() {
+                let name = match member {
+                    DeviceIdentifier::Virtual { name } if failed.contains(name) => {
+                        plan.failures.push(format!(
+                            "{}: depends on {}, which couldn't be configured",
+                            vif.name, name
+                        ));
+                        continue;
+                    }
+                    _ => match interface_name(member, sysfs) {
+                        Ok(name) => name,
+                        Err(e) => {
+                            plan.failures
+                                .push(format!("{} (member of {}): {}", member, vif.name, e));
+                            continue;
+                        }
+                    },
+                };
+
+                if let Some(existing) = plan.get(&name) {
+                    // Bond and bridge members belong to their master, and nothing else. VLANs can
+                    // share their parent with other VLANs, and with the parent's own addresses.
+                    let claimed = plan.interfaces.iter().any(|i| {
+                        matches!(
+                            i.kind,
+                            InterfaceKind::Bond { .. } | InterfaceKind::Bridge { .. }
+                        ) && i.dependencies().contains(&name.as_str())
+                    });
+                    let exclusive = !matches!(vif.kind, VirtualKind::Vlan { .. });
+                    if exclusive && (claimed || existing.addressing.is_some()) {
+                        return Err(anyhow!(
+                            "{} can't be a member of {}, as it's already in use elsewhere",
+                            name,
+                            vif.name
+                        ));
+                    }
+                } else {
+                    plan.interfaces.push(InterfacePlan {
+                        name: name.clone(),
+                        kind: InterfaceKind::Physical,
+                        addressing: None,
+                        wireless: None,
+                    });
+                }
+                members.push(name);
+            }
+
+            let kind = match &vif.kind {
+                VirtualKind::Bond { mode, .. } => InterfaceKind::Bond {
+                    mode: *mode,
+                    members,
+                },
+                VirtualKind::Bridge { .. } => InterfaceKind::Bridge { members },
+                VirtualKind::Vlan { id, .. } => match members.pop() {
+                    Some(parent) => InterfaceKind::Vlan { parent, id: *id },
+                    None => {
+                        failed.push(vif.name.clone());
+                        continue;
+                    }
+                },
+            };
+            if matches!(&kind, InterfaceKind::Bond { members, .. } if members.is_empty()) {
+                plan.failures
+                    .push(format!("{}: none of its members could be found", vif.name));
+                failed.push(vif.name.clone());
+                continue;
+            }
+            if plan.get(&vif.name).is_some() {
+                return Err(anyhow!(
+                    "Virtual interface {} has the same name as an existing interface",
+                    vif.name
+                ));
+            }
+            plan.interfaces.push(InterfacePlan {
+                name: vif.name.clone(),
+                kind,
+                addressing: Some(vif.ip_settings()?),
+                wireless: None,
+            });
+        }
+    }
+
+    Ok(plan)
+}
+
+/// Where the wpa_supplicant configuration for a wireless interface lives.
+pub fn wpa_supplicant_conf_path(interface: &str) -> String {
+    format!(
+        "{}/wpa_supplicant-{}.conf",
+        WPA_SUPPLICANT_CONF_DIR, interface
+    )
+}
+
+/// Renders the wpa_supplicant configuration for a wireless network. This contains credentials, so
+/// must only ever be written out with restrictive permissions.
+pub fn wpa_supplicant_conf(wireless: &WirelessConfig) -> String {
+    let mut conf = "# Generated by holos-config. Changes made here will be overwritten.\n\
+                    ctrl_interface=/var/run/wpa_supplicant\n\
+                    network={\n"
+        .to_string();
+
+    // SSIDs are arbitrary bytes. Anything that can't be safely quoted is given in hex instead.
+    if wireless
+        .ssid
+        .chars()
+        .all(|c| (' '..='~').contains(&c) && c != '"')
+    {
+        conf += format!("\tssid=\"{}\"\n", wireless.ssid).as_str();
+    } else {
+        let hex: String = wireless
+            .ssid
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        conf += format!("\tssid={}\n", hex).as_str();
+    }
+    if wireless.hidden {
+        conf += "\tscan_ssid=1\n";
+    }
+
+    match wireless.key_management() {
+        KeyManagement::WpaPsk => conf += "\tkey_mgmt=WPA-PSK\n",
+        // Management frame protection is mandatory with WPA3.
+        KeyManagement::Sae => conf += "\tkey_mgmt=SAE\n\tieee80211w=2\n",
+        KeyManagement::None => conf += "\tkey_mgmt=NONE\n",
+    }
+    if let Some(psk) = &wireless.psk {
+        match wireless.key_management() {
+            KeyManagement::Sae => conf += format!("\tsae_password=\"{}\"\n", psk).as_str(),
+            _ => conf += format!("\tpsk=\"{}\"\n", psk).as_str(),
+        }
+    } else if let Some(hash) = &wireless.psk_hash {
+        conf += format!("\tpsk={}\n", hash.to_lowercase()).as_str();
+    }
+
+    conf += "}\n";
+    conf
+}
+
+/// Renders the resolver configuration for the configured nameservers, or `None` if there are no
+/// nameservers configured and DHCP should be left to look after it.
+pub fn resolv_conf(network: &NetworkConfig) -> Option<String> {
+    if network.nameservers.is_empty() {
+        return None;
+    }
+
+    let mut conf = "# Generated by holos-config from the nameservers in its configuration file.\n\
+                    # Changes made here will be overwritten.\n"
+        .to_string();
+    if !network.search_domains.is_empty() {
+        conf += format!("search {}\n", network.search_domains.join(" ")).as_str();
+    }
+    for ns in &network.nameservers {
+        conf += format!("nameserver {}\n", ns).as_str();
+    }
+    if !network.resolver_options.is_empty() {
+        conf += format!("options {}\n", network.resolver_options.join(" ")).as_str();
+    }
+    Some(conf)
+}
+
+/// Where the names of the interfaces chosen automatically are recorded, for the benefit of later
+/// boot steps such as writing `/etc/issue`.
+pub const AUTOMATIC_INTERFACES_PATH: &str = "/run/holos-config/automatic-interfaces";
+
+/// Records which interfaces were chosen automatically on `system`, one per line, or removes the
+/// record if the interfaces were configured explicitly. This is run state rather than
+/// configuration, so it's only written once the configuration has been applied.
+pub fn record_automatic(plan: &NetworkPlan, system: &SystemRoot) -> Result<(), Error> {
+    let path = system.path(AUTOMATIC_INTERFACES_PATH);
+    if plan.automatic {
+        let names: String = plan
+            .interfaces
+            .iter()
+            .map(|i| format!("{}\n", i.name))
+            .collect();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, names)
+            .map_err(|e| anyhow!("Unable to write {}: {}", AUTOMATIC_INTERFACES_PATH, e))?;
+    } else {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(anyhow!(
+                    "Unable to remove {}: {}",
+                    AUTOMATIC_INTERFACES_PATH,
+                    e
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The interfaces recorded by [`record_automatic`] on `system`, if any.
+pub fn automatic_interfaces(system: &SystemRoot) -> Result<Vec<String>, Error> {
+    Ok(read_or_empty(&system.path(AUTOMATIC_INTERFACES_PATH))?
+        .lines()
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// The distinct default gateways across a set of static addresses, in the order they're given.
+pub fn default_gateways(static_addresses: &[InterfaceAddress]) -> Vec<IpAddr> {
+    let mut gateways: Vec<IpAddr> = vec![];
+    for gateway in static_addresses.iter().filter_map(|a| a.gateway) {
+        if !gateways.contains(&gateway) {
+            gateways.push(gateway);
+        }
+    }
+    gateways
+}
+
+/// The arguments to `ip route add` for a static route, without the device.
+pub fn ip_route_args(route: &Route) -> String {
+    let mut args = route.destination.to_string();
+    if let Some(gateway) = route.gateway {
+        args += format!(" via {}", gateway).as_str();
+    }
+    if let Some(metric) = route.metric {
+        args += format!(" metric {}", metric).as_str();
+    }
+    args
+}
+
+/// A configuration file produced by a backend, with its absolute path on the target system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigFile {
+    pub path: String,
+    pub content: String,
+    /// Files containing credentials are only ever readable by root, and never shown in diffs.
+    pub secret: bool,
+}
+
+impl ConfigFile {
+    pub fn new(path: &str, content: String) -> Self {
+        ConfigFile {
+            path: path.to_string(),
+            content,
+            secret: false,
+        }
+    }
+
+    pub fn secret(path: &str, content: String) -> Self {
+        ConfigFile {
+            path: path.to_string(),
+            content,
+            secret: true,
+        }
+    }
+}
+
+/// Everything a backend wants changed on the target system to bring it in line with the plan.
+/// Paths are absolute, and are interpreted relative to the system passed to [`Rendered::apply`].
+#[derive(Debug, Default)]
+pub struct Rendered {
+    pub files: Vec<ConfigFile>,
+    /// Symbolic links to create if they don't already exist, as `(target, link)` pairs.
+    pub symlinks: Vec<(String, String)>,
+    /// Files left behind by earlier runs that are no longer wanted. Missing files are ignored.
+    pub remove: Vec<String>,
+}
+
+impl Rendered {
+    /// Describes the changes that [`Rendered::apply`] would make, as diffs for ordinary files and
+    /// a single line for everything else. Unchanged files are left out.
+    pub fn diff(&self, system: &SystemRoot) -> Result<String, Error> {
+        let mut out = String::new();
+        for file in &self.files {
+            let existing = read_or_empty(&system.path(&file.path))?;
+            if existing == file.content {
+                continue;
+            }
+            if file.secret {
+                writeln!(
+                    out,
+                    "Would write {} (contains credentials, not shown)",
+                    file.path
+                )?;
+            } else {
+                writeln!(out, "--- {}\n+++ {}", file.path, file.path)?;
+                out += crate::managed::diff(&existing, &file.content).as_str();
+            }
+        }
+        for (target, link) in &self.symlinks {
+            if fs::symlink_metadata(system.path(link)).is_err() {
+                writeln!(out, "Would link {} to {}", link, target)?;
+            }
+        }
+        for path in &self.remove {
+            if fs::symlink_metadata(system.path(path)).is_ok() {
+                writeln!(out, "Would remove {}", path)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Writes out the rendered configuration under `root`. Files are only rewritten if their
+    /// content has changed, so that running configure again is harmless.
+    pub fn apply(&self, system: &SystemRoot) -> Result<(), Error> {
+        for file in &self.files {
+            let path = system.path(&file.path);
+            if file.secret {
+                // Always rewritten, so that the permissions are put right too.
+                write_secret(&path, &file.content)
+                    .map_err(|e| anyhow!("Unable to write {}: {}", file.path, e))?;
+            } else if read_or_empty(&path)? != file.content {
+                info!("Writing {}", file.path);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(&path, &file.content)
+                    .map_err(|e| anyhow!("Unable to write {}: {}", file.path, e))?;
+            }
+        }
+        for (target, link) in &self.symlinks {
+            let path = system.path(link);
+            if fs::symlink_metadata(&path).is_err() {
+                info!("Linking {} to {}", link, target);
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                symlink(target, &path)
+                    .map_err(|e| anyhow!("Unable to link {} to {}: {}", link, target, e))?;
+            }
+        }
+        for path in &self.remove {
+            match fs::remove_file(system.path(path)) {
+                Ok(()) => info!("Removed {}", path),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(anyhow!("Unable to remove {}: {}", path, e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes out a file containing credentials, making sure that only root can ever read it. The
+/// permissions are restricted before any content is written, including for existing files.
+fn write_secret(path: &Path, content: &str) -> Result<(), std::io::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.set_len(0)?;
+    file.write_all(content.as_bytes())
+}
+
+/// Something that knows how to turn a network plan into configuration for a particular network
+/// management system.
+pub trait Backend {
+    /// A short name for the backend, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Checks `network` for settings the backend can't apply, before anything is changed.
+    fn validate(&self, _network: &NetworkConfig) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Renders the configuration for `plan`. The existing files on `system` are consulted, so that
+    /// configuration left behind by earlier runs can be updated or cleaned up.
+    fn render(
+        &self,
+        plan: &NetworkPlan,
+        network: &NetworkConfig,
+        system: &SystemRoot,
+    ) -> Result<Rendered, Error>;
+
+    /// The commands that make the running network pick up the configuration rendered for `plan`,
+    /// in the order they need running. Only used when reconfiguring a live system.
+    fn reload(&self, plan: &NetworkPlan) -> Vec<Vec<String>>;
+
+    /// The commands that take down the interfaces brought up for `plan`. A guarded
+    /// reconfiguration that's rolled back runs them before putting the previous files back, while
+    /// the configuration they were brought up with is still there.
+    fn stop(&self, plan: &NetworkPlan) -> Vec<Vec<String>>;
+
+    /// The commands that make the running network pick up the configuration already on `system`,
+    /// worked out from the files written for an earlier plan. A guarded reconfiguration records
+    /// them before changing anything, to reload the previous configuration with if it's rolled
+    /// back.
+    fn reload_existing(&self, system: &SystemRoot) -> Result<Vec<Vec<String>>, Error>;
+}
+
+/// The network management systems we can generate configuration for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    Netifrc,
+    Ifupdown,
+    Networkd,
+}
+
+impl BackendKind {
+    /// Works out which network management system is in use on the system at `root`. HolOS itself
+    /// uses netifrc, so that's what we assume if nothing else is recognised.
+    pub fn detect(system: &SystemRoot) -> BackendKind {
+        if system.path(netifrc::NET_LO_PATH).exists() {
+            BackendKind::Netifrc
+        } else if system.path("/run/systemd/system").is_dir() {
+            BackendKind::Networkd
+        } else if system.path("/etc/network/interfaces").exists() {
+            BackendKind::Ifupdown
+        } else {
+            BackendKind::Netifrc
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "netifrc" => Ok(BackendKind::Netifrc),
+            "ifupdown" => Ok(BackendKind::Ifupdown),
+            "networkd" | "systemd-networkd" => Ok(BackendKind::Networkd),
+            _ => Err(format!(
+                "Unknown network backend {}; expected netifrc, ifupdown or networkd",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{Ipv4Mode, NetworkInterface};
+    use tempfile::TempDir;
+
+    /// Builds a fake sysfs tree from a list of directories to create.
+    pub(crate) fn fake_sysfs(dirs: &[&str]) -> TempDir {
+        let root = TempDir::new().unwrap();
+        for dir in dirs {
+            fs::create_dir_all(root.path().join(dir)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    /// Virtio devices can be found by their full path, their virtio bus name, or a PCI address
+    /// with a single virtio device under it.
+    fn virtio_names() {
+        let sysfs = fake_sysfs(&[
+            "bus/pci/devices/0000:00:03.0/virtio2/net/eth0",
+            "bus/virtio/devices/virtio2/net/eth0",
+            "bus/pci/devices/0000:00:04.0/virtio3/net/eth1",
+            "bus/pci/devices/0000:00:04.0/virtio4/net/eth2",
+        ]);
+        let virtio = |address: &str| DeviceIdentifier::Virtio {
+            address: address.to_string(),
+        };
+
+        for address in ["0000:00:03.0/virtio2", "virtio2", "0000:00:03.0"] {
+            assert_eq!(
+                interface_name(&virtio(address), sysfs.path()).unwrap(),
+                "eth0"
+            );
+        }
+        assert_eq!(
+            interface_name(&virtio("0000:00:04.0/virtio4"), sysfs.path()).unwrap(),
+            "eth2"
+        );
+        // Ambiguous or missing devices are errors.
+        assert!(interface_name(&virtio("0000:00:04.0"), sysfs.path()).is_err());
+        assert!(interface_name(&virtio("0000:00:05.0/virtio5"), sysfs.path()).is_err());
+        assert!(interface_name(&virtio("virtio9"), sysfs.path()).is_err());
+    }
+
+    /// Adds a fake USB device with the given attributes to a fake sysfs tree.
+    fn fake_usb(sysfs: &Path, port: &str, id: &str, serial: Option<&str>, iface: Option<&str>) {
+        let dev = sysfs.join("bus/usb/devices").join(port);
+        fs::create_dir_all(&dev).unwrap();
+        let (vendor, product) = id.split_once(':').unwrap();
+        fs::write(dev.join("idVendor"), format!("{}\n", vendor)).unwrap();
+        fs::write(dev.join("idProduct"), format!("{}\n", product)).unwrap();
+        if let Some(serial) = serial {
+            fs::write(dev.join("serial"), format!("{}\n", serial)).unwrap();
+        }
+        let usb_iface = sysfs.join("bus/usb/devices").join(format!("{}:1.0", port));
+        fs::create_dir_all(&usb_iface).unwrap();
+        if let Some(name) = iface {
+            fs::create_dir_all(usb_iface.join("net").join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    /// USB devices can be matched by any combination of id, serial and port, as long as only one
+    /// network device matches.
+    fn usb_names() {
+        let sysfs = fake_sysfs(&["bus/usb/devices/usb1"]);
+        fake_usb(
+            sysfs.path(),
+            "1-1",
+            "0bda:8153",
+            Some("000001"),
+            Some("eth1"),
+        );
+        fake_usb(
+            sysfs.path(),
+            "1-2",
+            "0bda:8153",
+            Some("000002"),
+            Some("eth2"),
+        );
+        fake_usb(sysfs.path(), "1-3", "0BDA:8179", None, Some("wlan0"));
+        // A storage device of the same model as the wireless adapter.
+        fake_usb(sysfs.path(), "2-1", "0bda:8179", None, None);
+
+        let usb =
+            |id: Option<&str>, serial: Option<&str>, port: Option<&str>| DeviceIdentifier::Usb {
+                id: id.map(|s| s.to_string()),
+                serial: serial.map(|s| s.to_string()),
+                port: port.map(|s| s.to_string()),
+            };
+
+        let name = |i| interface_name(&i, sysfs.path());
+        assert_eq!(name(usb(Some("0bda:8179"), None, None)).unwrap(), "wlan0");
+        assert_eq!(name(usb(None, Some("000002"), None)).unwrap(), "eth2");
+        assert_eq!(name(usb(None, None, Some("1-1"))).unwrap(), "eth1");
+        assert_eq!(
+            name(usb(Some("0bda:8153"), Some("000001"), Some("1-1"))).unwrap(),
+            "eth1"
+        );
+        assert_eq!(
+            name(usb(Some("0BDA:8153"), None, Some("1-2"))).unwrap(),
+            "eth2"
+        );
+
+        let ambiguous = name(usb(Some("0bda:8153"), None, None)).unwrap_err();
+        assert!(ambiguous.to_string().contains("1-1, 1-2"));
+        assert!(name(usb(Some("0bda:8153"), Some("000001"), Some("1-2"))).is_err());
+        assert!(name(usb(None, None, Some("2-1"))).is_err());
+    }
+
+    /// Adds a fake physical interface to a fake sysfs tree, with its device and driver links.
+    fn fake_nic(sysfs: &Path, name: &str, device: &str, driver: &str, mac: &str) {
+        let dev = sysfs.join("devices/pci0000:00").join(device);
+        let drv = sysfs.join("bus/pci/drivers").join(driver);
+        fs::create_dir_all(&dev).unwrap();
+        fs::create_dir_all(&drv).unwrap();
+        if !dev.join("driver").exists() {
+            std::os::unix::fs::symlink(&drv, dev.join("driver")).unwrap();
+        }
+        let iface = sysfs.join("class/net").join(name);
+        fs::create_dir_all(&iface).unwrap();
+        std::os::unix::fs::symlink(&dev, iface.join("device")).unwrap();
+        fs::write(iface.join("address"), format!("{}\n", mac)).unwrap();
+    }
+
+    #[test]
+    /// With nothing configured, only wired interfaces backed by hardware are brought up.
+    fn automatic() {
+        let sysfs = fake_sysfs(&["class/net/lo", "class/net/br0/bridge", "class/net/bond0"]);
+        fake_nic(
+            sysfs.path(),
+            "enp2s0",
+            "0000:02:00.0",
+            "igb",
+            "00:11:22:33:44:02",
+        );
+        fake_nic(
+            sysfs.path(),
+            "enp1s0",
+            "0000:01:00.0",
+            "r8169",
+            "00:11:22:33:44:01",
+        );
+        fake_nic(
+            sysfs.path(),
+            "wlp3s0",
+            "0000:03:00.0",
+            "iwlwifi",
+            "00:11:22:33:44:03",
+        );
+        fs::create_dir_all(sysfs.path().join("class/net/wlp3s0/wireless")).unwrap();
+        fake_nic(
+            sysfs.path(),
+            "ib0",
+            "0000:04:00.0",
+            "mlx4_core",
+            "00:11:22:33:44:04",
+        );
+        fs::write(sysfs.path().join("class/net/ib0/type"), "32\n").unwrap();
+
+        let network: NetworkConfig = serde_yaml::from_str("nameservers:\ninterfaces:\n").unwrap();
+        let p = plan(&network, sysfs.path()).unwrap();
+        assert!(p.automatic);
+        let names: Vec<&str> = p.interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["enp1s0", "enp2s0"]);
+        assert!(
+            p.interfaces
+                .iter()
+                .all(|i| i.addressing.as_ref().unwrap().ipv4 == Ipv4Mode::Dhcp4)
+        );
+
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        record_automatic(&p, &system).unwrap();
+        assert_eq!(
+            automatic_interfaces(&system).unwrap(),
+            vec!["enp1s0", "enp2s0"]
+        );
+
+        // Nothing to choose from is a failure, rather than silently having no network.
+        let empty = fake_sysfs(&["class/net/lo"]);
+        let p = plan(&network, empty.path()).unwrap();
+        assert!(p.interfaces.is_empty());
+        assert_eq!(p.failures.len(), 1);
+
+        // Any configuration at all turns the automatic mode off.
+        let network: NetworkConfig = serde_yaml::from_str(BONDED_VLAN).unwrap();
+        let p = plan(&network, sysfs.path()).unwrap();
+        assert!(!p.automatic);
+        record_automatic(&p, &system).unwrap();
+        assert!(automatic_interfaces(&system).unwrap().is_empty());
+    }
+
+    #[test]
+    /// MAC addresses match physical interfaces only, ignoring case.
+    fn mac_names() {
+        let sysfs = fake_sysfs(&["class/net/br0"]);
+        fake_nic(
+            sysfs.path(),
+            "eth0",
+            "0000:00:19.0",
+            "e1000e",
+            "00:1a:2b:3c:4d:5e",
+        );
+        fake_nic(
+            sysfs.path(),
+            "eth1",
+            "0000:02:00.0",
+            "r8169",
+            "00:1a:2b:3c:4d:5f",
+        );
+        // A bridge sharing the MAC address of one of its members.
+        fs::write(
+            sysfs.path().join("class/net/br0/address"),
+            "00:1a:2b:3c:4d:5e\n",
+        )
+        .unwrap();
+
+        let mac = |address: &str| DeviceIdentifier::MacAddress {
+            address: address.to_string(),
+        };
+        assert_eq!(
+            interface_name(&mac("00:1A:2B:3C:4D:5E"), sysfs.path()).unwrap(),
+            "eth0"
+        );
+        assert_eq!(
+            interface_name(&mac("00:1a:2b:3c:4d:5f"), sysfs.path()).unwrap(),
+            "eth1"
+        );
+        assert!(interface_name(&mac("00:1a:2b:3c:4d:60"), sysfs.path()).is_err());
+    }
+
+    #[test]
+    /// Drivers are tried in order of preference, taking the first interface in bus order.
+    fn driver_names() {
+        let sysfs = fake_sysfs(&[]);
+        fake_nic(
+            sysfs.path(),
+            "eth5",
+            "0000:03:00.0",
+            "r8169",
+            "00:00:00:00:00:03",
+        );
+        fake_nic(
+            sysfs.path(),
+            "eth9",
+            "0000:02:00.0",
+            "r8169",
+            "00:00:00:00:00:02",
+        );
+        fake_nic(
+            sysfs.path(),
+            "eth0",
+            "0000:00:19.0",
+            "e1000e",
+            "00:00:00:00:00:01",
+        );
+
+        let driver = |names: &[&str]| DeviceIdentifier::Driver {
+            names: names.iter().map(|n| n.to_string()).collect(),
+        };
+        assert_eq!(
+            interface_name(&driver(&["r8169"]), sysfs.path()).unwrap(),
+            "eth9"
+        );
+        assert_eq!(
+            interface_name(&driver(&["igb", "e1000e", "r8169"]), sysfs.path()).unwrap(),
+            "eth0"
+        );
+        assert!(interface_name(&driver(&["igb"]), sysfs.path()).is_err());
+    }
+
+    #[test]
+    fn pci_names() {
+        let sysfs = fake_sysfs(&["bus/pci/devices/0000:01:00.0/net/enp1s0"]);
+        let pci = |address: &str| DeviceIdentifier::PciAddress {
+            address: address.to_string(),
+        };
+        assert_eq!(
+            interface_name(&pci("0000:01:00.0"), sysfs.path()).unwrap(),
+            "enp1s0"
+        );
+        assert!(interface_name(&pci("0000:02:00.0"), sysfs.path()).is_err());
+    }
+
+    /// Plans a single physical interface, named `name`, from its configuration.
+    pub(crate) fn single(name: &str, yaml: &str) -> NetworkPlan {
+        let iface: NetworkInterface = serde_yaml::from_str(yaml).unwrap();
+        NetworkPlan {
+            interfaces: vec![InterfacePlan {
+                name: name.to_string(),
+                kind: InterfaceKind::Physical,
+                addressing: Some(iface.ip_settings().unwrap()),
+                wireless: iface.wireless,
+            }],
+            failures: vec![],
+            automatic: false,
+        }
+    }
+
+    /// A statically addressed interface with IPv6 turned off, jumbo frames, and an extra route.
+    pub(crate) const ROUTED: &str = "
+identifier: !pci_address
+    address: '0000:01:00.0'
+static_addresses:
+    - address: 10.0.0.100/24
+      gateway: 10.0.0.1
+ipv6: disabled
+mtu: 9000
+routes:
+    - destination: 192.168.0.0/16
+      gateway: 10.0.0.254
+      metric: 100
+";
+
+    #[test]
+    fn route_args() {
+        let p = single("eth0", ROUTED);
+        let ip = p.interfaces[0].addressing.as_ref().unwrap();
+        assert_eq!(
+            ip_route_args(&ip.routes[0]),
+            "192.168.0.0/16 via 10.0.0.254 metric 100"
+        );
+    }
+
+    #[test]
+    /// Credentials and scanning options end up in the wpa_supplicant configuration.
+    fn wireless_conf() {
+        let p = single(
+            "wlan0",
+            "
+identifier: !usb
+    id: 0bda:8179
+static_addresses:
+wireless:
+    ssid: Home Network
+    psk: correct horse battery
+    hidden: true
+",
+        );
+        assert_eq!(
+            wpa_supplicant_conf(p.interfaces[0].wireless.as_ref().unwrap()),
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             ctrl_interface=/var/run/wpa_supplicant\n\
+             network={\n\
+             \tssid=\"Home Network\"\n\
+             \tscan_ssid=1\n\
+             \tkey_mgmt=WPA-PSK\n\
+             \tpsk=\"correct horse battery\"\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    /// Open networks, and SSIDs that can't be quoted.
+    fn wireless_open_hex_ssid() {
+        let w: WirelessConfig =
+            serde_yaml::from_str("ssid: 'Café \"Free\"'\nkey_management: none\n").unwrap();
+        assert_eq!(
+            wpa_supplicant_conf(&w),
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             ctrl_interface=/var/run/wpa_supplicant\n\
+             network={\n\
+             \tssid=436166c3a920224672656522\n\
+             \tkey_mgmt=NONE\n\
+             }\n"
+        );
+    }
+
+    pub(crate) const BONDED_VLAN: &str = "
+nameservers:
+interfaces:
+virtual_interfaces:
+    - name: vlan100
+      kind: !vlan
+        parent: !virtual
+            name: bond0
+        id: 100
+      static_addresses:
+        - address: 10.0.100.2/24
+    - name: bond0
+      kind: !bond
+        mode: active-backup
+        members:
+            - !pci_address
+                address: '0000:01:00.0'
+            - !pci_address
+                address: '0000:02:00.0'
+";
+
+    #[test]
+    /// Members come before their masters, whatever order they're listed in.
+    fn bonded_vlan() {
+        let (_, p) = bonded_vlan_plan();
+
+        let names: Vec<&str> = p.interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["eth0", "eth1", "bond0", "vlan100"]);
+        assert_eq!(p.master_of("eth1").unwrap().name, "bond0");
+        assert!(p.master_of("bond0").is_none());
+        assert_eq!(p.vlans_on("bond0"), vec![(100, "vlan100")]);
+    }
+
+    /// Plans [`BONDED_VLAN`] on a machine with both of the bond members present.
+    pub(crate) fn bonded_vlan_plan() -> (NetworkConfig, NetworkPlan) {
+        let sysfs = fake_sysfs(&[
+            "bus/pci/devices/0000:01:00.0/net/eth0",
+            "bus/pci/devices/0000:02:00.0/net/eth1",
+        ]);
+        let network: NetworkConfig = serde_yaml::from_str(BONDED_VLAN).unwrap();
+        network.validate().unwrap();
+        let p = plan(&network, sysfs.path()).unwrap();
+        assert!(p.failures.is_empty());
+        (network, p)
+    }
+
+    #[test]
+    /// A missing member is reported, but the bond carries on with the members it has. Without any
+    /// members, the bond and everything on top of it fails.
+    fn missing_members() {
+        let sysfs = fake_sysfs(&["bus/pci/devices/0000:02:00.0/net/eth1"]);
+        let network: NetworkConfig = serde_yaml::from_str(BONDED_VLAN).unwrap();
+        let p = plan(&network, sysfs.path()).unwrap();
+        let names: Vec<&str> = p.interfaces.iter().map(|i| i.name.as_str()).collect();
+        assert_eq!(names, vec!["eth1", "bond0", "vlan100"]);
+        assert_eq!(p.failures.len(), 1);
+
+        let sysfs = fake_sysfs(&["bus/pci/devices"]);
+        let p = plan(&network, sysfs.path()).unwrap();
+        assert!(p.interfaces.is_empty());
+        assert_eq!(p.failures.len(), 4);
+    }
+
+    #[test]
+    /// A device can't be a bond member and have its own addresses too.
+    fn member_in_use() {
+        let sysfs = fake_sysfs(&[
+            "bus/pci/devices/0000:01:00.0/net/eth0",
+            "bus/pci/devices/0000:02:00.0/net/eth1",
+        ]);
+        let mut network: NetworkConfig = serde_yaml::from_str(BONDED_VLAN).unwrap();
+        // Listed by a different identifier, so that validation doesn't spot it.
+        network.interfaces.push(
+            serde_yaml::from_str(
+                "identifier: !virtio {address: '0000:01:00.0'}\nstatic_addresses:\n",
+            )
+            .unwrap(),
+        );
+        fs::create_dir_all(
+            sysfs
+                .path()
+                .join("bus/pci/devices/0000:01:00.0/virtio1/net/eth0"),
+        )
+        .unwrap();
+        assert!(plan(&network, sysfs.path()).is_err());
+    }
+
+    #[test]
+    /// The resolver configuration is only taken over if there are nameservers configured.
+    fn static_dns() {
+        let mut network: NetworkConfig = serde_yaml::from_str(
+            "
+nameservers:
+    - 1.1.1.1
+    - 2606:4700:4700::1111
+search_domains:
+    - example.com
+    - example.net
+resolver_options:
+    - timeout:2
+interfaces:
+",
+        )
+        .unwrap();
+        assert_eq!(
+            resolv_conf(&network).unwrap(),
+            "# Generated by holos-config from the nameservers in its configuration file.\n\
+             # Changes made here will be overwritten.\n\
+             search example.com example.net\n\
+             nameserver 1.1.1.1\n\
+             nameserver 2606:4700:4700::1111\n\
+             options timeout:2\n"
+        );
+
+        network.nameservers.clear();
+        assert_eq!(resolv_conf(&network), None);
+    }
+}
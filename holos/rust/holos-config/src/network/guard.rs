@@ -0,0 +1,683 @@
+/// Guarded reconfiguration of a live system. Everything the new configuration is about to replace
+/// is saved first, along with how to reload it. The new configuration is then applied and the
+/// network reloaded, after which the node has to show that it can still reach a default gateway,
+/// resolve names, and connect to the probe target. If it can't do that before the timeout, the new
+/// interfaces are stopped, the saved files are put back and the previous configuration reloaded. A
+/// bad configuration pushed to a remote node therefore doesn't leave it unreachable.
+use super::{Backend, NetworkPlan, Rendered};
+use crate::VerifyConfig;
+use crate::managed::read_or_empty;
+use crate::system::SystemRoot;
+use anyhow::{Error, anyhow};
+use log::{error, info, warn};
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt, symlink};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long to wait between rounds of checks while the network settles.
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a single connection attempt to the probe target may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a path touched by a rendered configuration held before it was applied.
+#[derive(Debug, PartialEq)]
+enum Saved {
+    File { content: Vec<u8>, mode: u32 },
+    Link(PathBuf),
+    Missing,
+}
+
+/// The previous state of every path that a rendered configuration is about to change, and the
+/// commands that reload the network with it.
+#[derive(Debug)]
+pub struct Snapshot {
+    saved: Vec<(String, Saved)>,
+    reload: Vec<Vec<String>>,
+}
+
+impl Snapshot {
+    pub fn take(
+        rendered: &Rendered,
+        backend: &dyn Backend,
+        system: &SystemRoot,
+    ) -> Result<Self, Error> {
+        let reload = backend.reload_existing(system)?;
+        let paths = rendered
+            .files
+            .iter()
+            .map(|f| f.path.as_str())
+            .chain(rendered.symlinks.iter().map(|(_, link)| link.as_str()))
+            .chain(rendered.remove.iter().map(|path| path.as_str()));
+
+        let mut saved: Vec<(String, Saved)> = vec![];
+        for path in paths {
+            if saved.iter().any(|(p, _)| p == path) {
+                continue;
+            }
+            let real = system.path(path);
+            let unable = |e: std::io::Error| anyhow!("Unable to save {}: {}", path, e);
+            let state = match fs::symlink_metadata(&real) {
+                Err(e) if e.kind() == ErrorKind::NotFound => Saved::Missing,
+                Err(e) => return Err(unable(e)),
+                Ok(meta) if meta.file_type().is_symlink() => {
+                    Saved::Link(fs::read_link(&real).map_err(unable)?)
+                }
+                Ok(meta) => Saved::File {
+                    content: fs::read(&real).map_err(unable)?,
+                    mode: meta.permissions().mode() & 0o7777,
+                },
+            };
+            saved.push((path.to_string(), state));
+        }
+        Ok(Snapshot { saved, reload })
+    }
+
+    /// Puts every saved path back the way it was, including its permissions.
+    pub fn restore(&self, system: &SystemRoot) -> Result<(), Error> {
+        for (path, state) in &self.saved {
+            let real = system.path(path);
+            let unable = |e: std::io::Error| anyhow!("Unable to restore {}: {}", path, e);
+            match fs::remove_file(&real) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(unable(e)),
+            }
+            match state {
+                Saved::Missing => {}
+                Saved::Link(target) => symlink(target, &real).map_err(unable)?,
+                Saved::File { content, mode } => {
+                    // Created with its final permissions, so that credentials are never exposed.
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(*mode)
+                        .open(&real)
+                        .map_err(unable)?;
+                    file.set_permissions(fs::Permissions::from_mode(*mode))
+                        .map_err(unable)?;
+                    file.write_all(content).map_err(unable)?;
+                }
+            }
+        }
+        info!("Restored {} configuration paths", self.saved.len());
+        Ok(())
+    }
+}
+
+/// A default route's next hop, as found in the kernel's routing tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gateway {
+    pub address: IpAddr,
+    pub interface: String,
+}
+
+/// Link-local gateways only make sense along with the interface they're reached through.
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address {
+            IpAddr::V6(v6) if v6.is_unicast_link_local() => write!(f, "{}%{}", v6, self.interface),
+            address => write!(f, "{}", address),
+        }
+    }
+}
+
+/// Lists the gateways of the default routes currently installed, for both address families. This
+/// covers routes from DHCP and router advertisements as well as static configuration.
+pub fn routed_gateways(system: &SystemRoot) -> Result<Vec<Gateway>, Error> {
+    let mut gateways = vec![];
+
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask ..., with addresses printed as
+    // native-endian integers of network-order bytes.
+    let hex32 = |field: &str| u32::from_str_radix(field, 16).map(|v| v.to_ne_bytes());
+    for line in read_or_empty(&system.path("/proc/net/route"))?
+        .lines()
+        .skip(1)
+    {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            continue;
+        }
+        let (Ok(destination), Ok(gateway), Ok(mask)) =
+            (hex32(fields[1]), hex32(fields[2]), hex32(fields[7]))
+        else {
+            continue;
+        };
+        if destination == [0; 4] && mask == [0; 4] && gateway != [0; 4] {
+            gateways.push(Gateway {
+                address: IpAddr::V4(Ipv4Addr::from(gateway)),
+                interface: fields[0].to_string(),
+            });
+        }
+    }
+
+    // Destination PrefixLen Source SourcePrefixLen NextHop Metric RefCnt Use Flags Iface, with
+    // addresses printed as 32 hex digits. The file is missing if IPv6 is disabled altogether.
+    for line in read_or_empty(&system.path("/proc/net/ipv6_route"))?.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 {
+            continue;
+        }
+        let (Ok(destination), Ok(next_hop)) = (
+            u128::from_str_radix(fields[0], 16),
+            u128::from_str_radix(fields[4], 16),
+        ) else {
+            continue;
+        };
+        if destination == 0 && fields[1] == "00" && next_hop != 0 && fields[9] != "lo" {
+            gateways.push(Gateway {
+                address: IpAddr::V6(Ipv6Addr::from(next_hop)),
+                interface: fields[9].to_string(),
+            });
+        }
+    }
+    Ok(gateways)
+}
+
+/// The ways guarded reconfiguration touches the live network, so that the decisions it makes can
+/// be tested without one.
+pub trait Connectivity {
+    /// Runs a command, failing if it can't be started or doesn't succeed.
+    fn run(&self, command: &[String]) -> Result<(), Error>;
+    /// Whether the gateway answers a ping.
+    fn ping(&self, gateway: &Gateway) -> bool;
+    /// Looks a host name up with the system resolver.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error>;
+    /// Whether a TCP connection can be made to the address.
+    fn connect(&self, address: &SocketAddr) -> bool;
+}
+
+/// The network of the machine we're running on.
+pub struct Live;
+
+impl Connectivity for Live {
+    fn run(&self, command: &[String]) -> Result<(), Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty command"))?;
+        let status = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .status()
+            .map_err(|e| anyhow!("Unable to run {}: {}", program, e))?;
+        if !status.success() {
+            return Err(anyhow!("{} failed: {}", command.join(" "), status));
+        }
+        Ok(())
+    }
+
+    fn ping(&self, gateway: &Gateway) -> bool {
+        // Both busybox and iputils ping work out the address family for themselves.
+        Command::new("ping")
+            .args(["-c", "1", "-W", "2", &gateway.to_string()])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+
+    fn connect(&self, address: &SocketAddr) -> bool {
+        TcpStream::connect_timeout(address, CONNECT_TIMEOUT).is_ok()
+    }
+}
+
+/// Makes a single round of checks, returning a description of each one that failed.
+pub fn check(verify: &VerifyConfig, system: &SystemRoot, host: &dyn Connectivity) -> Vec<String> {
+    let mut failures = vec![];
+
+    match routed_gateways(system) {
+        Ok(gateways) if gateways.is_empty() => failures.push("there is no default route".into()),
+        Ok(gateways) => {
+            if !gateways.iter().any(|gateway| host.ping(gateway)) {
+                let names: Vec<String> = gateways.iter().map(|g| g.to_string()).collect();
+                failures.push(format!(
+                    "no default gateway answered ({})",
+                    names.join(", ")
+                ));
+            }
+        }
+        Err(e) => failures.push(format!("unable to read the routing table: {}", e)),
+    }
+
+    // DNS is checked on its own, as the probe may be given by address, or not at all.
+    let mut unresolved = None;
+    if let Some(name) = &verify.resolve {
+        let failure = match host.resolve(name, 0) {
+            Ok(addresses) if !addresses.is_empty() => None,
+            Ok(_) => Some(format!("{} doesn't resolve to any addresses", name)),
+            Err(e) => Some(format!("unable to resolve {}: {}", name, e)),
+        };
+        if let Some(failure) = failure {
+            failures.push(format!("DNS isn't working: {}", failure));
+            unresolved = Some(name.as_str());
+        }
+    }
+
+    let (name, port) = match verify.probe_target() {
+        Ok(Some(target)) => target,
+        Ok(None) => return failures,
+        Err(e) => {
+            failures.push(e.to_string());
+            return failures;
+        }
+    };
+    let addresses = match name.parse::<IpAddr>() {
+        Ok(address) => vec![SocketAddr::new(address, port)],
+        // Already reported as a DNS failure.
+        Err(_) if unresolved == Some(name.as_str()) => return failures,
+        Err(_) => match host.resolve(&name, port) {
+            Ok(addresses) if !addresses.is_empty() => addresses,
+            Ok(_) => {
+                failures.push(format!("{} doesn't resolve to any addresses", name));
+                return failures;
+            }
+            Err(e) => {
+                failures.push(format!("unable to resolve {}: {}", name, e));
+                return failures;
+            }
+        },
+    };
+    if !addresses.iter().any(|address| host.connect(address)) {
+        failures.push(format!("unable to connect to {} port {}", name, port));
+    }
+    failures
+}
+
+/// Repeats the checks until they all pass, or the timeout runs out.
+pub fn wait_until_reachable(
+    verify: &VerifyConfig,
+    system: &SystemRoot,
+    host: &dyn Connectivity,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + Duration::from_secs(verify.timeout);
+    loop {
+        let failures = check(verify, system, host);
+        if failures.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() + RETRY_INTERVAL > deadline {
+            return Err(anyhow!("{}", failures.join("; ")));
+        }
+        info!("Waiting for the network: {}", failures.join("; "));
+        sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Runs each of the commands in turn. Failures are only logged, as the checks that follow are what
+/// decide whether the network is usable.
+fn run_all(commands: &[Vec<String>], host: &dyn Connectivity) {
+    for command in commands {
+        info!("Running {}", command.join(" "));
+        if let Err(e) = host.run(command) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Applies `rendered`, the configuration `backend` rendered for `plan`, reloads the network, and
+/// waits for the checks in `verify` to pass. If they don't, or the configuration can't be applied,
+/// the interfaces in `plan` are stopped, the previous configuration is restored and reloaded, and
+/// an error returned.
+pub fn guarded_apply(
+    rendered: &Rendered,
+    plan: &NetworkPlan,
+    backend: &dyn Backend,
+    verify: &VerifyConfig,
+    system: &SystemRoot,
+    host: &dyn Connectivity,
+) -> Result<(), Error> {
+    let snapshot = Snapshot::take(rendered, backend, system)?;
+    let result = rendered.apply(system).and_then(|()| {
+        run_all(&backend.reload(plan), host);
+        wait_until_reachable(verify, system, host)
+    });
+    let problem = match result {
+        Ok(()) => {
+            info!("Network connectivity verified");
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+
+    error!(
+        "New network configuration failed ({}); restoring the previous configuration",
+        problem
+    );
+    // The new interfaces are stopped while the configuration they were brought up with is still
+    // in place, as neither init scripts nor ifupdown can stop an interface they no longer know.
+    run_all(&backend.stop(plan), host);
+    snapshot.restore(system)?;
+    run_all(&snapshot.reload, host);
+    Err(anyhow!(
+        "Network configuration was rolled back, as {}",
+        problem
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::ifupdown::Ifupdown;
+    use crate::network::netifrc::Netifrc;
+    use crate::network::{ConfigFile, InterfaceKind, InterfacePlan};
+    use crate::{IpSettings, NetworkConfig};
+    use std::cell::RefCell;
+    use tempfile::TempDir;
+
+    /// A network where either everything works, or nothing does. Names resolve whenever the
+    /// network is reachable, unless DNS is broken on its own.
+    struct Fake {
+        reachable: bool,
+        resolves: bool,
+        commands: RefCell<Vec<String>>,
+    }
+
+    impl Connectivity for Fake {
+        fn run(&self, command: &[String]) -> Result<(), Error> {
+            self.commands.borrow_mut().push(command.join(" "));
+            Ok(())
+        }
+
+        fn ping(&self, _gateway: &Gateway) -> bool {
+            self.reachable
+        }
+
+        fn resolve(&self, _host: &str, port: u16) -> Result<Vec<SocketAddr>, Error> {
+            match self.reachable && self.resolves {
+                true => Ok(vec![SocketAddr::new("192.0.2.1".parse().unwrap(), port)]),
+                false => Err(anyhow!("no nameservers")),
+            }
+        }
+
+        fn connect(&self, _address: &SocketAddr) -> bool {
+            self.reachable
+        }
+    }
+
+    /// A system with a default route via 10.0.2.2 on eth0, and an existing configuration.
+    fn routed_system() -> (TempDir, SystemRoot) {
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        fs::create_dir_all(system.path("/proc/net")).unwrap();
+        fs::write(
+            system.path("/proc/net/route"),
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t0002000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0\n\
+             eth0\t00000000\t0202000A\t0003\t0\t0\t100\t00000000\t0\t0\t0\n",
+        )
+        .unwrap();
+        fs::write(
+            system.path("/proc/net/ipv6_route"),
+            "fe800000000000000000000000000000 40 00000000000000000000000000000000 00 \
+             00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0\n\
+             00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+             fe800000000000000000000000000001 00000400 00000001 00000000 00000003     eth0\n\
+             00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+             00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n",
+        )
+        .unwrap();
+
+        fs::create_dir_all(system.path("/etc/conf.d")).unwrap();
+        fs::create_dir_all(system.path("/etc/init.d")).unwrap();
+        fs::write(system.path("/etc/conf.d/net"), NET_ETH0).unwrap();
+        fs::set_permissions(
+            system.path("/etc/conf.d/net"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        symlink("net.lo", system.path("/etc/init.d/net.eth0")).unwrap();
+        (root, system)
+    }
+
+    const NET_ETH0: &str =
+        "# BEGIN holos-config eth0\nconfig_eth0=\"dhcp\"\n# END holos-config eth0\n";
+
+    /// A plan bringing up each of the named interfaces with DHCP.
+    fn plan(names: &[&str]) -> NetworkPlan {
+        NetworkPlan {
+            interfaces: names
+                .iter()
+                .map(|name| InterfacePlan {
+                    name: name.to_string(),
+                    kind: InterfaceKind::Physical,
+                    addressing: Some(IpSettings::default()),
+                    wireless: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// What netifrc renders for a plan bringing up eth1 in place of eth0, and a wireless
+    /// interface.
+    fn rendered() -> Rendered {
+        Rendered {
+            files: vec![
+                ConfigFile::new(
+                    "/etc/conf.d/net",
+                    "# BEGIN holos-config eth1\nconfig_eth1=\"dhcp\"\n# END holos-config eth1\n"
+                        .to_string(),
+                ),
+                ConfigFile::secret(
+                    "/etc/wpa_supplicant/wpa_supplicant-wlan0.conf",
+                    "psk".into(),
+                ),
+            ],
+            symlinks: vec![("net.lo".to_string(), "/etc/init.d/net.eth1".to_string())],
+            remove: vec!["/etc/init.d/net.eth0".to_string()],
+        }
+    }
+
+    #[test]
+    fn gateways() {
+        let (root, system) = routed_system();
+        let gateways = routed_gateways(&system).unwrap();
+        let names: Vec<String> = gateways.iter().map(|g| g.to_string()).collect();
+        assert_eq!(names, vec!["10.0.2.2", "fe80::1%eth0"]);
+        assert!(
+            routed_gateways(&SystemRoot::new(root.path().join("none")))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn verified() {
+        let (_root, system) = routed_system();
+        let host = Fake {
+            reachable: true,
+            resolves: true,
+            commands: RefCell::new(vec![]),
+        };
+        guarded_apply(
+            &rendered(),
+            &plan(&["eth1"]),
+            &Netifrc,
+            &VerifyConfig::default(),
+            &system,
+            &host,
+        )
+        .unwrap();
+
+        assert_eq!(*host.commands.borrow(), vec!["rc-service net.eth1 restart"]);
+        assert!(
+            fs::read_to_string(system.path("/etc/conf.d/net"))
+                .unwrap()
+                .contains("config_eth1=\"dhcp\"\n")
+        );
+        assert!(system.path("/etc/init.d/net.eth1").is_symlink());
+        assert!(!system.path("/etc/init.d/net.eth0").is_symlink());
+    }
+
+    #[test]
+    /// When the checks fail, the new interfaces are stopped, every file goes back to how it was,
+    /// and the interfaces of the previous configuration are brought back up.
+    fn rolled_back() {
+        let (_root, system) = routed_system();
+        let host = Fake {
+            reachable: false,
+            resolves: true,
+            commands: RefCell::new(vec![]),
+        };
+        let verify = VerifyConfig {
+            timeout: 0,
+            ..Default::default()
+        };
+        let error = guarded_apply(
+            &rendered(),
+            &plan(&["eth1"]),
+            &Netifrc,
+            &verify,
+            &system,
+            &host,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("no default gateway answered"));
+        assert!(error.to_string().contains("unable to resolve holo.host"));
+
+        assert_eq!(
+            *host.commands.borrow(),
+            vec![
+                "rc-service net.eth1 restart",
+                "rc-service net.eth1 stop",
+                "rc-service net.eth0 restart"
+            ]
+        );
+        let net = system.path("/etc/conf.d/net");
+        assert_eq!(fs::read_to_string(&net).unwrap(), NET_ETH0);
+        assert_eq!(
+            fs::metadata(&net).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        assert_eq!(
+            fs::read_link(system.path("/etc/init.d/net.eth0")).unwrap(),
+            PathBuf::from("net.lo")
+        );
+        assert!(!system.path("/etc/init.d/net.eth1").exists());
+        assert!(
+            !system
+                .path("/etc/wpa_supplicant/wpa_supplicant-wlan0.conf")
+                .exists()
+        );
+    }
+
+    #[test]
+    /// ifupdown can only take an interface down while it's still in the interfaces file, so the
+    /// new interfaces are taken down before the previous file goes back, and only the previous
+    /// interfaces are brought back up.
+    fn rolled_back_ifupdown() {
+        let (_root, system) = routed_system();
+        let backend = Ifupdown::default();
+        fs::create_dir_all(system.path("/etc/network/interfaces.d")).unwrap();
+        fs::write(
+            system.path(&backend.interfaces_path()),
+            "auto eth0\niface eth0 inet dhcp\niface eth0 inet6 auto\n\nauto br0\niface br0 inet dhcp\n",
+        )
+        .unwrap();
+        let host = Fake {
+            reachable: false,
+            resolves: true,
+            commands: RefCell::new(vec![]),
+        };
+        let verify = VerifyConfig {
+            timeout: 0,
+            ..Default::default()
+        };
+        let network: NetworkConfig =
+            serde_yaml::from_str("nameservers: []\ninterfaces: []").unwrap();
+        let new = plan(&["eth1", "eth2"]);
+        let rendered = backend.render(&new, &network, &system).unwrap();
+        guarded_apply(&rendered, &new, &backend, &verify, &system, &host).unwrap_err();
+
+        assert_eq!(
+            *host.commands.borrow(),
+            vec![
+                "ifdown --force eth2 eth1",
+                "ifup eth1 eth2",
+                "ifdown --force eth2 eth1",
+                "ifdown --force br0 eth0",
+                "ifup eth0 br0"
+            ]
+        );
+        assert!(
+            fs::read_to_string(system.path(&backend.interfaces_path()))
+                .unwrap()
+                .contains("iface br0 inet dhcp")
+        );
+    }
+
+    #[test]
+    /// DNS is checked even when the probe is given by address or left out, and is only reported
+    /// once when the probe is the name being resolved.
+    fn probe_targets() {
+        let (_root, system) = routed_system();
+        let host = Fake {
+            reachable: false,
+            resolves: true,
+            commands: RefCell::new(vec![]),
+        };
+        let verify = |probe: Option<&str>| VerifyConfig {
+            probe: probe.map(|p| p.to_string()),
+            timeout: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&verify(Some("[2001:db8::1]:22")), &system, &host),
+            vec![
+                "no default gateway answered (10.0.2.2, fe80::1%eth0)",
+                "DNS isn't working: unable to resolve holo.host: no nameservers",
+                "unable to connect to 2001:db8::1 port 22"
+            ]
+        );
+        assert_eq!(
+            check(&verify(Some("holo.host:443")), &system, &host),
+            vec![
+                "no default gateway answered (10.0.2.2, fe80::1%eth0)",
+                "DNS isn't working: unable to resolve holo.host: no nameservers"
+            ]
+        );
+        assert_eq!(check(&verify(None), &system, &host).len(), 2);
+        let gateway_only = VerifyConfig {
+            resolve: None,
+            ..verify(None)
+        };
+        assert_eq!(check(&gateway_only, &system, &host).len(), 1);
+    }
+
+    #[test]
+    /// A probe given by address still fails the check when names don't resolve.
+    fn ip_probe_without_dns() {
+        let (_root, system) = routed_system();
+        let host = Fake {
+            reachable: true,
+            resolves: true,
+            commands: RefCell::new(vec![]),
+        };
+        let verify = VerifyConfig {
+            probe: Some("192.0.2.1:443".to_string()),
+            resolve: Some("holo.host".to_string()),
+            timeout: 0,
+        };
+        assert!(check(&verify, &system, &host).is_empty());
+
+        let host = Fake {
+            resolves: false,
+            ..host
+        };
+        assert_eq!(
+            check(&verify, &system, &host),
+            vec!["DNS isn't working: unable to resolve holo.host: no nameservers"]
+        );
+    }
+}
@@ -0,0 +1,368 @@
+/// The ifupdown backend, for Debian-style systems. All of our interfaces go in a single file of
+/// our own in `/etc/network/interfaces.d`, which the stock `/etc/network/interfaces` sources, so
+/// there's no need to share a file with the administrator.
+use super::{
+    Backend, ConfigFile, InterfaceKind, InterfacePlan, RESOLV_CONF_PATH, Rendered,
+    default_gateways, ip_route_args, resolv_conf, wpa_supplicant_conf, wpa_supplicant_conf_path,
+};
+use crate::managed::read_or_empty;
+use crate::network::NetworkPlan;
+use crate::system::SystemRoot;
+use crate::{Ipv4Mode, Ipv6Mode, NetworkConfig};
+use anyhow::Error;
+
+/// Where ifupdown looks for additional interface definitions by default.
+pub const INTERFACES_DIR: &str = "/etc/network/interfaces.d";
+/// A dhclient hook that stops it from rewriting the resolver configuration, which we only install
+/// while nameservers are configured statically.
+pub const DHCLIENT_HOOK_PATH: &str = "/etc/dhcp/dhclient-enter-hooks.d/holos-nodns";
+
+pub struct Ifupdown {
+    /// The directory sourced by `/etc/network/interfaces`, which our file is written to.
+    pub interfaces_dir: String,
+}
+
+impl Default for Ifupdown {
+    fn default() -> Self {
+        Ifupdown {
+            interfaces_dir: INTERFACES_DIR.to_string(),
+        }
+    }
+}
+
+impl Ifupdown {
+    /// The file holding all of the interfaces we configure.
+    pub fn interfaces_path(&self) -> String {
+        format!("{}/holos-config", self.interfaces_dir.trim_end_matches('/'))
+    }
+}
+
+impl Backend for Ifupdown {
+    fn name(&self) -> &'static str {
+        "ifupdown"
+    }
+
+    fn render(
+        &self,
+        plan: &NetworkPlan,
+        network: &NetworkConfig,
+        _system: &SystemRoot,
+    ) -> Result<Rendered, Error> {
+        let mut rendered = Rendered::default();
+
+        let mut interfaces =
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+                              # Interfaces configured by hand belong in their own file.\n"
+                .to_string();
+        for iface in &plan.interfaces {
+            if let Some(wireless) = &iface.wireless {
+                rendered.files.push(ConfigFile::secret(
+                    &wpa_supplicant_conf_path(&iface.name),
+                    wpa_supplicant_conf(wireless),
+                ));
+            }
+            interfaces += "\n";
+            interfaces += interfaces_stanza(iface).as_str();
+        }
+        rendered
+            .files
+            .push(ConfigFile::new(&self.interfaces_path(), interfaces));
+
+        match resolv_conf(network) {
+            Some(resolver) => {
+                rendered.files.push(ConfigFile::new(
+                    DHCLIENT_HOOK_PATH,
+                    "# Generated by holos-config, as nameservers are configured statically.\n\
+                     # Stops dhclient from overwriting /etc/resolv.conf.\n\
+                     make_resolv_conf() { :; }\n"
+                        .to_string(),
+                ));
+                rendered
+                    .files
+                    .push(ConfigFile::new(RESOLV_CONF_PATH, resolver));
+            }
+            None => rendered.remove.push(DHCLIENT_HOOK_PATH.to_string()),
+        }
+        Ok(rendered)
+    }
+
+    /// Takes every interface down, dependents first, and brings them back up with the new
+    /// configuration. Interfaces that weren't up before are ignored by `ifdown --force`.
+    fn reload(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        let names: Vec<String> = plan.interfaces.iter().map(|i| i.name.clone()).collect();
+        restart(&names)
+    }
+
+    fn stop(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        let names: Vec<String> = plan.interfaces.iter().map(|i| i.name.clone()).collect();
+        match names.is_empty() {
+            true => vec![],
+            false => vec![down(&names)],
+        }
+    }
+
+    /// Brings up the interfaces with an `iface` stanza in our file, as it was written for an
+    /// earlier plan. Nothing needs doing if there's no such file.
+    fn reload_existing(&self, system: &SystemRoot) -> Result<Vec<Vec<String>>, Error> {
+        let existing = read_or_empty(&system.path(&self.interfaces_path()))?;
+        let mut names: Vec<String> = vec![];
+        for line in existing.lines() {
+            let mut words = line.split_whitespace();
+            if words.next() == Some("iface")
+                && let Some(name) = words.next()
+                && !names.iter().any(|n| n == name)
+            {
+                names.push(name.to_string());
+            }
+        }
+        match names.is_empty() {
+            true => Ok(vec![]),
+            false => Ok(restart(&names)),
+        }
+    }
+}
+
+/// Takes the named interfaces down and brings them back up.
+fn restart(names: &[String]) -> Vec<Vec<String>> {
+    let mut up = vec!["ifup".to_string()];
+    up.extend(names.iter().cloned());
+    vec![down(names), up]
+}
+
+/// Takes the named interfaces down in the reverse of the order they were brought up in.
+fn down(names: &[String]) -> Vec<String> {
+    let mut down = vec!["ifdown".to_string(), "--force".to_string()];
+    down.extend(names.iter().rev().cloned());
+    down
+}
+
+/// Renders the `iface` stanzas for a single interface from the plan.
+///
+/// Each address family gets a stanza for its mode, except that static addresses each get a stanza
+/// of their own, which ifupdown adds to the interface in turn. Options describing the interface
+/// itself, such as bond members, routes and the MTU, go in the first stanza.
+pub fn interfaces_stanza(iface: &InterfacePlan) -> String {
+    let name = &iface.name;
+    let mut options: Vec<String> = vec![];
+    if iface.wireless.is_some() {
+        options.push(format!("wpa-conf {}", wpa_supplicant_conf_path(name)));
+    }
+    match &iface.kind {
+        InterfaceKind::Physical => {}
+        InterfaceKind::Bond { mode, members } => {
+            options.push(format!("bond-slaves {}", members.join(" ")));
+            options.push(format!("bond-mode {}", mode));
+            options.push("bond-miimon 100".to_string());
+        }
+        InterfaceKind::Bridge { members } if members.is_empty() => {
+            options.push("bridge_ports none".to_string());
+        }
+        InterfaceKind::Bridge { members } => {
+            options.push(format!("bridge_ports {}", members.join(" ")));
+        }
+        InterfaceKind::Vlan { parent, .. } => {
+            options.push(format!("vlan-raw-device {}", parent));
+        }
+    }
+
+    let mut stanzas: Vec<(String, Vec<String>)> = vec![];
+    if let Some(ip) = &iface.addressing {
+        // Each static address gets a stanza of its own, with each gateway only given once.
+        let mut gateways = default_gateways(&ip.addresses);
+        let mut static_stanzas = |family: &str, v4: bool, stanzas: &mut Vec<_>| {
+            for address in ip
+                .addresses
+                .iter()
+                .filter(|a| a.address.addr().is_ipv4() == v4)
+            {
+                let mut lines = vec![format!("address {}", address.address)];
+                if let Some(gateway) = address.gateway
+                    && let Some(i) = gateways.iter().position(|g| *g == gateway)
+                {
+                    lines.push(format!("gateway {}", gateways.remove(i)));
+                }
+                stanzas.push((format!("{} static", family), lines));
+            }
+        };
+
+        match ip.ipv4 {
+            Ipv4Mode::Dhcp4 => stanzas.push(("inet dhcp".to_string(), vec![])),
+            Ipv4Mode::Static => static_stanzas("inet", true, &mut stanzas),
+            Ipv4Mode::Disabled => {}
+        }
+        let first_v6 = stanzas.len();
+        match ip.ipv6 {
+            Ipv6Mode::Slaac => stanzas.push(("inet6 auto".to_string(), vec![])),
+            Ipv6Mode::Dhcp6 => stanzas.push(("inet6 dhcp".to_string(), vec![])),
+            Ipv6Mode::Static => {
+                static_stanzas("inet6", false, &mut stanzas);
+                stanzas[first_v6].1.push("accept_ra 0".to_string());
+                stanzas[first_v6].1.push("autoconf 0".to_string());
+            }
+            Ipv6Mode::Disabled => options.push(format!(
+                "post-up echo 1 > /proc/sys/net/ipv6/conf/{}/disable_ipv6",
+                name
+            )),
+        }
+
+        if let Some(mtu) = ip.mtu {
+            options.push(format!("post-up ip link set dev {} mtu {}", name, mtu));
+        }
+        for route in &ip.routes {
+            let args = ip_route_args(route);
+            options.push(format!("post-up ip route add {} dev {}", args, name));
+            options.push(format!("pre-down ip route del {} dev {}", args, name));
+        }
+    }
+    // Interfaces with nothing to configure still need bringing up.
+    if stanzas.is_empty() {
+        stanzas.push(("inet manual".to_string(), vec![]));
+    }
+
+    let mut out = format!("auto {}\n", name);
+    for (i, (method, lines)) in stanzas.iter().enumerate() {
+        out += format!("iface {} {}\n", name, method).as_str();
+        let extra = if i == 0 { options.as_slice() } else { &[] };
+        for line in lines.iter().chain(extra) {
+            out += format!("    {}\n", line).as_str();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::{ROUTED, bonded_vlan_plan, single};
+    use tempfile::TempDir;
+
+    #[test]
+    /// Each static address gets its own stanza, with shared gateways only given once.
+    fn static_stanza() {
+        let p = single(
+            "eth0",
+            "
+identifier: !pci_address
+    address: '0000:01:00.0'
+static_addresses:
+    - address: 10.0.0.100/24
+      gateway: 10.0.0.1
+    - address: 10.0.0.101/24
+      gateway: 10.0.0.1
+    - address: 2001:db8::100/64
+      gateway: 2001:db8::1
+",
+        );
+        assert_eq!(
+            interfaces_stanza(&p.interfaces[0]),
+            "auto eth0\n\
+             iface eth0 inet static\n    address 10.0.0.100/24\n    gateway 10.0.0.1\n\
+             iface eth0 inet static\n    address 10.0.0.101/24\n\
+             iface eth0 inet6 static\n    address 2001:db8::100/64\n    gateway 2001:db8::1\n    \
+             accept_ra 0\n    autoconf 0\n"
+        );
+    }
+
+    #[test]
+    /// Routes, the MTU and turning off IPv6 are all done once the interface is up.
+    fn ip_modes() {
+        let p = single("eth0", ROUTED);
+        assert_eq!(
+            interfaces_stanza(&p.interfaces[0]),
+            "auto eth0\n\
+             iface eth0 inet static\n    address 10.0.0.100/24\n    gateway 10.0.0.1\n    \
+             post-up echo 1 > /proc/sys/net/ipv6/conf/eth0/disable_ipv6\n    \
+             post-up ip link set dev eth0 mtu 9000\n    \
+             post-up ip route add 192.168.0.0/16 via 10.0.0.254 metric 100 dev eth0\n    \
+             pre-down ip route del 192.168.0.0/16 via 10.0.0.254 metric 100 dev eth0\n"
+        );
+    }
+
+    #[test]
+    /// Wireless interfaces have wpa_supplicant started by ifupdown.
+    fn wireless_stanza() {
+        let p = single(
+            "wlan0",
+            "
+identifier: !usb
+    id: 0bda:8179
+static_addresses:
+wireless:
+    ssid: Home Network
+    psk: correct horse battery
+",
+        );
+        assert_eq!(
+            interfaces_stanza(&p.interfaces[0]),
+            "auto wlan0\n\
+             iface wlan0 inet dhcp\n    wpa-conf /etc/wpa_supplicant/wpa_supplicant-wlan0.conf\n\
+             iface wlan0 inet6 auto\n"
+        );
+    }
+
+    #[test]
+    fn bonded_vlan() {
+        let (network, p) = bonded_vlan_plan();
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        let rendered = Ifupdown::default().render(&p, &network, &system).unwrap();
+        assert_eq!(
+            rendered.files[0].path,
+            "/etc/network/interfaces.d/holos-config"
+        );
+        assert_eq!(
+            rendered.files[0].content,
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             # Interfaces configured by hand belong in their own file.\n\
+             \n\
+             auto eth0\niface eth0 inet manual\n\
+             \n\
+             auto eth1\niface eth1 inet manual\n\
+             \n\
+             auto bond0\n\
+             iface bond0 inet dhcp\n    bond-slaves eth0 eth1\n    bond-mode active-backup\n    \
+             bond-miimon 100\n\
+             iface bond0 inet6 auto\n\
+             \n\
+             auto vlan100\n\
+             iface vlan100 inet static\n    address 10.0.100.2/24\n    vlan-raw-device bond0\n\
+             iface vlan100 inet6 auto\n"
+        );
+        // Without static nameservers, dhclient is left to manage the resolver.
+        assert_eq!(rendered.files.len(), 1);
+        assert_eq!(rendered.remove, vec![DHCLIENT_HOOK_PATH]);
+
+        // VLANs and bonds come down before their members, and up after them.
+        assert_eq!(
+            Ifupdown::default().reload(&p),
+            vec![
+                vec!["ifdown", "--force", "vlan100", "bond0", "eth1", "eth0"],
+                vec!["ifup", "eth0", "eth1", "bond0", "vlan100"],
+            ]
+        );
+    }
+
+    #[test]
+    /// Static nameservers stop dhclient from touching the resolver configuration.
+    fn static_dns() {
+        let (mut network, p) = bonded_vlan_plan();
+        network.nameservers.push("1.1.1.1".parse().unwrap());
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        let rendered = Ifupdown {
+            interfaces_dir: "/etc/network/holos/".to_string(),
+        }
+        .render(&p, &network, &system)
+        .unwrap();
+        let paths: Vec<&str> = rendered.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/etc/network/holos/holos-config",
+                DHCLIENT_HOOK_PATH,
+                RESOLV_CONF_PATH
+            ]
+        );
+        assert!(rendered.remove.is_empty());
+    }
+}
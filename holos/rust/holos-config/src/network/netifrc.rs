@@ -0,0 +1,628 @@
+/// The netifrc backend, used by HolOS itself under OpenRC. Each interface gets a block of
+/// variables in `/etc/conf.d/net`, which is shared with the administrator, and an init script
+/// symlinked to `net.lo`.
+use super::{
+    Backend, ConfigFile, InterfaceKind, InterfacePlan, NetworkPlan, RESOLV_CONF_PATH, Rendered,
+    default_gateways, ip_route_args, resolv_conf, wpa_supplicant_conf, wpa_supplicant_conf_path,
+};
+use crate::managed::{ManagedFile, read_or_empty};
+use crate::system::SystemRoot;
+use crate::{Ipv4Mode, Ipv6Mode, NetworkConfig};
+use anyhow::{Error, anyhow};
+
+/// The netifrc configuration file, shared between holos-config and the administrator.
+pub const NETIFRC_CONF_PATH: &str = "/etc/conf.d/net";
+/// The init script that every interface's `net.<iface>` script is a link to.
+pub const NET_LO_PATH: &str = "/etc/init.d/net.lo";
+
+pub struct Netifrc;
+
+impl Backend for Netifrc {
+    fn name(&self) -> &'static str {
+        "netifrc"
+    }
+
+    fn validate(&self, network: &NetworkConfig) -> Result<(), Error> {
+        let physical = network
+            .interfaces
+            .iter()
+            .map(|iface| (iface.identifier.to_string(), iface.ipv6));
+        let virtual_ = network
+            .virtual_interfaces
+            .iter()
+            .map(|iface| (iface.name.clone(), iface.ipv6));
+        for (name, ipv6) in physical.chain(virtual_) {
+            if ipv6 == Some(Ipv6Mode::Dhcp6) {
+                return Err(no_dhcp6(&name));
+            }
+        }
+        Ok(())
+    }
+
+    fn render(
+        &self,
+        plan: &NetworkPlan,
+        network: &NetworkConfig,
+        system: &SystemRoot,
+    ) -> Result<Rendered, Error> {
+        let mut rendered = Rendered::default();
+        let resolver = resolv_conf(network);
+
+        let mut stanzas: Vec<(String, String)> = vec![];
+        for iface in &plan.interfaces {
+            if let Some(wireless) = &iface.wireless {
+                rendered.files.push(ConfigFile::secret(
+                    &wpa_supplicant_conf_path(&iface.name),
+                    wpa_supplicant_conf(wireless),
+                ));
+            }
+            if needs_init_script(iface) {
+                rendered.symlinks.push((
+                    NET_LO_PATH.to_string(),
+                    format!("/etc/init.d/net.{}", iface.name),
+                ));
+            }
+            if iface
+                .addressing
+                .as_ref()
+                .is_some_and(|ip| ip.ipv6 == Ipv6Mode::Dhcp6)
+            {
+                return Err(no_dhcp6(&iface.name));
+            }
+            stanzas.push((
+                iface.name.clone(),
+                netifrc_stanza(plan, iface, resolver.is_some()),
+            ));
+        }
+
+        let existing = read_or_empty(&system.path(NETIFRC_CONF_PATH))?;
+        if stanzas
+            .iter()
+            .any(|(_, stanza)| stanza.contains("holos_ipv6_"))
+        {
+            // There can only be one postup() function, so make sure it's not already in use.
+            let mut unmanaged = ManagedFile::parse(&existing)?;
+            unmanaged.retain_blocks(|_| false);
+            if unmanaged
+                .render()
+                .lines()
+                .any(|l| l.trim_start().starts_with("postup()"))
+            {
+                return Err(anyhow!(
+                    "{} already defines postup(), which is needed to configure IPv6",
+                    NETIFRC_CONF_PATH
+                ));
+            }
+            stanzas.push((HOOK_BLOCK.to_string(), POSTUP_HOOK.to_string()));
+        }
+        for stale in ManagedFile::parse(&existing)?
+            .block_names()
+            .into_iter()
+            .filter(|name| name != HOOK_BLOCK && plan.get(name).is_none())
+        {
+            rendered.remove.push(format!("/etc/init.d/net.{}", stale));
+        }
+        rendered.files.push(ConfigFile::new(
+            NETIFRC_CONF_PATH,
+            update_netifrc(&existing, &stanzas)?,
+        ));
+
+        // With no nameservers configured, leave resolv.conf for DHCP to manage.
+        if let Some(resolver) = resolver {
+            rendered
+                .files
+                .push(ConfigFile::new(RESOLV_CONF_PATH, resolver));
+        }
+        Ok(rendered)
+    }
+
+    /// Restarts the init script of each interface, which also restarts anything depending on it.
+    fn reload(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        plan.interfaces
+            .iter()
+            .filter(|iface| needs_init_script(iface))
+            .map(|iface| {
+                vec![
+                    "rc-service".to_string(),
+                    format!("net.{}", iface.name),
+                    "restart".to_string(),
+                ]
+            })
+            .collect()
+    }
+
+    /// Stops the init scripts in the reverse of the order they were started in.
+    fn stop(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        plan.interfaces
+            .iter()
+            .rev()
+            .filter(|iface| needs_init_script(iface))
+            .map(|iface| {
+                vec![
+                    "rc-service".to_string(),
+                    format!("net.{}", iface.name),
+                    "stop".to_string(),
+                ]
+            })
+            .collect()
+    }
+
+    /// Restarts the init script of each interface with a block of its own in `/etc/conf.d/net`.
+    fn reload_existing(&self, system: &SystemRoot) -> Result<Vec<Vec<String>>, Error> {
+        let existing = read_or_empty(&system.path(NETIFRC_CONF_PATH))?;
+        Ok(ManagedFile::parse(&existing)?
+            .block_names()
+            .into_iter()
+            .filter(|name| name != HOOK_BLOCK)
+            .filter(|name| {
+                system
+                    .path(&format!("/etc/init.d/net.{}", name))
+                    .symlink_metadata()
+                    .is_ok()
+            })
+            .map(|name| {
+                vec![
+                    "rc-service".to_string(),
+                    format!("net.{}", name),
+                    "restart".to_string(),
+                ]
+            })
+            .collect())
+    }
+}
+
+/// netifrc names its variables after the interface, with anything that isn't valid in a shell
+/// variable name replaced by an underscore. Eg, `config_eth0_100` for `eth0.100`.
+fn netifrc_var(interface: &str) -> String {
+    interface
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Whether an interface needs its own `net.<iface>` init script under netifrc. VLANs are brought
+/// up by the init script of the interface carrying them.
+pub fn needs_init_script(iface: &InterfacePlan) -> bool {
+    !matches!(iface.kind, InterfaceKind::Vlan { .. })
+}
+
+/// The error for an interface set to use DHCPv6, which udhcpc can't do.
+fn no_dhcp6(name: &str) -> Error {
+    anyhow!(
+        "{}: netifrc can't do DHCPv6 with udhcpc; use slaac or static addresses",
+        name
+    )
+}
+
+/// Renders the netifrc variables needed to bring up a single interface from the plan.
+///
+/// DHCP and each static address are listed in `config_<iface>`, and gateways become default routes
+/// in `routes_<iface>` alongside any other routes. IPv6 modes other than autoconfiguration are
+/// applied by the [`POSTUP_HOOK`]. If `static_dns` is set, DHCP is told to leave the resolver
+/// configuration alone. Bonds and bridges list their members, and depend on their init scripts so
+/// that members come up first.
+pub fn netifrc_stanza(plan: &NetworkPlan, iface: &InterfacePlan, static_dns: bool) -> String {
+    let var = netifrc_var(&iface.name);
+    let mut stanza = String::new();
+
+    // Wireless interfaces need to associate before netifrc can configure their addresses.
+    if iface.wireless.is_some() {
+        stanza += format!(
+            "modules_{}=\"wpa_supplicant\"\nwpa_supplicant_{}=\"-Dnl80211,wext -c{}\"\n",
+            var,
+            var,
+            wpa_supplicant_conf_path(&iface.name)
+        )
+        .as_str();
+    }
+
+    match &iface.kind {
+        InterfaceKind::Physical | InterfaceKind::Vlan { .. } => {}
+        InterfaceKind::Bond { mode, members } => {
+            stanza += format!(
+                "slaves_{}=\"{}\"\nmode_{}=\"{}\"\nmiimon_{}=\"100\"\n",
+                var,
+                members.join(" "),
+                var,
+                mode,
+                var
+            )
+            .as_str();
+        }
+        InterfaceKind::Bridge { members } => {
+            stanza += format!("bridge_{}=\"{}\"\n", var, members.join(" ")).as_str();
+        }
+    }
+    let needs: Vec<String> = iface
+        .dependencies()
+        .iter()
+        .filter_map(|d| plan.get(d))
+        .filter(|d| needs_init_script(d))
+        .map(|d| format!("net.{}", d.name))
+        .collect();
+    if !needs.is_empty() && needs_init_script(iface) {
+        stanza += format!("rc_need_{}=\"{}\"\n", var, needs.join(" ")).as_str();
+    }
+
+    let vlans = plan.vlans_on(&iface.name);
+    if !vlans.is_empty() {
+        let ids: Vec<String> = vlans.iter().map(|(id, _)| id.to_string()).collect();
+        stanza += format!("vlans_{}=\"{}\"\n", var, ids.join(" ")).as_str();
+        for (id, name) in vlans {
+            stanza += format!("{}_vlan{}_name=\"{}\"\n", var, id, name).as_str();
+        }
+    }
+
+    let ip = match &iface.addressing {
+        Some(ip) => ip,
+        None => {
+            stanza += format!("config_{}=\"null\"\n", var).as_str();
+            return stanza;
+        }
+    };
+
+    // netifrc takes lists as newline-separated entries inside a single quoted variable. DHCP is
+    // just another entry, alongside any static addresses.
+    let mut config: Vec<String> = vec![];
+    if ip.ipv4 == Ipv4Mode::Dhcp4 {
+        config.push("dhcp".to_string());
+    }
+    config.extend(ip.addresses.iter().map(|a| a.address.to_string()));
+    if config.is_empty() {
+        config.push("null".to_string());
+    }
+    stanza += format!("config_{}=\"{}\"\n", var, config.join("\n")).as_str();
+    if ip.ipv4 == Ipv4Mode::Dhcp4 {
+        stanza += format!("udhcpc_{}=\"-b -t 7\"\n", var).as_str();
+        if static_dns {
+            stanza += format!("dhcp_{}=\"nodns\"\n", var).as_str();
+        }
+    }
+
+    let routes: Vec<String> = default_gateways(&ip.addresses)
+        .iter()
+        .map(|gw| format!("default via {}", gw))
+        .chain(ip.routes.iter().map(ip_route_args))
+        .collect();
+    if !routes.is_empty() {
+        stanza += format!("routes_{}=\"{}\"\n", var, routes.join("\n")).as_str();
+    }
+    if let Some(mtu) = ip.mtu {
+        stanza += format!("mtu_{}=\"{}\"\n", var, mtu).as_str();
+    }
+    // The kernel autoconfigures IPv6 by default, so only the other modes need the hook.
+    match ip.ipv6 {
+        Ipv6Mode::Static => stanza += format!("holos_ipv6_{}=\"static\"\n", var).as_str(),
+        Ipv6Mode::Disabled => stanza += format!("holos_ipv6_{}=\"disabled\"\n", var).as_str(),
+        Ipv6Mode::Slaac | Ipv6Mode::Dhcp6 => {}
+    }
+
+    stanza
+}
+
+/// Name of the managed block holding [`POSTUP_HOOK`].
+const HOOK_BLOCK: &str = "postup-hook";
+
+/// netifrc calls `postup()` from /etc/conf.d/net once each interface is up. We use it to apply
+/// the IPv6 mode of interfaces that aren't simply autoconfigured, as netifrc has no variables for
+/// that itself.
+pub const POSTUP_HOOK: &str = "\
+# Applies the holos_ipv6_<iface> settings, once each interface is up.
+postup() {
+\tlocal mode conf=/proc/sys/net/ipv6/conf/${IFACE}
+\teval mode=\\${holos_ipv6_${IFVAR}}
+\tcase \"${mode}\" in
+\tdisabled) echo 1 > \"${conf}/disable_ipv6\" ;;
+\tstatic) echo 0 > \"${conf}/accept_ra\"; echo 0 > \"${conf}/autoconf\" ;;
+\tesac
+\treturn 0
+}
+";
+
+/// Updates the existing content of the netifrc configuration file so that it contains exactly
+/// one managed stanza for each of the `(interface name, stanza)` pairs given. Stanzas for
+/// interfaces that are no longer configured are removed, and anything written by hand is left
+/// alone.
+pub fn update_netifrc(existing: &str, stanzas: &[(String, String)]) -> Result<String, Error> {
+    let mut file = ManagedFile::parse(existing)?;
+
+    // Earlier versions of holos-config appended an unmarked DHCP stanza on every boot. Clean
+    // those duplicates up for any interface we now manage.
+    let legacy: Vec<String> = stanzas
+        .iter()
+        .flat_map(|(name, _)| {
+            [
+                format!("config_{}=\"dhcp\"", name),
+                format!("udhcpc_{}=\"-b -t 7\"", name),
+            ]
+        })
+        .collect();
+    file.remove_unmanaged_lines(|line| legacy.iter().any(|l| l == line));
+
+    file.retain_blocks(|block| stanzas.iter().any(|(name, _)| name == block));
+    for (name, stanza) in stanzas {
+        file.set_block(name, stanza);
+    }
+
+    Ok(file.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::{ROUTED, bonded_vlan_plan, single};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    /// No static addresses means DHCP.
+    fn dhcp_stanza() {
+        let p = single(
+            "eth0",
+            "
+identifier: !pci_address
+    address: '0000:01:00.0'
+static_addresses:
+",
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "config_eth0=\"dhcp\"\nudhcpc_eth0=\"-b -t 7\"\n"
+        );
+    }
+
+    #[test]
+    /// Several addresses of mixed families, each with their own gateway.
+    fn static_stanza() {
+        let p = single(
+            "eth0",
+            "
+identifier: !pci_address
+    address: '0000:01:00.0'
+static_addresses:
+    - address: 10.0.0.100/24
+      gateway: 10.0.0.1
+    - address: 10.0.0.101/24
+      gateway: 10.0.0.1
+    - address: 2001:db8::100/64
+      gateway: 2001:db8::1
+",
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "config_eth0=\"10.0.0.100/24\n10.0.0.101/24\n2001:db8::100/64\"\n\
+             routes_eth0=\"default via 10.0.0.1\ndefault via 2001:db8::1\"\n\
+             holos_ipv6_eth0=\"static\"\n"
+        );
+    }
+
+    #[test]
+    /// Static addresses without a gateway shouldn't produce an empty routes variable.
+    fn static_no_gateway() {
+        let p = single(
+            "enp1s0",
+            "
+identifier: !pci_address
+    address: '0000:01:00.0'
+static_addresses:
+    - address: 192.168.10.5/24
+",
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "config_enp1s0=\"192.168.10.5/24\"\n"
+        );
+    }
+
+    #[test]
+    /// Running configure twice shouldn't change anything the second time, and legacy stanzas
+    /// from older versions are cleaned up.
+    fn idempotent_update() {
+        let existing = "\
+modules=\"iproute2\"
+config_eth0=\"dhcp\"
+udhcpc_eth0=\"-b -t 7\"
+config_eth0=\"dhcp\"
+udhcpc_eth0=\"-b -t 7\"
+# BEGIN holos-config eth9
+config_eth9=\"dhcp\"
+# END holos-config eth9
+";
+        let stanzas = vec![(
+            "eth0".to_string(),
+            "config_eth0=\"dhcp\"\nudhcpc_eth0=\"-b -t 7\"\n".to_string(),
+        )];
+        let first = update_netifrc(existing, &stanzas).unwrap();
+        assert_eq!(
+            first,
+            "\
+modules=\"iproute2\"
+# BEGIN holos-config eth0
+config_eth0=\"dhcp\"
+udhcpc_eth0=\"-b -t 7\"
+# END holos-config eth0
+"
+        );
+        assert_eq!(update_netifrc(&first, &stanzas).unwrap(), first);
+    }
+
+    #[test]
+    /// Wireless interfaces get wpa_supplicant started before their addresses are configured.
+    fn wireless_stanza() {
+        let p = single(
+            "wlan0",
+            "
+identifier: !usb
+    id: 0bda:8179
+static_addresses:
+wireless:
+    ssid: Home Network
+    psk: correct horse battery
+",
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "modules_wlan0=\"wpa_supplicant\"\n\
+             wpa_supplicant_wlan0=\"-Dnl80211,wext -c/etc/wpa_supplicant/wpa_supplicant-wlan0.conf\"\n\
+             config_wlan0=\"dhcp\"\n\
+             udhcpc_wlan0=\"-b -t 7\"\n"
+        );
+    }
+
+    #[test]
+    /// Masters depend on the init scripts of their members, and VLANs are brought up by their
+    /// parent's init script.
+    fn bonded_vlan() {
+        let (_, p) = bonded_vlan_plan();
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "config_eth0=\"null\"\n"
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[2], false),
+            "slaves_bond0=\"eth0 eth1\"\n\
+             mode_bond0=\"active-backup\"\n\
+             miimon_bond0=\"100\"\n\
+             rc_need_bond0=\"net.eth0 net.eth1\"\n\
+             vlans_bond0=\"100\"\n\
+             bond0_vlan100_name=\"vlan100\"\n\
+             config_bond0=\"dhcp\"\n\
+             udhcpc_bond0=\"-b -t 7\"\n"
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[3], false),
+            "config_vlan100=\"10.0.100.2/24\"\n"
+        );
+        assert!(!needs_init_script(&p.interfaces[3]));
+    }
+
+    #[test]
+    /// Static nameservers stop DHCP from touching the resolver configuration.
+    fn static_dns() {
+        let p = single(
+            "eth0",
+            "identifier: !pci_address {address: '0000:01:00.0'}\nstatic_addresses:\n",
+        );
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], true),
+            "config_eth0=\"dhcp\"\nudhcpc_eth0=\"-b -t 7\"\ndhcp_eth0=\"nodns\"\n"
+        );
+    }
+
+    #[test]
+    /// DHCPv6 is turned away before anything is changed, whether it's asked for by a physical or a
+    /// virtual interface.
+    fn dhcp6_refused() {
+        let network = |yaml: &str| -> NetworkConfig {
+            serde_yaml::from_str(&format!("nameservers:\n{}", yaml)).unwrap()
+        };
+        let physical = network(
+            "
+interfaces:
+    - identifier: !mac_address
+        address: 00:1a:2b:3c:4d:5e
+      static_addresses:
+      ipv6: dhcp6
+",
+        );
+        assert!(Netifrc.validate(&physical).is_err());
+        let bridged = network(
+            "
+interfaces:
+virtual_interfaces:
+    - name: br0
+      kind: !bridge
+        members:
+      ipv6: dhcp6
+",
+        );
+        assert!(Netifrc.validate(&bridged).is_err());
+        assert!(Netifrc.validate(&network("interfaces:\n")).is_ok());
+    }
+
+    #[test]
+    /// Routes and the MTU have netifrc variables of their own, but turning off IPv6 needs our
+    /// postup() hook, which can't be added alongside one written by hand.
+    fn ip_modes() {
+        let p = single("eth0", ROUTED);
+        assert_eq!(
+            netifrc_stanza(&p, &p.interfaces[0], false),
+            "config_eth0=\"10.0.0.100/24\"\n\
+             routes_eth0=\"default via 10.0.0.1\n192.168.0.0/16 via 10.0.0.254 metric 100\"\n\
+             mtu_eth0=\"9000\"\n\
+             holos_ipv6_eth0=\"disabled\"\n"
+        );
+
+        let network: NetworkConfig = serde_yaml::from_str("nameservers:\ninterfaces:\n").unwrap();
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        let rendered = Netifrc.render(&p, &network, &system).unwrap();
+        let conf = &rendered.files[0].content;
+        assert!(conf.contains("# BEGIN holos-config postup-hook\n# Applies the holos_ipv6_"));
+        rendered.apply(&system).unwrap();
+        assert_eq!(
+            Netifrc
+                .render(&p, &network, &system)
+                .unwrap()
+                .diff(&system)
+                .unwrap(),
+            ""
+        );
+
+        // Without anything needing it, the hook goes away again.
+        let dhcp = single(
+            "eth0",
+            "identifier: !pci_address {address: '0000:01:00.0'}\nstatic_addresses:\n",
+        );
+        let rendered = Netifrc.render(&dhcp, &network, &system).unwrap();
+        assert!(!rendered.files[0].content.contains("postup"));
+
+        fs::write(
+            root.path().join("etc/conf.d/net"),
+            "postup() {\n\treturn 0\n}\n",
+        )
+        .unwrap();
+        assert!(Netifrc.render(&p, &network, &system).is_err());
+    }
+
+    #[test]
+    /// Rendering against an existing system links new init scripts, cleans up the scripts of
+    /// interfaces that are no longer configured, and leaves unchanged files alone.
+    fn render_and_apply() {
+        let (network, p) = bonded_vlan_plan();
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        fs::create_dir_all(root.path().join("etc/init.d")).unwrap();
+        fs::create_dir_all(root.path().join("etc/conf.d")).unwrap();
+        fs::write(
+            root.path().join("etc/conf.d/net"),
+            "# BEGIN holos-config eth9\nconfig_eth9=\"dhcp\"\n# END holos-config eth9\n",
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("net.lo", root.path().join("etc/init.d/net.eth9")).unwrap();
+
+        let rendered = Netifrc.render(&p, &network, &system).unwrap();
+        let links: Vec<&str> = rendered.symlinks.iter().map(|(_, l)| l.as_str()).collect();
+        assert_eq!(
+            links,
+            vec![
+                "/etc/init.d/net.eth0",
+                "/etc/init.d/net.eth1",
+                "/etc/init.d/net.bond0"
+            ]
+        );
+        assert_eq!(rendered.remove, vec!["/etc/init.d/net.eth9"]);
+        assert!(
+            rendered
+                .diff(&system)
+                .unwrap()
+                .contains("-config_eth9=\"dhcp\"")
+        );
+
+        rendered.apply(&system).unwrap();
+        assert!(root.path().join("etc/init.d/net.bond0").is_symlink());
+        assert!(!root.path().join("etc/init.d/net.eth9").is_symlink());
+        let again = Netifrc.render(&p, &network, &system).unwrap();
+        assert_eq!(again.diff(&system).unwrap(), "");
+    }
+}
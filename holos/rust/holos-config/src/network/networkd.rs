@@ -0,0 +1,327 @@
+/// The systemd-networkd backend. Each interface gets a `.network` file of its own, and each
+/// virtual interface a `.netdev` file too, all prefixed so that we can tell them apart from files
+/// written by the administrator. networkd manages DNS through systemd-resolved, so resolv.conf is
+/// left alone.
+use super::{
+    Backend, ConfigFile, InterfaceKind, InterfacePlan, NetworkPlan, Rendered, default_gateways,
+    wpa_supplicant_conf, wpa_supplicant_conf_path,
+};
+use crate::system::SystemRoot;
+use crate::{Ipv4Mode, Ipv6Mode, NetworkConfig};
+use anyhow::{Error, anyhow};
+use log::warn;
+use std::fs;
+use std::io::ErrorKind;
+
+/// Where networkd looks for configuration written by the administrator.
+pub const NETWORKD_DIR: &str = "/etc/systemd/network";
+/// All of our files start with this, which also puts them ahead of most distribution defaults.
+const FILE_PREFIX: &str = "10-holos-";
+/// The per-interface wpa_supplicant unit, which reads `wpa_supplicant-<iface>.conf`.
+const WPA_SUPPLICANT_UNIT_PATH: &str = "/lib/systemd/system/wpa_supplicant@.service";
+const WANTS_DIR: &str = "/etc/systemd/system/multi-user.target.wants";
+
+const HEADER: &str = "# Generated by holos-config. Changes made here will be overwritten.\n";
+
+pub struct Networkd;
+
+impl Backend for Networkd {
+    fn name(&self) -> &'static str {
+        "networkd"
+    }
+
+    fn render(
+        &self,
+        plan: &NetworkPlan,
+        network: &NetworkConfig,
+        system: &SystemRoot,
+    ) -> Result<Rendered, Error> {
+        let mut rendered = Rendered::default();
+        if !network.resolver_options.is_empty() {
+            warn!(
+                "systemd-networkd has no equivalent of resolver options; ignoring {}",
+                network.resolver_options.join(" ")
+            );
+        }
+
+        for iface in &plan.interfaces {
+            if let Some(wireless) = &iface.wireless {
+                rendered.files.push(ConfigFile::secret(
+                    &wpa_supplicant_conf_path(&iface.name),
+                    wpa_supplicant_conf(wireless),
+                ));
+                rendered.symlinks.push((
+                    WPA_SUPPLICANT_UNIT_PATH.to_string(),
+                    format!("{}/wpa_supplicant@{}.service", WANTS_DIR, iface.name),
+                ));
+            }
+            if let Some(netdev) = netdev_unit(iface) {
+                rendered.files.push(ConfigFile::new(
+                    &format!("{}/{}{}.netdev", NETWORKD_DIR, FILE_PREFIX, iface.name),
+                    netdev,
+                ));
+            }
+            rendered.files.push(ConfigFile::new(
+                &format!("{}/{}{}.network", NETWORKD_DIR, FILE_PREFIX, iface.name),
+                network_unit(plan, iface, network),
+            ));
+        }
+
+        // Clean up after interfaces that are no longer configured.
+        rendered.remove = our_files(system)?
+            .into_iter()
+            .map(|name| format!("{}/{}", NETWORKD_DIR, name))
+            .filter(|path| !rendered.files.iter().any(|f| f.path == *path))
+            .collect();
+
+        Ok(rendered)
+    }
+
+    fn reload(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        let mut reconfigure = vec!["networkctl".to_string(), "reconfigure".to_string()];
+        reconfigure.extend(plan.interfaces.iter().map(|i| i.name.clone()));
+        vec![
+            vec!["networkctl".to_string(), "reload".to_string()],
+            reconfigure,
+        ]
+    }
+
+    /// Deletes the virtual interfaces created for `plan`, and takes the others down. Reloading
+    /// brings back whatever the configuration that's put back in place still wants.
+    fn stop(&self, plan: &NetworkPlan) -> Vec<Vec<String>> {
+        let mut delete = vec!["networkctl".to_string(), "delete".to_string()];
+        let mut down = vec!["networkctl".to_string(), "down".to_string()];
+        for iface in plan.interfaces.iter().rev() {
+            match iface.kind {
+                InterfaceKind::Physical => down.push(iface.name.clone()),
+                _ => delete.push(iface.name.clone()),
+            }
+        }
+        [delete, down]
+            .into_iter()
+            .filter(|command| command.len() > 2)
+            .collect()
+    }
+
+    /// Reloads networkd, and reconfigures each interface with a `.network` file of ours.
+    fn reload_existing(&self, system: &SystemRoot) -> Result<Vec<Vec<String>>, Error> {
+        let names: Vec<String> = our_files(system)?
+            .iter()
+            .filter_map(|name| {
+                let iface = name.strip_prefix(FILE_PREFIX)?.strip_suffix(".network")?;
+                Some(iface.to_string())
+            })
+            .collect();
+        let mut commands = vec![vec!["networkctl".to_string(), "reload".to_string()]];
+        if !names.is_empty() {
+            let mut reconfigure = vec!["networkctl".to_string(), "reconfigure".to_string()];
+            reconfigure.extend(names);
+            commands.push(reconfigure);
+        }
+        Ok(commands)
+    }
+}
+
+/// The names of the files of ours in the networkd directory, sorted.
+fn our_files(system: &SystemRoot) -> Result<Vec<String>, Error> {
+    let dir = system.path(NETWORKD_DIR);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
+        Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+        Err(e) => return Err(anyhow!("Unable to list {}: {}", dir.display(), e)),
+    };
+    let mut names: Vec<String> = entries
+        .iter()
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with(FILE_PREFIX))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Renders the `.netdev` file creating a virtual interface, or `None` for physical interfaces.
+pub fn netdev_unit(iface: &InterfacePlan) -> Option<String> {
+    let kind = match &iface.kind {
+        InterfaceKind::Physical => return None,
+        InterfaceKind::Bond { mode, .. } => {
+            format!("bond\n\n[Bond]\nMode={}\nMIIMonitorSec=100ms", mode)
+        }
+        InterfaceKind::Bridge { .. } => "bridge".to_string(),
+        InterfaceKind::Vlan { id, .. } => format!("vlan\n\n[VLAN]\nId={}", id),
+    };
+    Some(format!(
+        "{}[NetDev]\nName={}\nKind={}\n",
+        HEADER, iface.name, kind
+    ))
+}
+
+/// Renders the `.network` file for a single interface from the plan.
+///
+/// Each address family is configured according to its mode, and members of bonds and bridges only
+/// attach to their master. Static nameservers are given to every interface with addresses, and stop
+/// DHCP and router advertisements from supplying their own.
+pub fn network_unit(plan: &NetworkPlan, iface: &InterfacePlan, network: &NetworkConfig) -> String {
+    let mut unit = format!("{}[Match]\nName={}\n\n", HEADER, iface.name);
+    if let Some(mtu) = iface.addressing.as_ref().and_then(|ip| ip.mtu) {
+        unit += format!("[Link]\nMTUBytes={}\n\n", mtu).as_str();
+    }
+    unit += "[Network]\n";
+    match &iface.addressing {
+        None => unit += "LinkLocalAddressing=no\n",
+        Some(ip) => {
+            let dhcp = match (ip.ipv4 == Ipv4Mode::Dhcp4, ip.ipv6 == Ipv6Mode::Dhcp6) {
+                (true, true) => Some("yes"),
+                (true, false) => Some("ipv4"),
+                (false, true) => Some("ipv6"),
+                (false, false) => None,
+            };
+            if let Some(dhcp) = dhcp {
+                unit += format!("DHCP={}\n", dhcp).as_str();
+            }
+            for address in &ip.addresses {
+                unit += format!("Address={}\n", address.address).as_str();
+            }
+            for gateway in default_gateways(&ip.addresses) {
+                unit += format!("Gateway={}\n", gateway).as_str();
+            }
+            match ip.ipv6 {
+                Ipv6Mode::Slaac | Ipv6Mode::Dhcp6 => unit += "IPv6AcceptRA=yes\n",
+                Ipv6Mode::Static => unit += "IPv6AcceptRA=no\n",
+                // Without a link-local address, the interface has no IPv6 at all.
+                Ipv6Mode::Disabled => unit += "LinkLocalAddressing=no\nIPv6AcceptRA=no\n",
+            }
+        }
+    }
+
+    if let Some(master) = plan.master_of(&iface.name) {
+        match master.kind {
+            InterfaceKind::Bond { .. } => unit += format!("Bond={}\n", master.name).as_str(),
+            _ => unit += format!("Bridge={}\n", master.name).as_str(),
+        }
+    }
+    for (_, vlan) in plan.vlans_on(&iface.name) {
+        unit += format!("VLAN={}\n", vlan).as_str();
+    }
+
+    let static_dns = !network.nameservers.is_empty() && iface.addressing.is_some();
+    if static_dns {
+        for ns in &network.nameservers {
+            unit += format!("DNS={}\n", ns).as_str();
+        }
+        if !network.search_domains.is_empty() {
+            unit += format!("Domains={}\n", network.search_domains.join(" ")).as_str();
+        }
+    }
+
+    for route in iface.addressing.iter().flat_map(|ip| &ip.routes) {
+        unit += format!("\n[Route]\nDestination={}\n", route.destination).as_str();
+        if let Some(gateway) = route.gateway {
+            unit += format!("Gateway={}\n", gateway).as_str();
+        }
+        if let Some(metric) = route.metric {
+            unit += format!("Metric={}\n", metric).as_str();
+        }
+    }
+
+    if static_dns {
+        unit += "\n[DHCPv4]\nUseDNS=no\nUseDomains=no\n\n[IPv6AcceptRA]\nUseDNS=no\n";
+    }
+    unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::tests::{ROUTED, bonded_vlan_plan, single};
+    use tempfile::TempDir;
+
+    #[test]
+    fn bonded_vlan() {
+        let (network, p) = bonded_vlan_plan();
+        let root = TempDir::new().unwrap();
+        let system = SystemRoot::new(root.path());
+        fs::create_dir_all(root.path().join("etc/systemd/network")).unwrap();
+        for name in ["10-holos-eth9.network", "20-local.network"] {
+            fs::write(root.path().join("etc/systemd/network").join(name), "").unwrap();
+        }
+
+        let rendered = Networkd.render(&p, &network, &system).unwrap();
+        let paths: Vec<&str> = rendered.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/etc/systemd/network/10-holos-eth0.network",
+                "/etc/systemd/network/10-holos-eth1.network",
+                "/etc/systemd/network/10-holos-bond0.netdev",
+                "/etc/systemd/network/10-holos-bond0.network",
+                "/etc/systemd/network/10-holos-vlan100.netdev",
+                "/etc/systemd/network/10-holos-vlan100.network",
+            ]
+        );
+        assert_eq!(
+            rendered.remove,
+            vec!["/etc/systemd/network/10-holos-eth9.network"]
+        );
+
+        assert_eq!(
+            rendered.files[0].content,
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [Match]\nName=eth0\n\n[Network]\nLinkLocalAddressing=no\nBond=bond0\n"
+        );
+        assert_eq!(
+            rendered.files[2].content,
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [NetDev]\nName=bond0\nKind=bond\n\n[Bond]\nMode=active-backup\nMIIMonitorSec=100ms\n"
+        );
+        assert_eq!(
+            rendered.files[3].content,
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [Match]\nName=bond0\n\n[Network]\nDHCP=ipv4\nIPv6AcceptRA=yes\nVLAN=vlan100\n"
+        );
+        assert_eq!(
+            rendered.files[4].content,
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [NetDev]\nName=vlan100\nKind=vlan\n\n[VLAN]\nId=100\n"
+        );
+    }
+
+    #[test]
+    fn ip_modes() {
+        let p = single("eth0", ROUTED);
+        let network: NetworkConfig = serde_yaml::from_str("nameservers:\ninterfaces:\n").unwrap();
+        assert_eq!(
+            network_unit(&p, &p.interfaces[0], &network),
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [Match]\nName=eth0\n\n[Link]\nMTUBytes=9000\n\n\
+             [Network]\nAddress=10.0.0.100/24\nGateway=10.0.0.1\n\
+             LinkLocalAddressing=no\nIPv6AcceptRA=no\n\n\
+             [Route]\nDestination=192.168.0.0/16\nGateway=10.0.0.254\nMetric=100\n"
+        );
+
+        let p = single(
+            "eth0",
+            "identifier: !pci_address {address: '0000:01:00.0'}\nstatic_addresses:\nipv4: disabled\nipv6: dhcp6\n",
+        );
+        assert!(
+            network_unit(&p, &p.interfaces[0], &network)
+                .contains("[Network]\nDHCP=ipv6\nIPv6AcceptRA=yes\n")
+        );
+    }
+
+    #[test]
+    /// Static nameservers replace whatever DHCP and router advertisements offer.
+    fn static_dns() {
+        let (mut network, p) = bonded_vlan_plan();
+        network.nameservers.push("1.1.1.1".parse().unwrap());
+        network.search_domains.push("example.com".to_string());
+        assert_eq!(
+            network_unit(&p, p.get("vlan100").unwrap(), &network),
+            "# Generated by holos-config. Changes made here will be overwritten.\n\
+             [Match]\nName=vlan100\n\n[Network]\nAddress=10.0.100.2/24\nIPv6AcceptRA=yes\n\
+             DNS=1.1.1.1\nDomains=example.com\n\n\
+             [DHCPv4]\nUseDNS=no\nUseDomains=no\n\n[IPv6AcceptRA]\nUseDNS=no\n"
+        );
+        // Bond members don't have addresses, so don't need nameservers either.
+        assert!(!network_unit(&p, p.get("eth0").unwrap(), &network).contains("DNS"));
+    }
+}
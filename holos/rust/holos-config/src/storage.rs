@@ -0,0 +1,927 @@
+/// Setting up the persistent data partition on an installed system: a filesystem is created on it
+/// if it's blank (and the configuration allows it), it's mounted at [`DATA_DIR`] with an entry in
+/// /etc/fstab so that it comes back after a reboot, and the directories the edge node container
+/// keeps its data in are created on it. When encryption is configured, the filesystem is kept in a
+/// LUKS volume, which is unlocked before it's mounted and listed in /etc/crypttab.
+use crate::blockdev::{Filesystem, is_blank, probe, resolve};
+use crate::cmdline::CmdLine;
+use crate::install::Tools;
+use crate::managed::{ManagedFile, read_or_empty};
+use crate::network::{ConfigFile, Rendered};
+use crate::system::SystemRoot;
+use crate::{StorageConfig, Unlock};
+use anyhow::{Error, anyhow};
+use log::{info, warn};
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+
+/// Where the persistent data partition is mounted. The edge node container is given this as its
+/// `/data` volume.
+pub const DATA_DIR: &str = "/data";
+/// The label given to filesystems (and encrypted volumes) we create on the persistent data
+/// partition.
+pub const DATA_LABEL: &str = "HolOS-data";
+/// The directories the edge node container expects to find under [`DATA_DIR`].
+pub const DATA_LAYOUT: &[&str] = &["holochain", "holochain/etc", "holochain/var", "logs"];
+/// The name an encrypted persistent data partition is unlocked as.
+pub const MAPPED_NAME: &str = "holos-data";
+/// Where the filesystem in an unlocked persistent data partition appears.
+pub const MAPPED_DEVICE: &str = "/dev/mapper/holos-data";
+/// Filesystems that data can be kept on. Anything else found on the partition is left alone.
+const DATA_FILESYSTEMS: &[&str] = &["ext4", "ext3", "ext2", "xfs", "btrfs"];
+/// What blkid (and [`probe`]) call a LUKS volume.
+const LUKS_TYPE: &str = "crypto_LUKS";
+/// The size of the key files we generate.
+const KEY_FILE_SIZE: usize = 64;
+const FSTAB_PATH: &str = "/etc/fstab";
+const CRYPTTAB_PATH: &str = "/etc/crypttab";
+/// The name of the block in /etc/fstab and /etc/crypttab holding the entry for the persistent data
+/// partition.
+const PERSIST_BLOCK: &str = "persist";
+
+/// Something to be done to bring the persistent data partition into use.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Create a LUKS volume on the device, with the given UUID so that the crypttab entry can be
+    /// written before it exists.
+    Encrypt {
+        device: String,
+        uuid: String,
+        unlock: Unlock,
+    },
+    /// Unlock the LUKS volume on the device as [`MAPPED_DEVICE`].
+    Unlock { device: String, unlock: Unlock },
+    /// Create an ext4 filesystem on the device, with the given UUID so that the fstab entry can be
+    /// written before it exists.
+    Format { device: String, uuid: String },
+    /// Mount the device at [`DATA_DIR`]. The filesystem type isn't known when it's in an encrypted
+    /// volume that hasn't been unlocked yet.
+    Mount {
+        device: String,
+        fs_type: Option<String>,
+    },
+    /// Create a directory under [`DATA_DIR`], once it's mounted.
+    CreateDir(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Encrypt { device, unlock, .. } => {
+                write!(
+                    f,
+                    "create an encrypted volume on {}, unlocked by {}",
+                    device, unlock
+                )
+            }
+            Action::Unlock { device, unlock } => write!(f, "unlock {} with {}", device, unlock),
+            Action::Format { device, .. } => write!(f, "create an ext4 filesystem on {}", device),
+            Action::Mount {
+                device,
+                fs_type: Some(fs_type),
+            } => write!(f, "mount {} ({}) on {}", device, fs_type, DATA_DIR),
+            Action::Mount {
+                device,
+                fs_type: None,
+            } => write!(f, "mount {} on {}", device, DATA_DIR),
+            Action::CreateDir(dir) => write!(f, "create {}", dir),
+        }
+    }
+}
+
+/// What needs doing to the persistent data partition. Everything already in place is left out,
+/// so applying a plan twice does nothing the second time.
+#[derive(Debug)]
+pub struct StoragePlan {
+    pub actions: Vec<Action>,
+    /// The updated /etc/fstab, and /etc/crypttab if the partition is encrypted.
+    pub files: Rendered,
+}
+
+/// Works out what needs doing to bring the persistent data partition into use, or None if there
+/// isn't one configured.
+pub fn plan(storage: &StorageConfig, system: &SystemRoot) -> Result<Option<StoragePlan>, Error> {
+    let Some(identifier) = &storage.persist_partition else {
+        return Ok(None);
+    };
+    let location = resolve(system, identifier)?;
+    let device = location.path();
+    if !system.path(&device).exists() {
+        return Err(anyhow!("{} ({}) doesn't exist", device, identifier));
+    }
+    info!("Using {} ({}) for persistent data", device, identifier);
+
+    let mut actions = vec![];
+    let found =
+        probe(&system.path(&device)).map_err(|e| anyhow!("Unable to read {}: {}", device, e))?;
+    let mut files = vec![];
+    let (mounted_device, fs_type, fstab) = match storage.encryption.as_ref().map(|e| &e.unlock) {
+        None => {
+            let (fs_type, uuid) = check_filesystem(&device, found, storage, system, &mut actions)?;
+            let fstab = format!(
+                "UUID={} {} {} defaults,noatime 0 2",
+                uuid, DATA_DIR, fs_type
+            );
+            (device.clone(), Some(fs_type), fstab)
+        }
+        Some(unlock) => {
+            let (uuid, created) = match found {
+                Some(Filesystem {
+                    fs_type,
+                    uuid: Some(uuid),
+                    ..
+                }) if fs_type == LUKS_TYPE => (uuid, false),
+                Some(fs) => {
+                    return Err(anyhow!(
+                        "{} holds an unencrypted {} filesystem, but encryption is configured. It's been left alone",
+                        device,
+                        fs.fs_type
+                    ));
+                }
+                None => {
+                    check_blank(&device, system)?;
+                    if !storage.format_persist {
+                        return Err(blank(&device));
+                    }
+                    let uuid = new_uuid()?;
+                    actions.push(Action::Encrypt {
+                        device: device.clone(),
+                        uuid: uuid.clone(),
+                        unlock: unlock.clone(),
+                    });
+                    (uuid, true)
+                }
+            };
+            files.push(crypttab(system, &uuid, unlock)?);
+
+            let fs_type = if system.path(MAPPED_DEVICE).exists() && !created {
+                let inner = probe(&system.path(MAPPED_DEVICE))
+                    .map_err(|e| anyhow!("Unable to read {}: {}", MAPPED_DEVICE, e))?;
+                Some(check_filesystem(MAPPED_DEVICE, inner, storage, system, &mut actions)?.0)
+            } else {
+                actions.push(Action::Unlock {
+                    device: device.clone(),
+                    unlock: unlock.clone(),
+                });
+                // There's no telling what's inside until it's unlocked, unless we've only just
+                // created it.
+                match created {
+                    true => {
+                        actions.push(Action::Format {
+                            device: MAPPED_DEVICE.to_string(),
+                            uuid: new_uuid()?,
+                        });
+                        Some("ext4".to_string())
+                    }
+                    false => None,
+                }
+            };
+            // Left for us to mount once it's unlocked, rather than at boot.
+            let fstab = format!("{} {} auto noauto,noatime 0 0", MAPPED_DEVICE, DATA_DIR);
+            (MAPPED_DEVICE.to_string(), fs_type, fstab)
+        }
+    };
+
+    match mounted_on(system, DATA_DIR)? {
+        Some(mounted) if mounted == mounted_device => {}
+        Some(mounted) => {
+            return Err(anyhow!(
+                "{} is already mounted on {}, rather than {}",
+                mounted,
+                DATA_DIR,
+                mounted_device
+            ));
+        }
+        None => actions.push(Action::Mount {
+            device: mounted_device,
+            fs_type,
+        }),
+    }
+
+    for dir in DATA_LAYOUT {
+        let dir = format!("{}/{}", DATA_DIR, dir);
+        if !system.path(&dir).is_dir() {
+            actions.push(Action::CreateDir(dir));
+        }
+    }
+
+    let mut managed = ManagedFile::parse(&read_or_empty(&system.path(FSTAB_PATH))?)?;
+    managed.set_block(PERSIST_BLOCK, &fstab);
+    files.insert(0, ConfigFile::new(FSTAB_PATH, managed.render()));
+    Ok(Some(StoragePlan {
+        actions,
+        files: Rendered {
+            files,
+            ..Default::default()
+        },
+    }))
+}
+
+/// Checks that a filesystem data can be kept on is on `device`, or arranges for one to be
+/// created if it's blank and that's allowed. Gives the type and UUID of the filesystem.
+fn check_filesystem(
+    device: &str,
+    found: Option<Filesystem>,
+    storage: &StorageConfig,
+    system: &SystemRoot,
+    actions: &mut Vec<Action>,
+) -> Result<(String, String), Error> {
+    match found {
+        Some(Filesystem {
+            fs_type,
+            uuid: Some(uuid),
+            ..
+        }) if DATA_FILESYSTEMS.contains(&fs_type.as_str()) => Ok((fs_type, uuid)),
+        Some(fs) if fs.fs_type == LUKS_TYPE => Err(anyhow!(
+            "{} is encrypted. Set encryption in the storage configuration to say how to unlock it",
+            device
+        )),
+        Some(fs) => Err(anyhow!(
+            "{} holds {}, which can't be used for persistent data. It's been left alone",
+            device,
+            fs.fs_type
+        )),
+        None => {
+            check_blank(device, system)?;
+            if !storage.format_persist {
+                return Err(blank(device));
+            }
+            let uuid = new_uuid()?;
+            actions.push(Action::Format {
+                device: device.to_string(),
+                uuid: uuid.clone(),
+            });
+            Ok(("ext4".to_string(), uuid))
+        }
+    }
+}
+
+/// Makes sure that a device [`probe`] found nothing on really is blank, rather than holding
+/// something it doesn't recognise, before anything is written to it.
+fn check_blank(device: &str, system: &SystemRoot) -> Result<(), Error> {
+    match is_blank(&system.path(device)) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(anyhow!(
+            "{} holds something that isn't recognised, which can't be used for persistent data. \
+             It's been left alone",
+            device
+        )),
+        Err(e) => Err(anyhow!("Unable to read {}: {}", device, e)),
+    }
+}
+
+fn blank(device: &str) -> Error {
+    anyhow!(
+        "{} is blank. Set format_persist in the storage configuration to have it set up",
+        device
+    )
+}
+
+/// /etc/crypttab with the entry for the encrypted persistent data partition.
+fn crypttab(system: &SystemRoot, uuid: &str, unlock: &Unlock) -> Result<ConfigFile, Error> {
+    let key = match unlock {
+        Unlock::Keyfile { path } => path.as_str(),
+        Unlock::Cmdline | Unlock::Prompt => "none",
+    };
+    let mut managed = ManagedFile::parse(&read_or_empty(&system.path(CRYPTTAB_PATH))?)?;
+    managed.set_block(
+        PERSIST_BLOCK,
+        &format!("{} UUID={} {} luks", MAPPED_NAME, uuid, key),
+    );
+    Ok(ConfigFile::new(CRYPTTAB_PATH, managed.render()))
+}
+
+/// Describes what [`apply`] would do.
+pub fn describe(plan: &StoragePlan, system: &SystemRoot) -> Result<String, Error> {
+    let mut out = String::new();
+    for action in &plan.actions {
+        writeln!(out, "Would {}", action)?;
+    }
+    out += plan.files.diff(system)?.as_str();
+    Ok(out)
+}
+
+/// Carries out a plan. /etc/fstab is only updated once the partition has been mounted, so that a
+/// partition that won't mount doesn't hold up the next boot.
+pub fn apply(plan: &StoragePlan, system: &SystemRoot, tools: &dyn Tools) -> Result<(), Error> {
+    for action in &plan.actions {
+        run(action, system, tools)?;
+    }
+    plan.files.apply(system)
+}
+
+fn run(action: &Action, system: &SystemRoot, tools: &dyn Tools) -> Result<(), Error> {
+    let path = |d: &str| system.path(d).display().to_string();
+    info!("Persistent data: {}", action);
+    match action {
+        Action::Encrypt {
+            device,
+            uuid,
+            unlock,
+        } => {
+            let key = Key::new(unlock, system, true)?;
+            let command = [
+                "cryptsetup",
+                "luksFormat",
+                "--batch-mode",
+                "--type",
+                "luks2",
+                "--uuid",
+                uuid,
+                "--label",
+                DATA_LABEL,
+                "--key-file",
+                &key.file,
+                &path(device),
+            ];
+            key.run(&command, tools)
+        }
+        Action::Unlock { device, unlock } => {
+            let key = Key::new(unlock, system, false)?;
+            let command = [
+                "cryptsetup",
+                "open",
+                "--type",
+                "luks",
+                "--key-file",
+                &key.file,
+                &path(device),
+                MAPPED_NAME,
+            ];
+            key.run(&command, tools)
+        }
+        Action::Format { device, uuid } => {
+            let command = ["mkfs.ext4", "-L", DATA_LABEL, "-U", uuid, &path(device)];
+            tools.run(&command.map(String::from))
+        }
+        Action::Mount { device, fs_type } => {
+            let dir = system.path(DATA_DIR);
+            fs::create_dir_all(&dir)
+                .map_err(|e| anyhow!("Unable to create {}: {}", DATA_DIR, e))?;
+            let mut command = vec!["mount".to_string()];
+            if let Some(fs_type) = fs_type {
+                command.extend(["-t".to_string(), fs_type.clone()]);
+            }
+            command.extend([
+                "-o".to_string(),
+                "noatime".to_string(),
+                path(device),
+                dir.display().to_string(),
+            ]);
+            tools.run(&command)
+        }
+        Action::CreateDir(dir) => fs::create_dir_all(system.path(dir))
+            .map_err(|e| anyhow!("Unable to create {}: {}", dir, e)),
+    }
+}
+
+/// Creates an encrypted volume holding an empty ext4 filesystem on `device`, and locks it again.
+/// Used by the installer, which leaves the rest to `configure` on first boot. A volume that's
+/// already there is kept, unless `overwrite` is given because anything on the device is left over
+/// from before it was partitioned. Otherwise, only a device that's really blank is encrypted.
+pub fn create_encrypted(
+    device: &str,
+    unlock: &Unlock,
+    overwrite: bool,
+    system: &SystemRoot,
+    tools: &dyn Tools,
+) -> Result<(), Error> {
+    match probe(&system.path(device))? {
+        Some(fs) if !overwrite && fs.fs_type == LUKS_TYPE => {
+            info!("{} is already encrypted; keeping it", device);
+            return Ok(());
+        }
+        Some(fs) if !overwrite => {
+            return Err(anyhow!(
+                "{} holds an unencrypted {} filesystem, which encrypting would destroy. It's been left alone",
+                device,
+                fs.fs_type
+            ));
+        }
+        None if !overwrite => check_blank(device, system)?,
+        _ => {}
+    }
+    run(
+        &Action::Encrypt {
+            device: device.to_string(),
+            uuid: new_uuid()?,
+            unlock: unlock.clone(),
+        },
+        system,
+        tools,
+    )?;
+    run(
+        &Action::Unlock {
+            device: device.to_string(),
+            unlock: unlock.clone(),
+        },
+        system,
+        tools,
+    )?;
+    let formatted = run(
+        &Action::Format {
+            device: MAPPED_DEVICE.to_string(),
+            uuid: new_uuid()?,
+        },
+        system,
+        tools,
+    );
+    let locked = tools.run(&["cryptsetup", "close", MAPPED_NAME].map(String::from));
+    formatted.and(locked)
+}
+
+/// The key for an encrypted volume, as cryptsetup's `--key-file` argument and what to give it on
+/// its standard input.
+struct Key {
+    file: String,
+    input: Option<Vec<u8>>,
+}
+
+impl Key {
+    /// Finds the key, generating a key file or asking for a passphrase to be confirmed when
+    /// `creating` a volume.
+    fn new(unlock: &Unlock, system: &SystemRoot, creating: bool) -> Result<Key, Error> {
+        let passphrase = |p: String| Key {
+            file: "-".to_string(),
+            input: Some(p.into_bytes()),
+        };
+        match unlock {
+            Unlock::Keyfile { path } => {
+                let file = system.path(path);
+                if !file.exists() {
+                    if !creating {
+                        return Err(anyhow!("Key file {} is missing", path));
+                    }
+                    generate_key_file(&file)
+                        .map_err(|e| anyhow!("Unable to create key file {}: {}", path, e))?;
+                    info!("Generated key file {}", path);
+                }
+                Ok(Key {
+                    file: file.display().to_string(),
+                    input: None,
+                })
+            }
+            Unlock::Cmdline => {
+                let cmdline = CmdLine::from_file(&system.cmdline().display().to_string())?;
+                let passphrase_given = cmdline.persist_passphrase.ok_or_else(|| {
+                    anyhow!("No persist_passphrase was given on the kernel command line")
+                })?;
+                Ok(passphrase(passphrase_given))
+            }
+            Unlock::Prompt => {
+                let typed = prompt("Passphrase for the persistent data partition: ")?;
+                if creating && prompt("Enter it again: ")? != typed {
+                    return Err(anyhow!("The passphrases didn't match"));
+                }
+                Ok(passphrase(typed))
+            }
+        }
+    }
+
+    fn run(&self, command: &[&str], tools: &dyn Tools) -> Result<(), Error> {
+        let command = command.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        match &self.input {
+            Some(input) => tools.run_with_input(&command, input),
+            None => tools.run(&command),
+        }
+    }
+}
+
+fn generate_key_file(path: &std::path::Path) -> Result<(), Error> {
+    let mut key = [0u8; KEY_FILE_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o400)
+        .open(path)?
+        .write_all(&key)?;
+    Ok(())
+}
+
+/// Asks for a passphrase on the terminal, without echoing it.
+fn prompt(message: &str) -> Result<String, Error> {
+    let mut tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| anyhow!("Unable to ask for a passphrase: {}", e))?;
+    tty.write_all(message.as_bytes())?;
+    let fd = tty.as_raw_fd();
+    // SAFETY: termios is plain data, filled in by tcgetattr before it's used.
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut termios) } == 0;
+    if echo_off {
+        let mut quiet = termios;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) };
+    } else {
+        warn!("Unable to turn off echo; the passphrase will be visible as it's typed");
+    }
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    }
+    tty.write_all(b"\n")?;
+    read?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The device mounted on `dir`, if anything is. Later mounts hide earlier ones on the same
+/// directory, so the last one listed is the one that counts. Not being able to read the list is
+/// an error, rather than taken to mean nothing's mounted, like it is for the installer.
+fn mounted_on(system: &SystemRoot, dir: &str) -> Result<Option<String>, Error> {
+    let mounts = fs::read_to_string(system.path("/proc/mounts")).map_err(|e| {
+        anyhow!(
+            "Unable to read /proc/mounts, so can't tell what's mounted on {}: {}",
+            dir,
+            e
+        )
+    })?;
+    Ok(mounts
+        .lines()
+        .rev()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|(_, point)| *point == dir)
+        .map(|(device, _)| device.to_string()))
+}
+
+/// A random (version 4) UUID.
+fn new_uuid() -> Result<String, Error> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| anyhow!("Unable to generate a UUID: {}", e))?;
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Ok(crate::blockdev::uuid(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockdev::tests::{add_device, ext4_image};
+    use crate::install::tests::Fake;
+    use crate::{EncryptionConfig, StorageIdentifier};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn luks_image(uuid: &str) -> Vec<u8> {
+        let mut luks = vec![0u8; 4096];
+        luks[..6].copy_from_slice(b"LUKS\xba\xbe");
+        luks[7] = 2;
+        luks[168..204].copy_from_slice(uuid.as_bytes());
+        luks
+    }
+
+    fn storage(format_persist: bool) -> StorageConfig {
+        StorageConfig {
+            install_partition: None,
+            persist_partition: Some(StorageIdentifier::Path {
+                path: "/dev/sda2".to_string(),
+            }),
+            format_persist,
+            encryption: None,
+        }
+    }
+
+    fn encrypted(format_persist: bool, unlock: Unlock) -> StorageConfig {
+        StorageConfig {
+            encryption: Some(EncryptionConfig { unlock }),
+            ..storage(format_persist)
+        }
+    }
+
+    fn system(dir: &TempDir, content: &[u8]) -> SystemRoot {
+        let system = SystemRoot::new(dir.path());
+        add_device(&system, "sda", &[]);
+        add_device(&system, "sda2", content);
+        fs::create_dir_all(system.path("/etc")).unwrap();
+        fs::write(system.path(FSTAB_PATH), "/dev/root / ext2 rw,noauto 0 1\n").unwrap();
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(system.path("/proc/mounts"), "proc /proc proc rw 0 0\n").unwrap();
+        system
+    }
+
+    #[test]
+    fn existing_filesystem() {
+        let dir = TempDir::new().unwrap();
+        let system = system(&dir, &ext4_image("data"));
+        let plan = plan(&storage(false), &system).unwrap().unwrap();
+        assert_eq!(
+            plan.actions,
+            vec![
+                Action::Mount {
+                    device: "/dev/sda2".to_string(),
+                    fs_type: Some("ext4".to_string())
+                },
+                Action::CreateDir("/data/holochain".to_string()),
+                Action::CreateDir("/data/holochain/etc".to_string()),
+                Action::CreateDir("/data/holochain/var".to_string()),
+                Action::CreateDir("/data/logs".to_string()),
+            ]
+        );
+        assert!(
+            describe(&plan, &system)
+                .unwrap()
+                .starts_with("Would mount /dev/sda2 (ext4) on /data\n")
+        );
+
+        let tools = Fake::new(&system, PathBuf::new());
+        apply(&plan, &system, &tools).unwrap();
+        assert_eq!(
+            *tools.commands.borrow(),
+            vec!["mount -t ext4 -o noatime /dev/sda2 /data"]
+        );
+        assert!(system.path("/data/logs").is_dir());
+        assert_eq!(
+            fs::read_to_string(system.path(FSTAB_PATH)).unwrap(),
+            "/dev/root / ext2 rw,noauto 0 1\n\
+             # BEGIN holos-config persist\n\
+             UUID=5a5a5a5a-5a5a-5a5a-5a5a-5a5a5a5a5a5a /data ext4 defaults,noatime 0 2\n\
+             # END holos-config persist\n"
+        );
+
+        // Once it's mounted and set up, there's nothing left to do.
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(
+            system.path("/proc/mounts"),
+            "/dev/sda2 /data ext4 rw,noatime 0 0\n",
+        )
+        .unwrap();
+        let plan = super::plan(&storage(false), &system).unwrap().unwrap();
+        assert!(plan.actions.is_empty());
+        assert_eq!(describe(&plan, &system).unwrap(), "");
+
+        fs::write(system.path("/proc/mounts"), "/dev/sdb1 /data ext4 rw 0 0\n").unwrap();
+        assert!(super::plan(&storage(false), &system).is_err());
+
+        // Without the list of mounts, there's no telling whether it's mounted already.
+        fs::remove_file(system.path("/proc/mounts")).unwrap();
+        let err = super::plan(&storage(false), &system)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Unable to read /proc/mounts, so can't tell what's mounted on /data"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn blank() {
+        let dir = TempDir::new().unwrap();
+        let system = system(&dir, &[0; 4096]);
+        let err = plan(&storage(false), &system).unwrap_err().to_string();
+        assert!(err.contains("format_persist"), "{}", err);
+
+        let plan = plan(&storage(true), &system).unwrap().unwrap();
+        let Action::Format { device, uuid } = &plan.actions[0] else {
+            panic!("{:?} doesn't format", plan.actions);
+        };
+        assert_eq!(device, "/dev/sda2");
+        assert_eq!(&uuid[14..15], "4");
+        assert!(
+            plan.files.files[0]
+                .content
+                .contains(&format!("UUID={} /data ext4", uuid))
+        );
+
+        let tools = Fake::new(&system, PathBuf::new());
+        apply(&plan, &system, &tools).unwrap();
+        assert_eq!(
+            tools.commands.borrow()[0],
+            format!("mkfs.ext4 -L HolOS-data -U {} /dev/sda2", uuid)
+        );
+    }
+
+    #[test]
+    fn unusable() {
+        let dir = TempDir::new().unwrap();
+        let mut swap = vec![0u8; 4096];
+        swap[4086..].copy_from_slice(b"SWAPSPACE2");
+        let system = system(&dir, &swap);
+        // Even with formatting allowed, something already on the partition is never overwritten.
+        let err = plan(&storage(true), &system).unwrap_err().to_string();
+        assert!(err.contains("swap"), "{}", err);
+
+        // That goes for things that aren't recognised, like NTFS, too, encrypted or not.
+        let mut ntfs = vec![0u8; 4096];
+        ntfs[3..11].copy_from_slice(b"NTFS    ");
+        fs::write(system.path("/dev/sda2"), &ntfs).unwrap();
+        for storage in [
+            storage(true),
+            storage(false),
+            encrypted(true, Unlock::Prompt),
+        ] {
+            let err = plan(&storage, &system).unwrap_err().to_string();
+            assert_eq!(
+                err,
+                "/dev/sda2 holds something that isn't recognised, which can't be used for \
+                 persistent data. It's been left alone"
+            );
+        }
+
+        let mut missing = storage(false);
+        missing.persist_partition = Some(StorageIdentifier::Path {
+            path: "/dev/sda3".to_string(),
+        });
+        assert!(plan(&missing, &system).is_err());
+        assert!(plan(&StorageConfig::default(), &system).unwrap().is_none());
+    }
+
+    #[test]
+    fn encryption() {
+        let dir = TempDir::new().unwrap();
+        let system = system(&dir, &[0; 4096]);
+        let keyfile = Unlock::Keyfile {
+            path: "/etc/holos/persist.key".to_string(),
+        };
+        let plan = plan(&encrypted(true, keyfile.clone()), &system)
+            .unwrap()
+            .unwrap();
+        let Action::Encrypt { uuid, .. } = &plan.actions[0] else {
+            panic!("{:?} doesn't encrypt", plan.actions);
+        };
+        let Action::Format { uuid: fs_uuid, .. } = &plan.actions[2] else {
+            panic!("{:?} doesn't format", plan.actions);
+        };
+        assert_eq!(
+            plan.actions[..4],
+            [
+                Action::Encrypt {
+                    device: "/dev/sda2".to_string(),
+                    uuid: uuid.clone(),
+                    unlock: keyfile.clone()
+                },
+                Action::Unlock {
+                    device: "/dev/sda2".to_string(),
+                    unlock: keyfile.clone()
+                },
+                Action::Format {
+                    device: MAPPED_DEVICE.to_string(),
+                    uuid: fs_uuid.clone()
+                },
+                Action::Mount {
+                    device: MAPPED_DEVICE.to_string(),
+                    fs_type: Some("ext4".to_string())
+                },
+            ]
+        );
+
+        let tools = Fake::new(&system, PathBuf::new());
+        apply(&plan, &system, &tools).unwrap();
+        assert_eq!(
+            tools.commands.borrow()[..2],
+            [
+                format!(
+                    "cryptsetup luksFormat --batch-mode --type luks2 --uuid {} --label HolOS-data \
+                     --key-file /etc/holos/persist.key /dev/sda2",
+                    uuid
+                ),
+                "cryptsetup open --type luks --key-file /etc/holos/persist.key /dev/sda2 \
+                 holos-data"
+                    .to_string()
+            ]
+        );
+        let key = system.path("/etc/holos/persist.key");
+        assert_eq!(fs::read(&key).unwrap().len(), KEY_FILE_SIZE);
+        assert_eq!(
+            fs::metadata(&key).unwrap().permissions().mode() & 0o777,
+            0o400
+        );
+        assert_eq!(
+            fs::read_to_string(system.path(CRYPTTAB_PATH)).unwrap(),
+            format!(
+                "# BEGIN holos-config persist\n\
+                 holos-data UUID={} /etc/holos/persist.key luks\n\
+                 # END holos-config persist\n",
+                uuid
+            )
+        );
+        assert!(
+            fs::read_to_string(system.path(FSTAB_PATH))
+                .unwrap()
+                .contains("/dev/mapper/holos-data /data auto noauto,noatime 0 0\n")
+        );
+
+        // On later boots, the volume only needs unlocking and mounting. What's in it can't be seen
+        // until then.
+        let uuid = "0b5a8e0e-2c5e-4f0a-9d7a-0c1c3bd2f7f1";
+        fs::write(system.path("/dev/sda2"), luks_image(uuid)).unwrap();
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(
+            system.path("/proc/cmdline"),
+            "root=LABEL=HolOS-system persist_passphrase=secret\n",
+        )
+        .unwrap();
+        let cmdline = encrypted(false, Unlock::Cmdline);
+        let plan = super::plan(&cmdline, &system).unwrap().unwrap();
+        assert_eq!(
+            plan.actions,
+            [
+                Action::Unlock {
+                    device: "/dev/sda2".to_string(),
+                    unlock: Unlock::Cmdline
+                },
+                Action::Mount {
+                    device: MAPPED_DEVICE.to_string(),
+                    fs_type: None
+                },
+            ]
+        );
+        let tools = Fake::new(&system, PathBuf::new());
+        apply(&plan, &system, &tools).unwrap();
+        assert_eq!(
+            *tools.commands.borrow(),
+            [
+                "cryptsetup open --type luks --key-file - /dev/sda2 holos-data",
+                "< secret",
+                "mount -o noatime /dev/mapper/holos-data /data"
+            ]
+        );
+
+        // Once it's unlocked and mounted, there's nothing left to do.
+        fs::create_dir_all(system.path("/dev/mapper")).unwrap();
+        fs::write(system.path(MAPPED_DEVICE), ext4_image("HolOS-data")).unwrap();
+        fs::write(
+            system.path("/proc/mounts"),
+            "/dev/mapper/holos-data /data ext4 rw 0 0\n",
+        )
+        .unwrap();
+        assert!(
+            super::plan(&cmdline, &system)
+                .unwrap()
+                .unwrap()
+                .actions
+                .is_empty()
+        );
+
+        // Encrypted volumes need to be configured as such, and unencrypted filesystems are never
+        // encrypted over.
+        let err = super::plan(&storage(false), &system)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("is encrypted"), "{}", err);
+        fs::write(system.path("/dev/sda2"), ext4_image("data")).unwrap();
+        let err = super::plan(&encrypted(true, Unlock::Prompt), &system)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unencrypted ext4"), "{}", err);
+    }
+
+    #[test]
+    /// The installer only encrypts over something it doesn't recognise on a partition it's just
+    /// created.
+    fn create() {
+        let dir = TempDir::new().unwrap();
+        let mut lvm = vec![0u8; 4096];
+        lvm[512..520].copy_from_slice(b"LABELONE");
+        let system = system(&dir, &lvm);
+        let keyfile = Unlock::Keyfile {
+            path: "/etc/holos/persist.key".to_string(),
+        };
+        let tools = Fake::new(&system, PathBuf::new());
+        let err = create_encrypted("/dev/sda2", &keyfile, false, &system, &tools).unwrap_err();
+        assert!(err.to_string().contains("isn't recognised"), "{}", err);
+        assert!(tools.commands.borrow().is_empty());
+
+        create_encrypted("/dev/sda2", &keyfile, true, &system, &tools).unwrap();
+        assert!(tools.commands.borrow()[0].starts_with("cryptsetup luksFormat"));
+
+        fs::write(system.path("/dev/sda2"), [0u8; 4096]).unwrap();
+        let tools = Fake::new(&system, PathBuf::new());
+        create_encrypted("/dev/sda2", &keyfile, false, &system, &tools).unwrap();
+        assert_eq!(tools.commands.borrow().len(), 4);
+    }
+
+    #[test]
+    fn keys() {
+        let dir = TempDir::new().unwrap();
+        let system = system(&dir, &[]);
+        let keyfile = Unlock::Keyfile {
+            path: "/etc/holos/persist.key".to_string(),
+        };
+        // Key files are only ever generated for a new volume.
+        assert!(Key::new(&keyfile, &system, false).is_err());
+        assert!(Key::new(&keyfile, &system, true).unwrap().input.is_none());
+        assert!(Key::new(&keyfile, &system, false).is_ok());
+
+        fs::create_dir_all(system.path("/proc")).unwrap();
+        fs::write(system.path("/proc/cmdline"), "root=LABEL=HolOS-system\n").unwrap();
+        assert!(Key::new(&Unlock::Cmdline, &system, false).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+/// Everything holos-config reads from or writes to the machine it's running on (sysfs, procfs,
+/// and configuration under /etc) is found through a [`SystemRoot`]. On a real system that's just
+/// `/`, but pointing it at a directory lets detection and configuration run against a synthetic
+/// fixture tree, or an installed system mounted somewhere else.
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// The environment variable that redirects all system access to another directory.
+pub const SYSTEM_ROOT_ENV: &str = "HOLOS_ROOT";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SystemRoot {
+    root: PathBuf,
+}
+
+impl Default for SystemRoot {
+    fn default() -> Self {
+        SystemRoot::new("/")
+    }
+}
+
+impl SystemRoot {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        SystemRoot {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The system root given by `HOLOS_ROOT`, or the real root directory if it isn't set.
+    pub fn from_env() -> Self {
+        match env::var(SYSTEM_ROOT_ENV) {
+            Ok(v) if !v.is_empty() => SystemRoot::new(v),
+            _ => SystemRoot::default(),
+        }
+    }
+
+    /// The directory everything is relative to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Maps an absolute path on the system to where it actually is under this root.
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    /// Where sysfs is mounted.
+    pub fn sysfs(&self) -> PathBuf {
+        self.path("/sys")
+    }
+
+    /// The kernel command line.
+    pub fn cmdline(&self) -> PathBuf {
+        self.path("/proc/cmdline")
+    }
+
+    /// Builds a glob pattern for an absolute path on the system, escaping the root so that any
+    /// special characters in it aren't treated as wildcards.
+    pub fn glob_pattern(&self, pattern: &str) -> String {
+        let root = glob::Pattern::escape(&self.root.to_string_lossy());
+        format!(
+            "{}/{}",
+            root.trim_end_matches('/'),
+            pattern.trim_start_matches('/')
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let host = SystemRoot::default();
+        assert_eq!(host.sysfs(), Path::new("/sys"));
+        assert_eq!(host.glob_pattern("/sys/class/*"), "/sys/class/*");
+
+        let fixture = SystemRoot::new("/tmp/fixture[1]");
+        assert_eq!(
+            fixture.path("/etc/conf.d/net"),
+            Path::new("/tmp/fixture[1]/etc/conf.d/net")
+        );
+        assert_eq!(fixture.cmdline(), Path::new("/tmp/fixture[1]/proc/cmdline"));
+        assert_eq!(
+            fixture.glob_pattern("/sys/class/*"),
+            "/tmp/fixture[[]1[]]/sys/class/*"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Installs onto a loop device backed by a disk image, from another standing in for the
+//! installation media. This needs root, and loop devices with partition support, so it's only run
+//! when asked for: `cargo test -- --ignored`.
+use holos_config::HolosConfig;
+use holos_config::blockdev::probe;
+use holos_config::install::archive::{ArchiveEntry, Content, pack};
+use holos_config::install::{Installer, Live, Slot, Tools};
+use holos_config::system::SystemRoot;
+use std::fs;
+use std::fs::File;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// The real tools, except that there's no bootloader to install into an image.
+struct NoBootloader;
+
+impl Tools for NoBootloader {
+    fn run(&self, command: &[String]) -> Result<(), anyhow::Error> {
+        match command[0].as_str() {
+            "grub-install" => Ok(()),
+            _ => Live.run(command),
+        }
+    }
+
+    fn run_with_input(&self, command: &[String], input: &[u8]) -> Result<(), anyhow::Error> {
+        Live.run_with_input(command, input)
+    }
+}
+
+fn run(command: &[&str]) -> String {
+    let output = Command::new(command[0])
+        .args(&command[1..])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?} failed", command);
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Detaches a loop device once the test is done with it, whether it passed or not.
+struct Loop(String);
+
+impl Drop for Loop {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup").args(["-d", &self.0]).status();
+    }
+}
+
+fn attach(image: &std::path::Path, size: u64) -> Loop {
+    File::create(image).unwrap().set_len(size).unwrap();
+    Loop(run(&[
+        "losetup",
+        "-f",
+        "-P",
+        "--show",
+        &image.display().to_string(),
+    ]))
+}
+
+#[test]
+#[ignore]
+fn loopback() {
+    let dir = TempDir::new().unwrap();
+
+    // The installation media, as an ext4 filesystem rather than ISO9660 so that it can be made
+    // without any more tools.
+    let media = dir.path().join("media");
+    fs::create_dir_all(media.join("boot")).unwrap();
+    fs::write(media.join("boot/bzImage"), "kernel").unwrap();
+    let entries = vec![
+        ArchiveEntry::new("bin", Content::Directory, 0o755),
+        ArchiveEntry::new("bin/busybox", Content::Data(b"busybox".to_vec()), 0o755),
+        ArchiveEntry::new("usr", Content::Directory, 0o755),
+        ArchiveEntry::new("usr/bin", Content::Directory, 0o755),
+        ArchiveEntry::new(
+            "usr/bin/holos-config",
+            Content::Data(b"holos-config".to_vec()),
+            0o755,
+        ),
+        ArchiveEntry::new("dev", Content::Directory, 0o755),
+        ArchiveEntry::new("dev/console", Content::CharDevice(5, 1), 0o600),
+        ArchiveEntry::new("lib", Content::Directory, 0o755),
+        ArchiveEntry::new(
+            "lib/ld-musl-x86_64.so.1",
+            Content::Data(b"musl".to_vec()),
+            0o755,
+        ),
+    ];
+    let rootfs = File::create(media.join("boot/rootfs.cpio.bz2")).unwrap();
+    pack(
+        &entries,
+        bzip2::write::BzEncoder::new(rootfs, bzip2::Compression::fast()),
+    )
+    .unwrap()
+    .finish()
+    .unwrap();
+    let source = attach(&dir.path().join("media.img"), 16 << 20);
+    run(&[
+        "mkfs.ext4",
+        "-q",
+        "-L",
+        "HolOS-install",
+        "-d",
+        &media.display().to_string(),
+        &source.0,
+    ]);
+
+    let target = attach(&dir.path().join("disk.img"), 64 << 20);
+    let config: HolosConfig = serde_yaml::from_str(&format!(
+        "
+storage:
+    install_partition: {}p1
+network:
+    nameservers:
+    interfaces:
+security:
+    github_usernames:
+    ssh_keys:
+    rootpw_hash:
+",
+        target.0
+    ))
+    .unwrap();
+
+    let system = SystemRoot::default();
+    let mut installer = Installer::new(&system, &NoBootloader);
+    installer.work_dir = dir.path().join("work");
+    installer.install(&config).unwrap();
+
+    let partition = format!("{}p1", target.0);
+    assert_eq!(
+        probe(partition.as_ref())
+            .unwrap()
+            .and_then(|fs| fs.label)
+            .as_deref(),
+        Some(Slot::A.label())
+    );
+    let mounted = dir.path().join("check");
+    fs::create_dir(&mounted).unwrap();
+    run(&[
+        "mount",
+        "-o",
+        "ro",
+        &partition,
+        &mounted.display().to_string(),
+    ]);
+    let kernel = fs::read_to_string(mounted.join("boot/bzImage"));
+    let initrd = mounted.join("boot/holos-initrd.img").exists();
+    let grub = fs::read_to_string(mounted.join("boot/grub/grub.cfg"));
+    run(&["umount", &mounted.display().to_string()]);
+    assert_eq!(kernel.unwrap(), "kernel");
+    assert!(initrd);
+    assert!(grub.unwrap().contains("root=LABEL=HolOS-system-a"));
+}
@@ -0,0 +1,164 @@
+//! Runs model detection and network configuration end to end against the system trees in
+//! `tests/fixtures`. The fixtures are synthetic: each is written by hand to look like just enough
+//! of the sysfs, procfs and /etc of a HoloPort, a HoloPort Plus or a VM for holos-config to work
+//! with, rather than captured from a real machine.
+use holos_config::HolosConfig;
+use holos_config::cmdline::CmdLine;
+use holos_config::models::Model;
+use holos_config::network::{
+    Backend, BackendKind, automatic_interfaces, netifrc::Netifrc, plan, record_automatic,
+};
+use holos_config::system::SystemRoot;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn manifest_path(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+/// Copies a fixture into a scratch directory, so that configuration can be written into it
+/// without touching the fixture. Symbolic links are copied as links.
+fn copy_tree(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path()).unwrap(), &target).unwrap();
+        } else if file_type.is_dir() {
+            copy_tree(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+fn fixture(name: &str) -> (TempDir, SystemRoot) {
+    let scratch = TempDir::new().unwrap();
+    copy_tree(
+        &manifest_path(&format!("tests/fixtures/{}", name)),
+        scratch.path(),
+    );
+    let system = SystemRoot::new(scratch.path());
+    (scratch, system)
+}
+
+fn config(yaml: &str) -> HolosConfig {
+    let config: HolosConfig = serde_yaml::from_str(yaml).unwrap();
+    config.validate().unwrap();
+    config
+}
+
+/// Detects the model, then configures the network with `config` and returns the resulting
+/// netifrc configuration file.
+fn configure(system: &SystemRoot, config: &HolosConfig) -> String {
+    assert_eq!(BackendKind::detect(system), BackendKind::Netifrc);
+    let plan = plan(&config.network, &system.sysfs()).unwrap();
+    assert!(plan.failures.is_empty(), "{:?}", plan.failures);
+    let rendered = Netifrc.render(&plan, &config.network, system).unwrap();
+    rendered.apply(system).unwrap();
+    record_automatic(&plan, system).unwrap();
+
+    // A second run has nothing left to do.
+    let again = Netifrc.render(&plan, &config.network, system).unwrap();
+    assert_eq!(again.diff(system).unwrap(), "");
+    fs::read_to_string(system.path("/etc/conf.d/net")).unwrap()
+}
+
+#[test]
+fn holoport() {
+    let (_scratch, system) = fixture("holoport");
+    assert!(matches!(
+        Model::detect_model(&system).unwrap(),
+        Model::Holoport
+    ));
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string()).unwrap();
+    assert!(!cmdline.live_flag);
+
+    let yaml = fs::read_to_string(manifest_path("contrib/holoport.yaml")).unwrap();
+    assert_eq!(
+        configure(&system, &config(&yaml)),
+        "# Hand-written settings are preserved.\n\
+         # BEGIN holos-config enp1s0\n\
+         config_enp1s0=\"dhcp\"\n\
+         udhcpc_enp1s0=\"-b -t 7\"\n\
+         # END holos-config enp1s0\n"
+    );
+    assert_eq!(
+        fs::read_link(system.path("/etc/init.d/net.enp1s0")).unwrap(),
+        Path::new("/etc/init.d/net.lo")
+    );
+}
+
+#[test]
+fn holoport_plus() {
+    let (_scratch, system) = fixture("holoport-plus");
+    assert!(matches!(
+        Model::detect_model(&system).unwrap(),
+        Model::HoloportPlus
+    ));
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string()).unwrap();
+    assert_eq!(cmdline.github_usernames, vec!["holosupport"]);
+
+    // Select the NIC by driver rather than address this time.
+    let yaml = fs::read_to_string(manifest_path("contrib/holoport-plus.yaml"))
+        .unwrap()
+        .replace(
+            "!pci_address\n            address: \"0000:01:00.0\"",
+            "!driver\n            names: [igb, r8169]",
+        );
+    assert!(yaml.contains("!driver"));
+    assert!(configure(&system, &config(&yaml)).contains("config_enp1s0=\"dhcp\"\n"));
+}
+
+#[test]
+fn vm() {
+    let (_scratch, system) = fixture("vm");
+    assert!(matches!(
+        Model::detect_model(&system).unwrap(),
+        Model::VirtioVM
+    ));
+    let cmdline = CmdLine::from_file(&system.cmdline().display().to_string()).unwrap();
+    assert!(cmdline.live_flag);
+
+    let config = config(
+        "
+storage:
+    install_partition: /dev/vda1
+network:
+    nameservers:
+        - 10.0.2.3
+    interfaces:
+        - identifier: !virtio
+            address: '0000:00:03.0'
+          static_addresses:
+            - address: 10.0.2.15/24
+              gateway: 10.0.2.2
+security:
+    github_usernames:
+    ssh_keys:
+    rootpw_hash:
+",
+    );
+    assert!(
+        configure(&system, &config)
+            .contains("config_eth0=\"10.0.2.15/24\"\nroutes_eth0=\"default via 10.0.2.2\"\n")
+    );
+    assert!(
+        fs::read_to_string(system.path("/etc/resolv.conf"))
+            .unwrap()
+            .contains("nameserver 10.0.2.3\n")
+    );
+}
+
+#[test]
+/// The default configuration doesn't name any interfaces, so brings up whatever is wired.
+fn automatic() {
+    let (_scratch, system) = fixture("vm");
+    let yaml = fs::read_to_string(manifest_path("contrib/default.yaml")).unwrap();
+    assert!(configure(&system, &config(&yaml)).contains("config_eth0=\"dhcp\"\n"));
+    assert_eq!(automatic_interfaces(&system).unwrap(), vec!["eth0"]);
+}